@@ -0,0 +1,65 @@
+//! Steps a dense, uniform field of moving balls under several broad phase margins
+//!
+//! Run with `cargo bench --bench broad_phase`.
+
+use std::time::{Duration, Instant};
+
+use rustlike::{sim, state};
+
+/// Balls along each side of the square field
+const SIDE: usize = 64;
+/// Steps timed for each configuration when benchmarking
+const STEPS: u32 = 200;
+
+fn main() {
+    // `cargo test` runs benchmarks without `--bench` only to check that they work
+    let steps = if std::env::args().any(|x| x == "--bench") {
+        STEPS
+    } else {
+        1
+    };
+    for &margin in &[0.25, 1.0, 4.0, 16.0] {
+        let elapsed = run(sim::BroadPhaseConfig { margin }, steps);
+        println!("margin {:>5}: {:?} per step", margin, elapsed / steps);
+    }
+}
+
+fn run(config: sim::BroadPhaseConfig, steps: u32) -> Duration {
+    let mut state = state::State::new();
+    sim::set_broad_phase(&mut state.world, config);
+    let radius = state
+        .world
+        .read_resource::<state::WorldScale>()
+        .ball_radius();
+    let spacing = 2.5 * radius;
+    for i in 0..SIDE * SIDE {
+        let position = na::Vector2::new((i % SIDE) as f32, (i / SIDE) as f32) * spacing;
+        let entity = sim::spawn_ball(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_storage(),
+            *state.world.read_resource(),
+            sim::Layer::ACTOR,
+            position,
+            radius,
+            sim::TOUCH_CONTACTS,
+        )
+        .expect("spawn limit reached");
+        // Spread headings evenly without any randomness, so runs are comparable
+        let heading = i as f32 * 2.399_963;
+        state
+            .world
+            .write_storage()
+            .insert(
+                entity,
+                sim::Velocity(na::Vector2::new(heading.cos(), heading.sin()) * radius),
+            )
+            .unwrap();
+    }
+    let start = Instant::now();
+    for _ in 0..steps {
+        state.step();
+    }
+    start.elapsed()
+}
@@ -0,0 +1,100 @@
+//! Plans paths across a grid-shaped mesh, with and without the route cache's help
+//!
+//! Run with `cargo bench --bench navmesh`.
+
+use std::time::{Duration, Instant};
+
+use rustlike::navmesh::{Edge, NavMesh, Node};
+
+/// Nodes along each side of the square mesh
+const SIDE: u32 = 64;
+/// Distinct (start, goal) pairs planned between, few enough for every route to stay cached
+const PAIRS: u32 = 32;
+/// Times each pair is planned when benchmarking
+const ROUNDS: u32 = 20;
+
+fn main() {
+    // `cargo test` runs benchmarks without `--bench` only to check that they work
+    let rounds = if std::env::args().any(|x| x == "--bench") {
+        ROUNDS
+    } else {
+        1
+    };
+    let mesh = grid();
+    let pairs = (0..PAIRS)
+        .map(|i| {
+            // Spread pairs over the mesh without any randomness, so runs are comparable
+            let start = (i * 7919) % (SIDE * SIDE);
+            let goal = (i * 104_729 + SIDE * SIDE / 2) % (SIDE * SIDE);
+            (start, center(start), goal, center(goal))
+        })
+        .collect::<Vec<_>>();
+
+    // Clones start with an empty cache, so every plan searches
+    let mut uncached = Duration::default();
+    for _ in 0..rounds {
+        for &(start_node, ref start, goal_node, ref goal) in &pairs {
+            let fresh = mesh.clone();
+            let begin = Instant::now();
+            fresh.plan(start_node, start, goal_node, goal).unwrap();
+            uncached += begin.elapsed();
+        }
+    }
+    for &(start_node, ref start, goal_node, ref goal) in &pairs {
+        mesh.plan(start_node, start, goal_node, goal).unwrap();
+    }
+    let begin = Instant::now();
+    for _ in 0..rounds {
+        for &(start_node, ref start, goal_node, ref goal) in &pairs {
+            mesh.plan(start_node, start, goal_node, goal).unwrap();
+        }
+    }
+    let cached = begin.elapsed();
+    let plans = rounds * PAIRS;
+    println!("uncached: {:?} per plan", uncached / plans);
+    println!("cached:   {:?} per plan", cached / plans);
+}
+
+fn center(node: u32) -> na::Point2<f32> {
+    na::Point2::new((node % SIDE) as f32 + 0.5, (node / SIDE) as f32 + 0.5)
+}
+
+/// A mesh of unit squares, each joined to its neighbors on all four sides
+fn grid() -> NavMesh {
+    let nodes = (0..SIDE * SIDE)
+        .map(|i| {
+            let (x, y) = (i % SIDE, i / SIDE);
+            let corner = |dx: u32, dy: u32| na::Point2::new((x + dx) as f32, (y + dy) as f32);
+            let mut edges = Vec::new();
+            if x + 1 < SIDE {
+                edges.push(Edge {
+                    vertices: [corner(1, 1), corner(1, 0)],
+                    neighbor: i + 1,
+                });
+            }
+            if x > 0 {
+                edges.push(Edge {
+                    vertices: [corner(0, 0), corner(0, 1)],
+                    neighbor: i - 1,
+                });
+            }
+            if y + 1 < SIDE {
+                edges.push(Edge {
+                    vertices: [corner(0, 1), corner(1, 1)],
+                    neighbor: i + SIDE,
+                });
+            }
+            if y > 0 {
+                edges.push(Edge {
+                    vertices: [corner(1, 0), corner(0, 0)],
+                    neighbor: i - SIDE,
+                });
+            }
+            Node {
+                center: center(i),
+                edges,
+            }
+        })
+        .collect();
+    NavMesh::new(nodes)
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(push_constant) uniform Params {
+    layout(offset = 64) vec4 tint;
+};
+
+void main() {
+    if (dot(uv, uv) > 1.0) {
+        discard;
+    }
+    // Premultiplied alpha
+    color = vec4(tint.rgb * tint.a, tint.a);
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(push_constant) uniform Params {
+    mat3x4 transform;
+    vec2 dimensions;
+    layout(offset = 80) vec2 pivot;
+};
+
+// Position within the quad, in [-1, 1]
+layout(location = 0) out vec2 uv;
+
+void main() {
+    uv = vec2(gl_VertexIndex & 1, gl_VertexIndex >> 1) * 2.0 - 1.0;
+    vec3 local = vec3(uv * 0.5 * dimensions - pivot, 1.0);
+    gl_Position = vec4((transform * local).xy, 0.0, 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+
+layout(location = 0) out vec4 color;
+
+layout(push_constant) uniform Params {
+    // Center of the visible area, in framebuffer coordinates
+    vec2 center;
+    // Distance from `center` beyond which the scene is fully dimmed, in pixels
+    float radius;
+    // Width of the transition from visible to dimmed, in pixels
+    float falloff;
+    // Factor the scene's brightness is multiplied by outside the visible area
+    float brightness;
+};
+
+// Blended such that the scene's color is multiplied by the output alpha
+void main() {
+    float d = distance(gl_FragCoord.xy, center);
+    color = vec4(0.0, 0.0, 0.0, mix(1.0, brightness, smoothstep(radius - falloff, radius, d)));
+}
@@ -0,0 +1,10 @@
+#version 450
+
+// Position within the screen, in [0, 1]
+layout(location = 0) out vec2 uv;
+
+// Draw with 3 vertices to cover the viewport with a single triangle
+void main() {
+    uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,46 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(push_constant) uniform Params {
+    // Size of a texel of `scene` in texture coordinates
+    vec2 texel;
+    float exposure;
+    float gamma;
+};
+
+const float REDUCE_MIN = 1.0 / 128.0;
+const float REDUCE_MUL = 1.0 / 8.0;
+const float SPAN_MAX = 8.0;
+const vec3 LUMA = vec3(0.299, 0.587, 0.114);
+
+void main() {
+    vec4 rgba_m = texture(scene, uv);
+    float luma_nw = dot(texture(scene, uv + vec2(-1.0, -1.0) * texel).rgb, LUMA);
+    float luma_ne = dot(texture(scene, uv + vec2(1.0, -1.0) * texel).rgb, LUMA);
+    float luma_sw = dot(texture(scene, uv + vec2(-1.0, 1.0) * texel).rgb, LUMA);
+    float luma_se = dot(texture(scene, uv + vec2(1.0, 1.0) * texel).rgb, LUMA);
+    float luma_m = dot(rgba_m.rgb, LUMA);
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Blur along the local edge direction
+    vec2 dir = vec2((luma_sw + luma_se) - (luma_nw + luma_ne),
+                    (luma_nw + luma_sw) - (luma_ne + luma_se));
+    float reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float scale = 1.0 / (min(abs(dir.x), abs(dir.y)) + reduce);
+    dir = clamp(dir * scale, vec2(-SPAN_MAX), vec2(SPAN_MAX)) * texel;
+
+    vec3 rgb_a = 0.5 * (texture(scene, uv + dir * (1.0 / 3.0 - 0.5)).rgb
+                        + texture(scene, uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    vec3 rgb_b = 0.5 * rgb_a + 0.25 * (texture(scene, uv - dir * 0.5).rgb
+                                       + texture(scene, uv + dir * 0.5).rgb);
+    float luma_b = dot(rgb_b, LUMA);
+    // Fall back to the narrower blur if the wider one sampled across a different edge
+    vec3 rgb = (luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b;
+    color = vec4(pow(rgb * exposure, vec3(1.0 / gamma)), rgba_m.a);
+}
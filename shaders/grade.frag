@@ -0,0 +1,19 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(push_constant) uniform Params {
+    // Unused; shares its layout with fxaa.frag
+    vec2 texel;
+    float exposure;
+    float gamma;
+};
+
+void main() {
+    vec4 rgba = texture(scene, uv);
+    color = vec4(pow(rgba.rgb * exposure, vec3(1.0 / gamma)), rgba.a);
+}
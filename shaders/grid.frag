@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) out vec4 color;
+
+layout(push_constant) uniform Params {
+    layout(offset = 80) vec4 line_color;
+};
+
+void main() {
+    // Premultiplied alpha
+    color = vec4(line_color.rgb * line_color.a, line_color.a);
+}
@@ -0,0 +1,25 @@
+#version 450
+
+layout(push_constant) uniform Params {
+    mat3x4 viewproj;
+    // Visible region, in world space
+    vec2 lo;
+    vec2 hi;
+    // Position of the first vertical and horizontal lines
+    vec2 first;
+    float spacing;
+    // Number of vertical lines; the remainder are horizontal
+    uint columns;
+};
+
+void main() {
+    uint line = uint(gl_VertexIndex) >> 1;
+    bool end = (gl_VertexIndex & 1) != 0;
+    vec2 world;
+    if (line < columns) {
+        world = vec2(first.x + float(line) * spacing, end ? hi.y : lo.y);
+    } else {
+        world = vec2(end ? hi.x : lo.x, first.y + float(line - columns) * spacing);
+    }
+    gl_Position = vec4((viewproj * vec3(world, 1.0)).xy, 0.0, 1.0);
+}
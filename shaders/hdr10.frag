@@ -0,0 +1,40 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(push_constant) uniform Params {
+    // Unused; shares its layout with fxaa.frag
+    vec2 texel;
+    float exposure;
+    float gamma;
+};
+
+// Luminance of a scene value of 1.0, in nits
+const float PAPER_WHITE = 200.0;
+
+// Linear BT.709 primaries to BT.2020, column-major
+const mat3 BT709_TO_BT2020 = mat3(
+    0.6274, 0.0691, 0.0164,
+    0.3293, 0.9195, 0.0880,
+    0.0433, 0.0114, 0.8956);
+
+// SMPTE ST 2084 inverse EOTF
+vec3 pq(vec3 nits) {
+    const float m1 = 0.1593017578125;
+    const float m2 = 78.84375;
+    const float c1 = 0.8359375;
+    const float c2 = 18.8515625;
+    const float c3 = 18.6875;
+    vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(m1));
+    return pow((c1 + c2 * y) / (1.0 + c3 * y), vec3(m2));
+}
+
+void main() {
+    vec4 rgba = texture(scene, uv);
+    vec3 graded = pow(max(rgba.rgb * exposure, 0.0), vec3(1.0 / gamma));
+    color = vec4(pq(BT709_TO_BT2020 * graded * PAPER_WHITE), rgba.a);
+}
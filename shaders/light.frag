@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(push_constant) uniform Params {
+    // Linear RGB; alpha is unused
+    layout(offset = 64) vec4 tint;
+};
+
+// Brightest at the center of the quad, fading smoothly to nothing at the inscribed circle
+void main() {
+    float falloff = max(1.0 - dot(uv, uv), 0.0);
+    color = vec4(tint.rgb * falloff * falloff, 0.0);
+}
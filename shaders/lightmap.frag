@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0, binding = 0) uniform sampler2D light;
+
+// Blended such that the scene's color is multiplied by the output color
+void main() {
+    color = vec4(texture(light, uv).rgb, 1.0);
+}
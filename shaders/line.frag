@@ -0,0 +1,10 @@
+#version 450
+
+layout(location = 0) in vec4 color_in;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    // Premultiplied alpha
+    color = vec4(color_in.rgb * color_in.a, color_in.a);
+}
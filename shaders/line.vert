@@ -0,0 +1,15 @@
+#version 450
+
+layout(push_constant) uniform Params {
+    mat3x4 viewproj;
+};
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec4 color_in;
+
+layout(location = 0) out vec4 color_out;
+
+void main() {
+    color_out = color_in;
+    gl_Position = vec4((viewproj * vec3(position, 1.0)).xy, 0.0, 1.0);
+}
@@ -0,0 +1,28 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(push_constant) uniform Params {
+    mat3x4 transform;
+    vec2 dimensions;
+    float radius;
+    float softness;
+    vec4 tint;
+};
+
+void main() {
+    vec2 half_size = 0.5 * dimensions;
+    float r = min(radius, min(half_size.x, half_size.y));
+    // Signed distance from the rounded rectangle's edge, in world units
+    vec2 q = abs(uv * half_size) - half_size + r;
+    float dist = length(max(q, 0.0)) + min(max(q.x, q.y), 0.0) - r;
+    // Fade inwards over about a pixel at any zoom, widened by any requested softness, so the
+    // edge never reaches past the quad
+    float width = max(fwidth(dist), 1e-6) + softness;
+    float coverage = clamp(-dist / width, 0.0, 1.0);
+    // Premultiplied alpha
+    float alpha = tint.a * coverage;
+    color = vec4(tint.rgb * alpha, alpha);
+}
@@ -0,0 +1,18 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0, binding = 0) uniform sampler2D tex;
+
+layout(push_constant) uniform Params {
+    layout(offset = 64) vec4 tint;
+    layout(offset = 88) vec2 uv_scale;
+};
+
+void main() {
+    vec4 texel = texture(tex, (vec2(uv.x, -uv.y) * 0.5 + 0.5) * uv_scale) * tint;
+    // Premultiplied alpha
+    color = vec4(texel.rgb * texel.a, texel.a);
+}
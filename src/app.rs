@@ -0,0 +1,932 @@
+//! Windows, rendering, and simulation bundled together for driving from any event loop
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ash::extensions::khr::Swapchain;
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+use log::{info, warn};
+use specs::RunNow;
+
+use crate::render::Renderer;
+use crate::{capture, console, graphics, profile, render, replay, sim, state, texture, window};
+
+/// Number of times to recreate an out-of-date swapchain before skipping a frame
+const MAX_ACQUIRE_ATTEMPTS: u32 = 8;
+/// Delay before each retry but the first, doubling every time
+const ACQUIRE_BACKOFF: Duration = Duration::from_millis(1);
+/// Factor gamma is scaled by per brightness adjustment keypress
+const GAMMA_STEP: f32 = 1.1;
+/// Radians the camera turns per Q or E keypress
+const CAMERA_ROTATION_STEP: f32 = std::f32::consts::PI / 12.0;
+/// Logical pixels left clear at each edge of the window when Home frames every collider
+const FIT_VIEW_PADDING: f32 = 32.0;
+
+/// How an `App` renders and what it reports
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    pub anti_alias: render::AntiAlias,
+    /// Allow adjusting brightness with the - and = keys
+    pub color_grading: bool,
+    /// Minimum fraction of samples shaded individually under MSAA
+    pub sample_shading: Option<f32>,
+    /// Present in an HDR format where the surface offers one
+    pub hdr: bool,
+    /// Device to replace a lost one with
+    pub device_preference: graphics::DevicePreference,
+    /// Only render when something changed, rather than continuously
+    pub on_demand: bool,
+    /// Log the average time spent in each phase of the main loop every second
+    pub profile: bool,
+    /// Log what the renderer did every frame
+    pub render_stats: bool,
+    /// Log what collision detection did every frame that ran a step
+    pub collision_stats: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            anti_alias: render::AntiAlias::None,
+            color_grading: false,
+            sample_shading: None,
+            hdr: false,
+            device_preference: graphics::DevicePreference::HighPerformance,
+            on_demand: false,
+            profile: false,
+            render_stats: false,
+            collision_stats: false,
+        }
+    }
+}
+
+/// A simulation and the windows it's shown in, driven by an event loop owned elsewhere
+///
+/// Pass every event from the loop to `handle_event`, then call `update` and `render_frame` once per
+/// iteration. The first window is the main one: closing it stops the app, while closing any other
+/// just removes it.
+pub struct App {
+    gfx: Arc<graphics::Graphics>,
+    outputs: Vec<Output>,
+    state: state::State,
+    /// Runs text submitted with Return
+    console: console::Console,
+    options: Options,
+    adaptive_quality: render::AdaptiveQuality,
+    recording: Option<replay::Recorder>,
+    playback: Option<replay::Replay>,
+    /// Steps run since startup, which recorded inputs are timed by
+    steps_run: u64,
+    last_frame: Instant,
+    last_report: Instant,
+    running: bool,
+    redraw: bool,
+    /// Whether input has arrived that hasn't yet been processed by a step
+    input_pending: bool,
+    /// Whether the device must be replaced before anything more can be drawn
+    device_lost: bool,
+    /// Whether the app was idle as of the last `update`, in which case the time since then is
+    /// assumed to have been spent waiting for events rather than simulated
+    was_idle: bool,
+}
+
+impl App {
+    /// Show `state` in `windows`, all of which must be supported by `gfx`
+    ///
+    /// A `TextureLoader` with default filtering is added to `state` if it doesn't already have one.
+    pub fn new(
+        gfx: Arc<graphics::Graphics>,
+        windows: Vec<Arc<window::Window>>,
+        mut state: state::State,
+        options: Options,
+    ) -> Self {
+        assert!(!windows.is_empty(), "an app needs a main window");
+        if !state.world.res.has_value::<texture::TextureLoader>() {
+            let loader = texture::TextureLoader::new(gfx.clone(), texture::Filtering::default());
+            state.world.add_resource(loader);
+        }
+        let outputs = windows
+            .into_iter()
+            .map(|window| {
+                Output::new(
+                    gfx.clone(),
+                    window,
+                    options.anti_alias,
+                    options.color_grading,
+                    options.sample_shading,
+                    options.hdr,
+                )
+            })
+            .collect();
+        let now = Instant::now();
+        Self {
+            gfx,
+            outputs,
+            state,
+            console: console::Console::default(),
+            options,
+            adaptive_quality: render::AdaptiveQuality::default(),
+            recording: None,
+            playback: None,
+            steps_run: 0,
+            last_frame: now,
+            last_report: now,
+            running: true,
+            redraw: true,
+            input_pending: false,
+            device_lost: false,
+            was_idle: false,
+        }
+    }
+
+    pub fn state(&self) -> &state::State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut state::State {
+        &mut self.state
+    }
+
+    /// The device currently in use, which changes if the previous one was lost
+    pub fn graphics(&self) -> &Arc<graphics::Graphics> {
+        &self.gfx
+    }
+
+    /// Whether the main window is still open
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Size of the main window
+    pub fn window_size(&self) -> winit::dpi::LogicalSize {
+        self.outputs[0].size
+    }
+
+    /// Whether nothing will change until another event arrives, so the event loop may block
+    pub fn is_idle(&self) -> bool {
+        !self.redraw
+            && !self.input_pending
+            && !self.device_lost
+            && self.playback.is_none()
+            && self.state.is_idle()
+            && !self
+                .state
+                .world
+                .read_resource::<texture::TextureLoader>()
+                .is_loading()
+    }
+
+    /// Request `mode` for every window's swapchain
+    pub fn set_present_mode(&mut self, mode: vk::PresentModeKHR) {
+        if mode == self.outputs[0].swapchain.preferred_present_mode() {
+            return;
+        }
+        for output in &mut self.outputs {
+            output.swapchain.set_present_mode(mode);
+            unsafe {
+                output.rebuild();
+            }
+        }
+        self.redraw = true;
+    }
+
+    /// Write numbered PNGs of the main window's frames into `dir`, `rate` times a second
+    pub fn capture(&mut self, dir: PathBuf, rate: f32) {
+        let output = &mut self.outputs[0];
+        if !output.swapchain.readable() {
+            warn!("surface doesn't support reading back frames, not capturing");
+        } else if output.swapchain.is_hdr() {
+            warn!("frames can't be captured from HDR surfaces, not capturing");
+        } else {
+            match capture::FrameCapture::new(self.gfx.clone(), dir.clone(), rate) {
+                Ok(x) => output.capture = Some(x),
+                Err(e) => warn!("failed to create {}: {}", dir.display(), e),
+            }
+        }
+    }
+
+    /// Write every input handled from now on to `recorder`
+    pub fn record(&mut self, recorder: replay::Recorder) {
+        self.recording = Some(recorder);
+    }
+
+    /// Feed inputs from `replay` in place of live input until it runs out
+    pub fn replay(&mut self, replay: replay::Replay) {
+        self.playback = Some(replay);
+    }
+
+    /// Respond to an event from the loop the windows were created with
+    pub fn handle_event(&mut self, event: &winit::Event) {
+        let (window_id, event) = match event {
+            winit::Event::WindowEvent { window_id, event } => (*window_id, event),
+            _ => return,
+        };
+        // Live input would diverge from the recorded session
+        if self.playback.is_some() && replay::Input::is_input(event) {
+            return;
+        }
+        if let Some(index) = self
+            .outputs
+            .iter()
+            .position(|x| x.window.window.id() == window_id)
+        {
+            self.handle_window_event(index, event);
+        }
+    }
+
+    fn handle_window_event(&mut self, index: usize, event: &winit::WindowEvent) {
+        use winit::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
+        let output = &mut self.outputs[index];
+        if let Some(ref mut recording) = self.recording {
+            if let Some(input) = replay::Input::from_event(event, output.size) {
+                if let Err(e) = recording.record(self.steps_run, index, input) {
+                    warn!("failed to record input: {}", e);
+                }
+            }
+        }
+        if let WindowEvent::KeyboardInput {
+            input:
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    ..
+                },
+            ..
+        } = *event
+        {
+            if self.state.text_input_active() {
+                // Typing shouldn't trigger actions; releases still go through, so
+                // keys held beforehand don't get stuck
+                return;
+            }
+        }
+        if let WindowEvent::KeyboardInput {
+            input:
+                KeyboardInput {
+                    state: s,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+            ..
+        } = *event
+        {
+            if self.state.key_input(key, s == ElementState::Pressed) {
+                self.input_pending = true;
+            }
+        }
+        match *event {
+            WindowEvent::CloseRequested => {
+                if index == 0 {
+                    self.running = false;
+                } else {
+                    self.outputs.remove(index);
+                }
+            }
+            WindowEvent::Resized(size) => {
+                output.suboptimal = true;
+                output.size = output.window.constrain_resize(size);
+                self.redraw = true;
+            }
+            WindowEvent::Refresh => {
+                self.redraw = true;
+            }
+            WindowEvent::Focused(focused) => {
+                self.state.set_focused(focused);
+                self.input_pending = true;
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = position.to_physical(output.window.dpi_factor());
+                self.state.move_cursor(
+                    &na::Point2::new(position.x as f32, position.y as f32),
+                    &physical_size(&output.window),
+                );
+            }
+            WindowEvent::ReceivedCharacter(c) => {
+                if !self.state.receive_character(c) {
+                    return;
+                }
+                let submitted = {
+                    let input = self.state.world.read_resource::<state::TextInput>();
+                    if input.active {
+                        info!("text input: {:?}", input.text);
+                    }
+                    match (&input.submitted, c) {
+                        (Some(text), '\r') if !input.active => Some(text.clone()),
+                        _ => None,
+                    }
+                };
+                if let Some(text) = submitted {
+                    match self.console.run(&mut self.state, &text) {
+                        Ok(output) => info!("> {}\n{}", text, output),
+                        Err(e) => warn!("> {}\n{}", text, e),
+                    }
+                    self.redraw = true;
+                }
+            }
+            WindowEvent::MouseInput {
+                button: MouseButton::Left,
+                state: s,
+                ..
+            } => {
+                self.state.cursor_pressed(s == ElementState::Pressed);
+                self.input_pending = true;
+            }
+            WindowEvent::MouseInput {
+                button: MouseButton::Middle,
+                state: ElementState::Pressed,
+                ..
+            } => {
+                let position = self.state.world.read_resource::<state::Cursor>().position;
+                render::ping(&self.state.world, position);
+                self.input_pending = true;
+            }
+            WindowEvent::MouseInput {
+                button: MouseButton::Right,
+                state: ElementState::Pressed,
+                ..
+            } => {
+                // Select and report what's under the cursor, topmost first
+                let cursor = self
+                    .state
+                    .world
+                    .read_resource::<state::Cursor>()
+                    .window_position;
+                let center = physical_size(&output.window) * 0.5;
+                let position = na::Point2::new(center.x + cursor.x, center.y - cursor.y);
+                let picked = output.render.pick(position);
+                if let Some(entity) = picked {
+                    let parallax = self
+                        .state
+                        .world
+                        .read_storage::<render::Parallax>()
+                        .get(entity)
+                        .map_or(1.0, |x| x.0);
+                    info!("picked {:?} on parallax layer {}", entity, parallax);
+                }
+                self.state.select(picked);
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F5),
+                        ..
+                    },
+                ..
+            } => {
+                // Start over from an empty world
+                self.state.reset();
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F3),
+                        ..
+                    },
+                ..
+            } => {
+                let mut debug = self.state.world.write_resource::<render::DebugDraw>();
+                debug.aabbs = !debug.aabbs;
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key @ VirtualKeyCode::Minus),
+                        ..
+                    },
+                ..
+            }
+            | WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key @ VirtualKeyCode::Equals),
+                        ..
+                    },
+                ..
+            } if self.options.color_grading => {
+                let mut grading = self.state.world.write_resource::<render::ColorGrading>();
+                grading.gamma *= if key == VirtualKeyCode::Equals {
+                    GAMMA_STEP
+                } else {
+                    1.0 / GAMMA_STEP
+                };
+                info!("gamma {:.2}", grading.gamma);
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key @ VirtualKeyCode::Q),
+                        ..
+                    },
+                ..
+            }
+            | WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key @ VirtualKeyCode::E),
+                        ..
+                    },
+                ..
+            } => {
+                // Q turns counterclockwise, E clockwise
+                self.state.rotate_camera(if key == VirtualKeyCode::Q {
+                    CAMERA_ROTATION_STEP
+                } else {
+                    -CAMERA_ROTATION_STEP
+                });
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Home),
+                        ..
+                    },
+                ..
+            } => {
+                // Frame every collider
+                let viewport = physical_size(&output.window);
+                let padding = FIT_VIEW_PADDING * output.window.dpi_factor() as f32;
+                if self.state.fit_view(viewport, padding) {
+                    self.redraw = true;
+                }
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F9),
+                        ..
+                    },
+                ..
+            } => {
+                // Exercise recovery as if the device had been lost
+                self.device_lost = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::P),
+                        ..
+                    },
+                ..
+            } => {
+                let paused = !self.state.is_paused();
+                self.state.set_paused(paused);
+                info!("{}", if paused { "paused" } else { "resumed" });
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Period),
+                        ..
+                    },
+                ..
+            } => {
+                // Advance a single step while paused
+                self.state.step_once();
+                self.redraw = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(index) = zoom_preset_index(key) {
+                    self.state.select_zoom_preset(index);
+                    self.input_pending = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advance the simulation by the time passed since the last call
+    ///
+    /// Returns the number of steps run.
+    pub fn update(&mut self) -> u32 {
+        if let Some(mut playback) = self.playback.take() {
+            for (index, input) in playback.take_due(self.steps_run) {
+                if let Some(size) = self.outputs.get(index).map(|x| x.size) {
+                    self.handle_window_event(index, &input.to_event(size));
+                }
+            }
+            if playback.is_finished() {
+                info!("replay finished");
+            } else {
+                self.playback = Some(playback);
+            }
+        }
+        for output in &mut self.outputs {
+            if output.suboptimal {
+                unsafe {
+                    output.rebuild();
+                }
+            }
+        }
+        let now = Instant::now();
+        if self.was_idle {
+            self.last_frame = now;
+        }
+        // Stop short of the next recorded input so it's handled after the same step as before
+        let steps = match self.playback.as_ref().and_then(|x| x.next_step()) {
+            Some(next) => {
+                let limit = (next - self.steps_run).min(u64::from(std::u32::MAX)) as u32;
+                self.state.advance_at_most(now - self.last_frame, limit)
+            }
+            None => self.state.advance(now - self.last_frame),
+        };
+        self.steps_run += u64::from(steps);
+        self.last_frame = now;
+        if self.options.collision_stats && steps > 0 {
+            info!(
+                "{}",
+                *self.state.world.read_resource::<sim::CollisionStats>()
+            );
+        }
+        // While paused, input waits for the next step without keeping the loop awake
+        if self.input_pending && (steps > 0 || self.state.is_paused()) {
+            self.input_pending = false;
+            self.redraw = true;
+        }
+        if !self.state.is_idle() {
+            self.redraw = true;
+        }
+        if self.options.profile && now - self.last_report >= Duration::from_secs(1) {
+            let mut profiler = self.state.world.write_resource::<profile::Profiler>();
+            info!("{}", *profiler);
+            profiler.reset();
+            self.last_report = now;
+        }
+        self.was_idle = self.is_idle();
+        steps
+    }
+
+    /// Draw to every window if anything changed, or unconditionally unless `Options::on_demand`
+    ///
+    /// Returns whether a frame was drawn or attempted.
+    pub fn render_frame(&mut self) -> bool {
+        if self.device_lost {
+            unsafe {
+                recover_device_lost(
+                    &mut self.gfx,
+                    &mut self.outputs,
+                    &self.state.world,
+                    self.options.device_preference,
+                );
+            }
+            self.device_lost = false;
+            self.redraw = true;
+        }
+        match unsafe {
+            self.state
+                .world
+                .write_resource::<texture::TextureLoader>()
+                .poll()
+        } {
+            Ok(ready) => self.redraw |= ready,
+            Err(_) => {
+                // Recover on the next call
+                self.device_lost = true;
+                return false;
+            }
+        }
+        if self.options.on_demand && !self.redraw {
+            return false;
+        }
+        self.redraw = false;
+        for output in &mut self.outputs {
+            match unsafe { output.draw(&self.state.world) } {
+                Ok(true) => {
+                    if self.options.render_stats {
+                        info!(
+                            "{}; {:?}, {} images",
+                            *self.state.world.read_resource::<render::RenderStats>(),
+                            output.swapchain.present_mode(),
+                            output.swapchain.image_count()
+                        );
+                        if let Some(budget) = self.gfx.memory_usage() {
+                            info!("{}", budget);
+                        }
+                    }
+                }
+                // Try again once the surface settles down
+                Ok(false) => self.redraw = true,
+                Err(_) => {
+                    self.device_lost = true;
+                    break;
+                }
+            }
+        }
+        self.adaptive_quality.run_now(&self.state.world.res);
+        let quality = *self.state.world.read_resource::<render::Quality>();
+        for output in &mut self.outputs {
+            unsafe {
+                output.set_quality(quality);
+            }
+        }
+        true
+    }
+}
+
+/// Index into `state::ZOOM_PRESETS` selected by a number key
+fn zoom_preset_index(key: winit::VirtualKeyCode) -> Option<usize> {
+    use winit::VirtualKeyCode::*;
+    [Key1, Key2, Key3].iter().position(|&x| x == key)
+}
+
+/// Replace a lost device and everything created from it, keeping the same windows and world
+///
+/// # Safety
+/// - Must not be called while rendering is in progress
+unsafe fn recover_device_lost(
+    gfx: &mut Arc<graphics::Graphics>,
+    outputs: &mut Vec<Output>,
+    world: &specs::World,
+    preference: graphics::DevicePreference,
+) {
+    warn!("graphics device lost, recreating");
+    let windows = outputs.iter().map(|x| x.window.clone()).collect::<Vec<_>>();
+    // The lost device's pipeline cache can't be retrieved, so start from scratch
+    *gfx = Arc::new(
+        graphics::Graphics::new(
+            gfx.core.clone(),
+            preference,
+            &[],
+            &[Swapchain::name()],
+            |physical, queue_family| windows.iter().all(|x| x.supports(physical, queue_family)),
+        )
+        .expect("no suitable device to recover with"),
+    );
+    *outputs = outputs.drain(..).map(|x| x.recreate(gfx.clone())).collect();
+    let loader = world
+        .read_resource::<texture::TextureLoader>()
+        .recreate(gfx.clone());
+    *world.write_resource::<texture::TextureLoader>() = loader;
+}
+
+/// Size of `window`'s drawable area in physical pixels, as the camera measures the view
+fn physical_size(window: &window::Window) -> na::Vector2<f32> {
+    let size = window.physical_size();
+    na::Vector2::new(size.width as f32, size.height as f32)
+}
+
+/// The views of `swapchain`'s images, in order
+fn image_views(swapchain: &window::SwapchainMgr) -> Vec<vk::ImageView> {
+    swapchain.frames().iter().map(|x| x.view).collect()
+}
+
+/// A window and the resources used to draw to it
+struct Output {
+    gfx: Arc<graphics::Graphics>,
+    window: Arc<window::Window>,
+    size: winit::dpi::LogicalSize,
+    swapchain: window::SwapchainMgr,
+    render: render::Render,
+    /// Anti-aliasing requested on the command line, which `Quality` may override
+    anti_alias: render::AntiAlias,
+    color_grading: bool,
+    /// Minimum fraction of samples shaded individually under MSAA
+    sample_shading: Option<f32>,
+    /// Whether an HDR surface format was requested
+    hdr: bool,
+    /// What `render` was created with
+    quality: render::Quality,
+    image_available: vk::Semaphore,
+    render_complete: vk::Semaphore,
+    capture: Option<capture::FrameCapture>,
+    /// Whether the swapchain must be recreated before the next frame
+    suboptimal: bool,
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        let device = &*self.gfx.device;
+        unsafe {
+            device.destroy_semaphore(self.image_available, None);
+            device.destroy_semaphore(self.render_complete, None);
+        }
+    }
+}
+
+impl Output {
+    fn new(
+        gfx: Arc<graphics::Graphics>,
+        window: Arc<window::Window>,
+        anti_alias: render::AntiAlias,
+        color_grading: bool,
+        sample_shading: Option<f32>,
+        hdr: bool,
+    ) -> Self {
+        let size = window.window.get_inner_size().unwrap();
+        let swapchain = window::SwapchainMgr::new(window.clone(), gfx.clone(), hdr);
+        let mut render = render::Render::new(
+            gfx.clone(),
+            swapchain.surface_format(),
+            swapchain.extent(),
+            anti_alias,
+            color_grading,
+            sample_shading,
+        );
+        unsafe {
+            render.resize(swapchain.extent(), &image_views(&swapchain));
+            let image_available = gfx
+                .device
+                .create_semaphore(&Default::default(), None)
+                .unwrap();
+            let render_complete = gfx
+                .device
+                .create_semaphore(&Default::default(), None)
+                .unwrap();
+            Self {
+                gfx,
+                window,
+                size,
+                swapchain,
+                render,
+                anti_alias,
+                color_grading,
+                sample_shading,
+                hdr,
+                quality: render::Quality::default(),
+                image_available,
+                render_complete,
+                capture: None,
+                suboptimal: false,
+            }
+        }
+    }
+
+    /// Recreate the swapchain and everything that depends on it
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    unsafe fn rebuild(&mut self) {
+        self.swapchain.update();
+        self.render
+            .resize(self.swapchain.extent(), &image_views(&self.swapchain));
+        debug_assert_eq!(
+            self.render.image_count() as usize,
+            self.swapchain.frames().len()
+        );
+        self.suboptimal = false;
+    }
+
+    /// Create an equivalent output using `gfx`, e.g. after the device was lost
+    ///
+    /// Frame capture isn't carried over.
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    unsafe fn recreate(self, gfx: Arc<graphics::Graphics>) -> Self {
+        if self.capture.is_some() {
+            warn!("frame capture stopped");
+        }
+        let window = self.window.clone();
+        let (anti_alias, color_grading, hdr) = (self.anti_alias, self.color_grading, self.hdr);
+        let sample_shading = self.sample_shading;
+        let (present_mode, quality) = (self.swapchain.preferred_present_mode(), self.quality);
+        // The surface can only have one swapchain at a time
+        drop(self);
+        let mut output = Self::new(gfx, window, anti_alias, color_grading, sample_shading, hdr);
+        if present_mode != output.swapchain.preferred_present_mode() {
+            output.swapchain.set_present_mode(present_mode);
+            output.rebuild();
+        }
+        output.set_quality(quality);
+        output
+    }
+
+    /// Recreate the renderer if `quality` differs from what it was created with
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    unsafe fn set_quality(&mut self, quality: render::Quality) {
+        if quality == self.quality {
+            return;
+        }
+        self.quality = quality;
+        let anti_alias = match self.anti_alias {
+            render::AntiAlias::Msaa(_) if !quality.msaa => render::AntiAlias::None,
+            x => x,
+        };
+        self.render = render::Render::new(
+            self.gfx.clone(),
+            self.swapchain.surface_format(),
+            self.swapchain.extent(),
+            anti_alias,
+            self.color_grading,
+            self.sample_shading,
+        );
+        self.render
+            .resize(self.swapchain.extent(), &image_views(&self.swapchain));
+    }
+
+    /// Render `world` and present the result, waiting for completion
+    ///
+    /// Returns `Ok(false)` if the frame was skipped because the swapchain couldn't be brought up
+    /// to date, e.g. due to the window being resized rapidly, or `ERROR_DEVICE_LOST`.
+    unsafe fn draw(&mut self, world: &specs::World) -> VkResult<bool> {
+        let gfx = self.gfx.clone();
+        // Catch resizes before acquiring, rather than after a suboptimal frame
+        if self.suboptimal || self.swapchain.is_stale() {
+            self.rebuild();
+        }
+        let mut attempts = 0;
+        let image_index = loop {
+            match self.swapchain.acquire_next_image(self.image_available) {
+                Ok((idx, sub)) => {
+                    self.suboptimal |= sub;
+                    break idx;
+                }
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                    attempts += 1;
+                    if attempts == MAX_ACQUIRE_ATTEMPTS {
+                        warn!(
+                            "swapchain still out of date after {} attempts, skipping frame",
+                            attempts
+                        );
+                        self.suboptimal = true;
+                        return Ok(false);
+                    }
+                    if attempts > 1 {
+                        thread::sleep(ACQUIRE_BACKOFF * (1 << (attempts - 2)));
+                    }
+                    self.rebuild();
+                }
+                Err(e @ vk::Result::ERROR_DEVICE_LOST) => return Err(e),
+                Err(e) => {
+                    panic!("{}", e);
+                }
+            }
+        };
+        let record = render::draw_frame(
+            &mut self.render,
+            world,
+            image_index,
+            self.image_available,
+            self.render_complete,
+        );
+        let mut profiler = world.write_resource::<profile::Profiler>();
+        let mut present_wait = self.render_complete;
+        if let Some(ref mut capture) = self.capture {
+            if capture.is_due() {
+                let start = Instant::now();
+                present_wait = capture.copy(
+                    self.swapchain.frames()[image_index as usize].image,
+                    self.swapchain.extent(),
+                    self.render_complete,
+                );
+                profiler.record("capture", start.elapsed());
+            }
+        }
+        let start = Instant::now();
+        match self
+            .swapchain
+            .queue_present(gfx.queue, present_wait, image_index)
+        {
+            Ok(false) => {}
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.suboptimal = true;
+            }
+            Err(e @ vk::Result::ERROR_DEVICE_LOST) => return Err(e),
+            Err(e) => panic!("{}", e),
+        };
+        profiler.record("present", start.elapsed());
+        let start = Instant::now();
+        // FIXME
+        match gfx.device.queue_wait_idle(gfx.queue) {
+            Ok(()) => {}
+            Err(e @ vk::Result::ERROR_DEVICE_LOST) => return Err(e),
+            Err(e) => panic!("{}", e),
+        }
+        let gpu = start.elapsed();
+        profiler.record("gpu", gpu);
+        if let Some(ref mut capture) = self.capture {
+            capture.finish();
+        }
+        world
+            .write_resource::<profile::FrameStats>()
+            .record(record + gpu);
+        Ok(true)
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use log::{info, warn};
+
+use crate::graphics::Graphics;
+
+/// Number of frames that may await encoding before further frames are dropped
+const MAX_PENDING: usize = 8;
+
+/// Reads back presented frames and writes them as PNGs numbered by capture interval
+///
+/// Encoding happens on a background thread so rendering only waits on the copy itself. Intervals
+/// missed during a stall, and frames dropped because encoding fell behind, leave gaps in the
+/// numbering so it stays proportional to presentation time.
+pub struct FrameCapture {
+    gfx: Arc<Graphics>,
+    interval: Duration,
+    /// When the next frame is due
+    next: Option<Instant>,
+    /// Number of the interval starting at `next`
+    next_index: u64,
+    command_pool: vk::CommandPool,
+    cmd: vk::CommandBuffer,
+    copied: vk::Semaphore,
+    buffer: Option<Readback>,
+    /// Interval number of the copy in `buffer`, if not yet handed off for encoding
+    pending: Option<u64>,
+    send: Option<mpsc::SyncSender<Pixels>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for FrameCapture {
+    fn drop(&mut self) {
+        let device = &*self.gfx.device;
+        unsafe {
+            if let Some(ref buffer) = self.buffer {
+                buffer.destroy(device);
+            }
+            device.destroy_semaphore(self.copied, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+        // Let the encoder finish writing everything it was handed
+        self.send = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl FrameCapture {
+    /// Capture `rate` frames per second of presentation time into `dir`
+    ///
+    /// Images read back must be B8G8R8A8.
+    pub fn new(gfx: Arc<Graphics>, dir: PathBuf, rate: f32) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (send, recv) = mpsc::sync_channel::<Pixels>(MAX_PENDING);
+        let thread = thread::spawn(move || {
+            for pixels in recv {
+                let path = dir.join(format!("frame_{:06}.png", pixels.index));
+                if let Err(e) = write_png(&path, pixels) {
+                    warn!("failed to write {}: {}", path.display(), e);
+                }
+            }
+        });
+        let device = &*gfx.device;
+        unsafe {
+            let command_pool = device
+                .create_command_pool(
+                    &vk::CommandPoolCreateInfo::builder()
+                        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                        .queue_family_index(gfx.queue_family),
+                    None,
+                )
+                .unwrap();
+            let cmd = device
+                .allocate_command_buffers(
+                    &vk::CommandBufferAllocateInfo::builder()
+                        .command_pool(command_pool)
+                        .level(vk::CommandBufferLevel::PRIMARY)
+                        .command_buffer_count(1),
+                )
+                .unwrap()
+                .into_iter()
+                .next()
+                .unwrap();
+            let copied = device.create_semaphore(&Default::default(), None).unwrap();
+            Ok(Self {
+                gfx,
+                interval: Duration::from_secs_f32(1.0 / rate),
+                next: None,
+                next_index: 0,
+                command_pool,
+                cmd,
+                copied,
+                buffer: None,
+                pending: None,
+                send: Some(send),
+                thread: Some(thread),
+            })
+        }
+    }
+
+    /// Whether the frame about to be presented should be captured
+    pub fn is_due(&self) -> bool {
+        match self.next {
+            Some(next) => Instant::now() >= next,
+            None => true,
+        }
+    }
+
+    /// Copy `image` into host memory once `wait` is signaled, returning a semaphore to present
+    /// after
+    ///
+    /// # Safety
+    /// - `image` must have been created with `TRANSFER_SRC` usage and be in `PRESENT_SRC_KHR`
+    ///   layout when `wait` is signaled
+    /// - The previous copy must have completed
+    /// - Must not be called concurrently with other submissions to `Graphics::queue`
+    pub unsafe fn copy(
+        &mut self,
+        image: vk::Image,
+        extent: vk::Extent2D,
+        wait: vk::Semaphore,
+    ) -> vk::Semaphore {
+        let now = Instant::now();
+        // Advance by whole intervals to hold a steady cadence, without bursting after a stall.
+        // The frame stands for the latest interval that has begun; any before it are skipped.
+        let mut index = self.next_index;
+        let mut next = self.next.unwrap_or(now) + self.interval;
+        while next <= now {
+            next += self.interval;
+            index += 1;
+        }
+        self.next = Some(next);
+        self.next_index = index + 1;
+
+        let gfx = &*self.gfx;
+        let device = &*gfx.device;
+        let stale = match self.buffer {
+            Some(ref x) => x.extent.width != extent.width || x.extent.height != extent.height,
+            None => true,
+        };
+        if stale {
+            if let Some(buffer) = self.buffer.take() {
+                buffer.destroy(device);
+            }
+            self.buffer = Some(Readback::new(gfx, extent));
+        }
+        let buffer = self.buffer.as_ref().unwrap();
+
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let cmd = self.cmd;
+        device
+            .begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )
+            .unwrap();
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::builder()
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .build()],
+        );
+        device.cmd_copy_image_to_buffer(
+            cmd,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            buffer.buffer,
+            &[vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                image_extent: vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                },
+            }],
+        );
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[vk::BufferMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer.buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE)
+                .build()],
+            &[vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .build()],
+        );
+        device.end_command_buffer(cmd).unwrap();
+
+        let result = device.queue_submit(
+            gfx.queue,
+            &[vk::SubmitInfo::builder()
+                .wait_semaphores(&[wait])
+                .wait_dst_stage_mask(&[vk::PipelineStageFlags::TRANSFER])
+                .command_buffers(&[cmd])
+                .signal_semaphores(&[self.copied])
+                .build()],
+            vk::Fence::null(),
+        );
+        match result {
+            // Reported again on presentation
+            Ok(()) | Err(vk::Result::ERROR_DEVICE_LOST) => {}
+            Err(e) => panic!("{}", e),
+        }
+        self.pending = Some(index);
+        self.copied
+    }
+
+    /// Hand the most recent copy off for encoding
+    ///
+    /// # Safety
+    /// - The copy submitted by `copy`, if any, must have completed
+    pub unsafe fn finish(&mut self) {
+        let index = match self.pending.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let buffer = self.buffer.as_ref().unwrap();
+        let pixels = Pixels {
+            index,
+            bgra: slice::from_raw_parts(buffer.mapped, buffer.size).to_vec(),
+            width: buffer.extent.width,
+            height: buffer.extent.height,
+        };
+        if let Err(mpsc::TrySendError::Full(_)) = self.send.as_ref().unwrap().try_send(pixels) {
+            info!("frame capture falling behind, dropping a frame");
+        }
+    }
+}
+
+/// Host-visible buffer a frame is copied into
+struct Readback {
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    mapped: *const u8,
+    size: usize,
+    extent: vk::Extent2D,
+}
+
+impl Readback {
+    unsafe fn new(gfx: &Graphics, extent: vk::Extent2D) -> Self {
+        let device = &*gfx.device;
+        let size = extent.width as usize * extent.height as usize * 4;
+        let buffer = device
+            .create_buffer(
+                &vk::BufferCreateInfo::builder()
+                    .size(size as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                None,
+            )
+            .unwrap();
+        let reqs = device.get_buffer_memory_requirements(buffer);
+        let memory_type = gfx
+            .memory_type(
+                reqs.memory_type_bits,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )
+            .expect("no suitable memory type for frame capture");
+        let memory = device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(reqs.size)
+                    .memory_type_index(memory_type),
+                None,
+            )
+            .unwrap();
+        device.bind_buffer_memory(buffer, memory, 0).unwrap();
+        let mapped = device
+            .map_memory(
+                memory,
+                0,
+                size as vk::DeviceSize,
+                vk::MemoryMapFlags::empty(),
+            )
+            .unwrap() as *const u8;
+        gfx.set_name(buffer, cstr!("frame capture"));
+        Self {
+            buffer,
+            memory,
+            mapped,
+            size,
+            extent,
+        }
+    }
+
+    unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_buffer(self.buffer, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+struct Pixels {
+    /// Capture interval the frame was taken in
+    index: u64,
+    bgra: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+fn write_png(path: &Path, mut pixels: Pixels) -> Result<(), png::EncodingError> {
+    for pixel in pixels.bgra.chunks_mut(4) {
+        pixel.swap(0, 2);
+        // The swapchain is composited opaque regardless of what was rendered
+        pixel[3] = 0xFF;
+    }
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        pixels.width,
+        pixels.height,
+    );
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels.bgra)?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! User preferences that persist between runs
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use ash::vk;
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::DevicePreference;
+
+/// Settings saved on exit and restored at startup
+///
+/// Fields missing from a saved file, e.g. one written by an older version, take their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Logical width and height of the main window
+    pub window_size: [f64; 2],
+    pub present_mode: PresentMode,
+    pub device_preference: DevicePreference,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_size: [1280.0, 720.0],
+            present_mode: PresentMode::Mailbox,
+            device_preference: DevicePreference::HighPerformance,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Write to `path`, creating its parent directory if necessary
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// How frames are synchronized with the display, falling back to `Fifo` where unsupported
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PresentMode {
+    /// Wait for vertical blank, replacing any frame already waiting with a newer one
+    Mailbox,
+    /// Wait for vertical blank, queueing every frame
+    Fifo,
+    /// Present without waiting for vertical blank, which may tear
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "mailbox" => PresentMode::Mailbox,
+            "fifo" | "vsync" => PresentMode::Fifo,
+            "immediate" => PresentMode::Immediate,
+            _ => return None,
+        })
+    }
+}
+
+impl From<PresentMode> for vk::PresentModeKHR {
+    fn from(x: PresentMode) -> Self {
+        match x {
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_default() {
+        let config: Config = serde_json::from_str(r#"{ "present_mode": "Immediate" }"#).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                present_mode: PresentMode::Immediate,
+                ..Config::default()
+            }
+        );
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<Config>(&json).unwrap(), config);
+    }
+}
@@ -0,0 +1,256 @@
+//! Debugging commands typed into the `TextInput` and run against the `State`
+
+use std::collections::BTreeMap;
+
+use crate::prefab::Prefabs;
+use crate::render::DebugDraw;
+use crate::sim;
+use crate::state::{Camera, State, WorldScale};
+
+/// Distance between the centers of balls spawned together, as a multiple of their radius
+const SPAWN_SPACING: f32 = 2.5;
+
+/// Runs a command's arguments against the state, describing the outcome or what went wrong
+pub type Command = Box<dyn Fn(&mut State, &[&str]) -> Result<String, String>>;
+
+/// A registry of named commands, which parses and runs lines of text
+pub struct Console {
+    /// Usage summary and implementation of each command, by name
+    commands: BTreeMap<&'static str, (&'static str, Command)>,
+}
+
+impl Console {
+    /// A console without any commands
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Make `command` available as `name`, replacing any existing command of that name
+    ///
+    /// `usage` briefly describes the arguments and effect, as listed by `help`.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        command: impl Fn(&mut State, &[&str]) -> Result<String, String> + 'static,
+    ) {
+        self.commands.insert(name, (usage, Box::new(command)));
+    }
+
+    /// Run the whitespace-separated command and arguments in `line`
+    pub fn run(&self, state: &mut State, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(x) => x,
+            None => return Ok(String::new()),
+        };
+        let args = words.collect::<Vec<_>>();
+        if name == "help" {
+            return Ok(self
+                .commands
+                .iter()
+                .map(|(name, (usage, _))| format!("{} {}", name, usage))
+                .collect::<Vec<_>>()
+                .join("\n"));
+        }
+        match self.commands.get(name) {
+            Some((_, command)) => command(state, &args),
+            None => Err(format!("unknown command {:?}; try \"help\"", name)),
+        }
+    }
+}
+
+impl Default for Console {
+    /// A console with the built-in commands
+    fn default() -> Self {
+        let mut console = Self::new();
+        console.register(
+            "spawn",
+            "[COUNT]: spawn balls in a row at the center of the view",
+            spawn,
+        );
+        console.register(
+            "clear",
+            ": delete every entity and reset the view",
+            |state, _| {
+                state.reset();
+                Ok("cleared".into())
+            },
+        );
+        console.register(
+            "prefab",
+            "NAME: spawn a prefab at the center of the view",
+            |state, args| {
+                let name = match *args {
+                    [name] => name,
+                    _ => return Err("expected NAME".into()),
+                };
+                let prefab = state
+                    .world
+                    .read_resource::<Prefabs>()
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown prefab {:?}", name))?;
+                let center = state
+                    .world
+                    .read_resource::<Camera>()
+                    .0
+                    .isometry
+                    .translation
+                    .vector;
+                match prefab.spawn(&state.world, center) {
+                    Some(_) => Ok(format!("spawned {}", name)),
+                    None => Err("spawn limit reached".into()),
+                }
+            },
+        );
+        console.register(
+            "linewidth",
+            "PIXELS: draw debug lines PIXELS wide, where supported",
+            |state, args| {
+                let width = match *args {
+                    [width] => parse::<f32>(width)?,
+                    _ => return Err("expected PIXELS".into()),
+                };
+                if width <= 0.0 || width.is_nan() {
+                    return Err("line width must be positive".into());
+                }
+                state.world.write_resource::<DebugDraw>().line_width = width;
+                Ok(format!("debug lines {} pixels wide", width))
+            },
+        );
+        console.register("tp", "X Y: move the camera to a point", |state, args| {
+            let (x, y) = match *args {
+                [x, y] => (parse::<f32>(x)?, parse::<f32>(y)?),
+                _ => return Err("expected X and Y".into()),
+            };
+            state
+                .world
+                .write_resource::<Camera>()
+                .0
+                .isometry
+                .translation
+                .vector = na::Vector2::new(x, y);
+            Ok(format!("camera at ({}, {})", x, y))
+        });
+        console.register(
+            "tickrate",
+            "RATE: run RATE steps per second",
+            |state, args| {
+                let rate = match *args {
+                    [rate] => parse::<f32>(rate)?,
+                    _ => return Err("expected RATE".into()),
+                };
+                if rate <= 0.0 || rate.is_nan() {
+                    return Err("tick rate must be positive".into());
+                }
+                state.set_tick_rate(rate);
+                Ok(format!("{} steps per second", rate))
+            },
+        );
+        console.register(
+            "timescale",
+            "SCALE: pass SCALE seconds of simulated time per second",
+            |state, args| {
+                let scale = match *args {
+                    [scale] => parse::<f32>(scale)?,
+                    _ => return Err("expected SCALE".into()),
+                };
+                if scale < 0.0 || !scale.is_finite() {
+                    return Err("time scale must be finite and not negative".into());
+                }
+                state.set_time_scale(scale);
+                Ok(format!("time scale {}", scale))
+            },
+        );
+        console
+    }
+}
+
+fn spawn(state: &mut State, args: &[&str]) -> Result<String, String> {
+    let count = match *args {
+        [] => 1,
+        [count] => parse::<u32>(count)?,
+        _ => return Err("expected at most one COUNT".into()),
+    };
+    let radius = state.world.read_resource::<WorldScale>().ball_radius();
+    let center = state
+        .world
+        .read_resource::<Camera>()
+        .0
+        .isometry
+        .translation
+        .vector;
+    let spacing = SPAWN_SPACING * radius;
+    let first = center.x - 0.5 * spacing * (count.max(1) - 1) as f32;
+    let mut spawned = 0;
+    for i in 0..count {
+        let position = na::Vector2::new(first + spacing * i as f32, center.y);
+        let entity = sim::spawn_ball(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_storage(),
+            *state.world.read_resource(),
+            sim::Layer::ACTOR,
+            position,
+            radius,
+            state.world.read_resource::<sim::SpawnQuery>().0,
+        );
+        if entity.is_none() {
+            break;
+        }
+        spawned += 1;
+    }
+    if spawned < count {
+        return Err(format!(
+            "spawn limit reached after {} of {}",
+            spawned, count
+        ));
+    }
+    Ok(format!("spawned {}", spawned))
+}
+
+fn parse<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse()
+        .map_err(|_| format!("invalid argument {:?}", arg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let console = Console::default();
+        let mut state = State::new();
+        assert_eq!(console.run(&mut state, "spawn 5"), Ok("spawned 5".into()));
+        assert_eq!(
+            state
+                .world
+                .read_resource::<sim::CollisionWorld>()
+                .collision_objects()
+                .count(),
+            5
+        );
+        assert!(console.run(&mut state, "spawn many").is_err());
+        assert!(console.run(&mut state, "tickrate 0").is_err());
+        assert_eq!(
+            console.run(&mut state, "timescale 0.5"),
+            Ok("time scale 0.5".into())
+        );
+        assert_eq!(state.time_scale(), 0.5);
+        assert!(console.run(&mut state, "timescale -1").is_err());
+        assert!(console.run(&mut state, "linewidth 3").is_ok());
+        assert_eq!(state.world.read_resource::<DebugDraw>().line_width, 3.0);
+        assert!(console.run(&mut state, "linewidth -1").is_err());
+        assert!(console.run(&mut state, "teleport").is_err());
+        assert_eq!(console.run(&mut state, "  "), Ok(String::new()));
+        assert!(console
+            .run(&mut state, "help")
+            .unwrap()
+            .contains("tickrate RATE"));
+    }
+}
@@ -0,0 +1,44 @@
+use specs::World;
+
+use crate::sim::CollisionEvent;
+
+/// Events of type `T` published during the latest step
+///
+/// Systems publish events as they occur, and systems that run later in the same step read them.
+/// Every channel is emptied by `clear` at the start of each step.
+pub struct EventChannel<T> {
+    events: Vec<T>,
+}
+
+impl<T> Default for EventChannel<T> {
+    fn default() -> Self {
+        Self { events: Vec::new() }
+    }
+}
+
+impl<T> EventChannel<T> {
+    pub fn publish(&mut self, event: T) {
+        self.events.push(event);
+    }
+
+    /// Events in the order they were published
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.events.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// Register a channel for each event type
+pub fn setup(world: &mut World) {
+    world.add_resource(EventChannel::<CollisionEvent>::default());
+}
+
+/// Empty every channel registered by `setup`
+pub fn clear(world: &World) {
+    world
+        .write_resource::<EventChannel<CollisionEvent>>()
+        .clear();
+}
@@ -1,20 +1,31 @@
+use std::cmp::Reverse;
+use std::collections::VecDeque;
 use std::ffi::CStr;
+use std::fmt;
+use std::mem;
 use std::os::raw::c_void;
-use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use ash::extensions::ext::DebugUtils;
 use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
 use ash::{vk, Device, Entry, Instance};
+use serde::{Deserialize, Serialize};
 
 use crate::defer;
 
+/// Number of debug messages retained by a `MessageLog`
+const MESSAGE_LOG_CAPACITY: usize = 32;
+
 pub struct Core {
     pub entry: Entry,
     pub instance: Instance,
 
     pub debug_utils: Option<DebugUtils>,
     messenger: vk::DebugUtilsMessengerEXT,
+    /// Written by `messenger_callback`, so must outlive `messenger`
+    messages: Arc<MessageLog>,
+    /// From `VK_KHR_get_physical_device_properties2`, if supported
+    get_memory_properties2: Option<vk::PFN_vkGetPhysicalDeviceMemoryProperties2>,
 }
 
 impl Drop for Core {
@@ -37,11 +48,26 @@ impl Core {
             let has_debug = supported_exts
                 .iter()
                 .any(|x| CStr::from_ptr(x.extension_name.as_ptr()) == DebugUtils::name());
+            // Exposes the HDR color spaces to surfaces
+            let has_colorspace = supported_exts.iter().any(|x| {
+                CStr::from_ptr(x.extension_name.as_ptr()) == vk::ExtSwapchainColorspaceFn::name()
+            });
+            // Needed to query memory budgets
+            let has_properties2 = supported_exts.iter().any(|x| {
+                CStr::from_ptr(x.extension_name.as_ptr())
+                    == vk::KhrGetPhysicalDeviceProperties2Fn::name()
+            });
 
             let mut exts = exts.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
             if has_debug {
                 exts.push(DebugUtils::name().as_ptr());
             }
+            if has_colorspace {
+                exts.push(vk::ExtSwapchainColorspaceFn::name().as_ptr());
+            }
+            if has_properties2 {
+                exts.push(vk::KhrGetPhysicalDeviceProperties2Fn::name().as_ptr());
+            }
 
             let name = cstr!("rustlike");
 
@@ -61,6 +87,17 @@ impl Core {
                 )
                 .unwrap();
             let instance_guard = defer(|| instance.destroy_instance(None));
+            let get_memory_properties2 = if has_properties2 {
+                entry
+                    .get_instance_proc_addr(
+                        instance.handle(),
+                        cstr!("vkGetPhysicalDeviceMemoryProperties2KHR").as_ptr(),
+                    )
+                    .map(|f| mem::transmute::<_, vk::PFN_vkGetPhysicalDeviceMemoryProperties2>(f))
+            } else {
+                None
+            };
+            let messages = Arc::new(MessageLog::default());
             let messenger_guard;
             let debug_utils;
             let messenger;
@@ -80,7 +117,7 @@ impl Core {
                                     | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
                             )
                             .pfn_user_callback(Some(messenger_callback))
-                            .user_data(ptr::null_mut()),
+                            .user_data(&*messages as *const MessageLog as *mut c_void),
                         None,
                     )
                     .unwrap();
@@ -104,7 +141,43 @@ impl Core {
                 instance,
                 debug_utils,
                 messenger,
+                messages,
+                get_memory_properties2,
+            }
+        }
+    }
+
+    /// The most recent validation and debug messages, oldest first
+    pub fn recent_messages(&self) -> Vec<String> {
+        self.messages.recent()
+    }
+
+    /// Shared handle to the messages, e.g. for reporting them from a panic hook
+    pub fn message_log(&self) -> Arc<MessageLog> {
+        self.messages.clone()
+    }
+}
+
+/// Ring buffer of the latest messages reported through `VK_EXT_debug_utils`
+#[derive(Default)]
+pub struct MessageLog(Mutex<VecDeque<String>>);
+
+impl MessageLog {
+    /// Retained messages, oldest first
+    pub fn recent(&self) -> Vec<String> {
+        match self.0.lock() {
+            Ok(messages) => messages.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn push(&self, message: String) {
+        // Unwinding out of the callback would abort, so don't propagate poisoning
+        if let Ok(mut messages) = self.0.lock() {
+            if messages.len() == MESSAGE_LOG_CAPACITY {
+                messages.pop_front();
             }
+            messages.push_back(message);
         }
     }
 }
@@ -113,14 +186,16 @@ unsafe extern "system" fn messenger_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     _message_types: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
     let callback_data = &*p_callback_data;
-    eprintln!(
+    let message = format!(
         "{:?} {}",
         message_severity,
         CStr::from_ptr(callback_data.p_message).to_string_lossy()
     );
+    eprintln!("{}", message);
+    (*(p_user_data as *const MessageLog)).push(message);
     vk::FALSE
 }
 
@@ -129,9 +204,22 @@ pub struct Graphics {
     pub physical: vk::PhysicalDevice,
     pub device: Arc<Device>,
     pub queue_family: u32,
+    /// Capabilities and queue counts of every queue family on `physical`, indexed by family
+    ///
+    /// Useful for finding families besides `queue_family` that could run work asynchronously.
+    pub queue_families: Vec<vk::QueueFamilyProperties>,
     pub queue: vk::Queue,
     pub memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub limits: vk::PhysicalDeviceLimits,
+    /// Human-readable name of the physical device
+    pub device_name: String,
+    /// Driver version, decoded according to the vendor's conventions
+    pub driver_version: String,
+    /// Optional features enabled on `device`
+    pub features: vk::PhysicalDeviceFeatures,
     pub pipeline_cache: vk::PipelineCache,
+    /// Whether `VK_EXT_memory_budget` is enabled on `device`
+    memory_budget: bool,
 }
 
 impl Drop for Graphics {
@@ -143,36 +231,100 @@ impl Drop for Graphics {
     }
 }
 
+/// Description of a physical device suitable for use by `Graphics`
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub ty: vk::PhysicalDeviceType,
+    /// Total size of device-local memory heaps, in bytes
+    pub vram: u64,
+}
+
+/// Kind of device favored by `Graphics::new`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DevicePreference {
+    /// Discrete GPUs, for performance
+    HighPerformance,
+    /// Integrated GPUs, to save power
+    LowPower,
+}
+
+impl DevicePreference {
+    /// How well a device of type `ty` suits this preference, higher being better
+    fn score(self, ty: vk::PhysicalDeviceType) -> u32 {
+        match (self, ty) {
+            (DevicePreference::HighPerformance, vk::PhysicalDeviceType::DISCRETE_GPU)
+            | (DevicePreference::LowPower, vk::PhysicalDeviceType::INTEGRATED_GPU) => 3,
+            (_, vk::PhysicalDeviceType::DISCRETE_GPU)
+            | (_, vk::PhysicalDeviceType::INTEGRATED_GPU) => 2,
+            (_, vk::PhysicalDeviceType::VIRTUAL_GPU) => 1,
+            _ => 0,
+        }
+    }
+}
+
 impl Graphics {
+    /// List the devices that `new_with` can choose from, in index order
+    pub fn enumerate(
+        core: &Core,
+        device_filter: impl FnMut(vk::PhysicalDevice, u32) -> bool,
+    ) -> Vec<DeviceInfo> {
+        unsafe {
+            candidates(&core.instance, device_filter)
+                .into_iter()
+                .map(|(physical, _)| device_info(&core.instance, physical))
+                .collect()
+        }
+    }
+
+    /// Construct using the suitable device that best matches `preference`, breaking ties by order
     pub fn new(
         core: Arc<Core>,
+        preference: DevicePreference,
         pipeline_cache_data: &[u8],
         device_exts: &[&CStr],
         mut device_filter: impl FnMut(vk::PhysicalDevice, u32) -> bool,
+    ) -> Option<Self> {
+        let (index, _) = Self::enumerate(&core, &mut device_filter)
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, info)| Reverse(preference.score(info.ty)))?;
+        Self::new_with(core, index, pipeline_cache_data, device_exts, device_filter)
+    }
+
+    /// Construct using the `device_index`th device listed by `enumerate`
+    pub fn new_with(
+        core: Arc<Core>,
+        device_index: usize,
+        pipeline_cache_data: &[u8],
+        device_exts: &[&CStr],
+        device_filter: impl FnMut(vk::PhysicalDevice, u32) -> bool,
     ) -> Option<Self> {
         unsafe {
             let instance = &core.instance;
-            let (physical, queue_family_index) = instance
-                .enumerate_physical_devices()
-                .unwrap()
+            let (physical, queue_family_index) = candidates(instance, device_filter)
                 .into_iter()
-                .find_map(|physical| {
-                    instance
-                        .get_physical_device_queue_family_properties(physical)
-                        .iter()
-                        .enumerate()
-                        .find_map(|(queue_family_index, ref info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && device_filter(physical, queue_family_index as u32);
-                            match supports_graphic_and_surface {
-                                true => Some((physical, queue_family_index as u32)),
-                                _ => None,
-                            }
-                        })
-                })?;
-
-            let device_exts = device_exts.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+                .nth(device_index)?;
+
+            let supported_exts = instance
+                .enumerate_device_extension_properties(physical)
+                .unwrap();
+            let memory_budget = core.get_memory_properties2.is_some()
+                && supported_exts.iter().any(|x| {
+                    CStr::from_ptr(x.extension_name.as_ptr()) == vk::ExtMemoryBudgetFn::name()
+                });
+            let mut device_exts = device_exts.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+            if memory_budget {
+                device_exts.push(vk::ExtMemoryBudgetFn::name().as_ptr());
+            }
+            let supported_features = instance.get_physical_device_features(physical);
+            let features = vk::PhysicalDeviceFeatures {
+                fill_mode_non_solid: supported_features.fill_mode_non_solid,
+                sample_rate_shading: supported_features.sample_rate_shading,
+                sampler_anisotropy: supported_features.sampler_anisotropy,
+                wide_lines: supported_features.wide_lines,
+                ..Default::default()
+            };
 
             let device = Arc::new(
                 instance
@@ -183,13 +335,21 @@ impl Graphics {
                                 .queue_family_index(queue_family_index)
                                 .queue_priorities(&[1.0])
                                 .build()])
-                            .enabled_extension_names(&device_exts),
+                            .enabled_extension_names(&device_exts)
+                            .enabled_features(&features),
                         None,
                     )
                     .unwrap(),
             );
             let queue = device.get_device_queue(queue_family_index, 0);
+            let queue_families = instance.get_physical_device_queue_family_properties(physical);
             let memory_properties = instance.get_physical_device_memory_properties(physical);
+            let properties = instance.get_physical_device_properties(physical);
+            let device_name = CStr::from_ptr(properties.device_name.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+            let driver_version =
+                format_driver_version(properties.vendor_id, properties.driver_version);
             let pipeline_cache = device
                 .create_pipeline_cache(
                     &vk::PipelineCacheCreateInfo::builder().initial_data(pipeline_cache_data),
@@ -202,10 +362,249 @@ impl Graphics {
                 physical,
                 device,
                 queue_family: queue_family_index,
+                queue_families,
                 queue,
                 memory_properties,
+                limits: properties.limits,
+                device_name,
+                driver_version,
+                features,
                 pipeline_cache,
+                memory_budget,
             })
         }
     }
+
+    /// Operations supported by `queue`
+    ///
+    /// Includes `TRANSFER`, which graphics and compute queues always support even where the driver
+    /// doesn't report it.
+    pub fn queue_flags(&self) -> vk::QueueFlags {
+        let flags = self.queue_families[self.queue_family as usize].queue_flags;
+        if flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE) {
+            flags | vk::QueueFlags::TRANSFER
+        } else {
+            flags
+        }
+    }
+
+    /// Number of queues in `queue_family`, of which only `queue` is created
+    pub fn queue_count(&self) -> u32 {
+        self.queue_families[self.queue_family as usize].queue_count
+    }
+
+    /// Submit `cmd` to the queue, waiting on `wait` before writing color attachments and
+    /// signaling `signal` and `fence` on completion
+    ///
+    /// # Safety
+    /// - `cmd` must be a valid, fully recorded command buffer not in use by the device
+    pub unsafe fn submit(
+        &self,
+        cmd: vk::CommandBuffer,
+        wait: vk::Semaphore,
+        signal: vk::Semaphore,
+        fence: vk::Fence,
+    ) {
+        let result = self.device.queue_submit(
+            self.queue,
+            &[vk::SubmitInfo::builder()
+                .wait_semaphores(&[wait])
+                .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
+                .command_buffers(&[cmd])
+                .signal_semaphores(&[signal])
+                .build()],
+            fence,
+        );
+        match result {
+            // Reported again by whatever next waits on the queue
+            Ok(()) | Err(vk::Result::ERROR_DEVICE_LOST) => {}
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Name `object` for display in debugging tools, if debug utils are available
+    ///
+    /// # Safety
+    /// - `object` must be a valid handle owned by `device`
+    pub unsafe fn set_name<T: vk::Handle>(&self, object: T, name: &CStr) {
+        if let Some(ref utils) = self.core.debug_utils {
+            utils
+                .debug_utils_set_object_name(
+                    self.device.handle(),
+                    &vk::DebugUtilsObjectNameInfoEXT::builder()
+                        .object_type(T::TYPE)
+                        .object_handle(object.as_raw())
+                        .object_name(name),
+                )
+                .unwrap();
+        }
+    }
+
+    /// Open a labeled region of `cmd` for debugging tools, closed by `end_label`
+    ///
+    /// # Safety
+    /// - `cmd` must be in the recording state
+    pub unsafe fn begin_label(&self, cmd: vk::CommandBuffer, name: &CStr) {
+        if let Some(ref utils) = self.core.debug_utils {
+            utils.cmd_begin_debug_utils_label(
+                cmd,
+                &vk::DebugUtilsLabelEXT::builder().label_name(name),
+            );
+        }
+    }
+
+    /// Close the region most recently opened in `cmd` by `begin_label`
+    ///
+    /// # Safety
+    /// - `cmd` must be in the recording state, with a region opened by `begin_label`
+    pub unsafe fn end_label(&self, cmd: vk::CommandBuffer) {
+        if let Some(ref utils) = self.core.debug_utils {
+            utils.cmd_end_debug_utils_label(cmd);
+        }
+    }
+
+    /// Current memory use and budget of each heap, if the device supports `VK_EXT_memory_budget`
+    pub fn memory_usage(&self) -> Option<MemoryBudget> {
+        if !self.memory_budget {
+            return None;
+        }
+        let get_memory_properties2 = self.core.get_memory_properties2?;
+        let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut properties = vk::PhysicalDeviceMemoryProperties2 {
+            p_next: &mut budget as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        get_memory_properties2(self.physical, &mut properties);
+        let memory = &properties.memory_properties;
+        Some(MemoryBudget {
+            heaps: (0..memory.memory_heap_count as usize)
+                .map(|i| HeapBudget {
+                    device_local: memory.memory_heaps[i]
+                        .flags
+                        .contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                    usage: budget.heap_usage[i],
+                    budget: budget.heap_budget[i],
+                })
+                .collect(),
+        })
+    }
+
+    /// Index of a memory type allowed by `type_bits` that has all of `flags`
+    pub fn memory_type(&self, type_bits: u32, flags: vk::MemoryPropertyFlags) -> Option<u32> {
+        let props = &self.memory_properties;
+        (0..props.memory_type_count).find(|&i| {
+            type_bits & (1 << i) != 0
+                && props.memory_types[i as usize]
+                    .property_flags
+                    .contains(flags)
+        })
+    }
+}
+
+/// Find each physical device with a graphics queue family accepted by `device_filter`
+unsafe fn candidates(
+    instance: &Instance,
+    mut device_filter: impl FnMut(vk::PhysicalDevice, u32) -> bool,
+) -> Vec<(vk::PhysicalDevice, u32)> {
+    instance
+        .enumerate_physical_devices()
+        .unwrap()
+        .into_iter()
+        .filter_map(|physical| {
+            instance
+                .get_physical_device_queue_family_properties(physical)
+                .iter()
+                .enumerate()
+                .find_map(|(queue_family_index, ref info)| {
+                    let supports_graphic_and_surface =
+                        info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                            && device_filter(physical, queue_family_index as u32);
+                    match supports_graphic_and_surface {
+                        true => Some((physical, queue_family_index as u32)),
+                        _ => None,
+                    }
+                })
+        })
+        .collect()
+}
+
+/// Memory use and availability of each heap, as reported by `Graphics::memory_usage`
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    pub heaps: Vec<HeapBudget>,
+}
+
+impl MemoryBudget {
+    /// Bytes in use across device-local heaps
+    pub fn vram_usage(&self) -> u64 {
+        self.vram().map(|x| x.usage).sum()
+    }
+
+    /// Bytes that may be used across device-local heaps without degrading performance
+    pub fn vram_budget(&self) -> u64 {
+        self.vram().map(|x| x.budget).sum()
+    }
+
+    fn vram(&self) -> impl Iterator<Item = &HeapBudget> {
+        self.heaps.iter().filter(|x| x.device_local)
+    }
+}
+
+impl fmt::Display for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MIB: u64 = 1 << 20;
+        write!(
+            f,
+            "{} of {} MiB VRAM in use",
+            self.vram_usage() / MIB,
+            self.vram_budget() / MIB
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HeapBudget {
+    pub device_local: bool,
+    /// Bytes in use by this process
+    pub usage: u64,
+    /// Bytes this process may use without degrading performance, which may change over time
+    pub budget: u64,
+}
+
+/// Decode a `driver_version` reported by a device from `vendor_id`
+///
+/// Only the API version has a standard encoding; some vendors pack driver versions differently.
+fn format_driver_version(vendor_id: u32, version: u32) -> String {
+    const NVIDIA: u32 = 0x10de;
+    match vendor_id {
+        NVIDIA => format!(
+            "{}.{}.{}.{}",
+            version >> 22,
+            (version >> 14) & 0xff,
+            (version >> 6) & 0xff,
+            version & 0x3f
+        ),
+        _ => format!(
+            "{}.{}.{}",
+            version >> 22,
+            (version >> 12) & 0x3ff,
+            version & 0xfff
+        ),
+    }
+}
+
+unsafe fn device_info(instance: &Instance, physical: vk::PhysicalDevice) -> DeviceInfo {
+    let properties = instance.get_physical_device_properties(physical);
+    let memory = instance.get_physical_device_memory_properties(physical);
+    DeviceInfo {
+        name: CStr::from_ptr(properties.device_name.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+        ty: properties.device_type,
+        vram: memory.memory_heaps[..memory.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum(),
+    }
 }
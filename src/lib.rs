@@ -12,7 +12,18 @@ pub mod graphics;
 pub mod window;
 pub mod state;
 pub mod sim;
+pub mod event;
 pub mod render;
-mod navmesh;
+pub mod texture;
+pub mod profile;
+pub mod capture;
+pub mod tilemap;
+pub mod tiled;
+pub mod replay;
+pub mod config;
+pub mod console;
+pub mod prefab;
+pub mod app;
+pub mod navmesh;
 
 pub use defer::defer;
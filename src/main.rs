@@ -1,156 +1,540 @@
+use std::env;
 use std::fs;
+use std::io;
+use std::panic;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use ash::extensions::khr::Swapchain;
 use ash::version::DeviceV1_0;
 use ash::vk;
-use specs::RunNow;
+use log::{info, warn};
+use ncollide2d::world::GeometricQueryType;
+use specs::Builder;
 
 use rustlike::*;
 
+/// Steps from the origin to the edge of the tiles filled by `--tiles`
+const TILE_DEMO_RADIUS: u32 = 6;
+/// Remaining wait below which the frame rate limiter spins rather than sleeping, since sleeps may
+/// overshoot
+const FRAME_LIMIT_SPIN: Duration = Duration::from_millis(2);
+
 fn main() {
+    env_logger::init();
     let dirs = directories::ProjectDirs::from("", "", "rustlike").unwrap();
     let pipeline_cache_path = dirs.cache_dir().join("pipeline_cache");
     let pipeline_cache_data = fs::read(&pipeline_cache_path).unwrap_or_else(|_| vec![]);
+    let config_path = dirs.config_dir().join("config.json");
+    // Preferences from the previous run, saved again on exit with only interactive changes applied
+    let mut saved_config = config::Config::load(&config_path).unwrap_or_else(|e| {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("failed to load {}: {}", config_path.display(), e);
+        }
+        config::Config::default()
+    });
+    // Preferences in effect for this run, which command line options override
+    let mut config = saved_config.clone();
+
+    // Only render when something changed, rather than continuously
+    let mut on_demand = false;
+    let mut inspector = false;
+    let mut icon = None;
+    // Limits on resizing the main window
+    let mut size_constraints = window::SizeConstraints::default();
+    // Log the average time spent in each phase of the main loop every second
+    let mut profile = false;
+    // Log what the renderer did every frame
+    let mut render_stats = false;
+    // Log what collision detection did every frame that ran a step
+    let mut collision_stats = false;
+    // Meters within which colliders spawned during play report contacts before touching
+    let mut contact_prediction = None;
+    let mut anti_alias = render::AntiAlias::None;
+    // Allow adjusting brightness with the - and = keys
+    let mut color_grading = false;
+    let mut sample_shading = None;
+    let mut grading = render::ColorGrading::default();
+    let mut debug_draw = render::DebugDraw::default();
+    let mut grid = render::Grid::default();
+    let mut cursor_snap = state::CursorSnap::None;
+    let mut pixel_snap = render::PixelSnap::default();
+    let mut partial_redraw = render::PartialRedraw::default();
+    let mut reuse_commands = render::ReuseCommands::default();
+    let mut target_frame_rate = render::TargetFrameRate::default();
+    let mut tick_rate = None;
+    let mut time_scale = None;
+    // Minimum time between frames
+    let mut frame_interval = None;
+    // Present in an HDR format where the surface offers one
+    let mut hdr = false;
+    let mut camera = state::InitialCamera::default();
+    // Images to show as textured sprites at the origin
+    let mut textures = Vec::new();
+    // Draw those images with hard edges, discarding translucent texels
+    let mut cutout = false;
+    // How those images are sampled
+    let mut filtering = texture::Filtering::default();
+    // Copies of those images across each sprite
+    let mut texture_repeat = None;
+    // Place a point light at the origin
+    let mut light = false;
+    // Show a rounded panel at the origin
+    let mut panel = false;
+    // Divide the window between the camera and a second one fixed at the initial view
+    let mut split_screen = false;
+    // Draw sprites lower in the world over those above them
+    let mut y_sort = false;
+    // Only advance the simulation when the player moves, with the arrow keys
+    let mut turn_based = false;
+    // Move the player a whole cell of the tilemap at a time
+    let mut grid_move = false;
+    // Emit a ball from the origin every this many seconds
+    let mut spawner_interval = None;
+    // Fill tiles of this shape around the origin
+    let mut tiles = None;
+    // Directory to write numbered PNGs of presented frames into
+    let mut capture_dir = None;
+    let mut capture_rate = 30.0;
+    // File to write every input to, and one to read inputs back from in place of live input
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut tiled_path = None;
+    // Directory of entity definitions spawnable by name from the console
+    let mut prefabs_dir = PathBuf::from("prefabs");
+    for arg in env::args().skip(1) {
+        if arg == "--on-demand" {
+            on_demand = true;
+        } else if arg == "--profile" {
+            profile = true;
+        } else if arg == "--render-stats" {
+            render_stats = true;
+        } else if arg == "--collision-stats" {
+            collision_stats = true;
+        } else if let Some(meters) = strip_flag(&arg, "--contact-prediction=") {
+            contact_prediction = Some(
+                meters
+                    .parse::<f32>()
+                    .expect("invalid contact prediction distance"),
+            );
+        } else if arg == "--inspector" {
+            inspector = true;
+        } else if let Some(path) = strip_flag(&arg, "--icon=") {
+            icon = Some(PathBuf::from(path));
+        } else if let Some(size) = strip_flag(&arg, "--min-size=") {
+            let mut dims = size
+                .split('x')
+                .map(|x| x.parse::<f64>().expect("invalid minimum window size"));
+            size_constraints.min = Some(winit::dpi::LogicalSize::new(
+                dims.next().expect("missing minimum width"),
+                dims.next().expect("missing minimum height"),
+            ));
+        } else if let Some(aspect) = strip_flag(&arg, "--aspect=") {
+            let mut dims = aspect
+                .split(':')
+                .map(|x| x.parse::<f64>().expect("invalid aspect ratio"));
+            let width = dims.next().expect("missing aspect width");
+            size_constraints.aspect = Some(width / dims.next().unwrap_or(1.0));
+        } else if arg == "--debug-contacts" {
+            debug_draw.contacts = true;
+        } else if arg == "--debug-aabbs" {
+            debug_draw.aabbs = true;
+        } else if arg == "--debug-headings" {
+            debug_draw.headings = true;
+        } else if let Some(width) = strip_flag(&arg, "--debug-line-width=") {
+            debug_draw.line_width = width.parse().expect("invalid debug line width");
+        } else if let Some(spacing) = strip_flag(&arg, "--grid=") {
+            grid.spacing = Some(spacing.parse().expect("invalid grid spacing"));
+        } else if let Some(spacing) = strip_flag(&arg, "--snap-grid=") {
+            cursor_snap = state::CursorSnap::Grid(spacing.parse().expect("invalid snap spacing"));
+        } else if arg == "--snap-collider" {
+            cursor_snap = state::CursorSnap::Collider;
+        } else if let Some(position) = strip_flag(&arg, "--camera=") {
+            let mut coords = position
+                .split(',')
+                .map(|x| x.parse::<f32>().expect("invalid camera position"));
+            camera.position = na::Vector2::new(
+                coords.next().expect("missing camera x"),
+                coords.next().expect("missing camera y"),
+            );
+        } else if let Some(zoom) = strip_flag(&arg, "--zoom=") {
+            camera.zoom = zoom.parse().expect("invalid zoom");
+        } else if arg == "--low-power" {
+            config.device_preference = graphics::DevicePreference::LowPower;
+        } else if arg == "--high-performance" {
+            config.device_preference = graphics::DevicePreference::HighPerformance;
+        } else if let Some(exposure) = strip_flag(&arg, "--exposure=") {
+            grading.exposure = exposure.parse().expect("invalid exposure");
+            color_grading = true;
+        } else if let Some(gamma) = strip_flag(&arg, "--gamma=") {
+            grading.gamma = gamma.parse().expect("invalid gamma");
+            color_grading = true;
+        } else if arg == "--color-grading" {
+            color_grading = true;
+        } else if arg == "--fxaa" {
+            anti_alias = render::AntiAlias::Fxaa;
+        } else if let Some(samples) = strip_flag(&arg, "--msaa=") {
+            let samples = samples.parse().expect("invalid MSAA sample count");
+            anti_alias = render::AntiAlias::Msaa(samples);
+        } else if let Some(fraction) = strip_flag(&arg, "--sample-shading=") {
+            let fraction = fraction
+                .parse::<f32>()
+                .expect("invalid sample shading fraction");
+            assert!(
+                fraction > 0.0 && fraction <= 1.0,
+                "sample shading fraction must be in (0, 1]"
+            );
+            sample_shading = Some(fraction);
+        } else if let Some(rate) = strip_flag(&arg, "--target-fps=") {
+            target_frame_rate.0 = Some(rate.parse().expect("invalid target frame rate"));
+        } else if let Some(rate) = strip_flag(&arg, "--max-fps=") {
+            let rate = rate.parse::<f32>().expect("invalid maximum frame rate");
+            frame_interval = Some(Duration::from_secs_f32(1.0 / rate));
+        } else if arg == "--immediate" {
+            config.present_mode = config::PresentMode::Immediate;
+        } else if let Some(mode) = strip_flag(&arg, "--present-mode=") {
+            config.present_mode = config::PresentMode::parse(mode).expect("unknown present mode");
+        } else if arg == "--hdr" {
+            hdr = true;
+        } else if arg == "--partial-redraw" {
+            partial_redraw.0 = true;
+        } else if arg == "--reuse-commands" {
+            reuse_commands.0 = true;
+        } else if arg == "--pixel-snap" {
+            pixel_snap.0 = true;
+        } else if let Some(rate) = strip_flag(&arg, "--tick-rate=") {
+            tick_rate = Some(rate.parse().expect("invalid tick rate"));
+        } else if let Some(scale) = strip_flag(&arg, "--time-scale=") {
+            time_scale = Some(scale.parse().expect("invalid time scale"));
+        } else if let Some(path) = strip_flag(&arg, "--capture=") {
+            capture_dir = Some(PathBuf::from(path));
+        } else if let Some(rate) = strip_flag(&arg, "--capture-fps=") {
+            capture_rate = rate.parse().expect("invalid capture frame rate");
+        } else if let Some(path) = strip_flag(&arg, "--record=") {
+            record_path = Some(PathBuf::from(path));
+        } else if let Some(path) = strip_flag(&arg, "--replay=") {
+            replay_path = Some(PathBuf::from(path));
+        } else if let Some(path) = strip_flag(&arg, "--tiled=") {
+            tiled_path = Some(PathBuf::from(path));
+        } else if let Some(path) = strip_flag(&arg, "--prefabs=") {
+            prefabs_dir = PathBuf::from(path);
+        } else if let Some(shape) = strip_flag(&arg, "--tiles=") {
+            tiles = Some(match shape {
+                "square" => tilemap::TileShape::Square,
+                "hex" => tilemap::TileShape::Hex,
+                _ => panic!("unknown tile shape {:?}", shape),
+            });
+        } else if arg == "--light" {
+            light = true;
+        } else if arg == "--panel" {
+            panel = true;
+        } else if arg == "--split-screen" {
+            split_screen = true;
+        } else if arg == "--y-sort" {
+            y_sort = true;
+        } else if arg == "--turn-based" {
+            turn_based = true;
+        } else if arg == "--grid-move" {
+            grid_move = true;
+        } else if let Some(interval) = strip_flag(&arg, "--spawner=") {
+            let interval = interval.parse::<f32>().expect("invalid spawner interval");
+            assert!(interval > 0.0, "spawner interval must be positive");
+            spawner_interval = Some(interval);
+        } else if let Some(path) = strip_flag(&arg, "--texture=") {
+            textures.push(PathBuf::from(path));
+        } else if arg == "--cutout" {
+            cutout = true;
+        } else if let Some(anisotropy) = strip_flag(&arg, "--anisotropy=") {
+            filtering.max_anisotropy = anisotropy.parse().expect("invalid anisotropy");
+        } else if arg == "--nearest-filter" {
+            filtering.mag_filter = vk::Filter::NEAREST;
+            filtering.min_filter = vk::Filter::NEAREST;
+        } else if let Some(mode) = strip_flag(&arg, "--texture-wrap=") {
+            filtering.address_mode = match mode {
+                "clamp" => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                "repeat" => vk::SamplerAddressMode::REPEAT,
+                "mirror" => vk::SamplerAddressMode::MIRRORED_REPEAT,
+                _ => panic!("unknown texture wrap mode {:?}", mode),
+            };
+        } else if let Some(count) = strip_flag(&arg, "--texture-repeat=") {
+            texture_repeat = Some(count.parse::<f32>().expect("invalid texture repeat count"));
+        }
+    }
 
     let mut events_loop = winit::EventsLoop::new();
     let core = Arc::new(graphics::Core::new(&window::Window::instance_exts()));
-    let mut window_size = winit::dpi::LogicalSize::new(1280.0, 720.0);
-    let window = Arc::new(window::Window::new(&events_loop, core.clone(), window_size));
+    // Validation messages often explain what led up to a crash
+    let messages = core.message_log();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let recent = messages.recent();
+        if !recent.is_empty() {
+            eprintln!("recent Vulkan messages, oldest first:");
+            for message in recent {
+                eprintln!("  {}", message);
+            }
+        }
+    }));
+    let mut windows = vec![Arc::new(window::Window::with_constraints(
+        &events_loop,
+        core.clone(),
+        "rustlike",
+        winit::dpi::LogicalSize::new(config.window_size[0], config.window_size[1]),
+        size_constraints,
+    ))];
+    if let Some(path) = icon {
+        match window::read_png(&path) {
+            Ok((rgba, width, height)) => {
+                if let Err(e) = windows[0].set_icon(rgba, width, height) {
+                    warn!("invalid icon {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => warn!("failed to load icon {}: {}", path.display(), e),
+        }
+    }
+    if inspector {
+        windows.push(Arc::new(window::Window::new(
+            &events_loop,
+            core.clone(),
+            "rustlike inspector",
+            winit::dpi::LogicalSize::new(640.0, 480.0),
+        )));
+    }
     let gfx = Arc::new(
         graphics::Graphics::new(
             core,
+            config.device_preference,
             &pipeline_cache_data,
             &[Swapchain::name()],
-            |physical, queue_family| window.supports(physical, queue_family),
+            |physical, queue_family| windows.iter().all(|x| x.supports(physical, queue_family)),
         )
         .unwrap(),
     );
+    info!("using {} (driver {})", gfx.device_name, gfx.driver_version);
+    info!(
+        "queue family {} supports {:?} with {} queues",
+        gfx.queue_family,
+        gfx.queue_flags(),
+        gfx.queue_count()
+    );
     drop(pipeline_cache_data);
-    let mut swapchain = window::SwapchainMgr::new(window.clone(), gfx.clone());
-    let mut render = render::Render::new(gfx.clone());
-    unsafe {
-        render.rebuild_framebuffers(
-            swapchain.extent(),
-            swapchain.frames().iter().map(|x| x.view),
-        );
+
+    let mut state = state::State::with_camera(state::WorldScale::default(), camera);
+    *state.world.write_resource::<render::DebugDraw>() = debug_draw;
+    *state.world.write_resource::<render::Grid>() = grid;
+    *state.world.write_resource::<state::CursorSnap>() = cursor_snap;
+    *state.world.write_resource::<render::ColorGrading>() = grading;
+    *state.world.write_resource::<render::PixelSnap>() = pixel_snap;
+    *state.world.write_resource::<render::PartialRedraw>() = partial_redraw;
+    *state.world.write_resource::<render::ReuseCommands>() = reuse_commands;
+    *state.world.write_resource::<render::TargetFrameRate>() = target_frame_rate;
+    if let Some(meters) = contact_prediction {
+        let scale = *state.world.read_resource::<state::WorldScale>();
+        state.world.write_resource::<sim::SpawnQuery>().0 =
+            GeometricQueryType::Contacts(meters * scale.0, 0.0);
+    }
+    if split_screen {
+        let scale = *state.world.read_resource::<state::WorldScale>();
+        let fixed = state::Camera::new(scale, camera).0;
+        let half = na::Vector2::new(0.5, 1.0);
+        *state.world.write_resource::<render::Views>() = render::Views(vec![
+            render::View {
+                camera: None,
+                offset: na::zero(),
+                extent: half,
+            },
+            render::View {
+                camera: Some(fixed),
+                offset: na::Vector2::new(0.5, 0.0),
+                extent: half,
+            },
+        ]);
+    }
+    if y_sort {
+        *state.world.write_resource::<render::DrawOrder>() = render::DrawOrder::y_sort();
+    }
+    if turn_based || grid_move {
+        state.world.write_resource::<state::TurnMode>().0 = turn_based;
+        let player = state.spawn_player(na::zero());
+        if let Some(player) = player.filter(|_| grid_move) {
+            state
+                .world
+                .write_storage()
+                .insert(player, sim::GridMove::default())
+                .unwrap();
+        }
+    }
+    if let Some(interval) = spawner_interval {
+        let scale = *state.world.read_resource::<state::WorldScale>();
+        state
+            .world
+            .create_entity()
+            .with(sim::Transform(na::Isometry2::identity()))
+            .with(sim::Spawner {
+                interval,
+                last_spawn: 0.0,
+                template: sim::SpawnTemplate {
+                    layer: sim::Layer::PROJECTILE,
+                    radius: scale.ball_radius(),
+                    velocity: Some(na::Vector2::new(0.0, 4.0 * scale.ball_radius())),
+                    lifetime: Some(5.0),
+                    despawn_on_contact: true,
+                },
+            })
+            .build();
+    }
+    if let Some(rate) = tick_rate {
+        state.set_tick_rate(rate);
+    }
+    if let Some(scale) = time_scale {
+        state.set_time_scale(scale);
+    }
+    let mut loader = texture::TextureLoader::new(gfx.clone(), filtering);
+    for path in textures {
+        let texture = loader.load(path);
+        let mut builder = state
+            .world
+            .create_entity()
+            .with(render::Sprite)
+            .with(sim::Transform(na::Isometry2::identity()))
+            .with(texture);
+        if cutout {
+            builder = builder.with(render::Material::Cutout);
+        }
+        if let Some(count) = texture_repeat {
+            builder = builder.with(texture::TextureRepeat(na::Vector2::repeat(count)));
+        }
+        builder.build();
+    }
+    state.world.add_resource(loader);
+    if panel {
+        let size = state
+            .world
+            .read_resource::<state::WorldScale>()
+            .sprite_dimensions();
+        state
+            .world
+            .create_entity()
+            .with(render::Sprite)
+            .with(sim::Transform(na::Isometry2::identity()))
+            .with(render::Material::Rounded)
+            .with(render::Rounding {
+                radius: size.x * 0.25,
+                softness: 0.0,
+            })
+            .with(render::Tint(na::Vector4::new(0.2, 0.3, 0.5, 0.9)))
+            .build();
+    }
+    if light {
+        let radius = state
+            .world
+            .read_resource::<state::WorldScale>()
+            .sight_radius()
+            * 0.5;
+        state
+            .world
+            .create_entity()
+            .with(render::PointLight {
+                color: na::Vector3::new(1.0, 0.8, 0.5),
+                radius,
+            })
+            .with(sim::Transform(na::Isometry2::identity()))
+            .build();
     }
 
-    let mut state = state::State::new();
+    if let Some(shape) = tiles {
+        let mut tilemap = state.world.write_resource::<tilemap::Tilemap>();
+        tilemap.grid.shape = shape;
+        let grid = tilemap.grid;
+        let origin = tilemap::Tile::new(0, 0);
+        // Breadth-first from the origin, shading alternate rings
+        let mut frontier = vec![origin];
+        while let Some(tile) = frontier.pop() {
+            let distance = grid.distance(origin, tile);
+            let shade = [0.15, 0.1][distance as usize % 2];
+            tilemap
+                .tiles
+                .insert(tile, na::Vector4::new(shade, shade * 1.5, shade, 1.0));
+            frontier.extend(grid.neighbors(tile).filter(|x| {
+                grid.distance(origin, *x) <= TILE_DEMO_RADIUS && !tilemap.tiles.contains_key(x)
+            }));
+        }
+    }
 
-    let image_available = unsafe {
-        gfx.device
-            .create_semaphore(&Default::default(), None)
-            .unwrap()
-    };
-    let render_complete = unsafe {
-        gfx.device
-            .create_semaphore(&Default::default(), None)
-            .unwrap()
-    };
-    let _sem_guard = defer(|| unsafe {
-        gfx.device.destroy_semaphore(image_available, None);
-        gfx.device.destroy_semaphore(render_complete, None);
-    });
+    match prefab::Prefabs::load(&prefabs_dir) {
+        Ok(prefabs) => *state.world.write_resource() = prefabs,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("failed to read {}: {}", prefabs_dir.display(), e),
+    }
 
-    let mut running = true;
-    while running {
-        let mut suboptimal;
-        unsafe {
-            let image_index = loop {
-                match swapchain.acquire_next_image(image_available) {
-                    Ok((idx, sub)) => {
-                        suboptimal = sub;
-                        break idx;
-                    }
-                    Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
-                        swapchain.update();
-                        render.rebuild_framebuffers(
-                            swapchain.extent(),
-                            swapchain.frames().iter().map(|x| x.view),
-                        );
-                    }
-                    Err(e) => {
-                        panic!("{}", e);
-                    }
-                }
-            };
-            let extent = swapchain.extent();
-            render.set_scissors(vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent,
-            });
-            render.set_viewport(vk::Viewport {
-                x: 0.0,
-                y: 0.0,
-                width: extent.width as f32,
-                height: extent.height as f32,
-                min_depth: 0.0,
-                max_depth: 1.0,
-            });
-            render.set_fb_index(image_index);
-            render.run_now(&state.world.res);
-            gfx.device
-                .queue_submit(
-                    gfx.queue,
-                    &[vk::SubmitInfo::builder()
-                        .wait_semaphores(&[image_available])
-                        .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-                        .command_buffers(&[render.cmd()])
-                        .signal_semaphores(&[render_complete])
-                        .build()],
-                    vk::Fence::null(),
-                )
-                .unwrap();
-            match swapchain.queue_present(gfx.queue, render_complete, image_index) {
-                Ok(false) => {}
-                Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
-                    suboptimal = true;
-                }
-                Err(e) => panic!("{}", e),
-            };
-            gfx.device.queue_wait_idle(gfx.queue).unwrap(); // FIXME
+    if let Some(path) = tiled_path {
+        tiled::load(&path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e))
+            .spawn(&state.world);
+    }
+
+    let mut app = app::App::new(
+        gfx,
+        windows,
+        state,
+        app::Options {
+            anti_alias,
+            color_grading,
+            sample_shading,
+            hdr,
+            device_preference: config.device_preference,
+            on_demand,
+            profile,
+            render_stats,
+            collision_stats,
+        },
+    );
+    app.set_present_mode(config.present_mode.into());
+    if let Some(dir) = capture_dir {
+        app.capture(dir, capture_rate);
+    }
+    if let Some(path) = record_path {
+        match replay::Recorder::create(&path) {
+            Ok(x) => app.record(x),
+            Err(e) => warn!("failed to create {}: {}", path.display(), e),
         }
-        events_loop.poll_events(|e| {
-            use winit::{ElementState, Event, MouseButton, WindowEvent};
-            match e {
-                Event::WindowEvent { event, .. } => match event {
-                    WindowEvent::CloseRequested => {
-                        running = false;
-                    }
-                    WindowEvent::Resized(size) => {
-                        suboptimal = true;
-                        window_size = size;
-                    }
-                    WindowEvent::CursorMoved { position, .. } => {
-                        let f = window.window.get_hidpi_factor() as f32;
-                        state.move_cursor(&(na::Vector2::new(
-                            (position.x - window_size.width / 2.0) as f32,
-                            -(position.y - window_size.height / 2.0) as f32,
-                        ) * f));
-                    }
-                    WindowEvent::MouseInput {
-                        button: MouseButton::Left,
-                        state: s,
-                        ..
-                    } => {
-                        state.cursor_pressed(s == ElementState::Pressed);
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
-        });
-        if suboptimal {
-            unsafe {
-                swapchain.update();
-                render.rebuild_framebuffers(
-                    swapchain.extent(),
-                    swapchain.frames().iter().map(|x| x.view),
-                );
+    }
+    if let Some(path) = replay_path {
+        app.replay(
+            replay::Replay::open(&path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e)),
+        );
+    }
+
+    let mut next_frame = Instant::now();
+    let mut events = Vec::new();
+    while app.is_running() {
+        if app.render_frame() {
+            if let Some(interval) = frame_interval {
+                limit_frame_rate(&mut next_frame, interval);
             }
+        } else if app.is_idle() {
+            // Nothing will change until an event arrives, so sleep until then
+            events_loop.run_forever(|e| {
+                events.push(e);
+                winit::ControlFlow::Break
+            });
         }
-        state.step();
+        events_loop.poll_events(|e| events.push(e));
+        for e in events.drain(..) {
+            app.handle_event(&e);
+        }
+        app.update();
+    }
+    let size = app.window_size();
+    saved_config.window_size = [size.width, size.height];
+    let gfx = app.graphics().clone();
+    drop(app);
+    if let Err(e) = saved_config.save(&config_path) {
+        eprintln!("failed to save config: {}", e);
     }
     let pipeline_cache_data = unsafe {
         gfx.device
@@ -163,3 +547,32 @@ fn main() {
         eprintln!("failed to save pipeline cache: {}", e);
     }
 }
+
+/// Wait until `deadline`, then advance it by `interval`
+///
+/// If `deadline` has already passed, the next is set `interval` from now rather than trying to
+/// catch up.
+fn limit_frame_rate(deadline: &mut Instant, interval: Duration) {
+    let now = Instant::now();
+    if *deadline <= now {
+        *deadline = now + interval;
+        return;
+    }
+    let remaining = *deadline - now;
+    if remaining > FRAME_LIMIT_SPIN {
+        thread::sleep(remaining - FRAME_LIMIT_SPIN);
+    }
+    while Instant::now() < *deadline {
+        thread::yield_now();
+    }
+    *deadline += interval;
+}
+
+/// The value of `arg` if it's the flag `prefix`, e.g. `"--zoom="`
+fn strip_flag<'a>(arg: &'a str, prefix: &str) -> Option<&'a str> {
+    if arg.starts_with(prefix) {
+        Some(&arg[prefix.len()..])
+    } else {
+        None
+    }
+}
@@ -1,40 +1,260 @@
 use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::f32;
+use std::sync::{Arc, Mutex};
 
-use na::allocator::Allocator;
 use ordered_float::NotNaN;
-use radix_heap::RadixHeapMap;
+use rayon::prelude::*;
+
+/// Number of routes remembered by `NavMesh::plan`
+const CACHE_CAPACITY: usize = 64;
 
 #[derive(Default, Clone)]
 pub struct NavMesh {
     nodes: Vec<Node>,
+    /// Indexed by node; blocked nodes are never entered
+    blocked: Vec<bool>,
+    /// Costs of (node, edge) pairs used in place of the distance between node centers
+    edge_costs: HashMap<(u32, u32), f32>,
+    cache: RouteCache,
 }
 
 impl NavMesh {
     pub fn new(nodes: Vec<Node>) -> Self {
-        Self { nodes }
+        Self {
+            blocked: vec![false; nodes.len()],
+            nodes,
+            edge_costs: HashMap::new(),
+            cache: RouteCache::default(),
+        }
+    }
+
+    /// Prevent paths from passing through `node`, or allow them again
+    pub fn set_blocked(&mut self, node: u32, blocked: bool) {
+        if self.blocked[node as usize] != blocked {
+            self.blocked[node as usize] = blocked;
+            self.cache.clear();
+        }
     }
 
+    /// Override the cost of traversing `edge` out of `node`, e.g. to steer paths away from
+    /// congestion, or restore the default with `None`
+    ///
+    /// The default is the distance between the nodes' centers. Overriding it with anything smaller
+    /// lets the search's distance estimates exceed the true cost, so it may settle for a path
+    /// costlier than the cheapest.
+    ///
+    /// # Panics
+    /// - If `cost` is negative or NaN
+    pub fn set_edge_cost(&mut self, node: u32, edge: u32, cost: Option<f32>) {
+        if let Some(cost) = cost {
+            assert!(cost >= 0.0, "edge costs must not be negative");
+        }
+        let old = match cost {
+            Some(cost) => self.edge_costs.insert((node, edge), cost),
+            None => self.edge_costs.remove(&(node, edge)),
+        };
+        if old != cost {
+            self.cache.clear();
+        }
+    }
+
+    /// Find waypoints leading from `start` in `start_node` to `goal` in `goal_node`
+    ///
+    /// `None` if blocked nodes cut `goal_node` off from `start_node`, or `goal_node` is itself
+    /// blocked.
     pub fn plan(
         &self,
         start_node: u32,
         start: &na::Point2<f32>,
         goal_node: u32,
         goal: &na::Point2<f32>,
-    ) -> Vec<na::Point2<f32>> {
-        let channel = self.plan_channel(start_node, goal_node, goal);
-        refine_path(start, &channel)
+    ) -> Option<Vec<na::Point2<f32>>> {
+        Some(
+            self.plan_with_channel(start_node, start, goal_node, goal)?
+                .1,
+        )
+    }
+
+    /// Like `plan`, but for an agent of `radius`, keeping the path at least that far from the ends
+    /// of the portals it passes through
+    ///
+    /// Waypoints are placed beside each corner the path bends around, where the straight runs
+    /// leading in and out of it touch a circle of `radius` about the corner. Portals narrower than
+    /// the agent are passed through at their midpoints.
+    pub fn plan_with_radius(
+        &self,
+        start_node: u32,
+        start: &na::Point2<f32>,
+        goal_node: u32,
+        goal: &na::Point2<f32>,
+        radius: f32,
+    ) -> Option<Vec<na::Point2<f32>>> {
+        let channel = self.plan_channel(start_node, goal_node, goal)?;
+        Some(refine_path_with_radius(start, &channel, radius))
     }
 
-    /// Compute a sequence of edges to traverse using A*
+    /// Plan a path for each (start node, start, goal node, goal) request, in parallel
+    ///
+    /// Each search keeps its own scratch space and bypasses the route cache, so requests don't
+    /// contend for it.
+    pub fn plan_batch(
+        &self,
+        requests: &[(u32, na::Point2<f32>, u32, na::Point2<f32>)],
+    ) -> Vec<Option<Vec<na::Point2<f32>>>> {
+        requests
+            .par_iter()
+            .map(|&(start_node, ref start, goal_node, ref goal)| {
+                let route = self.search(start_node, goal_node, goal)?;
+                Some(refine_path(start, &self.channel(&route, goal)))
+            })
+            .collect()
+    }
+
+    /// Like `plan`, but also returns the channel of portals the path was refined from
+    ///
+    /// Each portal is a pair of vertices ordered left, right as seen when traveling towards the
+    /// goal, beginning with the portal out of `start_node` and ending with a degenerate portal at
+    /// `goal`. Useful for visualizing pathfinding.
+    pub fn plan_with_channel(
+        &self,
+        start_node: u32,
+        start: &na::Point2<f32>,
+        goal_node: u32,
+        goal: &na::Point2<f32>,
+    ) -> Option<(Channel, Vec<na::Point2<f32>>)> {
+        let channel = self.plan_channel(start_node, goal_node, goal)?;
+        let path = refine_path(start, &channel);
+        Some((channel, path))
+    }
+
+    /// The portal out of `node` nearest `point`, as its edge index, vertices, and distance from
+    /// `point`
+    ///
+    /// `point` is expected to lie within `node`. Portals into blocked nodes aren't exits, so they're
+    /// never returned. `None` if `node` has no other exits.
+    pub fn nearest_portal(
+        &self,
+        node: u32,
+        point: &na::Point2<f32>,
+    ) -> Option<(u32, [na::Point2<f32>; 2], f32)> {
+        self.nodes[node as usize]
+            .edges
+            .iter()
+            .enumerate()
+            .filter(|(_, edge)| !self.blocked[edge.neighbor as usize])
+            .map(|(i, edge)| {
+                let distance = segment_distance(&edge.vertices, point);
+                (i as u32, edge.vertices, distance)
+            })
+            .min_by(|x, y| x.2.partial_cmp(&y.2).expect("NaN"))
+    }
+
+    /// Draw the mesh's topology as an SVG document, for inspection in external tools
+    ///
+    /// Portals are blue segments, and adjacent nodes' centers are joined by gray lines. Each center
+    /// is marked with a dot labeled with its node's index, red if the node is blocked.
+    pub fn to_svg(&self) -> String {
+        let mut lo = na::Point2::new(f32::INFINITY, f32::INFINITY);
+        let mut hi = na::Point2::new(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for node in &self.nodes {
+            let vertices = node.edges.iter().flat_map(|x| x.vertices.iter());
+            for p in Some(&node.center).into_iter().chain(vertices) {
+                lo = na::Point2::new(lo.x.min(p.x), lo.y.min(p.y));
+                hi = na::Point2::new(hi.x.max(p.x), hi.y.max(p.y));
+            }
+        }
+        if self.nodes.is_empty() {
+            lo = na::Point2::origin();
+            hi = na::Point2::origin();
+        }
+        // Sized relative to the whole mesh so it reads the same at any scale
+        let stroke = (hi - lo).amax().max(1.0) / 200.0;
+        let pad = 4.0 * stroke;
+
+        // SVG's y axis points down
+        let mut out = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+            lo.x - pad,
+            -hi.y - pad,
+            hi.x - lo.x + 2.0 * pad,
+            hi.y - lo.y + 2.0 * pad
+        );
+        let mut line = |a: &na::Point2<f32>, b: &na::Point2<f32>, color: &str| {
+            out += &format!(
+                "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" \
+                 stroke=\"{}\" stroke-width=\"{}\"/>\n",
+                a.x, -a.y, b.x, -b.y, color, stroke
+            );
+        };
+        for (i, node) in self.nodes.iter().enumerate() {
+            // Each portal is listed by the nodes on both sides of it
+            for edge in node.edges.iter().filter(|x| x.neighbor > i as u32) {
+                let neighbor = &self.nodes[edge.neighbor as usize];
+                line(&node.center, &neighbor.center, "gray");
+                line(&edge.vertices[0], &edge.vertices[1], "blue");
+            }
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let color = if self.blocked[i] { "red" } else { "black" };
+            out += &format!(
+                "<circle cx=\"{x}\" cy=\"{y}\" r=\"{r}\" fill=\"{color}\"/>\n\
+                 <text x=\"{x}\" y=\"{y}\" dx=\"{r}\" font-size=\"{size}\">{i}</text>\n",
+                x = node.center.x,
+                y = -node.center.y,
+                r = 2.0 * stroke,
+                color = color,
+                size = 8.0 * stroke,
+                i = i
+            );
+        }
+        out += "</svg>\n";
+        out
+    }
+
+    /// Compute a sequence of edges to traverse, reusing the route from an earlier search towards
+    /// the same goal if possible
+    ///
+    /// The search's estimates depend on where in `goal_node` the goal lies, so routes are only
+    /// reused for exactly the same goal point; the start point doesn't affect the search.
     fn plan_channel(
         &self,
         start_node: u32,
         goal_node: u32,
         goal: &na::Point2<f32>,
-    ) -> Vec<[na::Point2<f32>; 2]> {
-        let mut frontier = RadixHeapMap::new_at(Reverse(NotNaN::new(0.0).unwrap()));
-        frontier.push(Reverse(NotNaN::new(0.0).unwrap()), start_node);
+    ) -> Option<Vec<[na::Point2<f32>; 2]>> {
+        let route = match self.cache.get(start_node, goal_node, goal) {
+            Some(route) => route,
+            None => {
+                let route = Arc::new(self.search(start_node, goal_node, goal)?);
+                self.cache
+                    .insert(start_node, goal_node, goal, route.clone());
+                route
+            }
+        };
+        Some(self.channel(&route, goal))
+    }
+
+    /// The portals crossed by `route`, followed by a degenerate portal at `goal`
+    fn channel(&self, route: &[(u32, u32)], goal: &na::Point2<f32>) -> Channel {
+        route
+            .iter()
+            .map(|&(node, edge)| self.nodes[node as usize].edges[edge as usize].vertices)
+            .chain(Some([*goal, *goal]))
+            .collect()
+    }
+
+    /// Find the (node, edge) pairs to traverse from `start_node` to `goal_node` using A*, if any
+    /// path exists
+    fn search(
+        &self,
+        start_node: u32,
+        goal_node: u32,
+        goal: &na::Point2<f32>,
+    ) -> Option<Vec<(u32, u32)>> {
+        // Overridden edge costs can make the heuristic overestimate, so keys aren't monotonic
+        let mut frontier = BinaryHeap::new();
+        frontier.push((Reverse(NotNaN::new(0.0).unwrap()), start_node));
         let mut came_from: Vec<Option<(u32, u32)>> = vec![None; self.nodes.len()];
         let mut cost = vec![f32::INFINITY; self.nodes.len()];
         cost[start_node as usize] = 0.0;
@@ -45,31 +265,37 @@ impl NavMesh {
             }
             for (i, next) in self.nodes[current as usize].edges.iter().enumerate() {
                 let next = next.neighbor;
+                if self.blocked[next as usize] {
+                    continue;
+                }
                 let next_cost = cost[current as usize] + self.edge_cost(current, i);
                 if next_cost >= cost[next as usize] {
                     continue;
                 }
                 cost[next as usize] = next_cost;
-                frontier.push(
+                frontier.push((
                     Reverse(NotNaN::new(next_cost + self.heuristic(next, goal)).expect("NaN")),
                     next,
-                );
+                ));
                 came_from[next as usize] = Some((current, i as u32));
             }
         }
 
-        let mut result = vec![[*goal, *goal]];
+        let mut result = Vec::new();
         let mut node = goal_node;
         while node != start_node {
-            let (prev, prev_edge) = came_from[node as usize].expect("unconnected graph");
-            result.push(self.nodes[prev as usize].edges[prev_edge as usize].vertices);
+            let (prev, prev_edge) = came_from[node as usize]?;
+            result.push((prev, prev_edge));
             node = prev;
         }
         result.reverse();
-        result
+        Some(result)
     }
 
     fn edge_cost(&self, node: u32, edge: usize) -> f32 {
+        if let Some(&cost) = self.edge_costs.get(&(node, edge as u32)) {
+            return cost;
+        }
         let node = &self.nodes[node as usize];
         let neighbor = &self.nodes[node.edges[edge].neighbor as usize];
         na::distance(&node.center, &neighbor.center)
@@ -87,6 +313,172 @@ impl NavMesh {
     }
 }
 
+/// Meshes covering the same space for different kinds of agent, e.g. a walking layer broken by
+/// gaps that a flying layer spans
+#[derive(Default, Clone)]
+pub struct NavMeshSet {
+    layers: HashMap<String, NavMesh>,
+}
+
+impl NavMeshSet {
+    /// Add or replace the layer called `name`, returning the previous one
+    pub fn insert(&mut self, name: impl Into<String>, mesh: NavMesh) -> Option<NavMesh> {
+        self.layers.insert(name.into(), mesh)
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&NavMesh> {
+        self.layers.get(name)
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut NavMesh> {
+        self.layers.get_mut(name)
+    }
+
+    /// Like `NavMesh::plan`, through the layer called `layer`, or `None` if there is no such layer
+    /// or no path through it
+    ///
+    /// Node indices are specific to each layer.
+    pub fn plan(
+        &self,
+        layer: &str,
+        start_node: u32,
+        start: &na::Point2<f32>,
+        goal_node: u32,
+        goal: &na::Point2<f32>,
+    ) -> Option<Vec<na::Point2<f32>>> {
+        self.layer(layer)?.plan(start_node, start, goal_node, goal)
+    }
+}
+
+/// A point the path must keep `radius` from, and the sign `area2` takes for a segment of the path
+/// followed by the point, or 0 if the path should pass through the point itself
+type Corner = (na::Point2<f32>, f32);
+
+/// Like `refine_path`, but keeping every segment of the path at least `radius` from the ends of the
+/// portals in `channel`
+///
+/// Runs the funnel algorithm over circles of `radius` about the portals' ends rather than the ends
+/// themselves, then joins the corners it finds with lines tangent to their circles.
+fn refine_path_with_radius(
+    start: &na::Point2<f32>,
+    channel: &[[na::Point2<f32>; 2]],
+    radius: f32,
+) -> Vec<na::Point2<f32>> {
+    let mut portals = Vec::with_capacity(channel.len() + 1);
+    portals.push([(*start, 0.0); 2]);
+    for &[left, right] in channel {
+        portals.push(if na::distance(&left, &right) <= 2.0 * radius {
+            [(na::center(&left, &right), 0.0); 2]
+        } else {
+            [(left, -1.0), (right, 1.0)]
+        });
+    }
+
+    let mut corners = vec![portals[0][0]];
+    let mut apex = portals[0][0];
+    // Indices of the portals whose ends bound the funnel, or `None` where a side meets the apex
+    let mut left = None::<usize>;
+    let mut right = None::<usize>;
+    let mut i = 1;
+    while i < portals.len() {
+        let [new_left, new_right] = portals[i];
+        let direction = tangent(&apex, &new_left, radius);
+        let narrower = left.map_or(true, |l| {
+            cross(&tangent(&apex, &portals[l][0], radius), &direction) >= 0.0
+        });
+        if narrower {
+            match right {
+                Some(r) if cross(&direction, &tangent(&apex, &portals[r][1], radius)) < 0.0 => {
+                    // Crossed over the right side, so the path bends around its end
+                    apex = portals[r][1];
+                    corners.push(apex);
+                    left = None;
+                    right = None;
+                    i = r + 1;
+                    continue;
+                }
+                _ => left = if new_left.0 == apex.0 { None } else { Some(i) },
+            }
+        }
+        let direction = tangent(&apex, &new_right, radius);
+        let narrower = right.map_or(true, |r| {
+            cross(&direction, &tangent(&apex, &portals[r][1], radius)) >= 0.0
+        });
+        if narrower {
+            match left {
+                Some(l) if cross(&tangent(&apex, &portals[l][0], radius), &direction) < 0.0 => {
+                    apex = portals[l][0];
+                    corners.push(apex);
+                    left = None;
+                    right = None;
+                    i = l + 1;
+                    continue;
+                }
+                _ => right = if new_right.0 == apex.0 { None } else { Some(i) },
+            }
+        }
+        i += 1;
+    }
+    corners.push(portals[portals.len() - 1][0]);
+
+    let mut result = Vec::new();
+    for window in corners.windows(3) {
+        let (point, side) = window[1];
+        if side == 0.0 || radius == 0.0 {
+            result.push(point);
+            continue;
+        }
+        // Directions away from the corner's center to where the path touches its circle
+        let touch = |from: &Corner, to: &Corner| {
+            let direction = tangent(from, to, radius).normalize();
+            na::Vector2::new(direction.y, -direction.x) * side
+        };
+        let incoming = touch(&window[0], &window[1]);
+        let outgoing = touch(&window[1], &window[2]);
+        // Where the tangents at `a` and `b` meet
+        let meet = |a: &na::Vector2<f32>, b: &na::Vector2<f32>| {
+            point + (a + b) * (radius / (1.0 + a.dot(b)))
+        };
+        if incoming.dot(&outgoing) >= 0.0 {
+            result.push(meet(&incoming, &outgoing));
+        } else {
+            // Too sharp a turn for the tangents to meet nearby, so go around by way of a third
+            let mut middle = incoming + outgoing;
+            if middle.norm_squared() < 1e-12 {
+                // Turning right around, so the far side is straight ahead
+                middle = na::Vector2::new(-incoming.y, incoming.x) * side;
+            }
+            let middle = middle.normalize();
+            result.push(meet(&incoming, &middle));
+            result.push(meet(&middle, &outgoing));
+        }
+    }
+    result
+}
+
+/// Direction of a line passing `to` on the side it requires, having passed `from` likewise, each
+/// at a distance of `radius`
+///
+/// Unit length unless the corners' circles overlap such that no such line exists, in which case
+/// it's the nearest thing.
+fn tangent(from: &Corner, to: &Corner, radius: f32) -> na::Vector2<f32> {
+    let offset = to.0 - from.0;
+    let length_squared = offset.norm_squared();
+    if length_squared == 0.0 {
+        return na::zero();
+    }
+    // Distance the line must cross between the sides of the corners it touches
+    let shift = (from.1 - to.1) * radius;
+    let along = (length_squared - shift * shift).max(0.0).sqrt();
+    let perp = na::Vector2::new(-offset.y, offset.x);
+    (offset * along + perp * shift) / length_squared
+}
+
+/// The z component of the cross product of `a` and `b`, positive if `b` is counterclockwise of `a`
+fn cross(a: &na::Vector2<f32>, b: &na::Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
 fn refine_path(start: &na::Point2<f32>, channel: &[[na::Point2<f32>; 2]]) -> Vec<na::Point2<f32>> {
     // https://digestingduck.blogspot.com/2010/03/simple-stupid-funnel-algorithm.html
     // https://skatgame.net/mburo/ps/thesis_demyen_2006.pdf
@@ -110,7 +502,7 @@ fn refine_path(start: &na::Point2<f32>, channel: &[[na::Point2<f32>; 2]]) -> Vec
             apex = right;
             result.push(*apex);
             left_index = right_index + 1;
-            right_index = right_index + 1;
+            right_index += 1;
             i = right_index + 2;
             continue;
         }
@@ -124,7 +516,7 @@ fn refine_path(start: &na::Point2<f32>, channel: &[[na::Point2<f32>; 2]]) -> Vec
         } else {
             apex = left;
             result.push(*apex);
-            left_index = left_index + 1;
+            left_index += 1;
             right_index = left_index + 1;
             i = right_index + 2;
             continue;
@@ -135,6 +527,83 @@ fn refine_path(start: &na::Point2<f32>, channel: &[[na::Point2<f32>; 2]]) -> Vec
     result
 }
 
+/// Portals passed through on the way to a goal, each as its left and right vertices
+pub type Channel = Vec<[na::Point2<f32>; 2]>;
+
+/// The (node, edge) pairs traversed between two nodes
+type Route = Arc<Vec<(u32, u32)>>;
+
+/// Least-recently-used map from start node, goal node, and goal point to the routes found
+/// between them
+#[derive(Default)]
+struct RouteCache(Mutex<RouteCacheInner>);
+
+#[derive(Default)]
+struct RouteCacheInner {
+    /// Incremented on every access
+    clock: u64,
+    /// Routes and the time they were last accessed
+    routes: HashMap<RouteKey, (u64, Route)>,
+}
+
+/// Start node, goal node, and bit patterns of the goal point's coordinates
+type RouteKey = (u32, u32, [u32; 2]);
+
+fn route_key(start: u32, goal_node: u32, goal: &na::Point2<f32>) -> RouteKey {
+    (start, goal_node, [goal.x.to_bits(), goal.y.to_bits()])
+}
+
+impl RouteCache {
+    fn get(&self, start: u32, goal_node: u32, goal: &na::Point2<f32>) -> Option<Route> {
+        let inner = &mut *self.0.lock().unwrap();
+        inner.clock += 1;
+        let entry = inner.routes.get_mut(&route_key(start, goal_node, goal))?;
+        entry.0 = inner.clock;
+        Some(entry.1.clone())
+    }
+
+    fn insert(&self, start: u32, goal_node: u32, goal: &na::Point2<f32>, route: Route) {
+        let inner = &mut *self.0.lock().unwrap();
+        if inner.routes.len() >= CACHE_CAPACITY {
+            let oldest = *inner
+                .routes
+                .iter()
+                .min_by_key(|(_, &(time, _))| time)
+                .unwrap()
+                .0;
+            inner.routes.remove(&oldest);
+        }
+        inner.clock += 1;
+        inner
+            .routes
+            .insert(route_key(start, goal_node, goal), (inner.clock, route));
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().routes.clear();
+    }
+}
+
+impl Clone for RouteCache {
+    /// Routes are cheap to recompute, so clones start out empty
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Distance from `point` to the nearest point on the line segment `segment`
+fn segment_distance(segment: &[na::Point2<f32>; 2], point: &na::Point2<f32>) -> f32 {
+    let [a, b] = *segment;
+    let ab = b - a;
+    let length_squared = ab.norm_squared();
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        na::clamp((point - a).dot(&ab) / length_squared, 0.0, 1.0)
+    };
+    na::distance(&(a + ab * t), point)
+}
+
 /// Compute two times the signed area of a triangle
 fn area2(a: &na::Point2<f32>, b: &na::Point2<f32>, c: &na::Point2<f32>) -> f32 {
     let b = b - a;
@@ -142,16 +611,21 @@ fn area2(a: &na::Point2<f32>, b: &na::Point2<f32>, c: &na::Point2<f32>) -> f32 {
     b.x * c.y - c.x * b.y
 }
 
+/// A convex region of the mesh
 #[derive(Clone)]
 pub struct Node {
-    center: na::Point2<f32>,
-    edges: Vec<Edge>,
+    pub center: na::Point2<f32>,
+    /// Portals into adjacent nodes
+    pub edges: Vec<Edge>,
 }
 
+/// A portal from one node into another
 #[derive(Clone)]
 pub struct Edge {
-    vertices: [na::Point2<f32>; 2],
-    neighbor: u32,
+    /// Ends of the portal, left then right as seen from inside the node it leads out of
+    pub vertices: [na::Point2<f32>; 2],
+    /// Index of the node the portal leads into
+    pub neighbor: u32,
 }
 
 #[cfg(test)]
@@ -182,7 +656,7 @@ mod tests {
             center: na::Point2::origin(),
             edges: vec![],
         }]);
-        let channel = mesh.plan_channel(0, 0, &na::Point2::origin());
+        let channel = mesh.plan_channel(0, 0, &na::Point2::origin()).unwrap();
         assert_eq!(channel.len(), 1);
     }
 
@@ -207,10 +681,423 @@ mod tests {
                 }],
             },
         ]);
-        let path = mesh.plan(0, &na::Point2::origin(), 1, &na::Point2::new(9.5, -5.0));
+        let path = mesh
+            .plan(0, &na::Point2::origin(), 1, &na::Point2::new(9.5, -5.0))
+            .unwrap();
         assert_eq!(path[..], [na::Point2::new(9.0, 0.0)][..]);
     }
 
+    #[test]
+    fn channel() {
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::origin(),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(9.0, 0.0), na::Point2::new(10.0, 1.0)],
+                    neighbor: 1,
+                }],
+            },
+            Node {
+                center: na::Point2::new(9.5, -5.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, 1.0), na::Point2::new(9.0, 0.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let goal = na::Point2::new(9.5, -5.0);
+        let (channel, path) = mesh
+            .plan_with_channel(0, &na::Point2::origin(), 1, &goal)
+            .unwrap();
+        assert_eq!(
+            channel[..],
+            [
+                [na::Point2::new(9.0, 0.0), na::Point2::new(10.0, 1.0)],
+                [goal, goal]
+            ][..]
+        );
+        assert_eq!(path, mesh.plan(0, &na::Point2::origin(), 1, &goal).unwrap());
+    }
+
+    #[test]
+    fn right_corner_radius() {
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::origin(),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(9.0, 0.0), na::Point2::new(10.0, 1.0)],
+                    neighbor: 1,
+                }],
+            },
+            Node {
+                center: na::Point2::new(9.5, -5.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, 1.0), na::Point2::new(9.0, 0.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let goal = na::Point2::new(9.5, -5.0);
+        let path = mesh
+            .plan_with_radius(0, &na::Point2::origin(), 1, &goal, 0.25)
+            .unwrap();
+        assert_eq!(path.len(), 1);
+        // Bends around the corner at exactly the agent's radius
+        let corner = na::Point2::new(9.0, 0.0);
+        let distance = clearance(&na::Point2::origin(), &path, &goal, &corner);
+        assert!((distance - 0.25).abs() < 1e-4);
+        assert_eq!(
+            mesh.plan_with_radius(0, &na::Point2::origin(), 1, &goal, 0.0)
+                .unwrap(),
+            mesh.plan(0, &na::Point2::origin(), 1, &goal).unwrap()
+        );
+    }
+
+    #[test]
+    fn left_corner_radius() {
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::origin(),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, 0.0), na::Point2::new(9.0, 1.0)],
+                    neighbor: 1,
+                }],
+            },
+            Node {
+                center: na::Point2::new(9.5, 5.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(9.0, 1.0), na::Point2::new(10.0, 0.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let goal = na::Point2::new(9.5, 5.0);
+        let path = mesh
+            .plan_with_radius(0, &na::Point2::origin(), 1, &goal, 0.25)
+            .unwrap();
+        assert_eq!(path.len(), 1);
+        let corner = na::Point2::new(9.0, 1.0);
+        let distance = clearance(&na::Point2::origin(), &path, &goal, &corner);
+        assert!((distance - 0.25).abs() < 1e-4);
+        assert_eq!(
+            mesh.plan_with_radius(0, &na::Point2::origin(), 1, &goal, 0.0)
+                .unwrap(),
+            mesh.plan(0, &na::Point2::origin(), 1, &goal).unwrap()
+        );
+    }
+
+    #[test]
+    fn narrow_portal() {
+        // --+--
+        // --+--
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::origin(),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, -1.0), na::Point2::new(10.0, 1.0)],
+                    neighbor: 1,
+                }],
+            },
+            Node {
+                center: na::Point2::new(20.0, 0.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, 1.0), na::Point2::new(10.0, -1.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let start = na::Point2::new(0.0, 0.5);
+        let goal = na::Point2::new(20.0, 0.5);
+        assert_eq!(
+            mesh.plan_with_radius(0, &start, 1, &goal, 0.25)
+                .unwrap()
+                .len(),
+            0
+        );
+        // Too narrow for the agent, so squeeze through the middle
+        assert_eq!(
+            mesh.plan_with_radius(0, &start, 1, &goal, 1.5).unwrap(),
+            vec![na::Point2::new(10.0, 0.0)]
+        );
+        // Starting within the radius of the portal's end, so get clear of it before passing
+        let start = na::Point2::new(9.9, 0.9);
+        let path = mesh.plan_with_radius(0, &start, 1, &goal, 0.5).unwrap();
+        assert_eq!(path.len(), 1);
+        let end = na::Point2::new(10.0, 1.0);
+        assert!(segment_distance(&[path[0], goal], &end) >= 0.5 - 1e-4);
+    }
+
+    #[test]
+    fn angled_portal_radius() {
+        //    /
+        // --/--
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::origin(),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(10.0, -1.0), na::Point2::new(12.0, 1.0)],
+                    neighbor: 1,
+                }],
+            },
+            Node {
+                center: na::Point2::new(20.0, 0.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(12.0, 1.0), na::Point2::new(10.0, -1.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let start = na::Point2::new(0.0, -0.9);
+        let goal = na::Point2::new(20.0, -0.9);
+        let path = mesh.plan_with_radius(0, &start, 1, &goal, 0.5).unwrap();
+        assert_eq!(path.len(), 1);
+        // Clear of both ends all the way through, despite crossing the portal at an angle
+        for end in &[na::Point2::new(10.0, -1.0), na::Point2::new(12.0, 1.0)] {
+            assert!(clearance(&start, &path, &goal, end) >= 0.5 - 1e-4);
+        }
+    }
+
+    #[test]
+    fn u_turn_radius() {
+        // 0 | 1
+        // --+
+        //   2
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::new(-1.0, 1.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(-2.0, 0.0), na::Point2::new(0.0, 0.0)],
+                    neighbor: 2,
+                }],
+            },
+            Node {
+                center: na::Point2::new(1.0, 1.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(0.0, 0.0), na::Point2::new(2.0, 0.0)],
+                    neighbor: 2,
+                }],
+            },
+            Node {
+                center: na::Point2::new(0.0, -1.0),
+                edges: vec![
+                    Edge {
+                        vertices: [na::Point2::new(0.0, 0.0), na::Point2::new(-2.0, 0.0)],
+                        neighbor: 0,
+                    },
+                    Edge {
+                        vertices: [na::Point2::new(2.0, 0.0), na::Point2::new(0.0, 0.0)],
+                        neighbor: 1,
+                    },
+                ],
+            },
+        ]);
+        let start = na::Point2::new(-0.5, 1.0);
+        let goal = na::Point2::new(0.5, 1.0);
+        let path = mesh.plan_with_radius(0, &start, 1, &goal, 0.25).unwrap();
+        // Too sharp a turn for one waypoint
+        assert_eq!(path.len(), 2);
+        let distance = clearance(&start, &path, &goal, &na::Point2::origin());
+        assert!((distance - 0.25).abs() < 1e-4);
+    }
+
+    /// Closest the path from `start` through `path` to `goal` comes to `point`
+    fn clearance(
+        start: &na::Point2<f32>,
+        path: &[na::Point2<f32>],
+        goal: &na::Point2<f32>,
+        point: &na::Point2<f32>,
+    ) -> f32 {
+        let points = Some(start)
+            .into_iter()
+            .chain(path)
+            .chain(Some(goal))
+            .collect::<Vec<_>>();
+        points
+            .windows(2)
+            .map(|x| segment_distance(&[*x[0], *x[1]], point))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn quad() -> NavMesh {
+        // +---+---+
+        // | 0 | 1 |
+        // +---+---+
+        // | 2 | 3 |
+        // +---+---+
+        let edge = |a: (f32, f32), b: (f32, f32), neighbor| Edge {
+            vertices: [na::Point2::new(a.0, a.1), na::Point2::new(b.0, b.1)],
+            neighbor,
+        };
+        NavMesh::new(vec![
+            Node {
+                center: na::Point2::new(-1.0, 1.0),
+                edges: vec![
+                    edge((0.0, 2.0), (0.0, 0.0), 1),
+                    edge((0.0, 0.0), (-2.0, 0.0), 2),
+                ],
+            },
+            Node {
+                center: na::Point2::new(1.0, 1.0),
+                edges: vec![
+                    edge((0.0, 0.0), (0.0, 2.0), 0),
+                    edge((2.0, 0.0), (0.0, 0.0), 3),
+                ],
+            },
+            Node {
+                center: na::Point2::new(-1.0, -1.0),
+                edges: vec![
+                    edge((-2.0, 0.0), (0.0, 0.0), 0),
+                    edge((0.0, 0.0), (0.0, -2.0), 3),
+                ],
+            },
+            Node {
+                center: na::Point2::new(1.0, -1.0),
+                edges: vec![
+                    edge((0.0, 0.0), (2.0, 0.0), 1),
+                    edge((0.0, -2.0), (0.0, 0.0), 2),
+                ],
+            },
+        ])
+    }
+
+    #[test]
+    fn svg() {
+        let mut mesh = quad();
+        mesh.set_blocked(3, true);
+        let svg = mesh.to_svg();
+        assert!(svg.starts_with("<svg"));
+        // A center-to-center line and a portal per adjacent pair
+        assert_eq!(svg.matches("<line").count(), 8);
+        assert_eq!(svg.matches("<circle").count(), 4);
+        assert_eq!(svg.matches("fill=\"red\"").count(), 1);
+        assert!(svg.contains(">3</text>"));
+    }
+
+    #[test]
+    fn cache() {
+        let mut mesh = quad();
+        let start = na::Point2::new(-1.0, 1.0);
+        let goal = na::Point2::new(1.0, -1.0);
+        assert!(mesh.cache.get(0, 3, &goal).is_none());
+        let first = mesh.plan_channel(0, 3, &goal).unwrap();
+        let route = mesh.cache.get(0, 3, &goal).unwrap();
+        assert_eq!(first, mesh.plan_channel(0, 3, &goal).unwrap());
+
+        mesh.set_blocked(route[1].0, true);
+        assert!(mesh.cache.get(0, 3, &goal).is_none());
+        let (channel, _) = mesh.plan_with_channel(0, &start, 3, &goal).unwrap();
+        assert_ne!(channel, first);
+        assert_eq!(channel.len(), 3);
+    }
+
+    #[test]
+    fn cache_goal_point() {
+        // Two goals in node 3, best reached through different neighbors
+        let start = na::Point2::new(-1.0, 1.0);
+        let goals = [na::Point2::new(1.9, -0.1), na::Point2::new(-0.1, -1.9)];
+        let mesh = quad();
+        for goal in &goals {
+            assert_eq!(
+                mesh.plan(0, &start, 3, goal),
+                quad().plan(0, &start, 3, goal)
+            );
+        }
+        assert_ne!(
+            mesh.cache.get(0, 3, &goals[0]),
+            mesh.cache.get(0, 3, &goals[1])
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        let mut mesh = quad();
+        let start = na::Point2::new(-1.0, 1.0);
+        let goal = na::Point2::new(1.0, -1.0);
+        assert!(mesh.plan(0, &start, 3, &goal).is_some());
+        mesh.set_blocked(3, true);
+        assert_eq!(mesh.plan(0, &start, 3, &goal), None);
+        // Cut the graph between the start and goal
+        mesh.set_blocked(3, false);
+        mesh.set_blocked(1, true);
+        mesh.set_blocked(2, true);
+        assert_eq!(mesh.plan(0, &start, 3, &goal), None);
+        assert_eq!(mesh.plan_with_radius(0, &start, 3, &goal, 0.5), None);
+    }
+
+    #[test]
+    fn edge_cost() {
+        let mut mesh = quad();
+        let goal = na::Point2::new(1.0, -1.0);
+        let first = mesh.plan_channel(0, 3, &goal).unwrap();
+        let route = mesh.cache.get(0, 3, &goal).unwrap();
+        mesh.set_edge_cost(route[0].0, route[0].1, Some(100.0));
+        let detour = mesh.plan_channel(0, 3, &goal).unwrap();
+        assert_ne!(detour, first);
+        assert_eq!(detour.len(), 3);
+        mesh.set_edge_cost(route[0].0, route[0].1, None);
+        assert_eq!(mesh.plan_channel(0, 3, &goal).unwrap(), first);
+    }
+
+    #[test]
+    fn cheap_edge_cost() {
+        let mut mesh = quad();
+        // Near a corner of node 1 that node 0 doesn't share, so the estimate drops sharply
+        // between them
+        let goal = na::Point2::new(1.9, 0.1);
+        // Free, where the heuristic assumes at least the centers' distance
+        mesh.set_edge_cost(2, 0, Some(0.0));
+        mesh.set_edge_cost(0, 0, Some(0.0));
+        let channel = mesh.plan_channel(2, 1, &goal).unwrap();
+        assert_eq!(channel.len(), 3);
+        assert_eq!(channel[0], mesh.nodes[2].edges[0].vertices);
+    }
+
+    #[test]
+    fn batch() {
+        let corners = [
+            na::Point2::new(-1.0, 1.0),
+            na::Point2::new(1.0, 1.0),
+            na::Point2::new(-1.0, -1.0),
+            na::Point2::new(1.0, -1.0),
+        ];
+        let requests = (0..4)
+            .flat_map(|a| (0..4).map(move |b| (a, b)))
+            .map(|(a, b)| (a, corners[a as usize], b, corners[b as usize]))
+            .collect::<Vec<_>>();
+        let expected = requests
+            .iter()
+            .map(|&(a, ref start, b, ref goal)| quad().plan(a, start, b, goal))
+            .collect::<Vec<_>>();
+        let mesh = quad();
+        assert_eq!(mesh.plan_batch(&requests), expected);
+        // Again, with routes now cached
+        assert_eq!(mesh.plan_batch(&requests), expected);
+    }
+
+    #[test]
+    fn batch_shared_goal_node() {
+        // Two goals in node 3, best reached through different neighbors
+        let start = na::Point2::new(-1.0, 1.0);
+        let goals = [na::Point2::new(1.9, -0.1), na::Point2::new(-0.1, -1.9)];
+        let expected = goals
+            .iter()
+            .map(|goal| quad().plan(0, &start, 3, goal))
+            .collect::<Vec<_>>();
+        assert_ne!(expected[0], expected[1]);
+        let requests = goals
+            .iter()
+            .map(|&goal| (0, start, 3, goal))
+            .collect::<Vec<_>>();
+        let mesh = quad();
+        for _ in 0..2 {
+            assert_eq!(mesh.plan_batch(&requests), expected);
+        }
+        // Cached by an earlier plan towards another goal in the same node
+        let mesh = quad();
+        mesh.plan(0, &start, 3, &goals[1]);
+        assert_eq!(mesh.plan_batch(&requests), expected);
+    }
+
     #[test]
     fn left_corner() {
         //   ||
@@ -232,7 +1119,9 @@ mod tests {
                 }],
             },
         ]);
-        let path = mesh.plan(0, &na::Point2::origin(), 1, &na::Point2::new(9.5, 5.0));
+        let path = mesh
+            .plan(0, &na::Point2::origin(), 1, &na::Point2::new(9.5, 5.0))
+            .unwrap();
         assert_eq!(path[..], [na::Point2::new(9.0, 1.0)][..]);
     }
 
@@ -256,7 +1145,9 @@ mod tests {
                 }],
             },
         ]);
-        let path = mesh.plan(0, &na::Point2::origin(), 1, &na::Point2::new(20.0, 0.0));
+        let path = mesh
+            .plan(0, &na::Point2::origin(), 1, &na::Point2::new(20.0, 0.0))
+            .unwrap();
         assert_eq!(path.len(), 0);
     }
 
@@ -295,12 +1186,94 @@ mod tests {
                 ],
             },
         ]);
-        let path = mesh.plan(
-            0,
-            &na::Point2::new(-1.0, 0.0),
-            1,
-            &na::Point2::new(1.0, 0.0),
-        );
+        let path = mesh
+            .plan(
+                0,
+                &na::Point2::new(-1.0, 0.0),
+                1,
+                &na::Point2::new(1.0, 0.0),
+            )
+            .unwrap();
         assert_eq!(path.len(), 1);
     }
+
+    #[test]
+    fn layers() {
+        // Walkers can't cross directly between 0 and 1
+        let mut walking = quad();
+        walking.nodes[0].edges.remove(0);
+        walking.nodes[1].edges.remove(0);
+        let mut set = NavMeshSet::default();
+        assert!(set.insert("walking", walking).is_none());
+        assert!(set.insert("flying", quad()).is_none());
+
+        let start = na::Point2::new(-1.0, 1.0);
+        let goal = na::Point2::new(1.0, 1.0);
+        // Flyers cross the gap, while walkers go the long way around
+        for &(layer, portals) in &[("flying", 1), ("walking", 3)] {
+            let mesh = set.layer(layer).unwrap();
+            let (channel, path) = mesh.plan_with_channel(0, &start, 1, &goal).unwrap();
+            assert_eq!(channel.len(), portals + 1);
+            assert_eq!(set.plan(layer, 0, &start, 1, &goal), Some(path));
+        }
+        assert_eq!(set.plan("swimming", 0, &start, 1, &goal), None);
+    }
+
+    #[test]
+    fn nearest_portal() {
+        // +--+--+
+        // |  |1 |
+        // +  +--+
+        // |0 |2 |
+        // +--+--+
+        let mesh = NavMesh::new(vec![
+            Node {
+                center: na::Point2::new(1.0, 2.0),
+                edges: vec![
+                    Edge {
+                        vertices: [na::Point2::new(2.0, 4.0), na::Point2::new(2.0, 2.0)],
+                        neighbor: 1,
+                    },
+                    Edge {
+                        vertices: [na::Point2::new(2.0, 2.0), na::Point2::new(2.0, 0.0)],
+                        neighbor: 2,
+                    },
+                ],
+            },
+            Node {
+                center: na::Point2::new(3.0, 3.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(2.0, 2.0), na::Point2::new(2.0, 4.0)],
+                    neighbor: 0,
+                }],
+            },
+            Node {
+                center: na::Point2::new(3.0, 1.0),
+                edges: vec![Edge {
+                    vertices: [na::Point2::new(2.0, 0.0), na::Point2::new(2.0, 2.0)],
+                    neighbor: 0,
+                }],
+            },
+        ]);
+        let (edge, vertices, distance) =
+            mesh.nearest_portal(0, &na::Point2::new(0.5, 3.5)).unwrap();
+        assert_eq!(edge, 0);
+        assert_eq!(
+            vertices,
+            [na::Point2::new(2.0, 4.0), na::Point2::new(2.0, 2.0)]
+        );
+        assert_eq!(distance, 1.5);
+        // Beyond the end of the nearest portal
+        let (edge, _, distance) = mesh.nearest_portal(0, &na::Point2::new(1.0, -1.0)).unwrap();
+        assert_eq!(edge, 1);
+        assert!((distance - 2.0f32.sqrt()).abs() < 1e-6);
+
+        let mut mesh = mesh;
+        mesh.set_blocked(1, true);
+        let (edge, _, distance) = mesh.nearest_portal(0, &na::Point2::new(0.5, 3.5)).unwrap();
+        assert_eq!(edge, 1);
+        assert!((distance - 1.5f32.hypot(1.5)).abs() < 1e-6);
+        mesh.set_blocked(0, true);
+        assert!(mesh.nearest_portal(1, &na::Point2::new(3.0, 3.0)).is_none());
+    }
 }
@@ -0,0 +1,253 @@
+//! Entity types defined by JSON files rather than code, spawned by name
+//!
+//! Sizes and speeds are in meters, converted to world units by the `WorldScale` in effect when a
+//! prefab is spawned.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::warn;
+use ncollide2d::shape;
+use serde::Deserialize;
+use specs::{Entity, World};
+
+use crate::render::{Material, Parallax, Tint};
+use crate::sim::{self, Layer};
+use crate::state::WorldScale;
+
+/// A bundle of components describing a kind of entity
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Prefab {
+    pub shape: PrefabShape,
+    /// Collision layer, defaulting to `Actor`
+    #[serde(default)]
+    pub layer: Option<PrefabLayer>,
+    /// `Material` to draw with, if not the default
+    #[serde(default)]
+    pub material: Option<Material>,
+    /// `Tint` in linear RGBA, if any
+    #[serde(default)]
+    pub tint: Option<[f32; 4]>,
+    /// `Parallax` factor, if not 1
+    #[serde(default)]
+    pub parallax: Option<f32>,
+    /// Initial `Velocity` in meters per second, if any
+    #[serde(default)]
+    pub velocity: Option<[f32; 2]>,
+    /// Seconds until the entity expires, if ever
+    #[serde(default)]
+    pub lifetime: Option<f32>,
+    /// Whether the entity is marked `DespawnOnContact`
+    #[serde(default)]
+    pub despawn_on_contact: bool,
+}
+
+/// Collider of a `Prefab`
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefabShape {
+    Ball { radius: f32 },
+    Box { half_extents: [f32; 2] },
+}
+
+/// Name of the `Layer` a `Prefab`'s collider is placed on
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefabLayer {
+    Terrain,
+    Actor,
+    Projectile,
+    Trigger,
+}
+
+impl From<PrefabLayer> for Layer {
+    fn from(x: PrefabLayer) -> Self {
+        match x {
+            PrefabLayer::Terrain => Layer::TERRAIN,
+            PrefabLayer::Actor => Layer::ACTOR,
+            PrefabLayer::Projectile => Layer::PROJECTILE,
+            PrefabLayer::Trigger => Layer::TRIGGER,
+        }
+    }
+}
+
+impl Prefab {
+    pub fn parse(json: &str) -> io::Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Create an entity with the prefab's components at `position`
+    ///
+    /// Returns `None` without spawning anything if the `SpawnLimit` has been reached.
+    pub fn spawn(&self, world: &World, position: na::Vector2<f32>) -> Option<Entity> {
+        let scale = world.read_resource::<WorldScale>().0;
+        let shape = match self.shape {
+            PrefabShape::Ball { radius } => {
+                shape::ShapeHandle::new(shape::Ball::new(radius * scale))
+            }
+            PrefabShape::Box { half_extents } => {
+                shape::ShapeHandle::new(shape::Cuboid::new(na::Vector2::from(half_extents) * scale))
+            }
+        };
+        let entity = sim::spawn_shape(
+            &world.entities(),
+            &mut world.write_resource(),
+            &mut world.write_resource(),
+            &mut world.write_storage(),
+            *world.read_resource(),
+            self.layer.map_or(Layer::ACTOR, Layer::from),
+            na::convert(na::Translation2::from(position)),
+            shape,
+            world.read_resource::<sim::SpawnQuery>().0,
+        )?;
+        if let Some(material) = self.material {
+            world.write_storage().insert(entity, material).unwrap();
+        }
+        if let Some(tint) = self.tint {
+            world
+                .write_storage()
+                .insert(entity, Tint(na::Vector4::from(tint)))
+                .unwrap();
+        }
+        if let Some(parallax) = self.parallax {
+            world
+                .write_storage()
+                .insert(entity, Parallax(parallax))
+                .unwrap();
+        }
+        if let Some(velocity) = self.velocity {
+            world
+                .write_storage()
+                .insert(entity, sim::Velocity(na::Vector2::from(velocity) * scale))
+                .unwrap();
+        }
+        if let Some(remaining) = self.lifetime {
+            world
+                .write_storage()
+                .insert(entity, sim::Timer { remaining })
+                .unwrap();
+        }
+        if self.despawn_on_contact {
+            world
+                .write_storage()
+                .insert(entity, sim::DespawnOnContact)
+                .unwrap();
+        }
+        Some(entity)
+    }
+}
+
+/// Every known `Prefab`, by name
+#[derive(Debug, Default, Clone)]
+pub struct Prefabs(pub HashMap<String, Prefab>);
+
+impl Prefabs {
+    /// Read every `.json` file in `dir`, each named for its file name without the extension
+    ///
+    /// Files that fail to parse are skipped with a warning.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut prefabs = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(x) => x.to_owned(),
+                None => continue,
+            };
+            match fs::read_to_string(&path).and_then(|x| Prefab::parse(&x)) {
+                Ok(prefab) => {
+                    prefabs.insert(name, prefab);
+                }
+                Err(e) => warn!("failed to load prefab {}: {}", path.display(), e),
+            }
+        }
+        Ok(Prefabs(prefabs))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.0.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ncollide2d::world::GeometricQueryType;
+
+    use super::*;
+    use crate::state::State;
+
+    #[test]
+    fn fireball() {
+        let prefab = Prefab::parse(include_str!("../prefabs/fireball.json")).unwrap();
+        let state = State::new();
+        state
+            .world
+            .write_resource::<Prefabs>()
+            .0
+            .insert("fireball".into(), prefab);
+        let prefab = state
+            .world
+            .read_resource::<Prefabs>()
+            .get("fireball")
+            .cloned()
+            .unwrap();
+        let entity = prefab
+            .spawn(&state.world, na::Vector2::new(1.0, 2.0))
+            .unwrap();
+        assert_eq!(
+            state.world.read_storage::<Material>().get(entity),
+            Some(&Material::Circle)
+        );
+        assert!(state.world.read_storage::<Tint>().get(entity).is_some());
+        assert!(state
+            .world
+            .read_storage::<sim::Velocity>()
+            .get(entity)
+            .is_some());
+        assert!(state
+            .world
+            .read_storage::<sim::Timer>()
+            .get(entity)
+            .is_some());
+        assert!(state
+            .world
+            .read_storage::<sim::DespawnOnContact>()
+            .get(entity)
+            .is_some());
+        assert!(Prefab::parse(r#"{ "shape": { "ball": { "radius": 1 } }, "size": 2 }"#).is_err());
+    }
+
+    #[test]
+    fn parallax() {
+        let prefab =
+            Prefab::parse(r#"{ "shape": { "ball": { "radius": 1 } }, "parallax": 0.5 }"#).unwrap();
+        let state = State::new();
+        let entity = prefab.spawn(&state.world, na::zero()).unwrap();
+        assert_eq!(state.dump_entity(entity)["Parallax"], 0.5);
+    }
+
+    #[test]
+    fn spawn_query() {
+        let prefab = Prefab::parse(r#"{ "shape": { "ball": { "radius": 1 } } }"#).unwrap();
+        let state = State::new();
+        state.world.write_resource::<sim::SpawnQuery>().0 = GeometricQueryType::Proximity(0.5);
+        let entity = prefab.spawn(&state.world, na::zero()).unwrap();
+        let root = state
+            .world
+            .read_storage::<sim::Collider>()
+            .get(entity)
+            .unwrap()
+            .root;
+        let collision = state.world.read_resource::<sim::CollisionWorld>();
+        assert!(collision
+            .collision_object(root)
+            .unwrap()
+            .query_type()
+            .is_proximity_query());
+    }
+}
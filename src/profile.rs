@@ -0,0 +1,78 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Accumulates time spent in named phases of the main loop
+#[derive(Default)]
+pub struct Profiler {
+    /// In order of first occurrence
+    phases: Vec<Phase>,
+}
+
+struct Phase {
+    name: &'static str,
+    total: Duration,
+    count: u32,
+}
+
+impl Profiler {
+    /// Account for one occurrence of `name` having taken `duration`
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|x| x.name == name) {
+            Some(phase) => {
+                phase.total += duration;
+                phase.count += 1;
+            }
+            None => self.phases.push(Phase {
+                name,
+                total: duration,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Mean duration of each phase recorded since the last reset
+    pub fn averages(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        self.phases.iter().map(|x| (x.name, x.total / x.count))
+    }
+
+    pub fn reset(&mut self) {
+        self.phases.clear();
+    }
+}
+
+/// Weight given to the latest frame by `FrameStats::average`
+const FRAME_SMOOTHING: f32 = 0.05;
+
+/// Recent time taken to render frames
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FrameStats {
+    /// Exponential moving average of the time spent rendering each frame, in seconds, excluding
+    /// waits for the swapchain
+    pub average: f32,
+    /// Frames recorded so far
+    pub frames: u64,
+}
+
+impl FrameStats {
+    pub fn record(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f32();
+        self.average = if self.frames == 0 {
+            seconds
+        } else {
+            self.average + (seconds - self.average) * FRAME_SMOOTHING
+        };
+        self.frames += 1;
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, average)) in self.averages().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {:.3}ms", name, average.as_secs_f64() * 1e3)?;
+        }
+        Ok(())
+    }
+}
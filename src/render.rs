@@ -1,33 +1,192 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::mem;
+use std::ptr;
+use std::slice;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use ash::version::DeviceV1_0;
-use ash::vk;
+use ash::{vk, Device};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use specs::shred::PanicHandler;
-use specs::{Join, Read, ReadStorage};
+use specs::{
+    Component, Entities, Entity, Join, NullStorage, Read, ReadStorage, RunNow, VecStorage, World,
+    Write, WriteStorage,
+};
+use specs_derive::Component;
 use vk_shader_macros::include_glsl;
 
 const SPRITE_VERT: &[u32] = include_glsl!("shaders/sprite.vert");
 const SPRITE_FRAG: &[u32] = include_glsl!("shaders/sprite.frag");
+const CIRCLE_VERT: &[u32] = include_glsl!("shaders/circle.vert");
+const CIRCLE_FRAG: &[u32] = include_glsl!("shaders/circle.frag");
+const QUAD_FRAG: &[u32] = include_glsl!("shaders/quad.frag");
+const TEXTURED_FRAG: &[u32] = include_glsl!("shaders/textured.frag");
+const CUTOUT_FRAG: &[u32] = include_glsl!("shaders/cutout.frag");
+const FULLSCREEN_VERT: &[u32] = include_glsl!("shaders/fullscreen.vert");
+const FXAA_FRAG: &[u32] = include_glsl!("shaders/fxaa.frag");
+const GRADE_FRAG: &[u32] = include_glsl!("shaders/grade.frag");
+const FOG_FRAG: &[u32] = include_glsl!("shaders/fog.frag");
+const GRID_VERT: &[u32] = include_glsl!("shaders/grid.vert");
+const GRID_FRAG: &[u32] = include_glsl!("shaders/grid.frag");
+const LINE_VERT: &[u32] = include_glsl!("shaders/line.vert");
+const LINE_FRAG: &[u32] = include_glsl!("shaders/line.frag");
+const ROUNDED_FRAG: &[u32] = include_glsl!("shaders/rounded.frag");
+const LIGHT_FRAG: &[u32] = include_glsl!("shaders/light.frag");
+const LIGHTMAP_FRAG: &[u32] = include_glsl!("shaders/lightmap.frag");
+const HDR10_FRAG: &[u32] = include_glsl!("shaders/hdr10.frag");
+
+/// Opacity of the preview drawn at the cursor while it's held
+const SPAWN_GHOST_ALPHA: f32 = 0.35;
+
+/// Seconds a ring placed by `ping` takes to expand and fade out
+const PING_DURATION: f32 = 0.75;
+
+/// Radius a ring placed by `ping` expands to, as a multiple of `WorldScale::ball_radius`
+const PING_RADIUS: f32 = 4.0;
+
+/// Number of line segments approximating each `Ping`'s ring
+const PING_SEGMENTS: usize = 32;
+
+/// Number of line segments approximating the outline of a round `Selected` sprite
+const SELECTION_SEGMENTS: usize = 32;
+
+/// Distance a `Billboard` is drawn up the screen per unit of height
+const BILLBOARD_LIFT: f32 = 0.5;
+/// Vertical squash of a `Billboard` whose height equals the sprite's
+const BILLBOARD_SQUASH: f32 = 0.25;
+
+/// Format of the light map, with headroom for overlapping lights to exceed 1
+const LIGHT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Format scenes are rendered in before being encoded for an HDR10 surface
+const HDR_SCENE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Minimum on-screen distance between grid lines, in pixels, below which the grid is hidden
+const MIN_GRID_SPACING: f32 = 4.0;
+
+/// Fraction of the frame budget that the average frame time must exceed to lower `Quality`
+const DEGRADE_THRESHOLD: f32 = 1.1;
+/// Fraction of the frame budget that the average frame time must fall below to raise `Quality`
+const RESTORE_THRESHOLD: f32 = 0.5;
+/// Frames to wait after changing `Quality` before changing it again, so `FrameStats` can settle
+const QUALITY_COOLDOWN: u32 = 120;
+/// Opacity below which `Render::pick` sees through a sprite, unless its material sets its own
+const MIN_PICK_ALPHA: f32 = 0.5;
 
 use crate::{
     defer,
     graphics::Graphics,
-    sim::{Collider, CollisionWorld},
-    state::Camera,
+    profile::{FrameStats, Profiler},
+    sim::{self, Collider, CollisionWorld, PreviousPosition, Transform},
+    state::{Camera, Cursor, FogOfWar, Player, TickProgress, Time, WorldScale},
+    texture::{self, Texture, TextureLoader, TextureRepeat},
+    tilemap::Tilemap,
 };
 
 pub struct Render {
     gfx: Arc<Graphics>,
     pipeline_layout: vk::PipelineLayout,
     render_pass: vk::RenderPass,
-    pipeline: vk::Pipeline,
+    /// Like `render_pass`, but preserving the contents of a previously presented image outside
+    /// the render area, if the scene is drawn directly to the swapchain
+    partial_pass: Option<vk::RenderPass>,
+    /// Indexed by `Material`
+    pipelines: Vec<vk::Pipeline>,
+    fog_layout: vk::PipelineLayout,
+    fog_pipeline: vk::Pipeline,
+    grid_layout: vk::PipelineLayout,
+    grid_pipeline: vk::Pipeline,
+    /// Draws solid-colored quads queued by `draw_quad`
+    quad_pipeline: vk::Pipeline,
+    /// Quads to draw in the next frame
+    quads: Vec<Quad>,
+    /// Every region pushed by `push_clip` for the next frame, each already within its parent
+    clips: Vec<vk::Rect2D>,
+    /// Indices into `clips` of the regions currently in effect, innermost last
+    clip_stack: Vec<usize>,
+    texture_set_layout: vk::DescriptorSetLayout,
+    textured_layout: vk::PipelineLayout,
+    /// Draws sprites whose `Texture` has loaded
+    textured_pipeline: vk::Pipeline,
+    /// Draws loaded textures with the `Cutout` material
+    cutout_pipeline: vk::Pipeline,
+    line_layout: vk::PipelineLayout,
+    /// Draws line segments queued by `draw_line`, with dynamic line width
+    line_pipeline: vk::Pipeline,
+    /// Line widths the device can draw, if it supports any besides 1
+    line_width_range: Option<[f32; 2]>,
+    /// Draws triangles queued by `draw_polygon`, with `line_layout`
+    polygon_pipeline: vk::Pipeline,
+    /// Line segments to draw in the next frame, as pairs of vertices
+    lines: Vec<LineVertex>,
+    /// Filled triangles to draw in the next frame, as triples of vertices
+    triangles: Vec<LineVertex>,
+    /// Triangles covering the `Tilemap`, drawn beneath sprites
+    tiles: Vec<LineVertex>,
+    /// Storage for `lines`, `triangles`, and `tiles`, grown as needed
+    line_buffer: Option<VertexBuffer>,
     pool: vk::CommandPool,
-    cmd: vk::CommandBuffer,
+    /// Indexed by framebuffer, the commands that draw into each
+    cmds: Vec<vk::CommandBuffer>,
+    /// Format the scene is rendered in, which differs from the output when post-processing for HDR
+    format: vk::Format,
     viewport: vk::Viewport,
     scissors: vk::Rect2D,
     framebuffers: Vec<vk::Framebuffer>,
     fb_index: u32,
+    /// Samples per pixel of the scene
+    samples: vk::SampleCountFlags,
+    /// Multisampled or offscreen image the scene is drawn into, if not drawn directly
+    target: Option<Attachment>,
+    /// Single-sampled image a multisampled `target` is resolved into for `post` to read
+    resolved: Option<Attachment>,
+    post: Option<Post>,
+    lighting: Lighting,
+    /// Sprites drawn in the previous frame, for finding what changed
+    drawn: HashMap<Entity, DrawnSprite>,
+    /// Everything else that affected the previous frame
+    drawn_scene: Option<DrawnScene>,
+    /// Sprites that survived culling in the previous frame
+    visible: u32,
+    /// Sprites drawn through each view in the previous frame, for `pick`
+    picking: Vec<PickView>,
+    /// Indexed by framebuffer, the area of each image that's out of date
+    stale: Vec<vk::Rect2D>,
+    /// Indexed by framebuffer, whether each image has been drawn since `rebuild_framebuffers`
+    presented: Vec<bool>,
+    /// Number of frames that differed from the one before, to tell when `cmds` are current
+    generation: u64,
+    /// Indexed by framebuffer, the `generation` each command buffer was recorded in along with its
+    /// number of draw calls, if it may be submitted again
+    recorded: Vec<Option<(u64, u32)>>,
+}
+
+/// What `Render` needs to know to redraw a sprite that changed
+#[derive(PartialEq)]
+struct DrawnSprite {
+    transform: na::Affine2<f32>,
+    tint: na::Vector4<f32>,
+    material: Material,
+    texture: Option<vk::DescriptorSet>,
+    params: [f32; 2],
+    uv_scale: na::Vector2<f32>,
+}
+
+/// State of the parts of a frame that aren't sprites, any change in which requires a full redraw
+#[derive(PartialEq)]
+struct DrawnScene {
+    viewproj: na::Affine2<f32>,
+    player: Option<na::Isometry2<f32>>,
+    fog: [f32; 3],
+    lights: Vec<(na::Affine2<f32>, PointLight)>,
+    ambient: na::Vector3<f32>,
+    /// `ColorGrading` exposure and gamma
+    grading: [f32; 2],
+    /// Whether quads, lines, polygons, or debug overlays were drawn
+    overlays: bool,
 }
 
 impl Drop for Render {
@@ -35,325 +194,4108 @@ impl Drop for Render {
         let device = &*self.gfx.device;
         unsafe {
             device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_pipeline_layout(self.fog_layout, None);
+            device.destroy_pipeline(self.fog_pipeline, None);
+            device.destroy_pipeline_layout(self.grid_layout, None);
+            device.destroy_pipeline(self.grid_pipeline, None);
+            device.destroy_pipeline(self.quad_pipeline, None);
+            device.destroy_pipeline_layout(self.textured_layout, None);
+            device.destroy_pipeline(self.textured_pipeline, None);
+            device.destroy_pipeline(self.cutout_pipeline, None);
+            device.destroy_descriptor_set_layout(self.texture_set_layout, None);
+            device.destroy_pipeline_layout(self.line_layout, None);
+            device.destroy_pipeline(self.line_pipeline, None);
+            device.destroy_pipeline(self.polygon_pipeline, None);
+            if let Some(ref buffer) = self.line_buffer {
+                buffer.destroy(device);
+            }
             device.destroy_render_pass(self.render_pass, None);
-            device.destroy_pipeline(self.pipeline, None);
+            if let Some(pass) = self.partial_pass {
+                device.destroy_render_pass(pass, None);
+            }
+            for &pipeline in &self.pipelines {
+                device.destroy_pipeline(pipeline, None);
+            }
             device.destroy_command_pool(self.pool, None);
             for &fb in &self.framebuffers {
                 device.destroy_framebuffer(fb, None);
             }
+            if let Some(ref target) = self.target {
+                target.destroy(device);
+            }
+            if let Some(ref resolved) = self.resolved {
+                resolved.destroy(device);
+            }
+            if let Some(ref post) = self.post {
+                post.destroy(device);
+            }
+            self.lighting.destroy(device);
         }
     }
 }
 
-impl<'a> specs::System<'a> for Render {
-    type SystemData = (
-        Read<'a, CollisionWorld, PanicHandler>,
-        Read<'a, Camera, PanicHandler>,
-        ReadStorage<'a, Collider>,
-    );
+/// Strategy for smoothing jagged edges
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AntiAlias {
+    None,
+    /// Multisampling with the given number of samples per pixel, or the nearest lower supported
+    /// count
+    Msaa(u32),
+    /// A post-process filter over the rendered image, cheaper but blurrier than multisampling
+    Fxaa,
+}
 
-    fn run(&mut self, (collision, camera, colliders): Self::SystemData) {
-        let projection = na::Affine2::from_matrix_unchecked(na::Matrix3::new_nonuniform_scaling(
-            &na::Vector2::new(2.0 / self.viewport.width, -2.0 / self.viewport.height),
-        ));
-        let viewproj = projection * camera.0.inverse();
+/// Selects the pipeline an entity is drawn with, defaulting to `Sprite`
+#[derive(Component, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub enum Material {
+    /// Opaque quad
+    Sprite,
+    /// Disc inscribed in the quad, blended according to the alpha of the entity's `Tint`
+    Circle,
+    /// Quad whose color is added to whatever is beneath it
+    Additive,
+    /// Edges of the quad's triangles
+    Wireframe,
+    /// Translucent quad with corners rounded according to the entity's `Rounding`, antialiased at
+    /// any zoom
+    Rounded,
+    /// Opaque texture with holes wherever its alpha is below the entity's `AlphaCutoff`
+    ///
+    /// Unlike blending, the result doesn't depend on the order overlapping sprites are drawn in.
+    /// Untextured sprites are drawn as with `Sprite`.
+    Cutout,
+}
 
-        let d = &*self.gfx.device;
-        let cmd = self.cmd;
-        unsafe {
-            d.begin_command_buffer(
-                cmd,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-            .unwrap();
-            d.cmd_set_viewport(cmd, 0, &[self.viewport]);
-            d.cmd_set_scissor(cmd, 0, &[self.scissors]);
+impl Material {
+    pub const ALL: [Material; 6] = [
+        Material::Sprite,
+        Material::Circle,
+        Material::Additive,
+        Material::Wireframe,
+        Material::Rounded,
+        Material::Cutout,
+    ];
+}
 
-            d.cmd_begin_render_pass(
-                cmd,
-                &vk::RenderPassBeginInfo::builder()
-                    .render_pass(self.render_pass)
-                    .framebuffer(self.framebuffers[self.fb_index as usize])
-                    .render_area(self.scissors)
-                    .clear_values(&[vk::ClearValue {
-                        color: vk::ClearColorValue {
-                            float32: [0.0, 0.0, 0.0, 0.0],
-                        },
-                    }]),
-                vk::SubpassContents::INLINE,
-            );
+/// Shape of a sprite drawn with the `Rounded` material, in world units
+///
+/// Entities without this component are drawn as sharp-cornered rectangles. A radius of at least
+/// half the sprite's smaller dimension gives a circle or capsule.
+#[derive(Component, Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Rounding {
+    pub radius: f32,
+    /// Width over which the edge fades out, in addition to the pixel needed to antialias it
+    pub softness: f32,
+}
 
-            d.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
-            for collider in (&colliders).join() {
-                let collider = collision
-                    .collision_object(collider.0)
-                    .expect("collider lifetime desync");
-                let transform = viewproj * collider.position();
-                d.cmd_push_constants(
-                    cmd,
-                    self.pipeline_layout,
-                    vk::ShaderStageFlags::VERTEX,
-                    0,
-                    &mem::transmute::<_, [u8; 56]>(SpriteParams {
-                        transform: transform.to_homogeneous().insert_row(3, 0.0),
-                        dimensions: na::Vector2::new(4.0, 4.0),
-                    }),
-                );
-                d.cmd_draw(cmd, 4, 1, 0, 0);
-            }
+/// Alpha below which a `Cutout` sprite's texels are discarded, defaulting to 0.5
+#[derive(Component, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct AlphaCutoff(pub f32);
 
-            d.cmd_end_render_pass(cmd);
+impl Default for AlphaCutoff {
+    fn default() -> Self {
+        AlphaCutoff(0.5)
+    }
+}
 
-            d.end_command_buffer(cmd).unwrap();
+/// Draw an entity that has no collider
+#[derive(Component, Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[storage(NullStorage)]
+pub struct Sprite;
+
+/// Color multiplied into an entity's sprite, in linear RGBA
+///
+/// Applied by the `Circle` and `Rounded` materials and by textured sprites. Untextured sprites drawn
+/// with any other material ignore it, so e.g. a `Fade` has no visible effect on them.
+#[derive(Component, Debug, Copy, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Tint(pub na::Vector4<f32>);
+
+impl Default for Tint {
+    fn default() -> Self {
+        Tint(na::Vector4::repeat(1.0))
+    }
+}
+
+/// Fraction of camera motion an entity appears to move against, for parallax layers
+///
+/// Entities without this component behave as if it were 1. Smaller factors appear more distant
+/// and by default are drawn beneath larger ones; 0 stays fixed on screen.
+#[derive(Component, Debug, Copy, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Parallax(pub f32);
+
+/// Height above the ground at which an entity appears, for a pseudo-3D look
+///
+/// The sprite is drawn shifted up the screen and squashed vertically the higher it is, as if the
+/// scene were viewed from above at an angle.
+#[derive(Component, Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Billboard {
+    /// In world units
+    pub height: f32,
+}
+
+/// Point a sprite rotates about, relative to its center in its unrotated frame, in world units
+///
+/// The pivot is drawn at the entity's position, so e.g. a weapon swings from its handle rather
+/// than spinning in place. Entities without this component pivot about their center.
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+#[storage(VecStorage)]
+pub struct Pivot(pub na::Vector2<f32>);
+
+/// Animates the alpha of an entity's `Tint` from `from` to `to` over `duration` seconds
+#[derive(Component, Debug, Copy, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Fade {
+    pub from: f32,
+    pub to: f32,
+    pub duration: f32,
+    pub elapsed: f32,
+}
+
+impl Fade {
+    pub fn new(from: f32, to: f32, duration: f32) -> Self {
+        Self {
+            from,
+            to,
+            duration,
+            elapsed: 0.0,
         }
     }
 }
 
-#[repr(C)]
-struct SpriteParams {
-    transform: na::Matrix4x3<f32>,
-    dimensions: na::Vector2<f32>,
+/// Illuminates the area within `radius` world units of an entity's `Transform`
+///
+/// While any point light is in view, the scene is multiplied by a light map made up of the
+/// `AmbientLight` plus the contribution of each light, which falls off smoothly with distance.
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+#[storage(VecStorage)]
+pub struct PointLight {
+    /// Linear RGB at the center of the light, which may exceed 1 for brighter lights
+    pub color: na::Vector3<f32>,
+    pub radius: f32,
 }
 
-impl Render {
-    pub fn new(gfx: Arc<Graphics>) -> Self {
-        let device = &*gfx.device;
-        unsafe {
-            let sprite_vert = device
-                .create_shader_module(
-                    &vk::ShaderModuleCreateInfo::builder().code(&SPRITE_VERT),
-                    None,
-                )
-                .unwrap();
-            let sv_guard = defer(|| device.destroy_shader_module(sprite_vert, None));
+/// A ring that expands from an entity's `Transform` while fading out, marking a spot for
+/// attention
+///
+/// Purely visual; `Pings` deletes the entity once the ring vanishes.
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+#[storage(VecStorage)]
+pub struct Ping {
+    /// Linear RGBA of the ring when it appears
+    pub color: na::Vector4<f32>,
+    /// Radius in world units the ring reaches as it vanishes
+    pub radius: f32,
+    pub duration: f32,
+    pub elapsed: f32,
+}
 
-            let sprite_frag = device
-                .create_shader_module(
-                    &vk::ShaderModuleCreateInfo::builder().code(&SPRITE_FRAG),
-                    None,
-                )
-                .unwrap();
-            let sf_guard = defer(|| device.destroy_shader_module(sprite_frag, None));
+impl Ping {
+    pub fn new(color: na::Vector4<f32>, radius: f32, duration: f32) -> Self {
+        Self {
+            color,
+            radius,
+            duration,
+            elapsed: 0.0,
+        }
+    }
 
-            let pipeline_layout = device
-                .create_pipeline_layout(
-                    &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[
-                        vk::PushConstantRange {
-                            stage_flags: vk::ShaderStageFlags::VERTEX,
-                            offset: 0,
-                            size: mem::size_of::<SpriteParams>() as u32,
-                        },
-                    ]),
-                    None,
-                )
-                .unwrap();
+    /// Fraction of the animation completed
+    fn progress(&self) -> f32 {
+        if self.duration > 0.0 {
+            (self.elapsed / self.duration).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
 
-            let render_pass = device
-                .create_render_pass(
-                    &vk::RenderPassCreateInfo::builder()
-                        .attachments(&[vk::AttachmentDescription {
-                            format: vk::Format::B8G8R8A8_SRGB,
-                            samples: vk::SampleCountFlags::TYPE_1,
-                            load_op: vk::AttachmentLoadOp::CLEAR,
-                            store_op: vk::AttachmentStoreOp::STORE,
-                            initial_layout: vk::ImageLayout::UNDEFINED,
-                            final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-                            ..Default::default()
-                        }])
-                        .subpasses(&[vk::SubpassDescription::builder()
-                            .color_attachments(&[vk::AttachmentReference {
-                                attachment: 0,
-                                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-                            }])
-                            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-                            .build()])
-                        .dependencies(&[vk::SubpassDependency {
-                            src_subpass: vk::SUBPASS_EXTERNAL,
-                            dst_subpass: 0,
-                            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
-                                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-                            ..Default::default()
-                        }]),
-                    None,
-                )
-                .unwrap();
+/// Mark `position` with an expanding, fading ring on a new entity of its own
+pub fn ping(world: &specs::World, position: na::Vector2<f32>) -> Entity {
+    let radius = PING_RADIUS * world.read_resource::<WorldScale>().ball_radius();
+    let entity = world.entities().create();
+    world
+        .write_storage()
+        .insert(entity, Transform(na::Isometry2::new(position, 0.0)))
+        .unwrap();
+    world
+        .write_storage()
+        .insert(
+            entity,
+            Ping::new(na::Vector4::new(1.0, 0.8, 0.2, 1.0), radius, PING_DURATION),
+        )
+        .unwrap();
+    entity
+}
 
-            let entry_point = b"main\0".as_ptr() as *const i8;
-            let noop_stencil_state = vk::StencilOpState {
-                fail_op: vk::StencilOp::KEEP,
-                pass_op: vk::StencilOp::KEEP,
-                depth_fail_op: vk::StencilOp::KEEP,
-                compare_op: vk::CompareOp::ALWAYS,
-                compare_mask: 0,
-                write_mask: 0,
-                reference: 0,
-            };
-            let mut pipelines = device
-                .create_graphics_pipelines(
-                    gfx.pipeline_cache,
-                    &[vk::GraphicsPipelineCreateInfo::builder()
-                        .stages(&[
-                            vk::PipelineShaderStageCreateInfo {
-                                stage: vk::ShaderStageFlags::VERTEX,
-                                module: sprite_vert,
-                                p_name: entry_point,
-                                ..Default::default()
-                            },
-                            vk::PipelineShaderStageCreateInfo {
-                                stage: vk::ShaderStageFlags::FRAGMENT,
-                                module: sprite_frag,
-                                p_name: entry_point,
-                                ..Default::default()
-                            },
-                        ])
-                        .vertex_input_state(&Default::default())
-                        .input_assembly_state(
-                            &vk::PipelineInputAssemblyStateCreateInfo::builder()
-                                .topology(vk::PrimitiveTopology::TRIANGLE_STRIP),
-                        )
-                        .viewport_state(
-                            &vk::PipelineViewportStateCreateInfo::builder()
-                                .scissor_count(1)
-                                .viewport_count(1),
-                        )
-                        .rasterization_state(
-                            &vk::PipelineRasterizationStateCreateInfo::builder()
-                                .cull_mode(vk::CullModeFlags::NONE)
-                                .polygon_mode(vk::PolygonMode::FILL)
-                                .line_width(1.0),
-                        )
-                        .multisample_state(
-                            &vk::PipelineMultisampleStateCreateInfo::builder()
-                                .rasterization_samples(vk::SampleCountFlags::TYPE_1),
-                        )
-                        .depth_stencil_state(
-                            &vk::PipelineDepthStencilStateCreateInfo::builder()
-                                .depth_test_enable(false)
-                                .front(noop_stencil_state)
-                                .back(noop_stencil_state),
-                        )
-                        .color_blend_state(
-                            &vk::PipelineColorBlendStateCreateInfo::builder().attachments(&[
-                                vk::PipelineColorBlendAttachmentState {
-                                    blend_enable: vk::TRUE,
-                                    src_color_blend_factor: vk::BlendFactor::ONE,
-                                    dst_color_blend_factor: vk::BlendFactor::ZERO,
-                                    color_blend_op: vk::BlendOp::ADD,
-                                    src_alpha_blend_factor: vk::BlendFactor::ONE,
-                                    dst_alpha_blend_factor: vk::BlendFactor::ZERO,
-                                    alpha_blend_op: vk::BlendOp::ADD,
-                                    color_write_mask: vk::ColorComponentFlags::all(),
-                                },
-                            ]),
-                        )
-                        .dynamic_state(
-                            &vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&[
-                                vk::DynamicState::VIEWPORT,
-                                vk::DynamicState::SCISSOR,
-                            ]),
-                        )
-                        .layout(pipeline_layout)
-                        .render_pass(render_pass)
-                        .subpass(0)
-                        .build()],
-                    None,
-                )
-                .unwrap()
-                .into_iter();
-            drop((sv_guard, sf_guard));
+/// Marks an entity as selected, outlining its sprite as configured by `SelectionHighlight`
+#[derive(Component, Debug, Copy, Clone, Default)]
+#[storage(NullStorage)]
+pub struct Selected;
 
-            let pipeline = pipelines.next().unwrap();
+/// Marks an entity whose sprite or collider survived culling in at least one view last frame
+///
+/// Maintained by `Render`, so this lags the simulation by up to a frame and is empty until the
+/// first frame is recorded.
+#[derive(Component, Debug, Copy, Clone, Default)]
+#[storage(NullStorage)]
+pub struct OnScreen;
 
-            let pool = gfx
-                .device
-                .create_command_pool(
-                    &vk::CommandPoolCreateInfo::builder()
-                        .flags(
-                            vk::CommandPoolCreateFlags::TRANSIENT
-                                | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
-                        )
-                        .queue_family_index(gfx.queue_family),
-                    None,
-                )
-                .unwrap();
-            let cmd = gfx
-                .device
-                .allocate_command_buffers(
-                    &vk::CommandBufferAllocateInfo::builder()
-                        .command_pool(pool)
-                        .level(vk::CommandBufferLevel::PRIMARY)
-                        .command_buffer_count(1),
-                )
-                .unwrap()
-                .into_iter()
-                .next()
-                .unwrap();
-            Self {
-                gfx,
-                pipeline_layout,
-                render_pass,
-                pipeline,
-                pool,
-                cmd,
-                viewport: Default::default(),
-                scissors: Default::default(),
-                framebuffers: vec![],
-                fb_index: 0,
-            }
+/// Appearance of the outline drawn around `Selected` entities
+///
+/// Outlines ignore `Parallax` and `Billboard`, surrounding where the sprite would be without them.
+#[derive(Debug, Copy, Clone)]
+pub struct SelectionHighlight {
+    /// Linear RGBA
+    pub color: na::Vector4<f32>,
+    /// Gap between the sprite and its outline, as a fraction of the sprite's size
+    pub padding: f32,
+}
+
+impl Default for SelectionHighlight {
+    fn default() -> Self {
+        Self {
+            color: na::Vector4::new(1.0, 0.9, 0.2, 1.0),
+            padding: 0.15,
         }
     }
+}
 
-    pub fn cmd(&self) -> vk::CommandBuffer {
-        self.cmd
+/// Light reaching every part of the scene when lighting is in effect, in linear RGB
+#[derive(Debug, Copy, Clone)]
+pub struct AmbientLight(pub na::Vector3<f32>);
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        AmbientLight(na::Vector3::repeat(0.2))
     }
+}
 
-    pub fn set_scissors(&mut self, scissors: vk::Rect2D) {
-        self.scissors = scissors;
+/// World-space reference grid drawn beneath everything else
+#[derive(Debug, Copy, Clone)]
+pub struct Grid {
+    /// Distance between adjacent lines, or `None` to hide the grid
+    pub spacing: Option<f32>,
+    /// Line color, in linear RGBA
+    pub color: na::Vector4<f32>,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self {
+            spacing: None,
+            color: na::Vector4::new(1.0, 1.0, 1.0, 0.1),
+        }
     }
+}
 
-    pub fn set_viewport(&mut self, viewport: vk::Viewport) {
-        self.viewport = viewport;
+/// Adjustments applied to the final image, for accessibility
+///
+/// Ignored unless `Render` was constructed with color grading enabled.
+#[derive(Debug, Copy, Clone)]
+pub struct ColorGrading {
+    /// Factor linear color is multiplied by
+    pub exposure: f32,
+    /// Linear color is raised to the power of `1 / gamma`, so larger values brighten midtones
+    pub gamma: f32,
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        Self {
+            exposure: 1.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+/// Whether sprites are drawn centered on pixel corners, so that pixel art moving at sub-pixel
+/// speeds doesn't shimmer
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PixelSnap(pub bool);
+
+/// Whether to redraw only the parts of the screen that changed since each swapchain image was
+/// last drawn, rather than the whole screen
+///
+/// Only sprites are tracked; any change to the camera, the fog, lighting, or immediate-mode drawing
+/// redraws everything. Has no effect with anti-aliasing or color grading enabled.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PartialRedraw(pub bool);
+
+/// Whether to submit the commands previously recorded for a swapchain image again, rather than
+/// recording new ones, when nothing drawn has changed since
+///
+/// Saves the CPU time spent recording while the scene is idle. Changes are detected as for
+/// `PartialRedraw`, so frames drawn through several `Views` are always recorded afresh.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ReuseCommands(pub bool);
+
+/// Cameras to draw the world through, each into its own part of the window, e.g. for split-screen
+///
+/// With no views, `Camera` fills the whole window. The cursor is always mapped through `Camera` as
+/// if it filled the window.
+#[derive(Debug, Clone, Default)]
+pub struct Views(pub Vec<View>);
+
+#[derive(Debug, Copy, Clone)]
+pub struct View {
+    /// In the same form as `Camera`, or `None` to use `Camera` itself
+    pub camera: Option<na::Similarity2<f32>>,
+    /// Top-left corner of the view as a fraction of the window's size, with y down
+    pub offset: na::Vector2<f32>,
+    /// Size of the view as a fraction of the window's
+    pub extent: na::Vector2<f32>,
+}
+
+impl View {
+    /// The part of `window` the view covers
+    fn viewport(&self, window: &vk::Viewport) -> vk::Viewport {
+        vk::Viewport {
+            x: window.x + self.offset.x * window.width,
+            y: window.y + self.offset.y * window.height,
+            width: self.extent.x * window.width,
+            height: self.extent.y * window.height,
+            min_depth: window.min_depth,
+            max_depth: window.max_depth,
+        }
+    }
+}
+
+/// What a `DrawOrder` knows about a sprite
+#[derive(Debug, Copy, Clone)]
+pub struct SortInfo {
+    pub entity: Entity,
+    /// Interpolated position in the world, before any parallax or billboard offset
+    pub position: na::Point2<f32>,
+    /// `Parallax` factor, or 1 if none
+    pub parallax: f32,
+    pub material: Material,
+}
+
+/// Key from which sprites are drawn in ascending order, so later sprites cover earlier ones
+///
+/// Keys compare lexicographically, and sprites with equal keys are drawn in order of entity ID. The
+/// default draws more distant parallax layers first.
+pub struct DrawOrder(pub Box<SortKeyFn>);
+
+type SortKeyFn = dyn Fn(&SortInfo) -> (f32, f32) + Send + Sync;
+
+impl DrawOrder {
+    /// Within each parallax layer, draw sprites nearer the bottom of the world over those above
+    /// them, as suits a top-down view
+    pub fn y_sort() -> Self {
+        DrawOrder(Box::new(|x| (x.parallax, -x.position.y)))
+    }
+}
+
+impl Default for DrawOrder {
+    fn default() -> Self {
+        DrawOrder(Box::new(|x| (x.parallax, 0.0)))
     }
+}
+
+/// What's drawn through one camera
+struct ViewDraws {
+    camera: na::Similarity2<f32>,
+    viewport: vk::Viewport,
+    scissors: vk::Rect2D,
+    viewproj: na::Affine2<f32>,
+    /// Sprites in drawing order
+    draws: Vec<SpriteDraw>,
+    lights: Vec<(na::Affine2<f32>, PointLight)>,
+}
+
+/// Sprites drawn through one camera, as `Render::pick` sees them
+struct PickView {
+    viewport: vk::Viewport,
+    /// Of each sprite, before its transform
+    half_extents: na::Vector2<f32>,
+    /// In drawing order
+    sprites: Vec<PickSprite>,
+}
+
+/// A sprite as `Render::pick` sees it
+struct PickSprite {
+    entity: Entity,
+    /// Into clip space
+    transform: na::Affine2<f32>,
+    material: Material,
+    params: [f32; 2],
+    /// Alpha of the sprite's `Tint`
+    opacity: f32,
+    /// Opacity of the sprite's texture, if it was drawn with one
+    texture: Option<Arc<texture::AlphaMask>>,
+    /// Copies of the texture across the quad
+    uv_scale: na::Vector2<f32>,
+    address_mode: vk::SamplerAddressMode,
+}
 
-    /// Recreate framebuffers for a new set of image views
+impl PickSprite {
+    /// Whether the sprite is drawn opaque enough at `uv`, in [-1, 1] across its quad with y up, to
+    /// be picked there
     ///
-    /// # Safety
-    /// - Must not be called while rendering is in progress
-    /// - Must be passed valid ImageViews that will outlive rendering done using them
-    pub unsafe fn rebuild_framebuffers(
-        &mut self,
-        extent: vk::Extent2D,
-        views: impl IntoIterator<Item = vk::ImageView>,
-    ) {
-        let fbs = views
-            .into_iter()
-            .map(|view| {
-                self.gfx.device.create_framebuffer(
-                    &vk::FramebufferCreateInfo::builder()
-                        .render_pass(self.render_pass)
-                        .attachments(&[view])
-                        .width(extent.width)
-                        .height(extent.height)
-                        .layers(1),
-                    None,
-                )
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
-        for &fb in &self.framebuffers {
-            self.gfx.device.destroy_framebuffer(fb, None);
+    /// `half_extents` is the size of the quad, in world units, before the sprite's transform.
+    fn covers(&self, uv: na::Point2<f32>, half_extents: &na::Vector2<f32>) -> bool {
+        if uv.x.abs() > 1.0 || uv.y.abs() > 1.0 {
+            return false;
         }
-        self.framebuffers = fbs;
+        let alpha = match self.texture {
+            Some(ref texture) => texture.sample(
+                (uv.x * 0.5 + 0.5) * self.uv_scale.x,
+                (uv.y * -0.5 + 0.5) * self.uv_scale.y,
+                self.address_mode,
+            ),
+            None => match self.material {
+                Material::Circle if uv.coords.norm_squared() > 1.0 => 0.0,
+                Material::Rounded => {
+                    // As in rounded.frag, ignoring the antialiased fringe
+                    let radius = self.params[0].min(half_extents.x.min(half_extents.y));
+                    let q = uv.coords.component_mul(half_extents).abs() - half_extents
+                        + na::Vector2::repeat(radius);
+                    let dist = q.map(|x| x.max(0.0)).norm() + q.x.max(q.y).min(0.0) - radius;
+                    if dist > 0.0 {
+                        0.0
+                    } else {
+                        1.0
+                    }
+                }
+                _ => 1.0,
+            },
+        } * self.opacity;
+        let threshold = match (self.material, &self.texture) {
+            (Material::Cutout, Some(_)) => self.params[0],
+            _ => MIN_PICK_ALPHA,
+        };
+        alpha >= threshold
+    }
+}
+
+/// Material, transform of the pivot, tint, `DrawOrder` key, texture, entity, material parameters,
+/// pivot, and texture repetitions
+type SpriteDraw = (
+    Material,
+    na::Affine2<f32>,
+    Tint,
+    (f32, f32),
+    Option<vk::DescriptorSet>,
+    Entity,
+    [f32; 2],
+    na::Vector2<f32>,
+    na::Vector2<f32>,
+);
+
+/// Frame rate that `AdaptiveQuality` tries to maintain, or `None` to leave quality alone
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TargetFrameRate(pub Option<f32>);
+
+/// Expensive rendering features currently allowed, as decided by `AdaptiveQuality`
+///
+/// Applying a change requires recreating the `Render`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Quality {
+    /// Whether `AntiAlias::Msaa` may be used; if not, no anti-aliasing is performed instead
+    pub msaa: bool,
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Self { msaa: true }
     }
+}
 
-    /// Set the index of the framebuffer to use on the next pass
+/// Lower `Quality` when frames take too long for the `TargetFrameRate`, and raise it again once
+/// there's plenty of headroom
+///
+/// The gap between the thresholds and a cooldown after each change prevent oscillation.
+#[derive(Default)]
+pub struct AdaptiveQuality {
+    /// Frames left until quality may change again
+    cooldown: u32,
+}
+
+impl<'a> specs::System<'a> for AdaptiveQuality {
+    type SystemData = (
+        Read<'a, TargetFrameRate, PanicHandler>,
+        Read<'a, FrameStats, PanicHandler>,
+        Write<'a, Quality, PanicHandler>,
+    );
+
+    fn run(&mut self, (target, stats, mut quality): Self::SystemData) {
+        let budget = match target.0 {
+            Some(rate) => 1.0 / rate,
+            None => return,
+        };
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+            return;
+        }
+        let average = stats.average;
+        if quality.msaa && average > budget * DEGRADE_THRESHOLD {
+            info!(
+                "frame time {:.3}ms over budget, disabling MSAA",
+                average * 1e3
+            );
+            quality.msaa = false;
+            self.cooldown = QUALITY_COOLDOWN;
+        } else if !quality.msaa && average < budget * RESTORE_THRESHOLD {
+            info!(
+                "frame time {:.3}ms well under budget, enabling MSAA",
+                average * 1e3
+            );
+            quality.msaa = true;
+            self.cooldown = QUALITY_COOLDOWN;
+        }
+    }
+}
+
+/// What `Render` did to draw the most recent frame
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RenderStats {
+    pub draw_calls: u32,
+    /// Entities with a sprite or collider, including those culled
+    pub sprites: u32,
+    /// Sprites skipped for lying entirely outside the viewport
+    pub culled: u32,
+}
+
+impl RenderStats {
+    /// Sprites at least partly within the viewport
+    pub fn visible(&self) -> u32 {
+        self.sprites - self.culled
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} draw calls, {} sprites, {} visible, {} culled",
+            self.draw_calls,
+            self.sprites,
+            self.visible(),
+            self.culled
+        )
+    }
+}
+
+/// Overlays drawn to aid debugging
+#[derive(Debug, Copy, Clone)]
+pub struct DebugDraw {
+    /// Mark each point where colliders touch, with a line along the contact normal
+    pub contacts: bool,
+    /// Outline the bounding box the broad phase uses for each collision object
+    pub aabbs: bool,
+    /// Draw a line from the center of each collider in the direction it faces
+    pub headings: bool,
+    /// Width in pixels of lines queued by `draw_line`, clamped to what the device supports
     ///
-    /// # Safety
-    /// - Must not currently be in use
-    pub unsafe fn set_fb_index(&mut self, index: u32) {
-        self.fb_index = index;
+    /// Devices without the `wide_lines` feature always draw lines one pixel wide.
+    pub line_width: f32,
+}
+
+impl Default for DebugDraw {
+    fn default() -> Self {
+        Self {
+            contacts: false,
+            aabbs: false,
+            headings: false,
+            line_width: 1.0,
+        }
+    }
+}
+
+pub fn setup(world: &mut specs::World) {
+    world.add_resource(DebugDraw::default());
+    world.add_resource(SelectionHighlight::default());
+    world.add_resource(Grid::default());
+    world.add_resource(ColorGrading::default());
+    world.add_resource(RenderStats::default());
+    world.add_resource(PixelSnap::default());
+    world.add_resource(PartialRedraw::default());
+    world.add_resource(ReuseCommands::default());
+    world.add_resource(TargetFrameRate::default());
+    world.add_resource(Quality::default());
+    world.add_resource(AmbientLight::default());
+    world.add_resource(Views::default());
+    world.add_resource(DrawOrder::default());
+    world.register::<Material>();
+    world.register::<Rounding>();
+    world.register::<AlphaCutoff>();
+    world.register::<Sprite>();
+    world.register::<Tint>();
+    world.register::<Parallax>();
+    world.register::<Billboard>();
+    world.register::<Pivot>();
+    world.register::<Fade>();
+    world.register::<Texture>();
+    world.register::<TextureRepeat>();
+    world.register::<PointLight>();
+    world.register::<Ping>();
+    world.register::<Selected>();
+    world.register::<OnScreen>();
+}
+
+/// Advance `Fade`s, removing those that have finished
+pub struct Fades;
+
+impl<'a> specs::System<'a> for Fades {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        WriteStorage<'a, Fade>,
+        WriteStorage<'a, Tint>,
+    );
+
+    fn run(&mut self, (entities, time, mut fades, mut tints): Self::SystemData) {
+        let mut finished = Vec::new();
+        for (entity, fade) in (&entities, &mut fades).join() {
+            fade.elapsed += time.dt;
+            let t = if fade.duration > 0.0 {
+                (fade.elapsed / fade.duration).min(1.0)
+            } else {
+                1.0
+            };
+            let mut tint = tints.get(entity).cloned().unwrap_or_default();
+            tint.0.w = fade.from + (fade.to - fade.from) * t;
+            tints.insert(entity, tint).unwrap();
+            if t >= 1.0 {
+                finished.push(entity);
+            }
+        }
+        for entity in finished {
+            fades.remove(entity);
+        }
+    }
+}
+
+/// Advance `Ping`s, deleting their entities once finished
+pub struct Pings;
+
+impl<'a> specs::System<'a> for Pings {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        WriteStorage<'a, Ping>,
+    );
+
+    fn run(&mut self, (entities, time, mut pings): Self::SystemData) {
+        for (entity, ping) in (&entities, &mut pings).join() {
+            ping.elapsed += time.dt;
+            if ping.progress() >= 1.0 {
+                entities.delete(entity).unwrap();
+            }
+        }
+    }
+}
+
+impl<'a> specs::System<'a> for Render {
+    type SystemData = (
+        (
+            Read<'a, Camera, PanicHandler>,
+            Read<'a, Views, PanicHandler>,
+            Read<'a, DrawOrder, PanicHandler>,
+            Read<'a, ReuseCommands, PanicHandler>,
+        ),
+        Read<'a, WorldScale, PanicHandler>,
+        Read<'a, TickProgress, PanicHandler>,
+        Read<'a, FogOfWar, PanicHandler>,
+        Read<'a, Grid, PanicHandler>,
+        Read<'a, ColorGrading, PanicHandler>,
+        Read<'a, DebugDraw, PanicHandler>,
+        Read<'a, PixelSnap, PanicHandler>,
+        Read<'a, PartialRedraw, PanicHandler>,
+        Read<'a, AmbientLight, PanicHandler>,
+        Read<'a, Tilemap, PanicHandler>,
+        Read<'a, Cursor, PanicHandler>,
+        Read<'a, CollisionWorld, PanicHandler>,
+        Entities<'a>,
+        Option<Read<'a, TextureLoader>>,
+        Write<'a, RenderStats, PanicHandler>,
+        ReadStorage<'a, Collider>,
+        ReadStorage<'a, PreviousPosition>,
+        (
+            ReadStorage<'a, Material>,
+            ReadStorage<'a, Rounding>,
+            ReadStorage<'a, AlphaCutoff>,
+        ),
+        ReadStorage<'a, Sprite>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Tint>,
+        (
+            ReadStorage<'a, Parallax>,
+            ReadStorage<'a, Billboard>,
+            ReadStorage<'a, Pivot>,
+        ),
+        (ReadStorage<'a, Texture>, ReadStorage<'a, TextureRepeat>),
+        (
+            ReadStorage<'a, PointLight>,
+            ReadStorage<'a, Ping>,
+            ReadStorage<'a, Selected>,
+            Read<'a, SelectionHighlight, PanicHandler>,
+            WriteStorage<'a, OnScreen>,
+        ),
+    );
+
+    fn run(
+        &mut self,
+        (
+            (camera, views, order, reuse),
+            scale,
+            progress,
+            fog,
+            grid,
+            grading,
+            debug,
+            pixel_snap,
+            partial_redraw,
+            ambient,
+            tilemap,
+            cursor,
+            collision,
+            entities,
+            loader,
+            mut stats,
+            colliders,
+            previous,
+            (materials, roundings, cutoffs),
+            sprites,
+            transforms,
+            players,
+            tints,
+            (parallaxes, billboards, pivots),
+            (textures, repeats),
+            (point_lights, pings, selected, highlight, mut on_screen),
+        ): Self::SystemData,
+    ) {
+        // Preview of the ball that releasing the cursor will spawn
+        if cursor.pressed {
+            self.draw_quad(
+                na::Isometry2::new(cursor.position, 0.0),
+                scale.sprite_dimensions(),
+                na::Vector4::new(1.0, 1.0, 1.0, SPAWN_GHOST_ALPHA),
+            );
+        }
+
+        for (&tile, &color) in &tilemap.tiles {
+            let corners = tilemap.grid.corners(tile);
+            for pair in corners[1..].windows(2) {
+                for &position in &[corners[0], pair[0], pair[1]] {
+                    self.tiles.push(LineVertex { position, color });
+                }
+            }
+        }
+
+        if debug.aabbs {
+            let color = na::Vector4::new(0.0, 1.0, 1.0, 0.5);
+            for aabb in sim::broad_phase_aabbs(&collision) {
+                let (lo, hi) = (*aabb.mins(), *aabb.maxs());
+                self.draw_polyline(
+                    &[
+                        lo,
+                        na::Point2::new(hi.x, lo.y),
+                        hi,
+                        na::Point2::new(lo.x, hi.y),
+                        lo,
+                    ],
+                    color,
+                );
+            }
+        }
+
+        if debug.headings {
+            let color = na::Vector4::new(1.0, 1.0, 0.0, 1.0);
+            let length = 2.0 * scale.ball_radius();
+            for (_, transform, previous) in (&colliders, &transforms, previous.maybe()).join() {
+                let position = match previous {
+                    Some(previous) => interpolate(&previous.0, &transform.0, progress.0),
+                    None => transform.0,
+                };
+                // Facing along the local x axis
+                self.draw_line(
+                    position * na::Point2::origin(),
+                    position * na::Point2::new(length, 0.0),
+                    color,
+                );
+            }
+        }
+
+        for (ping, transform) in (&pings, &transforms).join() {
+            // Expand quickly at first, slowing as the ring fades
+            let t = ping.progress();
+            let radius = ping.radius * (1.0 - (1.0 - t) * (1.0 - t));
+            let center = transform.0 * na::Point2::origin();
+            let points = (0..=PING_SEGMENTS)
+                .map(|i| {
+                    let angle = i as f32 / PING_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+                    center + na::Vector2::new(angle.cos(), angle.sin()) * radius
+                })
+                .collect::<Vec<_>>();
+            let mut color = ping.color;
+            color.w *= 1.0 - t;
+            self.draw_polyline(&points, color);
+        }
+
+        let outline_extents = scale.sprite_dimensions() * (0.5 + highlight.padding);
+        for (_, transform, previous, material) in
+            (&selected, &transforms, previous.maybe(), materials.maybe()).join()
+        {
+            let position = match previous {
+                Some(previous) => interpolate(&previous.0, &transform.0, progress.0),
+                None => transform.0,
+            };
+            let outline = match material {
+                Some(Material::Circle) => (0..=SELECTION_SEGMENTS)
+                    .map(|i| {
+                        let angle =
+                            i as f32 / SELECTION_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+                        position
+                            * na::Point2::from(
+                                na::Vector2::new(angle.cos(), angle.sin())
+                                    .component_mul(&outline_extents),
+                            )
+                    })
+                    .collect::<Vec<_>>(),
+                _ => [
+                    (-1.0, -1.0),
+                    (1.0, -1.0),
+                    (1.0, 1.0),
+                    (-1.0, 1.0),
+                    (-1.0, -1.0),
+                ]
+                .iter()
+                .map(|&(x, y)| {
+                    position * na::Point2::new(x * outline_extents.x, y * outline_extents.y)
+                })
+                .collect::<Vec<_>>(),
+            };
+            self.draw_polyline(&outline, highlight.color);
+        }
+
+        let player = (&players, &transforms, previous.maybe()).join().next().map(
+            |(_, transform, previous)| match previous {
+                Some(previous) => interpolate(&previous.0, &transform.0, progress.0),
+                None => transform.0,
+            },
+        );
+
+        let cameras = if views.0.is_empty() {
+            vec![(camera.0, self.viewport, self.scissors)]
+        } else {
+            views
+                .0
+                .iter()
+                .map(|view| {
+                    let viewport = view.viewport(&self.viewport);
+                    (
+                        view.camera.unwrap_or(camera.0),
+                        viewport,
+                        pixel_rect(&viewport),
+                    )
+                })
+                .collect()
+        };
+        let visible = colliders.mask() | sprites.mask();
+        let half_extents = scale.sprite_dimensions() * 0.5;
+        let mut sprites = 0;
+        let mut culled = 0;
+        let mut views = Vec::with_capacity(cameras.len());
+        for (camera, viewport, scissors) in cameras {
+            let projection =
+                na::Affine2::from_matrix_unchecked(na::Matrix3::new_nonuniform_scaling(
+                    &na::Vector2::new(2.0 / viewport.width, -2.0 / viewport.height),
+                ));
+            let viewproj = projection * camera.inverse();
+            let mut draws = (
+                &entities,
+                &visible,
+                &transforms,
+                previous.maybe(),
+                materials.maybe(),
+                tints.maybe(),
+                parallaxes.maybe(),
+                textures.maybe(),
+                roundings.maybe(),
+                cutoffs.maybe(),
+                billboards.maybe(),
+                pivots.maybe(),
+                repeats.maybe(),
+            )
+                .join()
+                .map(
+                    |(
+                        entity,
+                        _,
+                        transform,
+                        previous,
+                        material,
+                        tint,
+                        parallax,
+                        texture,
+                        rounding,
+                        cutoff,
+                        billboard,
+                        pivot,
+                        repeat,
+                    )| {
+                        let mut position = match previous {
+                            Some(previous) => interpolate(&previous.0, &transform.0, progress.0),
+                            None => transform.0,
+                        };
+                        let factor = parallax.map_or(1.0, |x| x.0);
+                        let material = material.cloned().unwrap_or(Material::Sprite);
+                        let key = (order.0)(&SortInfo {
+                            entity,
+                            position: na::Point2::from(position.translation.vector),
+                            parallax: factor,
+                            material,
+                        });
+                        position.translation.vector +=
+                            camera.isometry.translation.vector * (1.0 - factor);
+                        let position = match billboard {
+                            Some(billboard) => raise(
+                                &position,
+                                billboard.height,
+                                &camera.isometry.rotation,
+                                scale.sprite_dimensions().y,
+                            ),
+                            None => na::Affine2::from_matrix_unchecked(position.to_homogeneous()),
+                        };
+                        let params = match material {
+                            Material::Rounded => {
+                                let rounding = rounding.cloned().unwrap_or_default();
+                                [rounding.radius, rounding.softness]
+                            }
+                            Material::Cutout => [cutoff.cloned().unwrap_or_default().0, 0.0],
+                            _ => [0.0; 2],
+                        };
+                        (
+                            material,
+                            position,
+                            tint.cloned().unwrap_or_default(),
+                            key,
+                            // Untextured until loaded
+                            texture.and_then(|&x| loader.as_ref()?.descriptor_set(x)),
+                            entity,
+                            params,
+                            pivot.map_or_else(na::zero, |x| x.0),
+                            repeat.map_or_else(|| na::Vector2::repeat(1.0), |x| x.0),
+                        )
+                    },
+                )
+                .collect::<Vec<_>>();
+            let total = draws.len() as u32;
+            draws.retain(|x| !is_offscreen(&(viewproj * centered(&x.1, &x.7)), &half_extents));
+            sprites += total;
+            culled += total - draws.len() as u32;
+            draws.sort_by(|a, b| {
+                a.3.partial_cmp(&b.3)
+                    .expect("NaN")
+                    .then(a.5.id().cmp(&b.5.id()))
+            });
+
+            let lights = (&point_lights, &transforms, previous.maybe())
+                .join()
+                .map(|(&light, transform, previous)| {
+                    let position = match previous {
+                        Some(previous) => interpolate(&previous.0, &transform.0, progress.0),
+                        None => transform.0,
+                    };
+                    (viewproj * position, light)
+                })
+                .filter(|(transform, light)| {
+                    !is_offscreen(transform, &na::Vector2::repeat(light.radius))
+                })
+                .collect::<Vec<_>>();
+
+            views.push(ViewDraws {
+                camera,
+                viewport,
+                scissors,
+                viewproj,
+                draws,
+                lights,
+            });
+        }
+        self.visible = sprites - culled;
+        on_screen.clear();
+        for view in &views {
+            for draw in &view.draws {
+                on_screen.insert(draw.5, OnScreen).unwrap();
+            }
+        }
+        self.picking = views
+            .iter()
+            .map(|view| PickView {
+                viewport: view.viewport,
+                half_extents,
+                sprites: view
+                    .draws
+                    .iter()
+                    .map(|x| PickSprite {
+                        entity: x.5,
+                        transform: view.viewproj * centered(&x.1, &x.7),
+                        material: x.0,
+                        params: x.6,
+                        opacity: (x.2).0.w,
+                        texture: x
+                            .4
+                            .and_then(|_| loader.as_ref()?.alpha_mask(*textures.get(x.5)?)),
+                        uv_scale: x.8,
+                        address_mode: match (&loader, textures.get(x.5)) {
+                            (Some(loader), Some(&texture)) => {
+                                loader.filtering(texture).address_mode
+                            }
+                            _ => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                        },
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        // Find the area that changed since the previous frame, which is only tracked for a single
+        // view
+        let damage = match views[..] {
+            [ref view] => {
+                let drawn = view
+                    .draws
+                    .iter()
+                    .map(
+                        |&(
+                            material,
+                            position,
+                            tint,
+                            _,
+                            texture,
+                            entity,
+                            params,
+                            pivot,
+                            uv_scale,
+                        )| {
+                            let sprite = DrawnSprite {
+                                transform: view.viewproj * centered(&position, &pivot),
+                                tint: tint.0,
+                                material,
+                                texture,
+                                params,
+                                uv_scale,
+                            };
+                            (entity, sprite)
+                        },
+                    )
+                    .collect::<HashMap<_, _>>();
+                let scene = DrawnScene {
+                    viewproj: view.viewproj,
+                    player,
+                    fog: [fog.radius, fog.softness, fog.brightness],
+                    lights: view.lights.clone(),
+                    ambient: ambient.0,
+                    grading: [grading.exposure, grading.gamma],
+                    overlays: !self.quads.is_empty()
+                        || !self.lines.is_empty()
+                        || !self.triangles.is_empty()
+                        || !self.tiles.is_empty()
+                        || debug.contacts,
+                };
+                let damage = if scene.overlays || self.drawn_scene.as_ref() != Some(&scene) {
+                    self.scissors
+                } else {
+                    let bounds = |x: &DrawnSprite| {
+                        screen_rect(&x.transform, &half_extents, &view.viewport, &view.scissors)
+                    };
+                    let removed = self
+                        .drawn
+                        .iter()
+                        .filter(|(entity, old)| drawn.get(entity) != Some(old))
+                        .map(|(_, old)| bounds(old));
+                    let added = drawn
+                        .iter()
+                        .filter(|(entity, new)| self.drawn.get(entity) != Some(new))
+                        .map(|(_, new)| bounds(new));
+                    removed.chain(added).fold(vk::Rect2D::default(), union)
+                };
+                self.drawn = drawn;
+                self.drawn_scene = Some(scene);
+                damage
+            }
+            _ => {
+                self.drawn.clear();
+                self.drawn_scene = None;
+                self.scissors
+            }
+        };
+        for stale in &mut self.stale {
+            *stale = union(*stale, damage);
+        }
+        let fb = self.fb_index as usize;
+        let (render_pass, area) = match self.partial_pass {
+            Some(pass) if partial_redraw.0 && self.presented[fb] => {
+                let stale = self.stale[fb];
+                if stale.extent.width == 0 || stale.extent.height == 0 {
+                    // Nothing changed, but an empty render area isn't allowed
+                    let pixel = vk::Rect2D {
+                        offset: self.scissors.offset,
+                        extent: vk::Extent2D {
+                            width: 1,
+                            height: 1,
+                        },
+                    };
+                    (pass, pixel)
+                } else {
+                    (pass, stale)
+                }
+            }
+            _ => (self.render_pass, self.scissors),
+        };
+        self.stale[fb] = vk::Rect2D::default();
+        self.presented[fb] = true;
+
+        if damage.extent.width != 0 && damage.extent.height != 0 {
+            self.generation += 1;
+        }
+        match self.recorded[fb] {
+            Some((generation, draw_calls)) if reuse.0 && generation == self.generation => {
+                *stats = RenderStats {
+                    draw_calls,
+                    sprites,
+                    culled,
+                };
+                self.clear_queued();
+                return;
+            }
+            _ => {}
+        }
+
+        let d = &*self.gfx.device;
+        let cmd = self.cmds[fb];
+        let mut draw_calls = 0;
+        unsafe {
+            let vertices = self.lines.len() + self.triangles.len() + self.tiles.len();
+            if vertices != 0 {
+                // The previous frame has finished by now, so the buffer is free to overwrite
+                let size = (vertices * mem::size_of::<LineVertex>()) as vk::DeviceSize;
+                match self.line_buffer {
+                    Some(ref buffer) if buffer.size >= size => {}
+                    _ => {
+                        if let Some(old) = self.line_buffer.take() {
+                            old.destroy(d);
+                        }
+                        self.line_buffer =
+                            Some(VertexBuffer::new(&self.gfx, size.next_power_of_two()));
+                    }
+                }
+                let memory = self.line_buffer.as_ref().unwrap().memory;
+                let mapped = d
+                    .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                    .unwrap() as *mut LineVertex;
+                ptr::copy_nonoverlapping(self.lines.as_ptr(), mapped, self.lines.len());
+                ptr::copy_nonoverlapping(
+                    self.triangles.as_ptr(),
+                    mapped.add(self.lines.len()),
+                    self.triangles.len(),
+                );
+                ptr::copy_nonoverlapping(
+                    self.tiles.as_ptr(),
+                    mapped.add(self.lines.len() + self.triangles.len()),
+                    self.tiles.len(),
+                );
+                d.unmap_memory(memory);
+            }
+
+            d.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::builder().flags(if reuse.0 {
+                    vk::CommandBufferUsageFlags::empty()
+                } else {
+                    vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT
+                }),
+            )
+            .unwrap();
+            d.cmd_set_viewport(cmd, 0, &[self.viewport]);
+
+            let lit = views.iter().any(|x| !x.lights.is_empty());
+            let mut passes = Vec::with_capacity(3);
+            if lit {
+                passes.push(PassDecl {
+                    pass: Pass::Lights,
+                    reads: &[],
+                    writes: &[Target::LightMap],
+                });
+            }
+            passes.push(PassDecl {
+                pass: Pass::Scene,
+                reads: if lit { &[Target::LightMap] } else { &[] },
+                writes: if self.post.is_some() {
+                    &[Target::Scene]
+                } else {
+                    &[Target::Output]
+                },
+            });
+            if self.post.is_some() {
+                passes.push(PassDecl {
+                    pass: Pass::Post,
+                    reads: &[Target::Scene],
+                    writes: &[Target::Output],
+                });
+            }
+            let clear = [vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            }];
+            for step in schedule(&passes).expect("cyclic pass dependencies") {
+                for &target in &step.barriers {
+                    self.read_barrier(cmd, target);
+                }
+                match step.pass {
+                    Pass::Lights => {
+                        self.gfx.begin_label(cmd, cstr!("lights"));
+                        d.cmd_set_scissor(cmd, 0, &[self.scissors]);
+                        d.cmd_begin_render_pass(
+                            cmd,
+                            &vk::RenderPassBeginInfo::builder()
+                                .render_pass(self.lighting.render_pass)
+                                .framebuffer(self.lighting.framebuffer.unwrap())
+                                .render_area(self.scissors)
+                                .clear_values(&[vk::ClearValue {
+                                    color: vk::ClearColorValue {
+                                        float32: [ambient.0.x, ambient.0.y, ambient.0.z, 1.0],
+                                    },
+                                }]),
+                            vk::SubpassContents::INLINE,
+                        );
+                        d.cmd_bind_pipeline(
+                            cmd,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            self.lighting.light_pipeline,
+                        );
+                        for view in &views {
+                            d.cmd_set_viewport(cmd, 0, &[view.viewport]);
+                            d.cmd_set_scissor(cmd, 0, &[view.scissors]);
+                            for &(transform, ref light) in &view.lights {
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.pipeline_layout,
+                                    vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                                    0,
+                                    SpriteParams {
+                                        transform: transform.into(),
+                                        dimensions: na::Vector2::repeat(2.0 * light.radius),
+                                        params: [0.0; 2],
+                                        tint: light.color.push(1.0),
+                                        pivot: na::zero(),
+                                        uv_scale: na::Vector2::repeat(1.0),
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_draw(cmd, 4, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+                        }
+                        d.cmd_end_render_pass(cmd);
+                        self.gfx.end_label(cmd);
+                    }
+                    Pass::Scene => {
+                        self.gfx.begin_label(cmd, cstr!("scene"));
+
+                        d.cmd_begin_render_pass(
+                            cmd,
+                            &vk::RenderPassBeginInfo::builder()
+                                .render_pass(render_pass)
+                                .framebuffer(self.framebuffers[fb])
+                                .render_area(area)
+                                .clear_values(&clear),
+                            vk::SubpassContents::INLINE,
+                        );
+
+                        for view in views.drain(..) {
+                            let ViewDraws {
+                                camera,
+                                viewport,
+                                scissors,
+                                viewproj,
+                                draws,
+                                ..
+                            } = view;
+                            d.cmd_set_viewport(cmd, 0, &[viewport]);
+                            d.cmd_set_scissor(cmd, 0, &[intersection(area, scissors)]);
+
+                            if let Some(spacing) = grid.spacing {
+                                if spacing / camera.scaling() >= MIN_GRID_SPACING {
+                                    let half_extents =
+                                        na::Vector2::new(viewport.width, viewport.height) * 0.5;
+                                    let corners = [
+                                        camera * na::Point2::from(half_extents),
+                                        camera * na::Point2::from(-half_extents),
+                                        camera * na::Point2::new(half_extents.x, -half_extents.y),
+                                        camera * na::Point2::new(-half_extents.x, half_extents.y),
+                                    ];
+                                    let lo = corners.iter().fold(corners[0].coords, |acc, x| {
+                                        acc.zip_map(&x.coords, f32::min)
+                                    });
+                                    let hi = corners.iter().fold(corners[0].coords, |acc, x| {
+                                        acc.zip_map(&x.coords, f32::max)
+                                    });
+                                    let first = (lo / spacing).map(f32::ceil) * spacing;
+                                    let columns = ((hi.x - first.x) / spacing).floor() as u32 + 1;
+                                    let rows = ((hi.y - first.y) / spacing).floor() as u32 + 1;
+                                    d.cmd_bind_pipeline(
+                                        cmd,
+                                        vk::PipelineBindPoint::GRAPHICS,
+                                        self.grid_pipeline,
+                                    );
+                                    d.cmd_push_constants(
+                                        cmd,
+                                        self.grid_layout,
+                                        vk::ShaderStageFlags::VERTEX
+                                            | vk::ShaderStageFlags::FRAGMENT,
+                                        0,
+                                        GridParams {
+                                            viewproj: viewproj.into(),
+                                            lo,
+                                            hi,
+                                            first,
+                                            spacing,
+                                            columns,
+                                            color: grid.color,
+                                        }
+                                        .as_bytes(),
+                                    );
+                                    d.cmd_draw(cmd, 2 * (columns + rows), 1, 0, 0);
+                                    draw_calls += 1;
+                                }
+                            }
+
+                            if !self.tiles.is_empty() {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.polygon_pipeline,
+                                );
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.line_layout,
+                                    vk::ShaderStageFlags::VERTEX,
+                                    0,
+                                    LineParams {
+                                        viewproj: viewproj.into(),
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_bind_vertex_buffers(
+                                    cmd,
+                                    0,
+                                    &[self.line_buffer.as_ref().unwrap().buffer],
+                                    &[0],
+                                );
+                                d.cmd_draw(
+                                    cmd,
+                                    self.tiles.len() as u32,
+                                    1,
+                                    (self.lines.len() + self.triangles.len()) as u32,
+                                    0,
+                                );
+                                draw_calls += 1;
+                            }
+
+                            let mut bound = None;
+                            for (material, position, tint, _, set, _, params, pivot, uv_scale) in
+                                draws
+                            {
+                                let (pipeline, layout) = match (set, material) {
+                                    (Some(_), Material::Cutout) => {
+                                        (self.cutout_pipeline, self.textured_layout)
+                                    }
+                                    (Some(_), _) => (self.textured_pipeline, self.textured_layout),
+                                    (None, _) => {
+                                        (self.pipelines[material as usize], self.pipeline_layout)
+                                    }
+                                };
+                                if bound != Some(pipeline) {
+                                    d.cmd_bind_pipeline(
+                                        cmd,
+                                        vk::PipelineBindPoint::GRAPHICS,
+                                        pipeline,
+                                    );
+                                    bound = Some(pipeline);
+                                }
+                                if let Some(set) = set {
+                                    d.cmd_bind_descriptor_sets(
+                                        cmd,
+                                        vk::PipelineBindPoint::GRAPHICS,
+                                        layout,
+                                        0,
+                                        &[set],
+                                        &[],
+                                    );
+                                }
+                                let mut transform = viewproj * position;
+                                if pixel_snap.0 {
+                                    snap_to_pixels(&mut transform, &viewport);
+                                }
+                                d.cmd_push_constants(
+                                    cmd,
+                                    layout,
+                                    vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                                    0,
+                                    SpriteParams {
+                                        transform: transform.into(),
+                                        dimensions: scale.sprite_dimensions(),
+                                        params,
+                                        tint: tint.0,
+                                        pivot,
+                                        uv_scale,
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_draw(cmd, 4, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+
+                            if !self.quads.is_empty() {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.quad_pipeline,
+                                );
+                            }
+                            let mut clip = None;
+                            for quad in &self.quads {
+                                if quad.clip != clip {
+                                    let mut rect = intersection(area, scissors);
+                                    if let Some(index) = quad.clip {
+                                        rect = intersection(rect, self.clips[index]);
+                                    }
+                                    d.cmd_set_scissor(cmd, 0, &[rect]);
+                                    clip = quad.clip;
+                                }
+                                let mut transform = viewproj * quad.transform;
+                                if pixel_snap.0 {
+                                    snap_to_pixels(&mut transform, &viewport);
+                                }
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.pipeline_layout,
+                                    vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                                    0,
+                                    SpriteParams {
+                                        transform: transform.into(),
+                                        dimensions: quad.dimensions,
+                                        params: [0.0; 2],
+                                        tint: quad.color,
+                                        pivot: na::zero(),
+                                        uv_scale: na::Vector2::repeat(1.0),
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_draw(cmd, 4, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+                            if clip.is_some() {
+                                d.cmd_set_scissor(cmd, 0, &[intersection(area, scissors)]);
+                            }
+
+                            if !self.triangles.is_empty() {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.polygon_pipeline,
+                                );
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.line_layout,
+                                    vk::ShaderStageFlags::VERTEX,
+                                    0,
+                                    LineParams {
+                                        viewproj: viewproj.into(),
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_bind_vertex_buffers(
+                                    cmd,
+                                    0,
+                                    &[self.line_buffer.as_ref().unwrap().buffer],
+                                    &[0],
+                                );
+                                d.cmd_draw(
+                                    cmd,
+                                    self.triangles.len() as u32,
+                                    1,
+                                    self.lines.len() as u32,
+                                    0,
+                                );
+                                draw_calls += 1;
+                            }
+
+                            if lit {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.lighting.composite_pipeline,
+                                );
+                                d.cmd_bind_descriptor_sets(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.lighting.composite_layout,
+                                    0,
+                                    &[self.lighting.descriptor_set],
+                                    &[],
+                                );
+                                d.cmd_draw(cmd, 3, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+
+                            if let Some(player) = player {
+                                let center = viewproj * na::Point2::from(player.translation.vector);
+                                let radius = fog.radius / camera.scaling();
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.fog_pipeline,
+                                );
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.fog_layout,
+                                    vk::ShaderStageFlags::FRAGMENT,
+                                    0,
+                                    FogParams {
+                                        center: na::Vector2::new(
+                                            viewport.x + (center.x + 1.0) * 0.5 * viewport.width,
+                                            viewport.y + (center.y + 1.0) * 0.5 * viewport.height,
+                                        ),
+                                        radius,
+                                        falloff: radius * fog.softness,
+                                        brightness: fog.brightness,
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_draw(cmd, 3, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+
+                            if debug.contacts {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.pipelines[Material::Circle as usize],
+                                );
+                                let radius = scale.ball_radius();
+                                let mut draw_circle =
+                                    |position: na::Isometry2<f32>, dimensions, tint| {
+                                        let transform = viewproj * position;
+                                        d.cmd_push_constants(
+                                            cmd,
+                                            self.pipeline_layout,
+                                            vk::ShaderStageFlags::VERTEX
+                                                | vk::ShaderStageFlags::FRAGMENT,
+                                            0,
+                                            SpriteParams {
+                                                transform: transform.into(),
+                                                dimensions,
+                                                params: [0.0; 2],
+                                                tint,
+                                                pivot: na::zero(),
+                                                uv_scale: na::Vector2::repeat(1.0),
+                                            }
+                                            .as_bytes(),
+                                        );
+                                        d.cmd_draw(cmd, 4, 1, 0, 0);
+                                        draw_calls += 1;
+                                    };
+                                for contact in sim::contacts(&collision) {
+                                    // A thin ellipse from the contact point along the normal
+                                    let length = radius;
+                                    draw_circle(
+                                        na::Isometry2::new(
+                                            contact.point.coords
+                                                + contact.normal.into_inner() * length * 0.5,
+                                            contact.normal.y.atan2(contact.normal.x),
+                                        ),
+                                        na::Vector2::new(length, 0.1 * radius),
+                                        na::Vector4::new(1.0, 1.0, 0.0, 1.0),
+                                    );
+                                    draw_circle(
+                                        na::Isometry2::new(contact.point.coords, 0.0),
+                                        na::Vector2::repeat(0.4 * radius),
+                                        na::Vector4::new(1.0, 0.0, 0.0, 1.0),
+                                    );
+                                }
+                            }
+
+                            if !self.lines.is_empty() {
+                                d.cmd_bind_pipeline(
+                                    cmd,
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    self.line_pipeline,
+                                );
+                                d.cmd_set_line_width(
+                                    cmd,
+                                    match self.line_width_range {
+                                        Some([min, max]) => debug.line_width.max(min).min(max),
+                                        None => 1.0,
+                                    },
+                                );
+                                d.cmd_push_constants(
+                                    cmd,
+                                    self.line_layout,
+                                    vk::ShaderStageFlags::VERTEX,
+                                    0,
+                                    LineParams {
+                                        viewproj: viewproj.into(),
+                                    }
+                                    .as_bytes(),
+                                );
+                                d.cmd_bind_vertex_buffers(
+                                    cmd,
+                                    0,
+                                    &[self.line_buffer.as_ref().unwrap().buffer],
+                                    &[0],
+                                );
+                                d.cmd_draw(cmd, self.lines.len() as u32, 1, 0, 0);
+                                draw_calls += 1;
+                            }
+                        }
+
+                        d.cmd_end_render_pass(cmd);
+                        self.gfx.end_label(cmd);
+                    }
+                    Pass::Post => {
+                        let post = self.post.as_ref().unwrap();
+                        d.cmd_set_viewport(cmd, 0, &[self.viewport]);
+                        d.cmd_set_scissor(cmd, 0, &[self.scissors]);
+                        self.gfx.begin_label(cmd, cstr!("post"));
+                        d.cmd_begin_render_pass(
+                            cmd,
+                            &vk::RenderPassBeginInfo::builder()
+                                .render_pass(post.render_pass)
+                                .framebuffer(post.framebuffers[self.fb_index as usize])
+                                .render_area(self.scissors)
+                                .clear_values(&clear),
+                            vk::SubpassContents::INLINE,
+                        );
+                        d.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, post.pipeline);
+                        d.cmd_bind_descriptor_sets(
+                            cmd,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            post.pipeline_layout,
+                            0,
+                            &[post.descriptor_set],
+                            &[],
+                        );
+                        d.cmd_push_constants(
+                            cmd,
+                            post.pipeline_layout,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            0,
+                            PostParams {
+                                texel: na::Vector2::new(
+                                    1.0 / self.viewport.width,
+                                    1.0 / self.viewport.height,
+                                ),
+                                exposure: grading.exposure,
+                                gamma: grading.gamma,
+                            }
+                            .as_bytes(),
+                        );
+                        d.cmd_draw(cmd, 3, 1, 0, 0);
+                        draw_calls += 1;
+                        d.cmd_end_render_pass(cmd);
+                        self.gfx.end_label(cmd);
+                    }
+                }
+            }
+
+            d.end_command_buffer(cmd).unwrap();
+            *stats = RenderStats {
+                draw_calls,
+                sprites,
+                culled,
+            };
+        }
+        self.recorded[fb] = Some((self.generation, draw_calls));
+        self.clear_queued();
+    }
+}
+
+/// Viewport covering the whole of an image of `extent`
+fn full_viewport(extent: vk::Extent2D) -> vk::Viewport {
+    vk::Viewport {
+        x: 0.0,
+        y: 0.0,
+        width: extent.width as f32,
+        height: extent.height as f32,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    }
+}
+
+fn full_scissors(extent: vk::Extent2D) -> vk::Rect2D {
+    vk::Rect2D {
+        offset: vk::Offset2D { x: 0, y: 0 },
+        extent,
+    }
+}
+
+/// Round the clip-space translation of `transform` to the nearest pixel corner of `viewport`
+fn snap_to_pixels(transform: &mut na::Affine2<f32>, viewport: &vk::Viewport) {
+    let m = transform.matrix_mut_unchecked();
+    for (i, &size) in [viewport.width, viewport.height].iter().enumerate() {
+        let pixels = ((m[(i, 2)] + 1.0) * 0.5 * size).round();
+        m[(i, 2)] = pixels / (0.5 * size) - 1.0;
+    }
+}
+
+/// Pixels of `viewport` within `bounds` covered by a quad with `half_extents` drawn with
+/// `transform`, with a one-pixel margin for filtering
+fn screen_rect(
+    transform: &na::Affine2<f32>,
+    half_extents: &na::Vector2<f32>,
+    viewport: &vk::Viewport,
+    bounds: &vk::Rect2D,
+) -> vk::Rect2D {
+    let m = transform.matrix();
+    let extent = m.fixed_slice::<na::U2, na::U2>(0, 0).abs() * half_extents;
+    let to_pixels = |ndc: f32, origin: f32, size: f32| origin + (ndc + 1.0) * 0.5 * size;
+    let lo_x = to_pixels(m[(0, 2)] - extent.x, viewport.x, viewport.width).floor() as i32 - 1;
+    let hi_x = to_pixels(m[(0, 2)] + extent.x, viewport.x, viewport.width).ceil() as i32 + 1;
+    let lo_y = to_pixels(m[(1, 2)] - extent.y, viewport.y, viewport.height).floor() as i32 - 1;
+    let hi_y = to_pixels(m[(1, 2)] + extent.y, viewport.y, viewport.height).ceil() as i32 + 1;
+    let lo_x = lo_x.max(bounds.offset.x);
+    let lo_y = lo_y.max(bounds.offset.y);
+    let hi_x = hi_x.min(bounds.offset.x + bounds.extent.width as i32);
+    let hi_y = hi_y.min(bounds.offset.y + bounds.extent.height as i32);
+    if hi_x <= lo_x || hi_y <= lo_y {
+        return vk::Rect2D::default();
+    }
+    vk::Rect2D {
+        offset: vk::Offset2D { x: lo_x, y: lo_y },
+        extent: vk::Extent2D {
+            width: (hi_x - lo_x) as u32,
+            height: (hi_y - lo_y) as u32,
+        },
+    }
+}
+
+/// Largest rectangle contained by both `a` and `b`, which may be empty
+fn intersection(a: vk::Rect2D, b: vk::Rect2D) -> vk::Rect2D {
+    let lo_x = a.offset.x.max(b.offset.x);
+    let lo_y = a.offset.y.max(b.offset.y);
+    let hi_x = (a.offset.x + a.extent.width as i32).min(b.offset.x + b.extent.width as i32);
+    let hi_y = (a.offset.y + a.extent.height as i32).min(b.offset.y + b.extent.height as i32);
+    vk::Rect2D {
+        offset: vk::Offset2D { x: lo_x, y: lo_y },
+        extent: vk::Extent2D {
+            width: (hi_x - lo_x).max(0) as u32,
+            height: (hi_y - lo_y).max(0) as u32,
+        },
+    }
+}
+
+/// Pixels covered by `viewport`, rounded to the nearest pixel edges
+fn pixel_rect(viewport: &vk::Viewport) -> vk::Rect2D {
+    let (lo_x, lo_y) = (viewport.x.round(), viewport.y.round());
+    vk::Rect2D {
+        offset: vk::Offset2D {
+            x: lo_x as i32,
+            y: lo_y as i32,
+        },
+        extent: vk::Extent2D {
+            width: ((viewport.x + viewport.width).round() - lo_x) as u32,
+            height: ((viewport.y + viewport.height).round() - lo_y) as u32,
+        },
+    }
+}
+
+/// Smallest rectangle containing both `a` and `b`, either of which may be empty
+fn union(a: vk::Rect2D, b: vk::Rect2D) -> vk::Rect2D {
+    let is_empty = |x: &vk::Rect2D| x.extent.width == 0 || x.extent.height == 0;
+    if is_empty(&a) {
+        return b;
+    }
+    if is_empty(&b) {
+        return a;
+    }
+    let lo_x = a.offset.x.min(b.offset.x);
+    let lo_y = a.offset.y.min(b.offset.y);
+    let hi_x = (a.offset.x + a.extent.width as i32).max(b.offset.x + b.extent.width as i32);
+    let hi_y = (a.offset.y + a.extent.height as i32).max(b.offset.y + b.extent.height as i32);
+    vk::Rect2D {
+        offset: vk::Offset2D { x: lo_x, y: lo_y },
+        extent: vk::Extent2D {
+            width: (hi_x - lo_x) as u32,
+            height: (hi_y - lo_y) as u32,
+        },
+    }
+}
+
+/// Transform of the center of a sprite whose `pivot` is drawn with `transform`
+fn centered(transform: &na::Affine2<f32>, pivot: &na::Vector2<f32>) -> na::Affine2<f32> {
+    transform * na::Translation2::from(-pivot)
+}
+
+/// Whether a quad with `half_extents` drawn with `transform` falls entirely outside clip space
+fn is_offscreen(transform: &na::Affine2<f32>, half_extents: &na::Vector2<f32>) -> bool {
+    let m = transform.matrix();
+    let center = na::Vector2::new(m[(0, 2)], m[(1, 2)]);
+    let extent = m.fixed_slice::<na::U2, na::U2>(0, 0).abs() * half_extents;
+    center.x.abs() - extent.x > 1.0 || center.y.abs() - extent.y > 1.0
+}
+
+/// Transform drawing a sprite of height `sprite_height` at `position` as if `height` above the
+/// ground, where `up` rotates world space to point at the top of the screen
+fn raise(
+    position: &na::Isometry2<f32>,
+    height: f32,
+    up: &na::UnitComplex<f32>,
+    sprite_height: f32,
+) -> na::Affine2<f32> {
+    let lift = na::Translation2::from(up * na::Vector2::new(0.0, height * BILLBOARD_LIFT));
+    let squash = na::Matrix3::new_nonuniform_scaling(&na::Vector2::new(
+        1.0,
+        1.0 / (1.0 + BILLBOARD_SQUASH * height / sprite_height),
+    ));
+    // Squash along the screen's vertical rather than the sprite's, about its center
+    na::Affine2::from_matrix_unchecked(
+        (lift * position.translation).to_homogeneous()
+            * up.to_homogeneous()
+            * squash
+            * up.inverse().to_homogeneous()
+            * position.rotation.to_homogeneous(),
+    )
+}
+
+/// Blend between two positions, with `t` = 0 yielding `a` and `t` = 1 yielding `b`
+fn interpolate(a: &na::Isometry2<f32>, b: &na::Isometry2<f32>, t: f32) -> na::Isometry2<f32> {
+    na::Isometry2::from_parts(
+        na::Translation2::from(a.translation.vector.lerp(&b.translation.vector, t)),
+        a.rotation.rotation_to(&b.rotation).powf(t) * a.rotation,
+    )
+}
+
+/// A quad queued by `Render::draw_quad`
+struct Quad {
+    transform: na::Isometry2<f32>,
+    dimensions: na::Vector2<f32>,
+    color: na::Vector4<f32>,
+    /// Index into `Render::clips` of the region the quad is clipped to, if any
+    clip: Option<usize>,
+}
+
+#[repr(C)]
+struct LineVertex {
+    position: na::Point2<f32>,
+    color: na::Vector4<f32>,
+}
+
+/// Data matching a shader's push constant block byte for byte
+///
+/// # Safety
+/// - Must be `#[repr(C)]` with no implicit padding, so every byte is initialized
+unsafe trait PushConstants: Copy {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// Define a `#[repr(C)]` struct implementing `PushConstants`, failing to compile if its fields
+/// leave any padding
+///
+/// Agreement with the shaders' push constant blocks isn't checked, so keep them in sync by hand.
+macro_rules! push_constants {
+    (
+        $(#[$attr:meta])*
+        struct $name:ident {
+            $($(#[$field_attr:meta])* $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        struct $name {
+            $($(#[$field_attr])* $field: $ty,)*
+        }
+
+        unsafe impl PushConstants for $name {}
+
+        const _: [(); 0 $(+ mem::size_of::<$ty>())*] = [(); mem::size_of::<$name>()];
+    };
+}
+
+/// A `mat3` as laid out in a push constant block, with each column padded to a `vec4`
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Mat3([[f32; 4]; 3]);
+
+impl From<na::Affine2<f32>> for Mat3 {
+    fn from(x: na::Affine2<f32>) -> Self {
+        let m = x.to_homogeneous();
+        let column = |i: usize| [m[(0, i)], m[(1, i)], m[(2, i)], 0.0];
+        Mat3([column(0), column(1), column(2)])
+    }
+}
+
+push_constants! {
+    struct SpriteParams {
+        transform: Mat3,
+        dimensions: na::Vector2<f32>,
+        /// Depends on the material: a `Rounding`'s radius and softness, or an `AlphaCutoff`. Also
+        /// aligns `tint` to 16 bytes, as required for a vec4.
+        params: [f32; 2],
+        tint: na::Vector4<f32>,
+        /// Offset from the quad's center to the origin of `transform`, in the quad's local space
+        pivot: na::Vector2<f32>,
+        /// Factor texture coordinates are scaled by, tiling the texture if its sampler repeats
+        uv_scale: na::Vector2<f32>,
+    }
+}
+
+push_constants! {
+    struct GridParams {
+        viewproj: Mat3,
+        lo: na::Vector2<f32>,
+        hi: na::Vector2<f32>,
+        first: na::Vector2<f32>,
+        spacing: f32,
+        columns: u32,
+        color: na::Vector4<f32>,
+    }
+}
+
+push_constants! {
+    /// Used by lines and polygons
+    struct LineParams {
+        viewproj: Mat3,
+    }
+}
+
+push_constants! {
+    struct PostParams {
+        texel: na::Vector2<f32>,
+        exposure: f32,
+        gamma: f32,
+    }
+}
+
+push_constants! {
+    struct FogParams {
+        center: na::Vector2<f32>,
+        radius: f32,
+        falloff: f32,
+        brightness: f32,
+    }
+}
+
+/// The operations a frame loop needs from a renderer
+///
+/// `Render` draws with Vulkan, while `Headless` draws nothing so that loops can run without a GPU.
+pub trait Renderer {
+    /// Prepare to draw into each of `views`, images of `extent`, resetting the viewport and
+    /// scissors to cover them
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    /// - Must be passed valid ImageViews that will outlive rendering done using them
+    unsafe fn resize(&mut self, extent: vk::Extent2D, views: &[vk::ImageView]);
+
+    /// Number of images passed to the latest `resize`
+    ///
+    /// Per-frame resources, indexed like the images passed to `record`, should have this many
+    /// elements.
+    fn image_count(&self) -> u32;
+
+    /// Record commands drawing `world`, along with everything queued since the previous frame,
+    /// into the image at index `image`
+    ///
+    /// # Safety
+    /// - The image's previous frame must have completed
+    unsafe fn record(&mut self, world: &World, image: u32);
+
+    /// Submit the latest recording, drawing once `wait` is signaled and signaling `signal` after
+    ///
+    /// # Safety
+    /// - Must follow a `record`
+    unsafe fn submit(&mut self, wait: vk::Semaphore, signal: vk::Semaphore);
+
+    /// The topmost sprite drawn at `position`, in pixels from the top-left of the window, in the
+    /// previous frame
+    ///
+    /// Sprites are seen through wherever they were drawn mostly transparent, as judged from their
+    /// texture's alpha or, if untextured, the shape of their material.
+    ///
+    /// Sprites are layered by `DrawOrder` with depth testing off, so there's no depth buffer to
+    /// read back. Instead the previous frame's draws are tested on the CPU, which also spares
+    /// waiting on the GPU. The layer of the sprite found is given by its `Parallax`.
+    fn pick(&self, position: na::Point2<f32>) -> Option<Entity>;
+
+    /// Draw a quad of `color` in linear RGBA and `dimensions` in world units, centered at
+    /// `transform`, in the next frame only
+    fn draw_quad(
+        &mut self,
+        transform: na::Isometry2<f32>,
+        dimensions: na::Vector2<f32>,
+        color: na::Vector4<f32>,
+    );
+
+    /// Draw a line segment from `a` to `b`, in world space, in the next frame only
+    fn draw_line(&mut self, a: na::Point2<f32>, b: na::Point2<f32>, color: na::Vector4<f32>);
+
+    /// Draw line segments connecting consecutive `points`, in world space, in the next frame only
+    fn draw_polyline(&mut self, points: &[na::Point2<f32>], color: na::Vector4<f32>) {
+        for pair in points.windows(2) {
+            self.draw_line(pair[0], pair[1], color);
+        }
+    }
+
+    /// Fill the convex polygon with vertices `points`, in world space, in the next frame only
+    ///
+    /// Vertices may wind in either direction. Nothing is drawn for fewer than three points.
+    fn draw_polygon(&mut self, points: &[na::Point2<f32>], color: na::Vector4<f32>);
+}
+
+impl Render {
+    /// Construct a renderer for images of `surface` format and `extent`, applying `ColorGrading`
+    /// to its output if `color_grading` is set
+    ///
+    /// Under MSAA, `sample_shading` is the minimum fraction of samples to shade individually,
+    /// which resolves the alpha edges of sprites that multisampling alone would leave jagged.
+    ///
+    /// On an HDR10 surface the scene is rendered in linear floating point and then graded and
+    /// encoded in a final pass, in place of FXAA.
+    pub fn new(
+        gfx: Arc<Graphics>,
+        surface: vk::SurfaceFormatKHR,
+        extent: vk::Extent2D,
+        anti_alias: AntiAlias,
+        color_grading: bool,
+        sample_shading: Option<f32>,
+    ) -> Self {
+        let device = &*gfx.device;
+        let samples = match anti_alias {
+            AntiAlias::Msaa(count) => {
+                let supported = gfx.limits.framebuffer_color_sample_counts;
+                let samples = (0..7)
+                    .rev()
+                    .map(|i| 1 << i)
+                    .find(|&x| x <= count && supported.contains(vk::SampleCountFlags::from_raw(x)))
+                    .unwrap_or(1);
+                if samples != count {
+                    warn!("{}x MSAA is unsupported, using {}x", count, samples);
+                }
+                vk::SampleCountFlags::from_raw(samples)
+            }
+            AntiAlias::None | AntiAlias::Fxaa => vk::SampleCountFlags::TYPE_1,
+        };
+        let sample_shading = match sample_shading {
+            Some(_) if samples == vk::SampleCountFlags::TYPE_1 => None,
+            Some(_) if gfx.features.sample_rate_shading != vk::TRUE => {
+                warn!("sample-rate shading is unsupported");
+                None
+            }
+            x => x,
+        };
+        unsafe {
+            let sprite_vert = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(&SPRITE_VERT),
+                    None,
+                )
+                .unwrap();
+            let sv_guard = defer(|| device.destroy_shader_module(sprite_vert, None));
+
+            let sprite_frag = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(&SPRITE_FRAG),
+                    None,
+                )
+                .unwrap();
+            let sf_guard = defer(|| device.destroy_shader_module(sprite_frag, None));
+
+            let circle_vert = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(CIRCLE_VERT),
+                    None,
+                )
+                .unwrap();
+            let cv_guard = defer(|| device.destroy_shader_module(circle_vert, None));
+
+            let circle_frag = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(CIRCLE_FRAG),
+                    None,
+                )
+                .unwrap();
+            let cf_guard = defer(|| device.destroy_shader_module(circle_frag, None));
+
+            let quad_frag = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(QUAD_FRAG), None)
+                .unwrap();
+            let qf_guard = defer(|| device.destroy_shader_module(quad_frag, None));
+
+            let textured_frag = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(TEXTURED_FRAG),
+                    None,
+                )
+                .unwrap();
+            let tf_guard = defer(|| device.destroy_shader_module(textured_frag, None));
+
+            let cutout_frag = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(CUTOUT_FRAG),
+                    None,
+                )
+                .unwrap();
+            let cof_guard = defer(|| device.destroy_shader_module(cutout_frag, None));
+
+            let fullscreen_vert = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(FULLSCREEN_VERT),
+                    None,
+                )
+                .unwrap();
+            let fsv_guard = defer(|| device.destroy_shader_module(fullscreen_vert, None));
+
+            let fog_frag = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(FOG_FRAG), None)
+                .unwrap();
+            let ff_guard = defer(|| device.destroy_shader_module(fog_frag, None));
+
+            let grid_vert = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(GRID_VERT), None)
+                .unwrap();
+            let gv_guard = defer(|| device.destroy_shader_module(grid_vert, None));
+
+            let grid_frag = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(GRID_FRAG), None)
+                .unwrap();
+            let gf_guard = defer(|| device.destroy_shader_module(grid_frag, None));
+
+            let line_vert = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(LINE_VERT), None)
+                .unwrap();
+            let lv_guard = defer(|| device.destroy_shader_module(line_vert, None));
+
+            let line_frag = device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(LINE_FRAG), None)
+                .unwrap();
+            let lf_guard = defer(|| device.destroy_shader_module(line_frag, None));
+
+            let rounded_frag = device
+                .create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(ROUNDED_FRAG),
+                    None,
+                )
+                .unwrap();
+            let rf_guard = defer(|| device.destroy_shader_module(rounded_frag, None));
+
+            let pipeline_layout = device
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[
+                        vk::PushConstantRange {
+                            stage_flags: vk::ShaderStageFlags::VERTEX
+                                | vk::ShaderStageFlags::FRAGMENT,
+                            offset: 0,
+                            size: mem::size_of::<SpriteParams>() as u32,
+                        },
+                    ]),
+                    None,
+                )
+                .unwrap();
+
+            let hdr10 = surface.color_space == vk::ColorSpaceKHR::HDR10_ST2084_EXT;
+            let format = if hdr10 {
+                HDR_SCENE_FORMAT
+            } else {
+                surface.format
+            };
+            let post = if hdr10 {
+                if anti_alias == AntiAlias::Fxaa {
+                    warn!("FXAA is unavailable on HDR10 surfaces");
+                }
+                Some(Post::new(&gfx, surface.format, HDR10_FRAG))
+            } else if anti_alias == AntiAlias::Fxaa {
+                Some(Post::new(&gfx, format, FXAA_FRAG))
+            } else if color_grading {
+                Some(Post::new(&gfx, format, GRADE_FRAG))
+            } else {
+                None
+            };
+            let render_pass = create_render_pass(device, format, samples, post.is_some(), false);
+            let partial_pass = if samples == vk::SampleCountFlags::TYPE_1 && post.is_none() {
+                Some(create_render_pass(device, format, samples, false, true))
+            } else {
+                None
+            };
+            let lighting = Lighting::new(&gfx, render_pass, samples, pipeline_layout);
+
+            let entry_point = b"main\0".as_ptr() as *const i8;
+            let noop_stencil_state = vk::StencilOpState {
+                fail_op: vk::StencilOp::KEEP,
+                pass_op: vk::StencilOp::KEEP,
+                depth_fail_op: vk::StencilOp::KEEP,
+                compare_op: vk::CompareOp::ALWAYS,
+                compare_mask: 0,
+                write_mask: 0,
+                reference: 0,
+            };
+            let sprite_stages = [
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::VERTEX,
+                    module: sprite_vert,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: sprite_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let circle_stages = [
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::VERTEX,
+                    module: circle_vert,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: circle_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let rounded_stages = [
+                circle_stages[0],
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: rounded_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let quad_stages = [
+                circle_stages[0],
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: quad_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let vertex_input = Default::default();
+            let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+                .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+            let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+                .scissor_count(1)
+                .viewport_count(1);
+            let fill = vk::PipelineRasterizationStateCreateInfo::builder()
+                .cull_mode(vk::CullModeFlags::NONE)
+                .polygon_mode(vk::PolygonMode::FILL)
+                .line_width(1.0);
+            // Non-fill polygon modes are an optional feature; fall back to solid quads without it
+            let wireframe = vk::PipelineRasterizationStateCreateInfo::builder()
+                .cull_mode(vk::CullModeFlags::NONE)
+                .polygon_mode(if gfx.features.fill_mode_non_solid == vk::TRUE {
+                    vk::PolygonMode::LINE
+                } else {
+                    vk::PolygonMode::FILL
+                })
+                .line_width(1.0);
+            let mut multisample =
+                vk::PipelineMultisampleStateCreateInfo::builder().rasterization_samples(samples);
+            if let Some(fraction) = sample_shading {
+                multisample = multisample
+                    .sample_shading_enable(true)
+                    .min_sample_shading(fraction);
+            }
+            let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+                .depth_test_enable(false)
+                .front(noop_stencil_state)
+                .back(noop_stencil_state);
+            let opaque_attachments = [vk::PipelineColorBlendAttachmentState {
+                blend_enable: vk::TRUE,
+                src_color_blend_factor: vk::BlendFactor::ONE,
+                dst_color_blend_factor: vk::BlendFactor::ZERO,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::all(),
+            }];
+            let opaque =
+                vk::PipelineColorBlendStateCreateInfo::builder().attachments(&opaque_attachments);
+            // Premultiplied alpha
+            let translucent_attachments = [vk::PipelineColorBlendAttachmentState {
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                ..opaque_attachments[0]
+            }];
+            let translucent = vk::PipelineColorBlendStateCreateInfo::builder()
+                .attachments(&translucent_attachments);
+            let additive_attachments = [vk::PipelineColorBlendAttachmentState {
+                dst_color_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE,
+                ..opaque_attachments[0]
+            }];
+            let additive =
+                vk::PipelineColorBlendStateCreateInfo::builder().attachments(&additive_attachments);
+            let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+                .dynamic_states(&[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR]);
+            let mut infos = Material::ALL
+                .iter()
+                .map(|material| {
+                    let (stages, rasterization, blend) = match *material {
+                        Material::Sprite => (&sprite_stages, &fill, &opaque),
+                        Material::Circle => (&circle_stages, &fill, &translucent),
+                        Material::Additive => (&sprite_stages, &fill, &additive),
+                        Material::Wireframe => (&sprite_stages, &wireframe, &opaque),
+                        Material::Rounded => (&rounded_stages, &fill, &translucent),
+                        Material::Cutout => (&sprite_stages, &fill, &opaque),
+                    };
+                    vk::GraphicsPipelineCreateInfo::builder()
+                        .stages(stages)
+                        .vertex_input_state(&vertex_input)
+                        .input_assembly_state(&input_assembly)
+                        .viewport_state(&viewport_state)
+                        .rasterization_state(rasterization)
+                        .multisample_state(&multisample)
+                        .depth_stencil_state(&depth_stencil)
+                        .color_blend_state(blend)
+                        .dynamic_state(&dynamic_state)
+                        .layout(pipeline_layout)
+                        .render_pass(render_pass)
+                        .subpass(0)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            infos.push(
+                vk::GraphicsPipelineCreateInfo::builder()
+                    .stages(&quad_stages)
+                    .vertex_input_state(&vertex_input)
+                    .input_assembly_state(&input_assembly)
+                    .viewport_state(&viewport_state)
+                    .rasterization_state(&fill)
+                    .multisample_state(&multisample)
+                    .depth_stencil_state(&depth_stencil)
+                    .color_blend_state(&translucent)
+                    .dynamic_state(&dynamic_state)
+                    .layout(pipeline_layout)
+                    .render_pass(render_pass)
+                    .subpass(0)
+                    .build(),
+            );
+
+            let texture_set_layout = texture::create_set_layout(device);
+            let textured_layout = device
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::builder()
+                        .set_layouts(&[texture_set_layout])
+                        .push_constant_ranges(&[vk::PushConstantRange {
+                            stage_flags: vk::ShaderStageFlags::VERTEX
+                                | vk::ShaderStageFlags::FRAGMENT,
+                            offset: 0,
+                            size: mem::size_of::<SpriteParams>() as u32,
+                        }]),
+                    None,
+                )
+                .unwrap();
+            let textured_stages = [
+                circle_stages[0],
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: textured_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let cutout_stages = [
+                circle_stages[0],
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: cutout_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            infos.extend(
+                [(&textured_stages, &translucent), (&cutout_stages, &opaque)]
+                    .iter()
+                    .map(|&(stages, blend)| {
+                        vk::GraphicsPipelineCreateInfo::builder()
+                            .stages(stages)
+                            .vertex_input_state(&vertex_input)
+                            .input_assembly_state(&input_assembly)
+                            .viewport_state(&viewport_state)
+                            .rasterization_state(&fill)
+                            .multisample_state(&multisample)
+                            .depth_stencil_state(&depth_stencil)
+                            .color_blend_state(blend)
+                            .dynamic_state(&dynamic_state)
+                            .layout(textured_layout)
+                            .render_pass(render_pass)
+                            .subpass(0)
+                            .build()
+                    }),
+            );
+
+            let fog_layout = device
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[
+                        vk::PushConstantRange {
+                            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                            offset: 0,
+                            size: mem::size_of::<FogParams>() as u32,
+                        },
+                    ]),
+                    None,
+                )
+                .unwrap();
+            let fog_stages = [
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::VERTEX,
+                    module: fullscreen_vert,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: fog_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            // Scale the existing color by the fragment's alpha
+            let fog_attachments = [vk::PipelineColorBlendAttachmentState {
+                src_color_blend_factor: vk::BlendFactor::ZERO,
+                dst_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+                src_alpha_blend_factor: vk::BlendFactor::ZERO,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE,
+                ..opaque_attachments[0]
+            }];
+            let fog_blend =
+                vk::PipelineColorBlendStateCreateInfo::builder().attachments(&fog_attachments);
+            infos.push(
+                vk::GraphicsPipelineCreateInfo::builder()
+                    .stages(&fog_stages)
+                    .vertex_input_state(&vertex_input)
+                    .input_assembly_state(&input_assembly)
+                    .viewport_state(&viewport_state)
+                    .rasterization_state(&fill)
+                    .multisample_state(&multisample)
+                    .depth_stencil_state(&depth_stencil)
+                    .color_blend_state(&fog_blend)
+                    .dynamic_state(&dynamic_state)
+                    .layout(fog_layout)
+                    .render_pass(render_pass)
+                    .subpass(0)
+                    .build(),
+            );
+
+            let grid_layout = device
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[
+                        vk::PushConstantRange {
+                            stage_flags: vk::ShaderStageFlags::VERTEX
+                                | vk::ShaderStageFlags::FRAGMENT,
+                            offset: 0,
+                            size: mem::size_of::<GridParams>() as u32,
+                        },
+                    ]),
+                    None,
+                )
+                .unwrap();
+            let grid_stages = [
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::VERTEX,
+                    module: grid_vert,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: grid_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let line_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+                .topology(vk::PrimitiveTopology::LINE_LIST);
+            infos.push(
+                vk::GraphicsPipelineCreateInfo::builder()
+                    .stages(&grid_stages)
+                    .vertex_input_state(&vertex_input)
+                    .input_assembly_state(&line_assembly)
+                    .viewport_state(&viewport_state)
+                    .rasterization_state(&fill)
+                    .multisample_state(&multisample)
+                    .depth_stencil_state(&depth_stencil)
+                    .color_blend_state(&translucent)
+                    .dynamic_state(&dynamic_state)
+                    .layout(grid_layout)
+                    .render_pass(render_pass)
+                    .subpass(0)
+                    .build(),
+            );
+
+            let line_layout = device
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[
+                        vk::PushConstantRange {
+                            stage_flags: vk::ShaderStageFlags::VERTEX,
+                            offset: 0,
+                            size: mem::size_of::<LineParams>() as u32,
+                        },
+                    ]),
+                    None,
+                )
+                .unwrap();
+            let line_stages = [
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::VERTEX,
+                    module: line_vert,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+                vk::PipelineShaderStageCreateInfo {
+                    stage: vk::ShaderStageFlags::FRAGMENT,
+                    module: line_frag,
+                    p_name: entry_point,
+                    ..Default::default()
+                },
+            ];
+            let line_bindings = [vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: mem::size_of::<LineVertex>() as u32,
+                input_rate: vk::VertexInputRate::VERTEX,
+            }];
+            let line_attributes = [
+                vk::VertexInputAttributeDescription {
+                    location: 0,
+                    binding: 0,
+                    format: vk::Format::R32G32_SFLOAT,
+                    offset: 0,
+                },
+                vk::VertexInputAttributeDescription {
+                    location: 1,
+                    binding: 0,
+                    format: vk::Format::R32G32B32A32_SFLOAT,
+                    offset: mem::size_of::<na::Point2<f32>>() as u32,
+                },
+            ];
+            let line_input = vk::PipelineVertexInputStateCreateInfo::builder()
+                .vertex_binding_descriptions(&line_bindings)
+                .vertex_attribute_descriptions(&line_attributes);
+            let polygon_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+                .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+            let line_dynamic_state =
+                vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&[
+                    vk::DynamicState::VIEWPORT,
+                    vk::DynamicState::SCISSOR,
+                    vk::DynamicState::LINE_WIDTH,
+                ]);
+            infos.extend(
+                [
+                    (&line_assembly, &line_dynamic_state),
+                    (&polygon_assembly, &dynamic_state),
+                ]
+                .iter()
+                .map(|&(assembly, dynamic_state)| {
+                    vk::GraphicsPipelineCreateInfo::builder()
+                        .stages(&line_stages)
+                        .vertex_input_state(&line_input)
+                        .input_assembly_state(assembly)
+                        .viewport_state(&viewport_state)
+                        .rasterization_state(&fill)
+                        .multisample_state(&multisample)
+                        .depth_stencil_state(&depth_stencil)
+                        .color_blend_state(&translucent)
+                        .dynamic_state(dynamic_state)
+                        .layout(line_layout)
+                        .render_pass(render_pass)
+                        .subpass(0)
+                        .build()
+                }),
+            );
+            // Create every variant at once, as derivatives of the first, so drivers can share
+            // compilation work between them and the pipeline cache is consulted in one pass
+            infos[0].flags |= vk::PipelineCreateFlags::ALLOW_DERIVATIVES;
+            for info in &mut infos[1..] {
+                info.flags |= vk::PipelineCreateFlags::DERIVATIVE;
+                info.base_pipeline_index = 0;
+            }
+            let start = Instant::now();
+            let mut pipelines = device
+                .create_graphics_pipelines(gfx.pipeline_cache, &infos, None)
+                .unwrap();
+            info!(
+                "created {} pipelines in {:.1}ms",
+                pipelines.len(),
+                start.elapsed().as_secs_f32() * 1e3
+            );
+            let rest = pipelines.split_off(Material::ALL.len());
+            let (
+                quad_pipeline,
+                textured_pipeline,
+                cutout_pipeline,
+                fog_pipeline,
+                grid_pipeline,
+                line_pipeline,
+                polygon_pipeline,
+            ) = (
+                rest[0], rest[1], rest[2], rest[3], rest[4], rest[5], rest[6],
+            );
+            drop((
+                sv_guard, sf_guard, cv_guard, cf_guard, qf_guard, tf_guard, fsv_guard, ff_guard,
+                gv_guard, gf_guard, lv_guard, lf_guard, rf_guard, cof_guard,
+            ));
+
+            let pool = gfx
+                .device
+                .create_command_pool(
+                    &vk::CommandPoolCreateInfo::builder()
+                        .flags(
+                            vk::CommandPoolCreateFlags::TRANSIENT
+                                | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+                        )
+                        .queue_family_index(gfx.queue_family),
+                    None,
+                )
+                .unwrap();
+            gfx.set_name(render_pass, cstr!("scene"));
+            for (&material, &pipeline) in Material::ALL.iter().zip(&pipelines) {
+                let name = match material {
+                    Material::Sprite => cstr!("sprite"),
+                    Material::Circle => cstr!("circle"),
+                    Material::Additive => cstr!("additive"),
+                    Material::Wireframe => cstr!("wireframe"),
+                    Material::Rounded => cstr!("rounded"),
+                    Material::Cutout => cstr!("cutout"),
+                };
+                gfx.set_name(pipeline, name);
+            }
+            gfx.set_name(quad_pipeline, cstr!("quad"));
+            gfx.set_name(textured_pipeline, cstr!("textured"));
+            gfx.set_name(cutout_pipeline, cstr!("textured cutout"));
+            gfx.set_name(fog_pipeline, cstr!("fog"));
+            gfx.set_name(grid_pipeline, cstr!("grid"));
+            gfx.set_name(line_pipeline, cstr!("line"));
+            gfx.set_name(polygon_pipeline, cstr!("polygon"));
+            let line_width_range = if gfx.features.wide_lines == vk::TRUE {
+                Some(gfx.limits.line_width_range)
+            } else {
+                None
+            };
+
+            Self {
+                gfx,
+                pipeline_layout,
+                render_pass,
+                partial_pass,
+                pipelines,
+                fog_layout,
+                fog_pipeline,
+                grid_layout,
+                grid_pipeline,
+                quad_pipeline,
+                quads: Vec::new(),
+                clips: Vec::new(),
+                clip_stack: Vec::new(),
+                texture_set_layout,
+                textured_layout,
+                textured_pipeline,
+                cutout_pipeline,
+                line_layout,
+                line_pipeline,
+                line_width_range,
+                polygon_pipeline,
+                lines: Vec::new(),
+                triangles: Vec::new(),
+                tiles: Vec::new(),
+                line_buffer: None,
+                pool,
+                cmds: Vec::new(),
+                format,
+                viewport: full_viewport(extent),
+                scissors: full_scissors(extent),
+                framebuffers: vec![],
+                fb_index: 0,
+                samples,
+                target: None,
+                resolved: None,
+                post,
+                lighting,
+                drawn: HashMap::new(),
+                drawn_scene: None,
+                visible: 0,
+                picking: Vec::new(),
+                stale: Vec::new(),
+                presented: Vec::new(),
+                generation: 0,
+                recorded: Vec::new(),
+            }
+        }
+    }
+
+    /// Clip quads queued by `draw_quad` to `rect`, in pixels from the top-left of the window, until
+    /// the matching `pop_clip`
+    ///
+    /// Regions nest, each clipping to its intersection with those already in effect. Any left in
+    /// effect at the end of a frame are discarded.
+    pub fn push_clip(&mut self, rect: vk::Rect2D) {
+        let rect = match self.clip_stack.last() {
+            Some(&parent) => intersection(self.clips[parent], rect),
+            None => rect,
+        };
+        self.clip_stack.push(self.clips.len());
+        self.clips.push(rect);
+    }
+
+    /// Stop clipping to the region most recently pushed by `push_clip`
+    pub fn pop_clip(&mut self) {
+        self.clip_stack.pop().expect("no clip region to pop");
+    }
+
+    /// Commands recorded by the latest `run`, for the framebuffer set by `set_fb_index`
+    fn cmd(&self) -> vk::CommandBuffer {
+        self.cmds[self.fb_index as usize]
+    }
+
+    /// Forget what was queued by `draw_quad`, `draw_line`, and the like for the frame just drawn
+    fn clear_queued(&mut self) {
+        self.quads.clear();
+        self.clips.clear();
+        self.clip_stack.clear();
+        self.lines.clear();
+        self.triangles.clear();
+        self.tiles.clear();
+    }
+
+    /// Number of sprites on screen in the most recent frame, e.g. for choosing a level of detail
+    pub fn visible_count(&self) -> u32 {
+        self.visible
+    }
+
+    pub fn set_scissors(&mut self, scissors: vk::Rect2D) {
+        self.scissors = scissors;
+    }
+
+    pub fn set_viewport(&mut self, viewport: vk::Viewport) {
+        self.viewport = viewport;
+    }
+
+    /// Recreate framebuffers for a new set of image views of `extent`, resetting the viewport and
+    /// scissors to cover them
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    /// - Must be passed valid ImageViews that will outlive rendering done using them
+    unsafe fn rebuild_framebuffers(
+        &mut self,
+        extent: vk::Extent2D,
+        views: impl IntoIterator<Item = vk::ImageView>,
+    ) {
+        let device = &*self.gfx.device;
+        self.viewport = full_viewport(extent);
+        self.scissors = full_scissors(extent);
+        let views = views.into_iter().collect::<Vec<_>>();
+        let multisampled = self.samples != vk::SampleCountFlags::TYPE_1;
+        let target = if multisampled || self.post.is_some() {
+            let usage = if multisampled {
+                vk::ImageUsageFlags::TRANSIENT_ATTACHMENT
+            } else {
+                vk::ImageUsageFlags::SAMPLED
+            };
+            Some(Attachment::new(
+                &self.gfx,
+                self.format,
+                extent,
+                self.samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | usage,
+            ))
+        } else {
+            None
+        };
+        let resolved = if multisampled && self.post.is_some() {
+            Some(Attachment::new(
+                &self.gfx,
+                self.format,
+                extent,
+                vk::SampleCountFlags::TYPE_1,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            ))
+        } else {
+            None
+        };
+        let framebuffer = |render_pass, attachments: &[vk::ImageView]| {
+            device.create_framebuffer(
+                &vk::FramebufferCreateInfo::builder()
+                    .render_pass(render_pass)
+                    .attachments(attachments)
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1),
+                None,
+            )
+        };
+
+        let fbs = views
+            .iter()
+            .map(|&view| match target {
+                Some(ref target) if multisampled => framebuffer(
+                    self.render_pass,
+                    &[target.view, resolved.as_ref().map_or(view, |x| x.view)],
+                ),
+                Some(ref target) => framebuffer(self.render_pass, &[target.view]),
+                None => framebuffer(self.render_pass, &[view]),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for &fb in &self.framebuffers {
+            device.destroy_framebuffer(fb, None);
+        }
+        self.stale = vec![vk::Rect2D::default(); fbs.len()];
+        self.presented = vec![false; fbs.len()];
+        self.recorded = vec![None; fbs.len()];
+        if !self.cmds.is_empty() {
+            device.free_command_buffers(self.pool, &self.cmds);
+        }
+        self.cmds = device
+            .allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(self.pool)
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(fbs.len() as u32),
+            )
+            .unwrap();
+        for &cmd in &self.cmds {
+            self.gfx.set_name(cmd, cstr!("render"));
+        }
+        self.framebuffers = fbs;
+
+        if let Some(ref mut post) = self.post {
+            let fbs = views
+                .iter()
+                .map(|&view| framebuffer(post.render_pass, &[view]))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            for &fb in &post.framebuffers {
+                device.destroy_framebuffer(fb, None);
+            }
+            post.framebuffers = fbs;
+            let image_info = [vk::DescriptorImageInfo {
+                sampler: post.sampler,
+                image_view: resolved.as_ref().or(target.as_ref()).unwrap().view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }];
+            device.update_descriptor_sets(
+                &[vk::WriteDescriptorSet::builder()
+                    .dst_set(post.descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_info)
+                    .build()],
+                &[],
+            );
+        }
+
+        self.lighting.rebuild(&self.gfx, extent);
+
+        if let Some(old) = mem::replace(&mut self.target, target) {
+            old.destroy(device);
+        }
+        if let Some(old) = mem::replace(&mut self.resolved, resolved) {
+            old.destroy(device);
+        }
+    }
+
+    /// Set the index of the framebuffer to use on the next pass
+    ///
+    /// # Safety
+    /// - Must not currently be in use
+    unsafe fn set_fb_index(&mut self, index: u32) {
+        debug_assert!(
+            index < self.framebuffer_count(),
+            "framebuffer index out of range"
+        );
+        self.fb_index = index;
+    }
+
+    /// Number of framebuffers, one per image passed to the latest `rebuild_framebuffers`
+    ///
+    /// Per-frame resources, indexed like `set_fb_index`, should have this many elements.
+    fn framebuffer_count(&self) -> u32 {
+        self.framebuffers.len() as u32
+    }
+
+    /// Record into `cmd` a transition making `target`, just drawn by an earlier pass, ready for
+    /// sampling
+    unsafe fn read_barrier(&self, cmd: vk::CommandBuffer, target: Target) {
+        let image = match target {
+            Target::LightMap => self.lighting.image.as_ref(),
+            Target::Scene => self.resolved.as_ref().or(self.target.as_ref()),
+            Target::Output => None,
+        }
+        .expect("target can't be sampled")
+        .image;
+        self.gfx.device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .build()],
+        );
+    }
+}
+
+impl Renderer for Render {
+    unsafe fn resize(&mut self, extent: vk::Extent2D, views: &[vk::ImageView]) {
+        self.rebuild_framebuffers(extent, views.iter().cloned());
+    }
+
+    fn image_count(&self) -> u32 {
+        self.framebuffer_count()
+    }
+
+    unsafe fn record(&mut self, world: &World, image: u32) {
+        self.set_fb_index(image);
+        self.run_now(&world.res);
+    }
+
+    unsafe fn submit(&mut self, wait: vk::Semaphore, signal: vk::Semaphore) {
+        self.gfx.submit(self.cmd(), wait, signal, vk::Fence::null());
+    }
+
+    fn pick(&self, position: na::Point2<f32>) -> Option<Entity> {
+        let view = self.picking.iter().find(|view| {
+            let viewport = &view.viewport;
+            position.x >= viewport.x
+                && position.y >= viewport.y
+                && position.x < viewport.x + viewport.width
+                && position.y < viewport.y + viewport.height
+        })?;
+        let viewport = &view.viewport;
+        let clip = na::Point2::new(
+            (position.x - viewport.x) / viewport.width * 2.0 - 1.0,
+            (position.y - viewport.y) / viewport.height * 2.0 - 1.0,
+        );
+        view.sprites
+            .iter()
+            .rev()
+            .find(|sprite| {
+                let local = match sprite.transform.try_inverse() {
+                    Some(inverse) => inverse * clip,
+                    None => return false,
+                };
+                let uv = na::Point2::from(local.coords.component_div(&view.half_extents));
+                sprite.covers(uv, &view.half_extents)
+            })
+            .map(|sprite| sprite.entity)
+    }
+
+    fn draw_quad(
+        &mut self,
+        transform: na::Isometry2<f32>,
+        dimensions: na::Vector2<f32>,
+        color: na::Vector4<f32>,
+    ) {
+        self.quads.push(Quad {
+            transform,
+            dimensions,
+            color,
+            clip: self.clip_stack.last().cloned(),
+        });
+    }
+
+    fn draw_line(&mut self, a: na::Point2<f32>, b: na::Point2<f32>, color: na::Vector4<f32>) {
+        self.lines.push(LineVertex { position: a, color });
+        self.lines.push(LineVertex { position: b, color });
+    }
+
+    fn draw_polygon(&mut self, points: &[na::Point2<f32>], color: na::Vector4<f32>) {
+        if points.len() < 3 {
+            return;
+        }
+        // Fan out from the first vertex
+        for pair in points[1..].windows(2) {
+            for &position in &[points[0], pair[0], pair[1]] {
+                self.triangles.push(LineVertex { position, color });
+            }
+        }
+    }
+}
+
+/// A `Renderer` that draws nothing, for driving frame loops in tests without a GPU
+///
+/// Keeps count of what it's asked to do, so tests can check on the loop.
+#[derive(Debug, Default)]
+pub struct Headless {
+    /// Number of images passed to the latest `resize`
+    pub images: u32,
+    /// Index of the image each frame was recorded into, in order
+    pub recorded: Vec<u32>,
+    /// Number of recordings submitted
+    pub submitted: u32,
+    /// Number of quads, line segments, and polygons queued since the latest recording
+    pub queued: u32,
+}
+
+impl Renderer for Headless {
+    unsafe fn resize(&mut self, _: vk::Extent2D, views: &[vk::ImageView]) {
+        self.images = views.len() as u32;
+    }
+
+    fn image_count(&self) -> u32 {
+        self.images
+    }
+
+    unsafe fn record(&mut self, _: &World, image: u32) {
+        assert!(image < self.images, "image index out of range");
+        self.recorded.push(image);
+        self.queued = 0;
+    }
+
+    unsafe fn submit(&mut self, _: vk::Semaphore, _: vk::Semaphore) {
+        assert!(
+            self.submitted < self.recorded.len() as u32,
+            "submitted without recording"
+        );
+        self.submitted += 1;
+    }
+
+    fn pick(&self, _: na::Point2<f32>) -> Option<Entity> {
+        None
+    }
+
+    fn draw_quad(&mut self, _: na::Isometry2<f32>, _: na::Vector2<f32>, _: na::Vector4<f32>) {
+        self.queued += 1;
+    }
+
+    fn draw_line(&mut self, _: na::Point2<f32>, _: na::Point2<f32>, _: na::Vector4<f32>) {
+        self.queued += 1;
+    }
+
+    fn draw_polygon(&mut self, _: &[na::Point2<f32>], _: na::Vector4<f32>) {
+        self.queued += 1;
+    }
+}
+
+/// Draw a frame of `world` into the image at index `image` with `renderer`, timing recording and
+/// submission in the `Profiler`
+///
+/// Returns the time taken to record.
+///
+/// # Safety
+/// As for `Renderer::record` and `Renderer::submit`
+pub unsafe fn draw_frame(
+    renderer: &mut impl Renderer,
+    world: &World,
+    image: u32,
+    wait: vk::Semaphore,
+    signal: vk::Semaphore,
+) -> Duration {
+    let start = Instant::now();
+    renderer.record(world, image);
+    let record = start.elapsed();
+    let start = Instant::now();
+    renderer.submit(wait, signal);
+    let submit = start.elapsed();
+    let mut profiler = world.write_resource::<Profiler>();
+    profiler.record("record", record);
+    profiler.record("submit", submit);
+    record
+}
+
+/// An image drawn by one pass of a frame and possibly sampled by later ones
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Target {
+    /// Light accumulated from `PointLight`s
+    LightMap,
+    /// The scene, when drawn offscreen for `Post` to filter
+    Scene,
+    /// The image to be presented
+    Output,
+}
+
+/// A pass recorded by `Render`'s `run`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pass {
+    Lights,
+    Scene,
+    Post,
+}
+
+/// A pass to record, along with the targets it samples and draws into
+struct PassDecl {
+    pass: Pass,
+    reads: &'static [Target],
+    writes: &'static [Target],
+}
+
+/// A pass in recording order, with the targets to transition for sampling before it begins
+#[derive(Debug, PartialEq)]
+struct Step {
+    pass: Pass,
+    barriers: Vec<Target>,
+}
+
+/// Order `passes` such that each follows every other pass that writes a target it reads
+///
+/// Passes that don't depend on each other keep their relative order. Returns `None` if the
+/// dependencies are cyclic.
+fn schedule(passes: &[PassDecl]) -> Option<Vec<Step>> {
+    let mut done = vec![false; passes.len()];
+    let mut written = Vec::new();
+    let mut steps = Vec::with_capacity(passes.len());
+    while steps.len() < passes.len() {
+        let ready = |i: usize| {
+            !done[i]
+                && passes[i].reads.iter().all(|target| {
+                    passes
+                        .iter()
+                        .enumerate()
+                        .all(|(j, other)| j == i || done[j] || !other.writes.contains(target))
+                })
+        };
+        let next = (0..passes.len()).find(|&i| ready(i))?;
+        done[next] = true;
+        let pass = &passes[next];
+        steps.push(Step {
+            pass: pass.pass,
+            barriers: pass
+                .reads
+                .iter()
+                .filter(|x| written.contains(*x))
+                .cloned()
+                .collect(),
+        });
+        written.extend_from_slice(pass.writes);
+    }
+    Some(steps)
+}
+
+/// Create a render pass with a single subpass drawing to one color attachment
+///
+/// If `samples` is more than one, the color attachment is resolved into a second,
+/// single-sampled attachment. The final image is left ready for presentation, or as a color
+/// attachment for `Render::read_barrier` to ready for sampling if `sampled` is set. If `preserve`
+/// is set, the pass must begin with an image previously left ready for presentation, whose
+/// contents outside the render area are kept.
+unsafe fn create_render_pass(
+    device: &Device,
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    sampled: bool,
+    preserve: bool,
+) -> vk::RenderPass {
+    let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+    let final_layout = if sampled {
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+    } else {
+        vk::ImageLayout::PRESENT_SRC_KHR
+    };
+    let attachments = [
+        vk::AttachmentDescription {
+            format,
+            samples,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: if multisampled {
+                vk::AttachmentStoreOp::DONT_CARE
+            } else {
+                vk::AttachmentStoreOp::STORE
+            },
+            initial_layout: if preserve {
+                vk::ImageLayout::PRESENT_SRC_KHR
+            } else {
+                vk::ImageLayout::UNDEFINED
+            },
+            final_layout: if multisampled {
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            } else {
+                final_layout
+            },
+            ..Default::default()
+        },
+        vk::AttachmentDescription {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::DONT_CARE,
+            store_op: vk::AttachmentStoreOp::STORE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout,
+            ..Default::default()
+        },
+    ];
+    let color_attachments = [vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+    let resolve_attachments = [vk::AttachmentReference {
+        attachment: 1,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+    let mut subpass = vk::SubpassDescription::builder()
+        .color_attachments(&color_attachments)
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+    if multisampled {
+        subpass = subpass.resolve_attachments(&resolve_attachments);
+    }
+    let dependencies = [vk::SubpassDependency {
+        src_subpass: vk::SUBPASS_EXTERNAL,
+        dst_subpass: 0,
+        src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        ..Default::default()
+    }];
+    device
+        .create_render_pass(
+            &vk::RenderPassCreateInfo::builder()
+                .attachments(&attachments[..if multisampled { 2 } else { 1 }])
+                .subpasses(&[subpass.build()])
+                .dependencies(&dependencies),
+            None,
+        )
+        .unwrap()
+}
+
+/// A device-local image with a view of its single mip level, for use as a render target
+/// Host-visible vertex buffer
+struct VertexBuffer {
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    /// Capacity in bytes
+    size: vk::DeviceSize,
+}
+
+impl VertexBuffer {
+    unsafe fn new(gfx: &Graphics, size: vk::DeviceSize) -> Self {
+        let device = &*gfx.device;
+        let buffer = device
+            .create_buffer(
+                &vk::BufferCreateInfo::builder()
+                    .size(size)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                None,
+            )
+            .unwrap();
+        let reqs = device.get_buffer_memory_requirements(buffer);
+        let memory_type = gfx
+            .memory_type(
+                reqs.memory_type_bits,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )
+            .expect("no suitable memory type for vertex buffer");
+        let memory = device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(reqs.size)
+                    .memory_type_index(memory_type),
+                None,
+            )
+            .unwrap();
+        device.bind_buffer_memory(buffer, memory, 0).unwrap();
+        Self {
+            buffer,
+            memory,
+            size,
+        }
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_buffer(self.buffer, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+struct Attachment {
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    view: vk::ImageView,
+}
+
+impl Attachment {
+    unsafe fn new(
+        gfx: &Graphics,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        samples: vk::SampleCountFlags,
+        usage: vk::ImageUsageFlags,
+    ) -> Self {
+        let device = &*gfx.device;
+        let image = device
+            .create_image(
+                &vk::ImageCreateInfo::builder()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(format)
+                    .extent(vk::Extent3D {
+                        width: extent.width,
+                        height: extent.height,
+                        depth: 1,
+                    })
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(samples)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(usage)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED),
+                None,
+            )
+            .unwrap();
+        let reqs = device.get_image_memory_requirements(image);
+        let memory_type = gfx
+            .memory_type(reqs.memory_type_bits, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            .expect("no suitable memory type for render target");
+        let memory = device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(reqs.size)
+                    .memory_type_index(memory_type),
+                None,
+            )
+            .unwrap();
+        device.bind_image_memory(image, memory, 0).unwrap();
+        let view = device
+            .create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }),
+                None,
+            )
+            .unwrap();
+        Self {
+            image,
+            memory,
+            view,
+        }
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+/// Resources for the FXAA pass, which filters the offscreen scene into the presented image
+struct Post {
+    render_pass: vk::RenderPass,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    /// Refers to the scene image; updated by `Render::rebuild_framebuffers`
+    descriptor_set: vk::DescriptorSet,
+    framebuffers: Vec<vk::Framebuffer>,
+}
+
+impl Post {
+    /// Build a pass running the fragment shader `code`, which takes `PostParams`, over the scene
+    unsafe fn new(gfx: &Graphics, format: vk::Format, code: &[u32]) -> Self {
+        let device = &*gfx.device;
+        let vert = device
+            .create_shader_module(
+                &vk::ShaderModuleCreateInfo::builder().code(FULLSCREEN_VERT),
+                None,
+            )
+            .unwrap();
+        let v_guard = defer(|| device.destroy_shader_module(vert, None));
+
+        let frag = device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)
+            .unwrap();
+        let f_guard = defer(|| device.destroy_shader_module(frag, None));
+
+        let render_pass =
+            create_render_pass(device, format, vk::SampleCountFlags::TYPE_1, false, false);
+
+        let set_layout = device
+            .create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
+                    vk::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: 1,
+                        stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                        ..Default::default()
+                    },
+                ]),
+                None,
+            )
+            .unwrap();
+
+        let pipeline_layout = device
+            .create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(&[set_layout])
+                    .push_constant_ranges(&[vk::PushConstantRange {
+                        stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                        offset: 0,
+                        size: mem::size_of::<PostParams>() as u32,
+                    }]),
+                None,
+            )
+            .unwrap();
+
+        let entry_point = b"main\0".as_ptr() as *const i8;
+        let stages = [
+            vk::PipelineShaderStageCreateInfo {
+                stage: vk::ShaderStageFlags::VERTEX,
+                module: vert,
+                p_name: entry_point,
+                ..Default::default()
+            },
+            vk::PipelineShaderStageCreateInfo {
+                stage: vk::ShaderStageFlags::FRAGMENT,
+                module: frag,
+                p_name: entry_point,
+                ..Default::default()
+            },
+        ];
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::FALSE,
+            color_write_mask: vk::ColorComponentFlags::all(),
+            ..Default::default()
+        }];
+        let pipeline = device
+            .create_graphics_pipelines(
+                gfx.pipeline_cache,
+                &[vk::GraphicsPipelineCreateInfo::builder()
+                    .stages(&stages)
+                    .vertex_input_state(&Default::default())
+                    .input_assembly_state(
+                        &vk::PipelineInputAssemblyStateCreateInfo::builder()
+                            .topology(vk::PrimitiveTopology::TRIANGLE_LIST),
+                    )
+                    .viewport_state(
+                        &vk::PipelineViewportStateCreateInfo::builder()
+                            .scissor_count(1)
+                            .viewport_count(1),
+                    )
+                    .rasterization_state(
+                        &vk::PipelineRasterizationStateCreateInfo::builder()
+                            .cull_mode(vk::CullModeFlags::NONE)
+                            .polygon_mode(vk::PolygonMode::FILL)
+                            .line_width(1.0),
+                    )
+                    .multisample_state(
+                        &vk::PipelineMultisampleStateCreateInfo::builder()
+                            .rasterization_samples(vk::SampleCountFlags::TYPE_1),
+                    )
+                    .color_blend_state(
+                        &vk::PipelineColorBlendStateCreateInfo::builder()
+                            .attachments(&blend_attachments),
+                    )
+                    .dynamic_state(
+                        &vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&[
+                            vk::DynamicState::VIEWPORT,
+                            vk::DynamicState::SCISSOR,
+                        ]),
+                    )
+                    .layout(pipeline_layout)
+                    .render_pass(render_pass)
+                    .subpass(0)
+                    .build()],
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+        drop((v_guard, f_guard));
+        gfx.set_name(render_pass, cstr!("post"));
+        gfx.set_name(pipeline, cstr!("post"));
+
+        let sampler = device
+            .create_sampler(
+                &vk::SamplerCreateInfo::builder()
+                    .mag_filter(vk::Filter::LINEAR)
+                    .min_filter(vk::Filter::LINEAR)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )
+            .unwrap();
+
+        let descriptor_pool = device
+            .create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::builder()
+                    .max_sets(1)
+                    .pool_sizes(&[vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: 1,
+                    }]),
+                None,
+            )
+            .unwrap();
+        let descriptor_set = device
+            .allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&[set_layout]),
+            )
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+
+        Self {
+            render_pass,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            descriptor_pool,
+            descriptor_set,
+            framebuffers: vec![],
+        }
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_render_pass(self.render_pass, None);
+        for &fb in &self.framebuffers {
+            device.destroy_framebuffer(fb, None);
+        }
+    }
+}
+
+/// Resources for accumulating `PointLight`s into a light map, and multiplying the scene by it
+struct Lighting {
+    render_pass: vk::RenderPass,
+    /// Draws a single light's falloff into the light map, using `Render::pipeline_layout`
+    light_pipeline: vk::Pipeline,
+    set_layout: vk::DescriptorSetLayout,
+    composite_layout: vk::PipelineLayout,
+    /// Multiplies the scene by the light map, within the scene's render pass
+    composite_pipeline: vk::Pipeline,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    /// Refers to `image`; updated by `rebuild`
+    descriptor_set: vk::DescriptorSet,
+    image: Option<Attachment>,
+    framebuffer: Option<vk::Framebuffer>,
+}
+
+impl Lighting {
+    /// Build pipelines for compositing into subpass 0 of `scene_pass`, which has `samples` samples
+    /// per pixel, and for drawing lights with `sprite_layout`
+    unsafe fn new(
+        gfx: &Graphics,
+        scene_pass: vk::RenderPass,
+        samples: vk::SampleCountFlags,
+        sprite_layout: vk::PipelineLayout,
+    ) -> Self {
+        let device = &*gfx.device;
+        let module = |code: &[u32]| {
+            device
+                .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)
+                .unwrap()
+        };
+        let circle_vert = module(CIRCLE_VERT);
+        let cv_guard = defer(|| device.destroy_shader_module(circle_vert, None));
+        let light_frag = module(LIGHT_FRAG);
+        let lf_guard = defer(|| device.destroy_shader_module(light_frag, None));
+        let fullscreen_vert = module(FULLSCREEN_VERT);
+        let fsv_guard = defer(|| device.destroy_shader_module(fullscreen_vert, None));
+        let lightmap_frag = module(LIGHTMAP_FRAG);
+        let lmf_guard = defer(|| device.destroy_shader_module(lightmap_frag, None));
+
+        let render_pass = create_render_pass(
+            device,
+            LIGHT_FORMAT,
+            vk::SampleCountFlags::TYPE_1,
+            true,
+            false,
+        );
+
+        let set_layout = device
+            .create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
+                    vk::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: 1,
+                        stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                        ..Default::default()
+                    },
+                ]),
+                None,
+            )
+            .unwrap();
+
+        let composite_layout = device
+            .create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder().set_layouts(&[set_layout]),
+                None,
+            )
+            .unwrap();
+
+        let entry_point = b"main\0".as_ptr() as *const i8;
+        let stage = |stage, module| vk::PipelineShaderStageCreateInfo {
+            stage,
+            module,
+            p_name: entry_point,
+            ..Default::default()
+        };
+        let light_stages = [
+            stage(vk::ShaderStageFlags::VERTEX, circle_vert),
+            stage(vk::ShaderStageFlags::FRAGMENT, light_frag),
+        ];
+        let composite_stages = [
+            stage(vk::ShaderStageFlags::VERTEX, fullscreen_vert),
+            stage(vk::ShaderStageFlags::FRAGMENT, lightmap_frag),
+        ];
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .scissor_count(1)
+            .viewport_count(1);
+        let fill = vk::PipelineRasterizationStateCreateInfo::builder()
+            .cull_mode(vk::CullModeFlags::NONE)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0);
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR]);
+        let additive_attachments = [vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: vk::BlendFactor::ONE,
+            dst_color_blend_factor: vk::BlendFactor::ONE,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ZERO,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::all(),
+        }];
+        // Scale the existing color by the fragment's color
+        let multiply_attachments = [vk::PipelineColorBlendAttachmentState {
+            src_color_blend_factor: vk::BlendFactor::DST_COLOR,
+            dst_color_blend_factor: vk::BlendFactor::ZERO,
+            ..additive_attachments[0]
+        }];
+        let pipelines = device
+            .create_graphics_pipelines(
+                gfx.pipeline_cache,
+                &[
+                    vk::GraphicsPipelineCreateInfo::builder()
+                        .stages(&light_stages)
+                        .vertex_input_state(&Default::default())
+                        .input_assembly_state(
+                            &vk::PipelineInputAssemblyStateCreateInfo::builder()
+                                .topology(vk::PrimitiveTopology::TRIANGLE_STRIP),
+                        )
+                        .viewport_state(&viewport_state)
+                        .rasterization_state(&fill)
+                        .multisample_state(
+                            &vk::PipelineMultisampleStateCreateInfo::builder()
+                                .rasterization_samples(vk::SampleCountFlags::TYPE_1),
+                        )
+                        .color_blend_state(
+                            &vk::PipelineColorBlendStateCreateInfo::builder()
+                                .attachments(&additive_attachments),
+                        )
+                        .dynamic_state(&dynamic_state)
+                        .layout(sprite_layout)
+                        .render_pass(render_pass)
+                        .subpass(0)
+                        .build(),
+                    vk::GraphicsPipelineCreateInfo::builder()
+                        .stages(&composite_stages)
+                        .vertex_input_state(&Default::default())
+                        .input_assembly_state(
+                            &vk::PipelineInputAssemblyStateCreateInfo::builder()
+                                .topology(vk::PrimitiveTopology::TRIANGLE_LIST),
+                        )
+                        .viewport_state(&viewport_state)
+                        .rasterization_state(&fill)
+                        .multisample_state(
+                            &vk::PipelineMultisampleStateCreateInfo::builder()
+                                .rasterization_samples(samples),
+                        )
+                        .color_blend_state(
+                            &vk::PipelineColorBlendStateCreateInfo::builder()
+                                .attachments(&multiply_attachments),
+                        )
+                        .dynamic_state(&dynamic_state)
+                        .layout(composite_layout)
+                        .render_pass(scene_pass)
+                        .subpass(0)
+                        .build(),
+                ],
+                None,
+            )
+            .unwrap();
+        drop((cv_guard, lf_guard, fsv_guard, lmf_guard));
+        let (light_pipeline, composite_pipeline) = (pipelines[0], pipelines[1]);
+        gfx.set_name(render_pass, cstr!("lights"));
+        gfx.set_name(light_pipeline, cstr!("light"));
+        gfx.set_name(composite_pipeline, cstr!("light composite"));
+
+        let sampler = device
+            .create_sampler(
+                &vk::SamplerCreateInfo::builder()
+                    .mag_filter(vk::Filter::NEAREST)
+                    .min_filter(vk::Filter::NEAREST)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )
+            .unwrap();
+
+        let descriptor_pool = device
+            .create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::builder()
+                    .max_sets(1)
+                    .pool_sizes(&[vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        descriptor_count: 1,
+                    }]),
+                None,
+            )
+            .unwrap();
+        let descriptor_set = device
+            .allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&[set_layout]),
+            )
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+
+        Self {
+            render_pass,
+            light_pipeline,
+            set_layout,
+            composite_layout,
+            composite_pipeline,
+            sampler,
+            descriptor_pool,
+            descriptor_set,
+            image: None,
+            framebuffer: None,
+        }
+    }
+
+    /// Recreate the light map to cover `extent`
+    ///
+    /// # Safety
+    /// - Must not be called while rendering is in progress
+    unsafe fn rebuild(&mut self, gfx: &Graphics, extent: vk::Extent2D) {
+        let device = &*gfx.device;
+        let image = Attachment::new(
+            gfx,
+            LIGHT_FORMAT,
+            extent,
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        );
+        let framebuffer = device
+            .create_framebuffer(
+                &vk::FramebufferCreateInfo::builder()
+                    .render_pass(self.render_pass)
+                    .attachments(&[image.view])
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1),
+                None,
+            )
+            .unwrap();
+        let image_info = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: image.view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        device.update_descriptor_sets(
+            &[vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_info)
+                .build()],
+            &[],
+        );
+        if let Some(old) = self.framebuffer.replace(framebuffer) {
+            device.destroy_framebuffer(old, None);
+        }
+        if let Some(old) = self.image.replace(image) {
+            old.destroy(device);
+        }
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.light_pipeline, None);
+        device.destroy_pipeline(self.composite_pipeline, None);
+        device.destroy_pipeline_layout(self.composite_layout, None);
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_render_pass(self.render_pass, None);
+        if let Some(fb) = self.framebuffer {
+            device.destroy_framebuffer(fb, None);
+        }
+        if let Some(ref image) = self.image {
+            image.destroy(device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mat3_layout() {
+        let x = na::Affine2::from_matrix_unchecked(na::Matrix3::new(
+            1.0, 2.0, 3.0, //
+            4.0, 5.0, 6.0, //
+            0.0, 0.0, 1.0,
+        ));
+        assert_eq!(
+            Mat3::from(x),
+            Mat3([
+                [1.0, 4.0, 0.0, 0.0],
+                [2.0, 5.0, 0.0, 0.0],
+                [3.0, 6.0, 1.0, 0.0]
+            ])
+        );
+        let bytes = LineParams { viewproj: x.into() }.as_bytes().to_vec();
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(bytes[32..36], 3.0f32.to_bits().to_ne_bytes());
+        assert_eq!(bytes[44..48], [0; 4]);
+    }
+
+    #[test]
+    fn raise_follows_screen() {
+        let up = na::UnitComplex::new(std::f32::consts::FRAC_PI_2);
+        let position = na::Isometry2::new(na::Vector2::new(1.0, 2.0), 0.3);
+        let x = raise(&position, 4.0, &up, 4.0);
+        // Lifted toward the top of the screen, which is world -x
+        let center = x * na::Point2::origin();
+        assert!((center - na::Point2::new(-1.0, 2.0)).norm() < 1e-5);
+        // Squashed along world x regardless of the sprite's own rotation, leaving world y alone
+        let local = |v: na::Vector2<f32>| x * (position.rotation.inverse() * v);
+        assert!((local(na::Vector2::x()) - na::Vector2::new(0.8, 0.0)).norm() < 1e-5);
+        assert!((local(na::Vector2::y()) - na::Vector2::y()).norm() < 1e-5);
+    }
+
+    #[test]
+    fn rotate_about_pivot() {
+        let position = na::Isometry2::new(na::Vector2::new(1.0, 2.0), std::f32::consts::FRAC_PI_2);
+        let position = na::Affine2::from_matrix_unchecked(position.to_homogeneous());
+        // A handle at the sprite's left edge stays put while the blade swings up
+        let x = centered(&position, &na::Vector2::new(-0.5, 0.0));
+        let handle = x * na::Point2::new(-0.5, 0.0);
+        assert!((handle - na::Point2::new(1.0, 2.0)).norm() < 1e-5);
+        let center = x * na::Point2::origin();
+        assert!((center - na::Point2::new(1.0, 2.5)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn schedule_orders_and_transitions() {
+        let passes = [
+            PassDecl {
+                pass: Pass::Post,
+                reads: &[Target::Scene],
+                writes: &[Target::Output],
+            },
+            PassDecl {
+                pass: Pass::Scene,
+                reads: &[Target::LightMap],
+                writes: &[Target::Scene],
+            },
+            PassDecl {
+                pass: Pass::Lights,
+                reads: &[],
+                writes: &[Target::LightMap],
+            },
+        ];
+        assert_eq!(
+            schedule(&passes).unwrap(),
+            vec![
+                Step {
+                    pass: Pass::Lights,
+                    barriers: vec![],
+                },
+                Step {
+                    pass: Pass::Scene,
+                    barriers: vec![Target::LightMap],
+                },
+                Step {
+                    pass: Pass::Post,
+                    barriers: vec![Target::Scene],
+                },
+            ]
+        );
+        // Reading a target nothing writes this frame needs no transition
+        assert_eq!(schedule(&passes[1..2]).unwrap()[0].barriers, vec![]);
+        let cycle = [
+            PassDecl {
+                pass: Pass::Scene,
+                reads: &[Target::LightMap],
+                writes: &[Target::Scene],
+            },
+            PassDecl {
+                pass: Pass::Lights,
+                reads: &[Target::Scene],
+                writes: &[Target::LightMap],
+            },
+        ];
+        assert!(schedule(&cycle).is_none());
+    }
+
+    #[test]
+    fn pick_sees_through_transparency() {
+        let world = specs::World::new();
+        let half_extents = na::Vector2::new(0.5, 0.5);
+        let mut sprite = PickSprite {
+            entity: world.entities().create(),
+            transform: na::Affine2::identity(),
+            material: Material::Circle,
+            params: [0.0; 2],
+            opacity: 1.0,
+            texture: None,
+            uv_scale: na::Vector2::repeat(1.0),
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        };
+        let corner = na::Point2::new(0.9, -0.9);
+        assert!(sprite.covers(na::Point2::origin(), &half_extents));
+        assert!(!sprite.covers(corner, &half_extents));
+        // A texture takes over from the material, and is flipped to put its first row on top
+        let mut rgba = vec![0xFF; 2 * 2 * 4];
+        rgba[3] = 0;
+        sprite.texture = Some(Arc::new(texture::AlphaMask::from_rgba(&rgba, 2, 2)));
+        assert!(sprite.covers(corner, &half_extents));
+        assert!(!sprite.covers(na::Point2::new(-0.9, 0.9), &half_extents));
+        // Repeated, each quadrant of the sprite shows a copy
+        sprite.uv_scale = na::Vector2::repeat(2.0);
+        sprite.address_mode = vk::SamplerAddressMode::REPEAT;
+        assert!(!sprite.covers(na::Point2::new(0.1, 0.9), &half_extents));
+        assert!(sprite.covers(na::Point2::new(0.9, 0.9), &half_extents));
+        sprite.opacity = 0.25;
+        assert!(!sprite.covers(corner, &half_extents));
+    }
+
+    #[test]
+    fn headless_frames() {
+        let mut state = crate::state::State::new();
+        let mut renderer = Headless::default();
+        unsafe {
+            renderer.resize(
+                vk::Extent2D {
+                    width: 640,
+                    height: 480,
+                },
+                &[vk::ImageView::null(); 3],
+            );
+        }
+        for frame in 0..5 {
+            state.advance(Duration::from_millis(16));
+            renderer.draw_polyline(
+                &[
+                    na::Point2::origin(),
+                    na::Point2::new(1.0, 0.0),
+                    na::Point2::new(1.0, 1.0),
+                ],
+                na::Vector4::repeat(1.0),
+            );
+            assert_eq!(renderer.queued, 2);
+            let image = frame % renderer.image_count();
+            unsafe {
+                draw_frame(
+                    &mut renderer,
+                    &state.world,
+                    image,
+                    vk::Semaphore::null(),
+                    vk::Semaphore::null(),
+                );
+            }
+            assert_eq!(renderer.queued, 0);
+        }
+        assert_eq!(renderer.recorded, [0, 1, 2, 0, 1]);
+        assert_eq!(renderer.submitted, 5);
+        assert!(state
+            .world
+            .read_resource::<Profiler>()
+            .averages()
+            .any(|(name, _)| name == "record"));
+    }
+
+    #[test]
+    fn ping_expires() {
+        let mut state = crate::state::State::new();
+        let entity = ping(&state.world, na::Vector2::new(1.0, 2.0));
+        assert!(!state.is_idle());
+        state.advance(Duration::from_secs_f32(PING_DURATION * 0.2));
+        let progress = state
+            .world
+            .read_storage::<Ping>()
+            .get(entity)
+            .unwrap()
+            .progress();
+        assert!(progress > 0.0 && progress < 1.0);
+        for _ in 0..10 {
+            state.advance(Duration::from_secs_f32(PING_DURATION * 0.1));
+        }
+        assert!(!state.world.is_alive(entity));
+        assert!(state.is_idle());
+    }
+
+    #[test]
+    fn select_one() {
+        let mut state = crate::state::State::new();
+        let a = ping(&state.world, na::zero());
+        let b = ping(&state.world, na::zero());
+        state.select(Some(a));
+        state.select(Some(b));
+        {
+            let selected = state.world.read_storage::<Selected>();
+            assert!(!selected.contains(a));
+            assert!(selected.contains(b));
+        }
+        state.select(None);
+        assert_eq!((&state.world.read_storage::<Selected>()).join().count(), 0);
     }
 }
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use winit::dpi::{LogicalPosition, LogicalSize};
+use winit::{DeviceId, ElementState, KeyboardInput, ModifiersState, MouseButton, WindowEvent};
+
+/// A window event that can influence the simulation, in a form that can be written out and read
+/// back
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Input {
+    /// Logical pixels from the center of the window, with y down
+    CursorMoved {
+        x: f64,
+        y: f64,
+    },
+    MouseInput {
+        state: ElementState,
+        button: MouseButton,
+    },
+    KeyboardInput(KeyboardInput),
+    ReceivedCharacter(char),
+    /// Losing focus releases every held key and button
+    Focused(bool),
+}
+
+impl Input {
+    /// Whether `event` conveys an input
+    pub fn is_input(event: &WindowEvent) -> bool {
+        match *event {
+            WindowEvent::CursorMoved { .. }
+            | WindowEvent::MouseInput { .. }
+            | WindowEvent::KeyboardInput { .. }
+            | WindowEvent::ReceivedCharacter(_)
+            | WindowEvent::Focused(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The input conveyed by `event` on a window of logical `size`, if any
+    pub fn from_event(event: &WindowEvent, size: LogicalSize) -> Option<Self> {
+        Some(match *event {
+            WindowEvent::CursorMoved { position, .. } => Input::CursorMoved {
+                x: position.x - size.width / 2.0,
+                y: position.y - size.height / 2.0,
+            },
+            WindowEvent::MouseInput { state, button, .. } => Input::MouseInput { state, button },
+            WindowEvent::KeyboardInput { input, .. } => Input::KeyboardInput(input),
+            WindowEvent::ReceivedCharacter(c) => Input::ReceivedCharacter(c),
+            WindowEvent::Focused(focused) => Input::Focused(focused),
+            _ => return None,
+        })
+    }
+
+    /// An event equivalent to the one this was recorded from, on a window of logical `size`
+    ///
+    /// The event's device is a placeholder that mustn't be passed back to winit.
+    pub fn to_event(self, size: LogicalSize) -> WindowEvent {
+        let device_id = unsafe { DeviceId::dummy() };
+        match self {
+            Input::CursorMoved { x, y } => WindowEvent::CursorMoved {
+                device_id,
+                position: LogicalPosition::new(x + size.width / 2.0, y + size.height / 2.0),
+                modifiers: ModifiersState::default(),
+            },
+            Input::MouseInput { state, button } => WindowEvent::MouseInput {
+                device_id,
+                state,
+                button,
+                modifiers: ModifiersState::default(),
+            },
+            Input::KeyboardInput(input) => WindowEvent::KeyboardInput { device_id, input },
+            Input::ReceivedCharacter(c) => WindowEvent::ReceivedCharacter(c),
+            Input::Focused(focused) => WindowEvent::Focused(focused),
+        }
+    }
+}
+
+/// An `Input` along with when and where it arrived
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Number of steps run before the input was handled
+    step: u64,
+    /// Index of the window the input was directed at
+    window: usize,
+    input: Input,
+}
+
+/// Writes inputs to a file as they arrive, one JSON object per line
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Record `input` arriving at `window` after `step` steps have run
+    pub fn record(&mut self, step: u64, window: usize, input: Input) -> io::Result<()> {
+        serde_json::to_writer(
+            &mut self.out,
+            &Entry {
+                step,
+                window,
+                input,
+            },
+        )?;
+        self.out.write_all(b"\n")
+    }
+}
+
+/// Inputs read back from a file written by a `Recorder`
+///
+/// Handling each input after the same number of steps it originally followed reproduces the
+/// recorded session exactly, since the simulation is otherwise deterministic.
+pub struct Replay {
+    entries: VecDeque<Entry>,
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut entries = VecDeque::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            entries.push_back(serde_json::from_str(&line?)?);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Number of steps that must have run before the next input is handled
+    pub fn next_step(&self) -> Option<u64> {
+        self.entries.front().map(|x| x.step)
+    }
+
+    /// Remove and return the window index and content of each input due once `step` steps have
+    /// run
+    pub fn take_due(&mut self, step: u64) -> Vec<(usize, Input)> {
+        let mut due = Vec::new();
+        while let Some(&entry) = self.entries.front() {
+            if entry.step > step {
+                break;
+            }
+            self.entries.pop_front();
+            due.push((entry.window, entry.input));
+        }
+        due
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
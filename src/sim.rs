@@ -1,23 +1,1580 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::iter;
+use std::sync::{Arc, RwLock};
+
+use bitflags::bitflags;
+use log::{debug, warn};
 use ncollide2d::{
+    bounding_volume::{BoundingVolume, AABB},
+    broad_phase::BroadPhasePairFilter,
+    events::ContactEvent,
+    query,
+    query::Proximity,
     shape,
-    world::{CollisionGroups, CollisionObjectHandle, GeometricQueryType},
+    world::{CollisionGroups, CollisionObject, CollisionObjectHandle, GeometricQueryType},
 };
 use specs::shred::PanicHandler;
-use specs::{Component, Entities, Entity, Read, VecStorage, Write, WriteStorage};
+use specs::{
+    Component, Entities, Entity, Join, NullStorage, Read, ReadStorage, VecStorage, Write,
+    WriteStorage,
+};
 use specs_derive::Component;
+use winit::VirtualKeyCode;
+
+use crate::event::EventChannel;
+use crate::render::{Fade, Material};
+use crate::state::{
+    Actions, Camera, CameraFollow, Cursor, Focused, InputState, Player, Time, WorldScale,
+    ZOOM_PRESETS,
+};
+use crate::tilemap::Tilemap;
 
-use crate::state::Cursor;
+/// Seconds over which a newly spawned ball fades in
+const SPAWN_FADE_DURATION: f32 = 0.25;
+/// Seconds over which the camera glides to a newly selected zoom preset or fitted view
+const CAMERA_TRANSITION_DURATION: f32 = 0.3;
+/// Seconds a `GridMove` takes to glide between cells by default
+const GRID_MOVE_DURATION: f32 = 0.15;
+/// Depth to which a `GridMove` may overlap what's in the cell it moves into, as a fraction of the
+/// cell size, so that bodies in neighboring cells can touch
+const GRID_MOVE_TOLERANCE: f32 = 0.01;
 
 pub type CollisionWorld = ncollide2d::world::CollisionWorld<f32, Option<Entity>>;
 
+/// Query type reporting contacts only between colliders that actually touch
+pub const TOUCH_CONTACTS: GeometricQueryType<f32> = GeometricQueryType::Contacts(0.0, 0.0);
+
+/// The collision objects making up an entity's body
+///
+/// The root's position is the entity's `Transform`. Any further parts keep a fixed offset from the
+/// root, allowing compound bodies such as a character with a separate weapon hitbox.
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Collider {
+    pub root: CollisionObjectHandle,
+    /// Additional collision objects, each with its position relative to the root
+    pub parts: Vec<(CollisionObjectHandle, na::Isometry2<f32>)>,
+    /// Groups to restore once re-enabled, if disabled by `set_enabled`
+    disabled: Option<CollisionGroups>,
+}
+
+impl Collider {
+    pub fn new(root: CollisionObjectHandle) -> Self {
+        Self {
+            root,
+            parts: Vec::new(),
+            disabled: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.disabled.is_none()
+    }
+
+    /// Stop or resume interacting with other colliders, without removing the body
+    ///
+    /// A disabled body keeps its position and continues to be rendered, but generates no contacts,
+    /// isn't found by queries, and isn't blocked when moving.
+    pub fn set_enabled(&mut self, collision: &mut CollisionWorld, enabled: bool) {
+        if enabled == self.is_enabled() {
+            return;
+        }
+        let groups = if enabled {
+            self.disabled.take().unwrap()
+        } else {
+            let root = collision
+                .collision_object(self.root)
+                .expect("collider lifetime desync");
+            self.disabled = Some(*root.collision_groups());
+            CollisionGroups::new()
+                .with_membership(&[])
+                .with_whitelist(&[])
+        };
+        for handle in self.handles() {
+            collision.set_collision_groups(handle, groups);
+        }
+    }
+
+    /// Every collision object in the body, starting with the root
+    pub fn handles(&self) -> impl Iterator<Item = CollisionObjectHandle> + '_ {
+        iter::once(self.root).chain(self.parts.iter().map(|&(handle, _)| handle))
+    }
+
+    /// Move the whole body so that its root is at `position`
+    pub fn set_position(&self, collision: &mut CollisionWorld, position: na::Isometry2<f32>) {
+        collision.set_position(self.root, position);
+        for &(handle, offset) in &self.parts {
+            collision.set_position(handle, position * offset);
+        }
+    }
+}
+
+/// An entity's `Transform` as of the start of the latest step, for interpolation
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct PreviousPosition(pub na::Isometry2<f32>);
+
+/// Position of an entity, from which it's rendered
+///
+/// For entities with a `Collider`, this is a copy of the collision object's position as of the end
+/// of the latest step.
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Transform(pub na::Isometry2<f32>);
+
+/// Linear velocity of an entity's collider, in world units per second
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Velocity(pub na::Vector2<f32>);
+
+/// Angular velocity of an entity's collider, in radians per second counterclockwise
 #[derive(Component)]
 #[storage(VecStorage)]
-pub struct Collider(pub CollisionObjectHandle);
+pub struct AngularVelocity(pub f32);
+
+/// Counts down simulated time, tagging the entity `Expired` on reaching zero
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Timer {
+    /// Seconds left
+    pub remaining: f32,
+}
+
+/// Periodically spawns a ball at the entity's `Transform`
+#[derive(Component, Debug, Copy, Clone)]
+#[storage(VecStorage)]
+pub struct Spawner {
+    /// Seconds between spawns; spawners with any other than a positive interval never spawn
+    pub interval: f32,
+    /// Seconds since the latest spawn
+    pub last_spawn: f32,
+    pub template: SpawnTemplate,
+}
+
+/// Description of the balls produced by a `Spawner`
+#[derive(Debug, Copy, Clone)]
+pub struct SpawnTemplate {
+    pub layer: Layer,
+    pub radius: f32,
+    /// Initial `Velocity`, if any
+    pub velocity: Option<na::Vector2<f32>>,
+    /// Seconds until the ball expires, if ever
+    pub lifetime: Option<f32>,
+    /// Whether the ball is marked `DespawnOnContact`
+    pub despawn_on_contact: bool,
+}
+
+/// Marks an entity to be despawned at the end of the current step
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Expired;
+
+/// Marks an entity to be despawned in the step its collider first touches another entity's
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct DespawnOnContact;
+
+/// The entity that owns each collision object
+///
+/// Every collision object has an owner, so this also counts them for the `SpawnLimit`.
+#[derive(Default)]
+pub struct ColliderOwners(pub HashMap<CollisionObjectHandle, Entity>);
+
+impl ColliderOwners {
+    pub fn get(&self, handle: CollisionObjectHandle) -> Entity {
+        *self.0.get(&handle).expect("collider lifetime desync")
+    }
+}
+
+/// A projectile, which never touches the entity that fired it
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Fired {
+    pub by: Entity,
+}
+
+/// Decides whether two entities' colliders may touch; see `register_pair_filter`
+pub type PairFilter = Arc<dyn Fn(Entity, Entity) -> bool + Send + Sync>;
+
+/// Every registered `PairFilter`, by name
+#[derive(Default)]
+pub struct PairFilters(Vec<(&'static str, PairFilter)>);
+
+impl PairFilters {
+    /// Whether every filter allows `a` and `b` to touch
+    pub fn accepts(&self, a: Entity, b: Entity) -> bool {
+        self.0.iter().all(|(_, filter)| filter(a, b))
+    }
+}
+
+/// Applies a `PairFilter` to collision objects that belong to entities
+struct EntityPairFilter(PairFilter);
+
+impl BroadPhasePairFilter<f32, Option<Entity>> for EntityPairFilter {
+    fn is_pair_valid(
+        &self,
+        a: &CollisionObject<f32, Option<Entity>>,
+        b: &CollisionObject<f32, Option<Entity>>,
+    ) -> bool {
+        match (*a.data(), *b.data()) {
+            (Some(a), Some(b)) => (self.0)(a, b),
+            _ => true,
+        }
+    }
+}
+
+/// The entity that fired each projectile, mirrored from `Fired` for the pair filter enforcing it
+#[derive(Default)]
+pub struct Shooters(Arc<RwLock<HashMap<Entity, Entity>>>);
+
+/// Work done by the latest `UpdateCollision`, for profiling
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CollisionStats {
+    pub objects: u32,
+    /// Pairs of objects found close enough by the broad phase to be tested by the narrow phase
+    pub pairs: u32,
+    /// Pairs found touching, or within each other's margins
+    pub touching: u32,
+    /// Contact points between touching pairs
+    pub contacts: u32,
+}
+
+impl fmt::Display for CollisionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} collision objects, {} pairs tested, {} touching, {} contacts",
+            self.objects, self.pairs, self.touching, self.contacts
+        )
+    }
+}
+
+/// Tuning for the collision world's broad phase, a bounding volume tree
+#[derive(Debug, Copy, Clone)]
+pub struct BroadPhaseConfig {
+    /// Distance by which each object's bounding box is loosened, as a multiple of
+    /// `WorldScale::collision_margin`
+    ///
+    /// A larger margin lets objects move further before the tree must be updated, at the cost of
+    /// more candidate pairs for the narrow phase to reject. Dense fields of slow-moving objects
+    /// favor small margins, and sparse, fast-moving ones large margins.
+    pub margin: f32,
+}
+
+impl Default for BroadPhaseConfig {
+    fn default() -> Self {
+        Self { margin: 1.0 }
+    }
+}
+
+/// How colliders spawned during play detect interactions, in world units, defaulting to
+/// `TOUCH_CONTACTS`
+///
+/// Used for balls spawned by `Input`, `Spawners`, the console, and `State::spawn_player`, and for
+/// prefabs. See `spawn_ball` for the effect of each query type.
+#[derive(Debug, Copy, Clone)]
+pub struct SpawnQuery(pub GeometricQueryType<f32>);
+
+impl Default for SpawnQuery {
+    fn default() -> Self {
+        SpawnQuery(TOUCH_CONTACTS)
+    }
+}
+
+/// Maximum number of colliders that may exist at once
+#[derive(Debug, Copy, Clone)]
+pub struct SpawnLimit(pub usize);
+
+impl Default for SpawnLimit {
+    fn default() -> Self {
+        SpawnLimit(10_000)
+    }
+}
 
 pub fn setup(world: &mut specs::World) {
-    let collision = CollisionWorld::new(0.01);
+    world.add_resource(BroadPhaseConfig::default());
+    let collision = new_collision_world(world);
     world.add_resource(collision);
+    world.add_resource(SpawnLimit::default());
+    world.add_resource(SpawnQuery::default());
+    world.add_resource(CollisionStats::default());
+    world.add_resource(ColliderOwners::default());
+    world.add_resource(PairFilters::default());
+    let shooters = Shooters::default();
+    let fired = shooters.0.clone();
+    world.add_resource(shooters);
+    register_pair_filter(world, "shooter", move |a, b| {
+        let fired = fired.read().unwrap();
+        fired.get(&a) != Some(&b) && fired.get(&b) != Some(&a)
+    });
     world.register::<Collider>();
+    world.register::<Fired>();
+    world.register::<PreviousPosition>();
+    world.register::<Velocity>();
+    world.register::<AngularVelocity>();
+    world.register::<Transform>();
+    world.register::<Timer>();
+    world.register::<Spawner>();
+    world.register::<Expired>();
+    world.register::<DespawnOnContact>();
+    world.register::<GridMove>();
+}
+
+/// Discard every collision object, leaving an empty collision world for the current `WorldScale`
+pub fn reset(world: &mut specs::World) {
+    let mut collision = new_collision_world(world);
+    apply_pair_filters(&mut collision, &world.read_resource::<PairFilters>());
+    *world.write_resource::<CollisionWorld>() = collision;
+    *world.write_resource::<CollisionStats>() = CollisionStats::default();
+    world.write_resource::<ColliderOwners>().0.clear();
+}
+
+/// Prevent contacts between the colliders of any two entities for which `filter` returns false
+///
+/// Replaces any filter previously registered under `name`. Filters survive `reset` and `rescale`.
+pub fn register_pair_filter(
+    world: &mut specs::World,
+    name: &'static str,
+    filter: impl Fn(Entity, Entity) -> bool + Send + Sync + 'static,
+) {
+    let filter: PairFilter = Arc::new(filter);
+    let mut filters = world.write_resource::<PairFilters>();
+    filters.0.retain(|&(x, _)| x != name);
+    filters.0.push((name, filter.clone()));
+    world
+        .write_resource::<CollisionWorld>()
+        .register_broad_phase_pair_filter(name, EntityPairFilter(filter));
+}
+
+fn apply_pair_filters(collision: &mut CollisionWorld, filters: &PairFilters) {
+    for (name, filter) in &filters.0 {
+        collision.register_broad_phase_pair_filter(name, EntityPairFilter(filter.clone()));
+    }
+}
+
+/// An empty collision world for the current `WorldScale` and `BroadPhaseConfig`
+fn new_collision_world(world: &specs::World) -> CollisionWorld {
+    let margin = world.read_resource::<WorldScale>().collision_margin()
+        * world.read_resource::<BroadPhaseConfig>().margin;
+    CollisionWorld::new(margin)
+}
+
+/// Rebuild the collision world with the broad phase described by `config`, keeping every object
+pub fn set_broad_phase(world: &mut specs::World, config: BroadPhaseConfig) {
+    *world.write_resource::<BroadPhaseConfig>() = config;
+    rescale(world, 1.0);
+}
+
+/// Rebuild the collision world for the current `WorldScale`, scaling existing objects, their
+/// velocities, and spawner templates by `ratio`
+pub fn rescale(world: &mut specs::World, ratio: f32) {
+    let mut rescaled = new_collision_world(world);
+    let mut collision = world.write_resource::<CollisionWorld>();
+    let mut colliders = world.write_storage::<Collider>();
+    let mut owners = world.write_resource::<ColliderOwners>();
+    apply_pair_filters(&mut rescaled, &world.read_resource::<PairFilters>());
+    let mut rescaled_owners = HashMap::new();
+    let mut handles = HashMap::new();
+    for obj in collision.collision_objects() {
+        let mut position = *obj.position();
+        position.translation.vector *= ratio;
+        let shape = rescale_shape(obj.shape(), ratio);
+        let handle = rescaled
+            .add(
+                position,
+                shape,
+                *obj.collision_groups(),
+                rescale_query(obj.query_type(), ratio),
+                *obj.data(),
+            )
+            .handle();
+        handles.insert(obj.handle(), handle);
+        if let Some(&entity) = owners.0.get(&obj.handle()) {
+            rescaled_owners.insert(handle, entity);
+        }
+    }
+    *collision = rescaled;
+    owners.0 = rescaled_owners;
+    let rescaled_handle = |handle| *handles.get(&handle).expect("collider lifetime desync");
+    for collider in (&mut colliders).join() {
+        collider.root = rescaled_handle(collider.root);
+        for (handle, offset) in &mut collider.parts {
+            *handle = rescaled_handle(*handle);
+            offset.translation.vector *= ratio;
+        }
+    }
+    for velocity in (&mut world.write_storage::<Velocity>()).join() {
+        velocity.0 *= ratio;
+    }
+    for spawner in (&mut world.write_storage::<Spawner>()).join() {
+        spawner.template.radius *= ratio;
+        if let Some(ref mut velocity) = spawner.template.velocity {
+            *velocity *= ratio;
+        }
+    }
+    for previous in (&mut world.write_storage::<PreviousPosition>()).join() {
+        previous.0.translation.vector *= ratio;
+    }
+    for transform in (&mut world.write_storage::<Transform>()).join() {
+        transform.0.translation.vector *= ratio;
+    }
+    let mut spawn_query = world.write_resource::<SpawnQuery>();
+    spawn_query.0 = rescale_query(spawn_query.0, ratio);
+}
+
+/// Scale `shape` by `ratio` about its local origin
+///
+/// Panics on shapes the game never builds, rather than leaving them at the old scale.
+fn rescale_shape(shape: &shape::ShapeHandle<f32>, ratio: f32) -> shape::ShapeHandle<f32> {
+    if let Some(ball) = shape.as_shape::<shape::Ball<f32>>() {
+        return shape::ShapeHandle::new(shape::Ball::new(ball.radius() * ratio));
+    }
+    if let Some(cuboid) = shape.as_shape::<shape::Cuboid<f32>>() {
+        return shape::ShapeHandle::new(shape::Cuboid::new(cuboid.half_extents() * ratio));
+    }
+    if let Some(polygon) = shape.as_shape::<shape::ConvexPolygon<f32>>() {
+        let points = polygon
+            .points()
+            .iter()
+            .map(|p| p * ratio)
+            .collect::<Vec<_>>();
+        return shape::ShapeHandle::new(
+            shape::ConvexPolygon::try_from_points(&points).expect("rescaled polygon degenerate"),
+        );
+    }
+    panic!("can't rescale collision shape");
+}
+
+/// Scale the distances of `query` by `ratio`
+fn rescale_query(query: GeometricQueryType<f32>, ratio: f32) -> GeometricQueryType<f32> {
+    match query {
+        GeometricQueryType::Contacts(linear, angular) => {
+            GeometricQueryType::Contacts(linear * ratio, angular)
+        }
+        GeometricQueryType::Proximity(linear) => GeometricQueryType::Proximity(linear * ratio),
+    }
+}
+
+bitflags! {
+    /// Categories of collider, determining which interact with each other
+    pub struct Layer: u32 {
+        const TERRAIN = 1 << 0;
+        const ACTOR = 1 << 1;
+        const PROJECTILE = 1 << 2;
+        /// Detects actors entering it without blocking them
+        const TRIGGER = 1 << 3;
+    }
+}
+
+impl Layer {
+    /// Layers that colliders on every layer in `self` interact with
+    pub fn interacts_with(self) -> Layer {
+        let mut result = Layer::empty();
+        if self.contains(Layer::TERRAIN) {
+            result |= Layer::ACTOR | Layer::PROJECTILE;
+        }
+        if self.contains(Layer::ACTOR) {
+            result |= Layer::all();
+        }
+        if self.contains(Layer::PROJECTILE) {
+            result |= Layer::TERRAIN | Layer::ACTOR;
+        }
+        if self.contains(Layer::TRIGGER) {
+            result |= Layer::ACTOR;
+        }
+        result
+    }
+
+    /// Groups for a collider on `self`, interacting as described by `interacts_with`
+    pub fn groups(self) -> CollisionGroups {
+        CollisionGroups::new()
+            .with_membership(&self.indices())
+            .with_whitelist(&self.interacts_with().indices())
+    }
+
+    /// Groups for a query that finds colliders on any of `self`
+    pub fn query_groups(self) -> CollisionGroups {
+        CollisionGroups::new().with_whitelist(&self.indices())
+    }
+
+    /// Collision group index of each layer in `self`
+    fn indices(self) -> Vec<usize> {
+        (0..32).filter(|i| self.bits() & (1 << i) != 0).collect()
+    }
+}
+
+/// Every collision object that belongs to an entity, with that entity and its position
+pub fn iter_colliders(
+    collision: &CollisionWorld,
+) -> impl Iterator<Item = (Entity, na::Isometry2<f32>)> + '_ {
+    collision
+        .collision_objects()
+        .filter_map(|obj| Some(((*obj.data())?, *obj.position())))
+}
+
+/// The bounding box the broad phase tracks for each collision object, loosened by its query limit
+pub fn broad_phase_aabbs(collision: &CollisionWorld) -> impl Iterator<Item = AABB<f32>> + '_ {
+    collision.collision_objects().map(|obj| {
+        obj.shape()
+            .aabb(obj.position())
+            .loosened(obj.query_type().query_limit())
+    })
+}
+
+/// Smallest box containing every collision object, aligned to the axes of the space `to_local`
+/// maps world space into
+///
+/// Returns `None` if there are no collision objects.
+pub fn collider_bounds(
+    collision: &CollisionWorld,
+    to_local: &na::Isometry2<f32>,
+) -> Option<AABB<f32>> {
+    collision
+        .collision_objects()
+        .map(|obj| obj.shape().aabb(&(to_local * obj.position())))
+        .fold(None, |bounds, aabb| match bounds {
+            Some(bounds) => Some(aabb.merged(&bounds)),
+            None => Some(aabb),
+        })
+}
+
+/// Invoke `f` on each entity whose collider is on one of `layers` and may intersect `aabb`
+///
+/// Only bounding volumes are tested, so colliders near but outside `aabb` may be included. Useful
+/// for area effects, which can apply a precise test of their own. Entities with compound bodies
+/// are visited once for each part that qualifies.
+pub fn for_each_in_layers(
+    collision: &CollisionWorld,
+    aabb: &AABB<f32>,
+    layers: Layer,
+    mut f: impl FnMut(Entity),
+) {
+    for obj in collision.interferences_with_aabb(aabb, &layers.query_groups()) {
+        if let Some(entity) = *obj.data() {
+            f(entity);
+        }
+    }
+}
+
+/// Bring the broad phase up to date with colliders spawned, moved, or despawned since the last step
+///
+/// Spatial queries such as `nearest` and `sweep` only see colliders the broad phase knows about.
+/// Steps do this themselves, so it's only needed to query changes made between steps.
+pub fn refresh_queries(collision: &mut CollisionWorld) {
+    collision.perform_broad_phase();
+}
+
+/// Find the collider on one of `layers` nearest to `point` within `radius`, and its distance
+///
+/// Colliders changed since the last step are only seen after `refresh_queries`.
+pub fn nearest(
+    collision: &CollisionWorld,
+    point: &na::Point2<f32>,
+    radius: f32,
+    layers: Layer,
+) -> Option<(Entity, f32)> {
+    let aabb = AABB::from_half_extents(*point, na::Vector2::repeat(radius));
+    collision
+        .interferences_with_aabb(&aabb, &layers.query_groups())
+        .filter_map(|obj| {
+            let entity = (*obj.data())?;
+            let query = obj.shape().as_point_query()?;
+            let distance = query.distance_to_point(obj.position(), point, true);
+            if distance <= radius {
+                Some((entity, distance))
+            } else {
+                None
+            }
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN"))
+}
+
+/// Entities with a collider on one of `layers` within `half_width` of the polyline `path`, e.g. one
+/// planned by `NavMesh::plan`
+///
+/// Entities are listed once each, in the order they're first found along the path. A path of a
+/// single point finds the entities within `half_width` of it.
+pub fn along_path(
+    collision: &CollisionWorld,
+    path: &[na::Point2<f32>],
+    half_width: f32,
+    layers: Layer,
+) -> Vec<Entity> {
+    let segments = path.windows(2).map(|x| (x[0], x[1])).chain(match *path {
+        [p] => Some((p, p)),
+        _ => None,
+    });
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for (a, b) in segments {
+        let segment = shape::Segment::new(a, b);
+        let aabb = shape::Shape::aabb(&segment, &na::Isometry2::identity()).loosened(half_width);
+        for obj in collision.interferences_with_aabb(&aabb, &layers.query_groups()) {
+            let entity = match *obj.data() {
+                Some(entity) => entity,
+                None => continue,
+            };
+            if seen.contains(&entity) {
+                continue;
+            }
+            let distance = query::distance(
+                &na::Isometry2::identity(),
+                &segment,
+                obj.position(),
+                &**obj.shape(),
+            );
+            if distance <= half_width {
+                seen.insert(entity);
+                result.push(entity);
+            }
+        }
+    }
+    result
+}
+
+/// Find the point on the surface of the collider on one of `layers` nearest to `point`, if any
+/// lies within `radius`
+pub fn nearest_surface_point(
+    collision: &CollisionWorld,
+    point: &na::Point2<f32>,
+    radius: f32,
+    layers: Layer,
+) -> Option<na::Point2<f32>> {
+    let aabb = AABB::from_half_extents(*point, na::Vector2::repeat(radius));
+    collision
+        .interferences_with_aabb(&aabb, &layers.query_groups())
+        .filter_map(|obj| {
+            obj.data().as_ref()?;
+            let query = obj.shape().as_point_query()?;
+            let surface = query.project_point(obj.position(), point, false).point;
+            let distance = na::distance(point, &surface);
+            if distance <= radius {
+                Some((surface, distance))
+            } else {
+                None
+            }
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN"))
+        .map(|(surface, _)| surface)
+}
+
+/// Find the first collider in `groups` that `shape` would hit moving in a straight line from `from`
+/// to the translation `to`, and the fraction of the motion completed at the moment of impact
+///
+/// Colliders belonging to entities rejected by `filter` are ignored, e.g. so that a moving object
+/// doesn't collide with itself, as are proximity sensors and triggers, which never block. Colliders
+/// already touching `shape` at `from` are hit at fraction 0, unless the motion is carrying `shape`
+/// away from them.
+pub fn sweep(
+    collision: &CollisionWorld,
+    shape: &dyn shape::Shape<f32>,
+    from: &na::Isometry2<f32>,
+    to: &na::Vector2<f32>,
+    groups: &CollisionGroups,
+    filter: impl Fn(Entity) -> bool,
+) -> Option<(Entity, f32)> {
+    let motion = to - from.translation.vector;
+    let end = na::Isometry2::from_parts(na::Translation2::from(*to), from.rotation);
+    let aabb = shape.aabb(from).merged(&shape.aabb(&end));
+    collision
+        .interferences_with_aabb(&aabb, groups)
+        .filter_map(|obj| {
+            let entity = (*obj.data())?;
+            if !filter(entity) || !is_solid(obj) {
+                return None;
+            }
+            let toi = query::time_of_impact(
+                from,
+                &motion,
+                shape,
+                obj.position(),
+                &na::zero(),
+                &**obj.shape(),
+            )?;
+            if toi > 1.0 {
+                return None;
+            }
+            if toi == 0.0 {
+                // Let touching objects separate
+                let contact = query::contact(from, shape, obj.position(), &**obj.shape(), 0.0);
+                if let Some(contact) = contact {
+                    if contact.normal.dot(&motion) <= 0.0 {
+                        return None;
+                    }
+                }
+            }
+            Some((entity, toi))
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN"))
+}
+
+/// A point at which two colliders touch
+#[derive(Debug, Copy, Clone)]
+pub struct ContactPoint {
+    pub entities: (Option<Entity>, Option<Entity>),
+    /// World-space location, midway between the two surfaces
+    pub point: na::Point2<f32>,
+    /// Direction from the first collider towards the second
+    pub normal: na::Unit<na::Vector2<f32>>,
+    /// Penetration depth
+    pub depth: f32,
+}
+
+/// Every contact found by the latest `UpdateCollision`
+pub fn contacts(collision: &CollisionWorld) -> impl Iterator<Item = ContactPoint> + '_ {
+    collision
+        .contact_pairs(true)
+        .flat_map(move |(a, b, _, manifold)| {
+            let entities = (
+                collision.collision_object(a).and_then(|x| *x.data()),
+                collision.collision_object(b).and_then(|x| *x.data()),
+            );
+            manifold.contacts().map(move |tracked| {
+                let contact = &tracked.contact;
+                ContactPoint {
+                    entities,
+                    point: na::center(&contact.world1, &contact.world2),
+                    normal: contact.normal,
+                    depth: contact.depth,
+                }
+            })
+        })
+}
+
+/// Every contact between `entity` and another entity found by the latest `UpdateCollision`
+///
+/// Each contact is oriented so that `entities.0` is `entity` and `normal` points away from it,
+/// paired with the entity touched.
+pub fn contacts_with(collision: &CollisionWorld, entity: Entity) -> Vec<(Entity, ContactPoint)> {
+    contacts(collision)
+        .filter_map(|contact| match contact.entities {
+            (Some(a), Some(b)) if a == entity && b != entity => Some((b, contact)),
+            (Some(a), Some(b)) if b == entity && a != entity => Some((
+                a,
+                ContactPoint {
+                    entities: (Some(b), Some(a)),
+                    normal: -contact.normal,
+                    ..contact
+                },
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Create a ball-shaped collider entity on `layer` at `position`
+///
+/// Interactions are detected according to `query`. `Contacts(prediction, angular_prediction)`
+/// reports contacts once the ball comes within `prediction` of another collider, which benefits
+/// fast-moving objects; `TOUCH_CONTACTS` waits for actual contact. `Proximity(margin)` only
+/// detects overlap, as for a sensor, still publishing `CollisionEvent`s but computing no contacts.
+///
+/// Returns `None` without spawning anything if `limit` colliders already exist.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_ball(
+    entities: &Entities,
+    collision: &mut CollisionWorld,
+    owners: &mut ColliderOwners,
+    colliders: &mut WriteStorage<Collider>,
+    limit: SpawnLimit,
+    layer: Layer,
+    position: na::Vector2<f32>,
+    radius: f32,
+    query: GeometricQueryType<f32>,
+) -> Option<Entity> {
+    spawn_shape(
+        entities,
+        collision,
+        owners,
+        colliders,
+        limit,
+        layer,
+        na::convert(na::Translation2::from(position)),
+        shape::ShapeHandle::new(shape::Ball::new(radius)),
+        query,
+    )
+}
+
+/// Create a collider entity on `layer` whose body is `shape` at `position`
+///
+/// Detects interactions and respects `limit` as described for `spawn_ball`.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_shape(
+    entities: &Entities,
+    collision: &mut CollisionWorld,
+    owners: &mut ColliderOwners,
+    colliders: &mut WriteStorage<Collider>,
+    limit: SpawnLimit,
+    layer: Layer,
+    position: na::Isometry2<f32>,
+    shape: shape::ShapeHandle<f32>,
+    query: GeometricQueryType<f32>,
+) -> Option<Entity> {
+    if owners.0.len() >= limit.0 {
+        warn!("spawn limit of {} colliders reached", limit.0);
+        return None;
+    }
+    let entity = entities.create();
+    let obj = collision.add(position, shape, layer.groups(), query, Some(entity));
+    owners.0.insert(obj.handle(), entity);
+    colliders
+        .insert(entity, Collider::new(obj.handle()))
+        .unwrap();
+    debug!("spawned {:?} at {}", entity, position.translation.vector);
+    Some(entity)
+}
+
+/// Add `shape` to the body of `entity`, placed at `offset` relative to its root
+///
+/// The new part shares the root's collision groups, and detects interactions according to `query`
+/// as described for `spawn_ball`. Returns `None` without adding anything if `entity` has no `Collider` or `limit`
+/// colliders already exist.
+#[allow(clippy::too_many_arguments)]
+pub fn attach_shape(
+    collision: &mut CollisionWorld,
+    owners: &mut ColliderOwners,
+    colliders: &mut WriteStorage<Collider>,
+    limit: SpawnLimit,
+    entity: Entity,
+    shape: shape::ShapeHandle<f32>,
+    offset: na::Isometry2<f32>,
+    query: GeometricQueryType<f32>,
+) -> Option<CollisionObjectHandle> {
+    let collider = colliders.get_mut(entity)?;
+    if owners.0.len() >= limit.0 {
+        warn!("spawn limit of {} colliders reached", limit.0);
+        return None;
+    }
+    let (position, groups) = {
+        let root = collision
+            .collision_object(collider.root)
+            .expect("collider lifetime desync");
+        (*root.position(), *root.collision_groups())
+    };
+    let handle = collision
+        .add(position * offset, shape, groups, query, Some(entity))
+        .handle();
+    owners.0.insert(handle, entity);
+    collider.parts.push((handle, offset));
+    Some(handle)
+}
+
+/// Destroy `entity` along with its collision objects, if any
+pub fn despawn(
+    entities: &Entities,
+    collision: &mut CollisionWorld,
+    owners: &mut ColliderOwners,
+    colliders: &WriteStorage<Collider>,
+    entity: Entity,
+) {
+    if let Some(collider) = colliders.get(entity) {
+        let handles = collider.handles().collect::<Vec<_>>();
+        collision.remove(&handles);
+        for handle in handles {
+            owners.0.remove(&handle);
+        }
+    }
+    entities.delete(entity).unwrap();
+}
+
+/// Record transforms before they're changed by a step
+pub struct SavePositions;
+
+impl<'a> specs::System<'a> for SavePositions {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Transform>,
+        WriteStorage<'a, PreviousPosition>,
+    );
+
+    fn run(&mut self, (entities, transforms, mut previous): Self::SystemData) {
+        for (entity, transform) in (&entities, &transforms).join() {
+            previous
+                .insert(entity, PreviousPosition(transform.0))
+                .unwrap();
+        }
+    }
+}
+
+/// Copy root collision object positions into the `Transform`s of the entities they belong to
+pub struct SyncTransforms;
+
+impl<'a> specs::System<'a> for SyncTransforms {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, CollisionWorld, PanicHandler>,
+        ReadStorage<'a, Collider>,
+        WriteStorage<'a, Transform>,
+    );
+
+    fn run(&mut self, (entities, collision, colliders, mut transforms): Self::SystemData) {
+        for (entity, collider) in (&entities, &colliders).join() {
+            let position = *collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            transforms.insert(entity, Transform(position)).unwrap();
+        }
+    }
+}
+
+/// Two entities' colliders starting or ceasing to touch
+///
+/// Colliders with a contact prediction margin touch once within it, and proximity sensors once they
+/// overlap.
+#[derive(Debug, Copy, Clone)]
+pub enum CollisionEvent {
+    Started(Entity, Entity),
+    Stopped(Entity, Entity),
+}
+
+/// Copy each `Fired` component into `Shooters`, so new projectiles pass through their shooter
+pub struct SyncShooters;
+
+impl<'a> specs::System<'a> for SyncShooters {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Shooters, PanicHandler>,
+        ReadStorage<'a, Fired>,
+    );
+
+    fn run(&mut self, (entities, shooters, fired): Self::SystemData) {
+        let mut shooters = shooters.0.write().unwrap();
+        shooters.clear();
+        shooters.extend((&entities, &fired).join().map(|(entity, x)| (entity, x.by)));
+    }
+}
+
+/// Update the collision world's broad and narrow phases, refreshing contacts and publishing a
+/// `CollisionEvent` for each change
+///
+/// Contacts between parts of the same entity's body are ignored.
+pub struct UpdateCollision;
+
+impl<'a> specs::System<'a> for UpdateCollision {
+    type SystemData = (
+        Write<'a, CollisionWorld, PanicHandler>,
+        Write<'a, EventChannel<CollisionEvent>, PanicHandler>,
+        Write<'a, CollisionStats, PanicHandler>,
+    );
+
+    fn run(&mut self, (mut collision, mut events, mut stats): Self::SystemData) {
+        // Events from earlier broad phase passes, e.g. `Movement`'s, haven't been published yet
+        collision.perform_broad_phase();
+        collision.perform_narrow_phase();
+        *stats = CollisionStats {
+            objects: collision.collision_objects().count() as u32,
+            pairs: collision.interaction_pairs(false).count() as u32,
+            touching: collision.interaction_pairs(true).count() as u32,
+            contacts: collision
+                .contact_pairs(true)
+                .map(|(_, _, _, manifold)| manifold.contacts().count() as u32)
+                .sum(),
+        };
+        let entity = |handle| collision.collision_object(handle).and_then(|x| *x.data());
+        for event in collision.contact_events().iter() {
+            let event = match *event {
+                ContactEvent::Started(a, b) => match (entity(a), entity(b)) {
+                    (Some(a), Some(b)) if a != b => CollisionEvent::Started(a, b),
+                    _ => continue,
+                },
+                ContactEvent::Stopped(a, b) => match (entity(a), entity(b)) {
+                    (Some(a), Some(b)) if a != b => CollisionEvent::Stopped(a, b),
+                    _ => continue,
+                },
+            };
+            events.publish(event);
+        }
+        for event in collision.proximity_events().iter() {
+            let (a, b) = match (entity(event.collider1), entity(event.collider2)) {
+                (Some(a), Some(b)) if a != b => (a, b),
+                _ => continue,
+            };
+            let event = match (event.prev_status, event.new_status) {
+                (Proximity::Intersecting, _) => CollisionEvent::Stopped(a, b),
+                (_, Proximity::Intersecting) => CollisionEvent::Started(a, b),
+                _ => continue,
+            };
+            events.publish(event);
+        }
+        collision.clear_events();
+    }
+}
+
+/// Tag each entity marked `DespawnOnContact` that began touching another this step `Expired`
+pub struct ExpireOnContact;
+
+impl<'a> specs::System<'a> for ExpireOnContact {
+    type SystemData = (
+        Read<'a, EventChannel<CollisionEvent>, PanicHandler>,
+        ReadStorage<'a, DespawnOnContact>,
+        WriteStorage<'a, Expired>,
+    );
+
+    fn run(&mut self, (events, despawn, mut expired): Self::SystemData) {
+        for event in events.iter() {
+            if let CollisionEvent::Started(a, b) = *event {
+                for &entity in &[a, b] {
+                    if despawn.contains(entity) {
+                        expired.insert(entity, Expired).unwrap();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Log each `CollisionEvent` published this step
+pub struct LogCollisions;
+
+impl<'a> specs::System<'a> for LogCollisions {
+    type SystemData = Read<'a, EventChannel<CollisionEvent>, PanicHandler>;
+
+    fn run(&mut self, events: Self::SystemData) {
+        for event in events.iter() {
+            debug!("{:?}", event);
+        }
+    }
+}
+
+/// Whether `obj` stops bodies moving into it, rather than only detecting them
+fn is_solid(obj: &CollisionObject<f32, Option<Entity>>) -> bool {
+    let trigger = Layer::TRIGGER.bits().trailing_zeros() as usize;
+    !obj.query_type().is_proximity_query() && !obj.collision_groups().is_member_of(trigger)
+}
+
+/// Fraction of `motion` that `collider`'s body can move before any part of it hits something
+///
+/// Parts that are sensors or triggers pass through everything.
+fn sweep_body(
+    collision: &CollisionWorld,
+    filters: &PairFilters,
+    entity: Entity,
+    collider: &Collider,
+    motion: &na::Vector2<f32>,
+) -> f32 {
+    let mut fraction = 1.0;
+    for handle in collider.handles() {
+        let obj = collision
+            .collision_object(handle)
+            .expect("collider lifetime desync");
+        if !is_solid(obj) {
+            continue;
+        }
+        let from = obj.position();
+        let to = from.translation.vector + motion;
+        let groups = obj.collision_groups();
+        let filter = |other| other != entity && filters.accepts(entity, other);
+        if let Some((_, x)) = sweep(collision, &**obj.shape(), from, &to, groups, filter) {
+            fraction = x.min(fraction);
+        }
+    }
+    fraction
+}
+
+/// Whether `collider`'s body, displaced by `motion`, would overlap anything by more than
+/// `tolerance`
+///
+/// Unlike with `sweep_body`, bodies merely touching don't count, so they can always move apart.
+fn body_overlaps(
+    collision: &CollisionWorld,
+    filters: &PairFilters,
+    entity: Entity,
+    collider: &Collider,
+    motion: &na::Vector2<f32>,
+    tolerance: f32,
+) -> bool {
+    collider.handles().any(|handle| {
+        let obj = collision
+            .collision_object(handle)
+            .expect("collider lifetime desync");
+        let mut position = *obj.position();
+        position.translation.vector += motion;
+        let aabb = obj.shape().aabb(&position);
+        collision
+            .interferences_with_aabb(&aabb, obj.collision_groups())
+            .any(|other| {
+                match *other.data() {
+                    Some(x) if x != entity && filters.accepts(entity, x) && is_solid(other) => {}
+                    _ => return false,
+                }
+                match query::contact(
+                    &position,
+                    &**obj.shape(),
+                    other.position(),
+                    &**other.shape(),
+                    0.0,
+                ) {
+                    Some(contact) => contact.depth > tolerance,
+                    None => false,
+                }
+            })
+    })
+}
+
+/// Keys that move the `Player` one tile in each direction
+const MOVE_KEYS: [(VirtualKeyCode, [f32; 2]); 4] = [
+    (VirtualKeyCode::Up, [0.0, 1.0]),
+    (VirtualKeyCode::Down, [0.0, -1.0]),
+    (VirtualKeyCode::Left, [-1.0, 0.0]),
+    (VirtualKeyCode::Right, [1.0, 0.0]),
+];
+
+/// Direction the player chose to move in since the previous step, if any
+pub fn player_move(keys: &InputState) -> Option<na::Vector2<f32>> {
+    MOVE_KEYS
+        .iter()
+        .find(|&&(key, _)| keys.just_pressed(key))
+        .map(|&(_, [x, y])| na::Vector2::new(x, y))
+}
+
+/// Confines an entity to moving a whole cell of the `Tilemap`'s grid at a time, gliding from the
+/// center of one cell to the next over `duration` seconds
+///
+/// Set `direction` to request a move to the neighboring cell lying most nearly that way. Moves
+/// that the entity's collider can't make unobstructed are rejected outright.
+#[derive(Component, Debug, Copy, Clone)]
+#[storage(VecStorage)]
+pub struct GridMove {
+    pub duration: f32,
+    /// Direction of the next move, taken up once any move in progress finishes
+    pub direction: Option<na::Vector2<f32>>,
+    /// Start and end of the move in progress, and seconds since it began
+    motion: Option<(na::Vector2<f32>, na::Vector2<f32>, f32)>,
+}
+
+impl GridMove {
+    pub fn new(duration: f32) -> Self {
+        Self {
+            duration,
+            direction: None,
+            motion: None,
+        }
+    }
+
+    /// Whether the entity is between cells
+    pub fn is_moving(&self) -> bool {
+        self.motion.is_some()
+    }
+}
+
+impl Default for GridMove {
+    fn default() -> Self {
+        Self::new(GRID_MOVE_DURATION)
+    }
+}
+
+/// Carry out `GridMove`s, taking up requested moves and animating those in progress
+pub struct GridMoves;
+
+impl<'a> specs::System<'a> for GridMoves {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        Read<'a, Tilemap, PanicHandler>,
+        Write<'a, CollisionWorld, PanicHandler>,
+        Read<'a, PairFilters, PanicHandler>,
+        ReadStorage<'a, Collider>,
+        WriteStorage<'a, GridMove>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, time, tilemap, mut collision, filters, colliders, mut moves): Self::SystemData,
+    ) {
+        let grid = &tilemap.grid;
+        for (entity, collider, grid_move) in (&entities, &colliders, &mut moves).join() {
+            let mut position = *collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            if grid_move.motion.is_none() {
+                let direction = match grid_move.direction.take() {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let here = na::Point2::from(position.translation.vector);
+                let tile = grid.tile_at(&here);
+                let center = grid.center(tile);
+                let alignment = |x: &na::Point2<f32>| (x - center).dot(&direction);
+                let target = grid
+                    .neighbors(tile)
+                    .map(|x| grid.center(x))
+                    .max_by(|a, b| alignment(a).partial_cmp(&alignment(b)).expect("NaN"))
+                    .expect("grid cells always have neighbors");
+                if alignment(&target) <= 0.0 {
+                    continue;
+                }
+                let motion = target - here;
+                let tolerance = GRID_MOVE_TOLERANCE * grid.size;
+                if body_overlaps(&collision, &filters, entity, collider, &motion, tolerance) {
+                    debug!(
+                        "{:?} can't move into blocked {:?}",
+                        entity,
+                        grid.tile_at(&target)
+                    );
+                    continue;
+                }
+                grid_move.motion = Some((here.coords, target.coords, 0.0));
+            }
+            let duration = grid_move.duration;
+            let (from, to, elapsed) = grid_move.motion.as_mut().unwrap();
+            *elapsed += time.dt;
+            let t = if duration > 0.0 {
+                (*elapsed / duration).min(1.0)
+            } else {
+                1.0
+            };
+            let eased = t * t * (3.0 - 2.0 * t);
+            position.translation.vector = *from + (*to - *from) * eased;
+            collider.set_position(&mut collision, position);
+            if t >= 1.0 {
+                grid_move.motion = None;
+            }
+        }
+    }
+}
+
+/// Move the `Player` one tile in the direction given by `player_move`, stopping at the first thing
+/// it hits
+///
+/// Players with a `GridMove` are handed the direction to move through their grid instead.
+pub struct PlayerMovement;
+
+impl<'a> specs::System<'a> for PlayerMovement {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Focused, PanicHandler>,
+        Read<'a, InputState, PanicHandler>,
+        Read<'a, WorldScale, PanicHandler>,
+        Write<'a, CollisionWorld, PanicHandler>,
+        Read<'a, PairFilters, PanicHandler>,
+        ReadStorage<'a, Collider>,
+        ReadStorage<'a, Player>,
+        WriteStorage<'a, GridMove>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, focused, keys, scale, mut collision, filters, colliders, players, mut moves): Self::SystemData,
+    ) {
+        if !focused.0 {
+            return;
+        }
+        let direction = match player_move(&keys) {
+            Some(x) => x,
+            None => return,
+        };
+        for (_, grid_move) in (&players, &mut moves).join() {
+            grid_move.direction = Some(direction);
+        }
+        let motion = direction * scale.tile_size();
+        for (entity, collider, _, _) in (&entities, &colliders, &players, !&moves).join() {
+            let fraction = sweep_body(&collision, &filters, entity, collider, &motion);
+            let mut position = *collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            position.translation.vector += motion * fraction;
+            collider.set_position(&mut collision, position);
+        }
+    }
+}
+
+/// Move colliders according to their velocity, stopping them at the first thing they hit, and
+/// rotate them according to their angular velocity
+pub struct Movement;
+
+impl<'a> specs::System<'a> for Movement {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        Write<'a, CollisionWorld, PanicHandler>,
+        Read<'a, PairFilters, PanicHandler>,
+        ReadStorage<'a, Collider>,
+        WriteStorage<'a, Velocity>,
+        ReadStorage<'a, AngularVelocity>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            mut collision,
+            filters,
+            colliders,
+            mut velocities,
+            angular_velocities,
+        ): Self::SystemData,
+    ) {
+        refresh_queries(&mut collision);
+        for (entity, collider, velocity) in (&entities, &colliders, &mut velocities).join() {
+            let motion = velocity.0 * time.dt;
+            // The whole body stops as soon as any part of it hits something
+            let fraction = sweep_body(&collision, &filters, entity, collider, &motion);
+            if fraction < 1.0 {
+                velocity.0 = na::zero();
+            }
+            let mut position = *collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            position.translation.vector += motion * fraction;
+            collider.set_position(&mut collision, position);
+        }
+        for (collider, angular_velocity) in (&colliders, &angular_velocities).join() {
+            let mut position = *collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            position.rotation =
+                na::UnitComplex::new(angular_velocity.0 * time.dt) * position.rotation;
+            collider.set_position(&mut collision, position);
+        }
+    }
+}
+
+/// Advance `Timer`s, replacing those that run out with `Expired`
+pub struct Timers;
+
+impl<'a> specs::System<'a> for Timers {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        WriteStorage<'a, Timer>,
+        WriteStorage<'a, Expired>,
+    );
+
+    fn run(&mut self, (entities, time, mut timers, mut expired): Self::SystemData) {
+        let mut finished = Vec::new();
+        for (entity, timer) in (&entities, &mut timers).join() {
+            timer.remaining -= time.dt;
+            if timer.remaining <= 0.0 {
+                finished.push(entity);
+            }
+        }
+        for entity in finished {
+            timers.remove(entity);
+            expired.insert(entity, Expired).unwrap();
+        }
+    }
+}
+
+/// Spawn a ball for each `Spawner` whose interval has elapsed
+pub struct Spawners;
+
+impl<'a> specs::System<'a> for Spawners {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time, PanicHandler>,
+        Read<'a, SpawnLimit, PanicHandler>,
+        Read<'a, SpawnQuery, PanicHandler>,
+        Write<'a, CollisionWorld, PanicHandler>,
+        Write<'a, ColliderOwners, PanicHandler>,
+        WriteStorage<'a, Collider>,
+        WriteStorage<'a, Spawner>,
+        ReadStorage<'a, Transform>,
+        WriteStorage<'a, Velocity>,
+        WriteStorage<'a, Timer>,
+        WriteStorage<'a, Fade>,
+        WriteStorage<'a, Material>,
+        WriteStorage<'a, DespawnOnContact>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            limit,
+            spawn_query,
+            mut collision,
+            mut owners,
+            mut colliders,
+            mut spawners,
+            transforms,
+            mut velocities,
+            mut timers,
+            mut fades,
+            mut materials,
+            mut despawn,
+        ): Self::SystemData,
+    ) {
+        let mut due = Vec::new();
+        for (spawner, transform) in (&mut spawners, &transforms).join() {
+            // Would otherwise spawn endlessly, or for NaN never catch up
+            if spawner.interval <= 0.0 || spawner.interval.is_nan() {
+                continue;
+            }
+            spawner.last_spawn += time.dt;
+            // Catch up on every spawn that fell within this step
+            while spawner.last_spawn >= spawner.interval {
+                spawner.last_spawn -= spawner.interval;
+                due.push((spawner.template, transform.0.translation.vector));
+            }
+        }
+        for (template, position) in due {
+            let entity = match spawn_ball(
+                &entities,
+                &mut collision,
+                &mut owners,
+                &mut colliders,
+                *limit,
+                template.layer,
+                position,
+                template.radius,
+                spawn_query.0,
+            ) {
+                Some(x) => x,
+                None => break,
+            };
+            if let Some(velocity) = template.velocity {
+                velocities.insert(entity, Velocity(velocity)).unwrap();
+            }
+            if let Some(remaining) = template.lifetime {
+                timers.insert(entity, Timer { remaining }).unwrap();
+            }
+            if template.despawn_on_contact {
+                despawn.insert(entity, DespawnOnContact).unwrap();
+            }
+            fade_in(&mut fades, &mut materials, entity);
+        }
+    }
+}
+
+/// Fade a newly spawned ball in, drawing it with a material that applies the fading `Tint`
+fn fade_in(fades: &mut WriteStorage<Fade>, materials: &mut WriteStorage<Material>, entity: Entity) {
+    fades
+        .insert(entity, Fade::new(0.0, 1.0, SPAWN_FADE_DURATION))
+        .unwrap();
+    materials.insert(entity, Material::Circle).unwrap();
+}
+
+/// Despawn every entity tagged `Expired`, removing the tags
+pub struct DespawnExpired;
+
+impl<'a> specs::System<'a> for DespawnExpired {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, CollisionWorld, PanicHandler>,
+        Write<'a, ColliderOwners, PanicHandler>,
+        WriteStorage<'a, Collider>,
+        WriteStorage<'a, Expired>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut collision, mut owners, colliders, mut expired): Self::SystemData,
+    ) {
+        for (entity, _) in (&entities, &expired).join() {
+            despawn(&entities, &mut collision, &mut owners, &colliders, entity);
+        }
+        // Despawned entities linger until the world is maintained; don't despawn them twice
+        expired.clear();
+    }
+}
+
+/// Smoothly move and zoom the camera to the view requested by `Actions::camera_target`, or zoom it
+/// to the preset selected by `Actions::zoom_preset`
+#[derive(Default)]
+pub struct CameraTransition {
+    transition: Option<Transition>,
+}
+
+/// A camera animation in progress
+#[derive(Debug, Copy, Clone)]
+struct Transition {
+    /// Positions being animated between, or `None` to leave the position alone
+    position: Option<(na::Vector2<f32>, na::Vector2<f32>)>,
+    /// Zoom levels being animated between
+    zoom: (f32, f32),
+    /// Seconds since the animation began
+    elapsed: f32,
+}
+
+impl CameraTransition {
+    /// Whether the camera is still moving
+    pub fn is_animating(&self) -> bool {
+        self.transition.is_some()
+    }
+}
+
+impl<'a> specs::System<'a> for CameraTransition {
+    type SystemData = (
+        Read<'a, Time, PanicHandler>,
+        Read<'a, WorldScale, PanicHandler>,
+        Write<'a, Actions, PanicHandler>,
+        Write<'a, Camera, PanicHandler>,
+    );
+
+    fn run(&mut self, (time, scale, mut actions, mut camera): Self::SystemData) {
+        let position = camera.0.isometry.translation.vector;
+        let zoom = camera.0.scaling();
+        if let Some((target, target_zoom)) = actions.camera_target.take() {
+            self.transition = Some(Transition {
+                position: Some((position, target)),
+                zoom: (zoom, target_zoom),
+                elapsed: 0.0,
+            });
+        }
+        if let Some(&level) = actions.zoom_preset.take().and_then(|i| ZOOM_PRESETS.get(i)) {
+            self.transition = Some(Transition {
+                position: None,
+                zoom: (zoom, level * scale.camera_zoom()),
+                elapsed: 0.0,
+            });
+        }
+        let transition = match self.transition {
+            Some(ref mut x) => x,
+            None => return,
+        };
+        transition.elapsed += time.unscaled_dt;
+        let t = (transition.elapsed / CAMERA_TRANSITION_DURATION).min(1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        if let Some((from, to)) = transition.position {
+            camera.0.isometry.translation.vector = from.lerp(&to, t);
+        }
+        // Interpolate geometrically so that zooming appears to proceed at a steady rate
+        let (from, to) = transition.zoom;
+        camera.0.set_scaling(from * (to / from).powf(t));
+        if transition.elapsed >= CAMERA_TRANSITION_DURATION {
+            self.transition = None;
+        }
+    }
+}
+
+/// Scroll the camera just enough to keep the `Player` within the `CameraFollow` deadzone
+pub struct FollowPlayer;
+
+impl<'a> specs::System<'a> for FollowPlayer {
+    type SystemData = (
+        Read<'a, CameraFollow, PanicHandler>,
+        Write<'a, Camera, PanicHandler>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Transform>,
+    );
+
+    fn run(&mut self, (follow, mut camera, players, transforms): Self::SystemData) {
+        if !follow.enabled {
+            return;
+        }
+        let player = match (&players, &transforms).join().next() {
+            Some((_, transform)) => transform.0.translation.vector,
+            None => return,
+        };
+        // In pixels from the center of the view, along its axes
+        let local = camera.0.inverse() * na::Point2::from(player);
+        let excess = local.coords
+            - local
+                .coords
+                .zip_map(&follow.deadzone, |x, half| x.max(-half).min(half));
+        if excess != na::zero() {
+            let offset = camera.0 * excess;
+            camera.0.isometry.translation.vector += offset;
+        }
+    }
 }
 
 pub struct Input {
@@ -33,24 +1590,91 @@ impl Input {
 impl<'a> specs::System<'a> for Input {
     type SystemData = (
         Entities<'a>,
+        Read<'a, Focused, PanicHandler>,
         Read<'a, Cursor, PanicHandler>,
+        Read<'a, InputState, PanicHandler>,
+        Read<'a, Camera, PanicHandler>,
+        Read<'a, WorldScale, PanicHandler>,
+        Read<'a, SpawnLimit, PanicHandler>,
+        Read<'a, SpawnQuery, PanicHandler>,
         Write<'a, CollisionWorld, PanicHandler>,
+        Write<'a, ColliderOwners, PanicHandler>,
         WriteStorage<'a, Collider>,
+        WriteStorage<'a, Fade>,
+        WriteStorage<'a, Material>,
     );
 
-    fn run(&mut self, (entities, cursor, mut collision, mut colliders): Self::SystemData) {
-        if cursor.pressed && !self.was_pressed {
-            println!("{}", cursor.position);
-            let entity = entities.create();
-            let shape = shape::ShapeHandle::new(shape::Ball::new(1.0));
-            let obj = collision.add(
-                na::convert(na::Translation2::from(cursor.position)),
-                shape,
-                CollisionGroups::new(),
-                GeometricQueryType::Contacts(0.0, 0.0),
-                Some(entity),
+    fn run(
+        &mut self,
+        (
+            entities,
+            focused,
+            cursor,
+            keys,
+            camera,
+            scale,
+            limit,
+            spawn_query,
+            mut collision,
+            mut owners,
+            mut colliders,
+            mut fades,
+            mut materials,
+        ): Self::SystemData,
+    ) {
+        if !focused.0 {
+            self.was_pressed = false;
+            return;
+        }
+        let mut spawned = Vec::new();
+        // Spawn on release, so the preview drawn while held can be positioned first
+        if !cursor.pressed && self.was_pressed {
+            spawned.extend(spawn_ball(
+                &entities,
+                &mut collision,
+                &mut owners,
+                &mut colliders,
+                *limit,
+                Layer::ACTOR,
+                cursor.position,
+                scale.ball_radius(),
+                spawn_query.0,
+            ));
+        }
+        if keys.just_pressed(VirtualKeyCode::Space) {
+            let entity = spawn_ball(
+                &entities,
+                &mut collision,
+                &mut owners,
+                &mut colliders,
+                *limit,
+                Layer::ACTOR,
+                camera.0.isometry.translation.vector,
+                scale.ball_radius(),
+                spawn_query.0,
             );
-            colliders.insert(entity, Collider(obj.handle())).unwrap();
+            // Holding shift adds a smaller ball to the side, forming a compound body
+            if let Some(entity) = entity {
+                if keys.is_key_down(VirtualKeyCode::LShift)
+                    || keys.is_key_down(VirtualKeyCode::RShift)
+                {
+                    let radius = scale.ball_radius();
+                    attach_shape(
+                        &mut collision,
+                        &mut owners,
+                        &mut colliders,
+                        *limit,
+                        entity,
+                        shape::ShapeHandle::new(shape::Ball::new(0.5 * radius)),
+                        na::Isometry2::new(na::Vector2::new(1.5 * radius, 0.0), 0.0),
+                        spawn_query.0,
+                    );
+                }
+            }
+            spawned.extend(entity);
+        }
+        for entity in spawned {
+            fade_in(&mut fades, &mut materials, entity);
         }
         self.was_pressed = cursor.pressed;
     }
@@ -1,54 +1,1258 @@
-use specs::{Component, HashMapStorage, RunNow, World};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specs::{Component, Entity, HashMapStorage, Join, RunNow, World};
 use specs_derive::Component;
+use winit::VirtualKeyCode;
+
+use crate::{
+    event,
+    prefab::Prefabs,
+    profile::{FrameStats, Profiler},
+    render, sim,
+    tilemap::Tilemap,
+};
 
-use crate::sim;
+/// Seconds of simulated time covered by a single step
+pub const TICK_DURATION: f32 = 1.0 / 60.0;
+
+/// Upper bound on real time simulated by one call to `State::advance`, so a long stall doesn't
+/// trigger an unbounded burst of catch-up steps
+const MAX_ADVANCE: f32 = 0.25;
 
 // Resources
 pub struct Step(pub u64);
+/// Simulation time
+pub struct Time {
+    /// Seconds of simulated time covered by the current step, scaled by `TimeScale`
+    pub dt: f32,
+    /// Like `dt`, but ignoring `TimeScale`, for animations that should proceed regardless, such as
+    /// the camera's
+    pub unscaled_dt: f32,
+}
+/// Fraction of a step's worth of real time that has elapsed since the latest step, in [0, 1), or
+/// 1 while paused
+pub struct TickProgress(pub f32);
+/// Debugging control over whether the simulation advances
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Pause {
+    pub paused: bool,
+    /// While paused, run exactly one step on the next `State::advance`
+    pub step_once: bool,
+}
+/// Steps run per second of real time
+///
+/// Each step always covers `TICK_DURATION` of simulated time, so rates other than
+/// `1 / TICK_DURATION` speed up or slow down the simulation.
+#[derive(Debug, Copy, Clone)]
+pub struct TickRate(pub f32);
+
+impl Default for TickRate {
+    fn default() -> Self {
+        TickRate(1.0 / TICK_DURATION)
+    }
+}
+
+/// Factor by which simulated time passes faster than normal, for slow motion or fast-forward
+///
+/// Unlike `TickRate`, this leaves the number of steps unchanged, scaling `Time::dt` instead; 0
+/// freezes the simulation while the camera and rendering carry on smoothly.
+#[derive(Debug, Copy, Clone)]
+pub struct TimeScale(pub f32);
+
+impl Default for TimeScale {
+    fn default() -> Self {
+        TimeScale(1.0)
+    }
+}
+
 pub struct Camera(pub na::Similarity2<f32>);
+
+impl Camera {
+    /// Positioned and zoomed as described by `initial`
+    pub fn new(scale: WorldScale, initial: InitialCamera) -> Self {
+        Camera(na::Similarity2::new(
+            initial.position,
+            0.0,
+            initial.zoom * scale.camera_zoom(),
+        ))
+    }
+
+    /// Counterclockwise angle of the view, in radians
+    pub fn rotation(&self) -> f32 {
+        self.0.isometry.rotation.angle()
+    }
+
+    pub fn set_rotation(&mut self, angle: f32) {
+        self.0.isometry.rotation = na::UnitComplex::new(angle);
+    }
+}
+
+/// Where the camera starts out, and returns to on `State::reset`
+#[derive(Debug, Copy, Clone)]
+pub struct InitialCamera {
+    /// World-space point at the center of the view
+    pub position: na::Vector2<f32>,
+    /// Multiple of `WorldScale::camera_zoom`, as in `ZOOM_PRESETS`
+    pub zoom: f32,
+}
+
+impl Default for InitialCamera {
+    fn default() -> Self {
+        Self {
+            position: na::zero(),
+            zoom: 1.0,
+        }
+    }
+}
+
+/// How the camera tracks the `Player`
+#[derive(Debug, Copy, Clone)]
+pub struct CameraFollow {
+    pub enabled: bool,
+    /// Half-extents of the rectangle around the center of the view, in pixels, within which the
+    /// player can move without the camera following
+    pub deadzone: na::Vector2<f32>,
+}
+
+impl Default for CameraFollow {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            deadzone: na::Vector2::new(160.0, 90.0),
+        }
+    }
+}
+
 pub struct Cursor {
     pub position: na::Vector2<f32>,
+    /// Physical pixels from the center of the window, with y up
+    pub window_position: na::Vector2<f32>,
     pub pressed: bool,
 }
 
-#[derive(Component)]
+impl Default for Cursor {
+    fn default() -> Self {
+        Self {
+            position: na::zero(),
+            window_position: na::zero(),
+            pressed: false,
+        }
+    }
+}
+
+/// How the cursor's world position is adjusted after it moves
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CursorSnap {
+    None,
+    /// Round to the nearest multiple of the spacing along each axis
+    Grid(f32),
+    /// Move onto the surface of the nearest collider within `WorldScale::snap_radius`
+    Collider,
+}
+
+/// Camera zoom levels selectable with the number keys, as multiples of `WorldScale::camera_zoom`
+pub const ZOOM_PRESETS: [f32; 3] = [0.5, 1.0, 2.0];
+
+/// Whether the simulation only advances when the player acts, as in a classic roguelike
+///
+/// Steps still run between actions to animate the camera and fades, but simulated time stands
+/// still and keyboard input is held for the next action.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TurnMode(pub bool);
+
+/// Keyboard-driven actions awaiting the next step
+#[derive(Default)]
+pub struct Actions {
+    /// Index into `ZOOM_PRESETS` selected since the latest step
+    pub zoom_preset: Option<usize>,
+    /// Position and zoom level for the camera to glide to, requested since the latest step
+    pub camera_target: Option<(na::Vector2<f32>, f32)>,
+}
+
+/// Keyboard state, for systems to poll
+///
+/// Presses and releases are remembered until the end of the next step, so a key tapped between
+/// two steps is still seen as just pressed and then just released.
+#[derive(Debug, Clone, Default)]
+pub struct InputState {
+    down: HashSet<VirtualKeyCode>,
+    pressed: HashSet<VirtualKeyCode>,
+    released: HashSet<VirtualKeyCode>,
+}
+
+impl InputState {
+    pub fn is_key_down(&self, key: VirtualKeyCode) -> bool {
+        self.down.contains(&key)
+    }
+
+    /// Whether `key` was pressed since the previous step
+    pub fn just_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    /// Whether `key` was released since the previous step
+    pub fn just_released(&self, key: VirtualKeyCode) -> bool {
+        self.released.contains(&key)
+    }
+
+    /// Record `key` being pressed or released, returning false for repeats
+    fn set_key(&mut self, key: VirtualKeyCode, down: bool) -> bool {
+        if down {
+            if !self.down.insert(key) {
+                return false;
+            }
+            self.pressed.insert(key);
+        } else {
+            if !self.down.remove(&key) {
+                return false;
+            }
+            self.released.insert(key);
+        }
+        true
+    }
+
+    /// Release every key that's down, as if each had been let go
+    fn release_all(&mut self) {
+        self.released.extend(self.down.drain());
+    }
+
+    fn end_step(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+    }
+}
+
+/// Whether the window has keyboard focus
+///
+/// Input systems ignore the keyboard and mouse while unfocused, since keys released in another
+/// window are never reported to this one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Focused(pub bool);
+
+impl Default for Focused {
+    fn default() -> Self {
+        Focused(true)
+    }
+}
+
+/// A single-line text field fed by typed characters, for consoles and naming entities
+///
+/// Return opens the field, and closes it again to submit the text; Escape closes it without
+/// submitting. Input methods compose characters before delivering them, so no special handling is
+/// needed for them here.
+#[derive(Debug, Clone, Default)]
+pub struct TextInput {
+    /// Whether the field has focus, so that typing edits `text` rather than triggering actions
+    pub active: bool,
+    pub text: String,
+    /// Contents of the field when it was most recently submitted
+    pub submitted: Option<String>,
+}
+
+impl TextInput {
+    /// Apply a typed character, returning whether that changed anything
+    pub fn receive(&mut self, c: char) -> bool {
+        if !self.active {
+            if c == '\r' {
+                self.active = true;
+                self.text.clear();
+                return true;
+            }
+            return false;
+        }
+        match c {
+            '\r' => {
+                self.active = false;
+                self.submitted = Some(self.text.split_off(0));
+            }
+            '\u{1b}' => {
+                self.active = false;
+                self.text.clear();
+            }
+            '\u{8}' => return self.text.pop().is_some(),
+            c if c.is_control() => return false,
+            c => self.text.push(c),
+        }
+        true
+    }
+}
+
+/// World units per meter, from which every scale-dependent constant is derived
+#[derive(Debug, Copy, Clone)]
+pub struct WorldScale(pub f32);
+
+impl WorldScale {
+    pub fn collision_margin(self) -> f32 {
+        0.01 * self.0
+    }
+
+    /// Radius of a newly spawned ball
+    pub fn ball_radius(self) -> f32 {
+        self.0
+    }
+
+    /// Size of the sprite drawn for a collider
+    pub fn sprite_dimensions(self) -> na::Vector2<f32> {
+        na::Vector2::new(4.0, 4.0) * self.0
+    }
+
+    /// Distance the player can see
+    pub fn sight_radius(self) -> f32 {
+        20.0 * self.0
+    }
+
+    /// Size of a cell of the `Tilemap`
+    pub fn tile_size(self) -> f32 {
+        2.0 * self.0
+    }
+
+    /// Distance within which the cursor snaps to colliders
+    pub fn snap_radius(self) -> f32 {
+        2.0 * self.0
+    }
+
+    /// World units per pixel at the initial zoom level
+    pub fn camera_zoom(self) -> f32 {
+        0.1 * self.0
+    }
+}
+
+impl Default for WorldScale {
+    fn default() -> Self {
+        WorldScale(1.0)
+    }
+}
+
+/// Dims everything outside a circle around the player
+#[derive(Debug, Copy, Clone)]
+pub struct FogOfWar {
+    /// Distance from the player beyond which the scene is fully dimmed
+    pub radius: f32,
+    /// Width of the transition from lit to dimmed, as a fraction of `radius`
+    pub softness: f32,
+    /// Factor that the brightness of dimmed areas is multiplied by
+    pub brightness: f32,
+}
+
+impl FogOfWar {
+    pub fn new(scale: WorldScale) -> Self {
+        Self {
+            radius: scale.sight_radius(),
+            softness: 0.2,
+            brightness: 0.2,
+        }
+    }
+}
+
+#[derive(Component, Serialize, Deserialize)]
 #[storage(HashMapStorage)]
 pub struct Player;
 
 pub struct State {
     pub world: World,
     input: sim::Input,
+    save_positions: sim::SavePositions,
+    sync_transforms: sim::SyncTransforms,
+    player_movement: sim::PlayerMovement,
+    grid_moves: sim::GridMoves,
+    movement: sim::Movement,
+    camera_transition: sim::CameraTransition,
+    timers: sim::Timers,
+    spawners: sim::Spawners,
+    fades: render::Fades,
+    pings: render::Pings,
+    despawn_expired: sim::DespawnExpired,
+    sync_shooters: sim::SyncShooters,
+    update_collision: sim::UpdateCollision,
+    log_collisions: sim::LogCollisions,
+    expire_on_contact: sim::ExpireOnContact,
+    follow_player: sim::FollowPlayer,
+    /// Real time not yet accounted for by a step, in seconds
+    accumulator: f32,
 }
 
 impl State {
     pub fn new() -> Self {
+        Self::with_scale(WorldScale::default())
+    }
+
+    pub fn with_scale(scale: WorldScale) -> Self {
+        Self::with_camera(scale, InitialCamera::default())
+    }
+
+    /// Construct with the camera starting out as described by `camera`
+    pub fn with_camera(scale: WorldScale, camera: InitialCamera) -> Self {
         let mut world = World::new();
         world.add_resource(Step(0));
-        world.add_resource(Camera(na::Similarity2::new(na::zero(), 0.0, 0.1)));
-        world.add_resource(Cursor {
-            position: na::zero(),
-            pressed: false,
+        world.add_resource(Profiler::default());
+        world.add_resource(FrameStats::default());
+        world.add_resource(Time {
+            dt: TICK_DURATION,
+            unscaled_dt: TICK_DURATION,
         });
+        world.add_resource(TickProgress(0.0));
+        world.add_resource(TickRate::default());
+        world.add_resource(TimeScale::default());
+        world.add_resource(Pause::default());
+        world.add_resource(TurnMode::default());
+        world.add_resource(scale);
+        world.add_resource(camera);
+        world.add_resource(Camera::new(scale, camera));
+        world.add_resource(CameraFollow::default());
+        world.add_resource(Cursor::default());
+        world.add_resource(CursorSnap::None);
+        world.add_resource(Actions::default());
+        world.add_resource(InputState::default());
+        world.add_resource(Focused::default());
+        world.add_resource(TextInput::default());
+        world.add_resource(FogOfWar::new(scale));
+        world.add_resource(Tilemap::new(scale));
+        world.add_resource(Prefabs::default());
+        world.register::<Player>();
+        event::setup(&mut world);
         crate::sim::setup(&mut world);
+        crate::render::setup(&mut world);
         Self {
             world,
             input: sim::Input::new(),
+            save_positions: sim::SavePositions,
+            sync_transforms: sim::SyncTransforms,
+            player_movement: sim::PlayerMovement,
+            grid_moves: sim::GridMoves,
+            movement: sim::Movement,
+            camera_transition: sim::CameraTransition::default(),
+            timers: sim::Timers,
+            spawners: sim::Spawners,
+            fades: render::Fades,
+            pings: render::Pings,
+            despawn_expired: sim::DespawnExpired,
+            sync_shooters: sim::SyncShooters,
+            update_collision: sim::UpdateCollision,
+            log_collisions: sim::LogCollisions,
+            expire_on_contact: sim::ExpireOnContact,
+            follow_player: sim::FollowPlayer,
+            accumulator: 0.0,
         }
     }
 
+    /// Account for `dt` of real time passing, running as many fixed-length steps as fit
+    ///
+    /// Returns the number of steps run.
+    pub fn advance(&mut self, dt: Duration) -> u32 {
+        self.advance_at_most(dt, std::u32::MAX)
+    }
+
+    /// Like `advance`, but run no more than `limit` steps, leaving any remaining time for a later
+    /// call
+    pub fn advance_at_most(&mut self, dt: Duration, limit: u32) -> u32 {
+        let pause = *self.world.read_resource::<Pause>();
+        if pause.paused {
+            self.accumulator = 0.0;
+            // Show the latest step as-is rather than lagging behind it
+            self.world.write_resource::<TickProgress>().0 = 1.0;
+            if !pause.step_once || limit == 0 {
+                return 0;
+            }
+            self.world.write_resource::<Pause>().step_once = false;
+            self.timed_step();
+            return 1;
+        }
+        let interval = 1.0 / self.tick_rate();
+        self.accumulator = (self.accumulator + dt.as_secs_f32()).min(MAX_ADVANCE.max(interval));
+        let mut steps = 0;
+        while self.accumulator >= interval && steps < limit {
+            self.timed_step();
+            self.accumulator -= interval;
+            steps += 1;
+        }
+        self.world.write_resource::<TickProgress>().0 = self.accumulator / interval;
+        steps
+    }
+
+    fn timed_step(&mut self) {
+        let start = Instant::now();
+        self.step();
+        self.world
+            .write_resource::<Profiler>()
+            .record("step", start.elapsed());
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.world.read_resource::<Pause>().paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.world.write_resource::<Pause>().paused = paused;
+    }
+
+    /// While paused, run a single step on the next `advance`
+    pub fn step_once(&mut self) {
+        let mut pause = self.world.write_resource::<Pause>();
+        if pause.paused {
+            pause.step_once = true;
+        }
+    }
+
+    pub fn tick_rate(&self) -> f32 {
+        self.world.read_resource::<TickRate>().0
+    }
+
+    /// Run `rate` steps per second of real time from now on
+    pub fn set_tick_rate(&mut self, rate: f32) {
+        assert!(rate > 0.0, "tick rate must be positive");
+        self.world.write_resource::<TickRate>().0 = rate;
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.world.read_resource::<TimeScale>().0
+    }
+
+    /// Pass `scale` seconds of simulated time per second from the next step on
+    pub fn set_time_scale(&mut self, scale: f32) {
+        assert!(scale >= 0.0, "time scale must not be negative");
+        self.world.write_resource::<TimeScale>().0 = scale;
+    }
+
+    /// Whether further steps would leave the world unchanged in the absence of new input
+    pub fn is_idle(&self) -> bool {
+        let pause = self.world.read_resource::<Pause>();
+        if pause.paused {
+            return !pause.step_once;
+        }
+        if self.awaiting_turn() {
+            return !self.camera_moving() && !self.pinging();
+        }
+        (&self.world.read_storage::<sim::Velocity>())
+            .join()
+            .all(|velocity| velocity.0 == na::zero())
+            && (&self.world.read_storage::<sim::AngularVelocity>())
+                .join()
+                .all(|velocity| velocity.0 == 0.0)
+            && (&self.world.read_storage::<sim::Spawner>())
+                .join()
+                .next()
+                .is_none()
+            && !self.camera_moving()
+            && !self.pinging()
+            && !self.grid_moving()
+    }
+
+    /// Whether any `GridMove` is between cells
+    fn grid_moving(&self) -> bool {
+        (&self.world.read_storage::<sim::GridMove>())
+            .join()
+            .any(|x| x.is_moving())
+    }
+
+    /// Whether any `Ping` is still animating
+    fn pinging(&self) -> bool {
+        (&self.world.read_storage::<render::Ping>())
+            .join()
+            .next()
+            .is_some()
+    }
+
+    /// Whether the camera is gliding, or about to start
+    fn camera_moving(&self) -> bool {
+        let actions = self.world.read_resource::<Actions>();
+        self.camera_transition.is_animating()
+            || actions.camera_target.is_some()
+            || actions.zoom_preset.is_some()
+    }
+
     pub fn step(&mut self) {
+        if self.awaiting_turn() {
+            self.idle_step();
+            return;
+        }
+        event::clear(&self.world);
+        let scale = self.time_scale();
+        self.world.write_resource::<Time>().dt = TICK_DURATION * scale;
+        self.save_positions.run_now(&self.world.res);
         self.input.run_now(&self.world.res);
+        self.animate_camera();
+        self.player_movement.run_now(&self.world.res);
+        self.grid_moves.run_now(&self.world.res);
+        // Before anything filters pairs, so projectiles fired since the last step pass through
+        // their shooters from the start
+        self.sync_shooters.run_now(&self.world.res);
+        self.movement.run_now(&self.world.res);
+        self.timers.run_now(&self.world.res);
+        self.spawners.run_now(&self.world.res);
+        self.fades.run_now(&self.world.res);
+        self.pings.run_now(&self.world.res);
+        self.despawn_expired.run_now(&self.world.res);
+        // The only place contacts are computed, once every collider has moved, spawned, or
+        // despawned for this step. Anything reading contacts or `CollisionEvent`s must run after.
+        self.update_collision.run_now(&self.world.res);
+        self.log_collisions.run_now(&self.world.res);
+        self.sync_transforms.run_now(&self.world.res);
+        // Despawn on contact within the same step, so nothing is ever drawn overlapping what it
+        // hit. Must follow everything that expects every collider to be in the collision world.
+        self.expire_on_contact.run_now(&self.world.res);
+        self.despawn_expired.run_now(&self.world.res);
+        self.follow_player.run_now(&self.world.res);
+        self.world.maintain();
+        self.world.write_resource::<InputState>().end_step();
         let mut step = self.world.write_resource::<Step>();
         step.0 = step.0.wrapping_add(1);
     }
 
-    /// World units wrt. center of camera
-    pub fn move_cursor(&mut self, window_pos: &na::Vector2<f32>) {
-        let world = self.world.read_resource::<Camera>().0 * window_pos;
-        self.world.write_resource::<Cursor>().position = world;
+    /// Digest of the simulation's progress: the step count, and the position and velocity of every
+    /// collider
+    ///
+    /// Instances fed the same input by the same build hash alike after every step, so comparing
+    /// hashes detects a simulation that has diverged, e.g. between lockstep peers. Floats are
+    /// hashed bitwise, so even the smallest difference shows.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.world.read_resource::<Step>().0);
+        let collision = self.world.read_resource::<sim::CollisionWorld>();
+        for (entity, collider, velocity, angular_velocity) in (
+            &self.world.entities(),
+            &self.world.read_storage::<sim::Collider>(),
+            self.world.read_storage::<sim::Velocity>().maybe(),
+            self.world.read_storage::<sim::AngularVelocity>().maybe(),
+        )
+            .join()
+        {
+            hasher.write_u32(entity.id());
+            let position = match collision.collision_object(collider.root) {
+                Some(x) => x.position(),
+                None => continue,
+            };
+            let velocity = velocity.map_or_else(na::zero, |x| x.0);
+            for x in &[
+                position.translation.vector.x,
+                position.translation.vector.y,
+                position.rotation.angle(),
+                velocity.x,
+                velocity.y,
+                angular_velocity.map_or(0.0, |x| x.0),
+            ] {
+                hasher.write_u32(x.to_bits());
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Advance any `CameraTransition`, keeping the cursor over the same point on screen
+    fn animate_camera(&mut self) {
+        let animating = self.camera_transition.is_animating();
+        self.camera_transition.run_now(&self.world.res);
+        if animating || self.camera_transition.is_animating() {
+            self.update_cursor_position();
+        }
+    }
+
+    /// Whether `TurnMode` is holding the simulation until the player acts
+    fn awaiting_turn(&self) -> bool {
+        self.world.read_resource::<TurnMode>().0
+            && sim::player_move(&self.world.read_resource::<InputState>()).is_none()
+            && !self.grid_moving()
+    }
+
+    /// Update only what animates independently of simulated time, while awaiting a turn
+    fn idle_step(&mut self) {
+        event::clear(&self.world);
+        // Settle the latest turn's motion rather than interpolating it again
+        self.save_positions.run_now(&self.world.res);
+        self.animate_camera();
+        self.fades.run_now(&self.world.res);
+        self.pings.run_now(&self.world.res);
+        self.follow_player.run_now(&self.world.res);
+        self.world.maintain();
+    }
+
+    /// Spawn a ball for the player to control at `position`
+    ///
+    /// Returns `None` without spawning anything if the `SpawnLimit` has been reached.
+    pub fn spawn_player(&mut self, position: na::Vector2<f32>) -> Option<Entity> {
+        let scale = *self.world.read_resource::<WorldScale>();
+        let entity = sim::spawn_ball(
+            &self.world.entities(),
+            &mut self.world.write_resource(),
+            &mut self.world.write_resource(),
+            &mut self.world.write_storage(),
+            *self.world.read_resource(),
+            sim::Layer::ACTOR,
+            position,
+            scale.ball_radius(),
+            self.world.read_resource::<sim::SpawnQuery>().0,
+        )?;
+        self.world
+            .write_storage::<Player>()
+            .insert(entity, Player)
+            .unwrap();
+        Some(entity)
+    }
+
+    /// Delete every entity and return the simulation to its initial state
+    ///
+    /// Configuration such as the `WorldScale` is preserved.
+    pub fn reset(&mut self) {
+        self.world.maintain();
+        self.world.delete_all();
+        sim::reset(&mut self.world);
+        event::clear(&self.world);
+        let scale = *self.world.read_resource::<WorldScale>();
+        self.world.write_resource::<Step>().0 = 0;
+        self.world.write_resource::<TickProgress>().0 = 0.0;
+        let initial = *self.world.read_resource::<InitialCamera>();
+        *self.world.write_resource::<Camera>() = Camera::new(scale, initial);
+        *self.world.write_resource::<Cursor>() = Cursor::default();
+        *self.world.write_resource::<Actions>() = Actions::default();
+        *self.world.write_resource::<InputState>() = InputState::default();
+        *self.world.write_resource::<TextInput>() = TextInput::default();
+        self.world.write_resource::<Tilemap>().tiles.clear();
+        self.input = sim::Input::new();
+        self.camera_transition = sim::CameraTransition::default();
+        self.accumulator = 0.0;
+    }
+
+    /// Change the world scale, rescaling the camera and all existing colliders to match
+    pub fn set_world_scale(&mut self, scale: WorldScale) {
+        let ratio = scale.0 / self.world.read_resource::<WorldScale>().0;
+        {
+            let mut camera = self.world.write_resource::<Camera>();
+            camera.0.isometry.translation.vector *= ratio;
+            let zoom = camera.0.scaling() * ratio;
+            camera.0.set_scaling(zoom);
+        }
+        self.world.write_resource::<InitialCamera>().position *= ratio;
+        self.world.write_resource::<FogOfWar>().radius *= ratio;
+        self.world.write_resource::<Tilemap>().grid.size *= ratio;
+        if let Some(ref mut spacing) = self.world.write_resource::<render::Grid>().spacing {
+            *spacing *= ratio;
+        }
+        if let CursorSnap::Grid(ref mut spacing) = *self.world.write_resource::<CursorSnap>() {
+            *spacing *= ratio;
+        }
+        *self.world.write_resource::<WorldScale>() = scale;
+        sim::rescale(&mut self.world, ratio);
+    }
+
+    /// Glide the camera over the next few steps, keeping its rotation, until every collider fits
+    /// within a `viewport` of that many pixels, leaving `padding` pixels clear at each edge
+    ///
+    /// Returns false without moving the camera if there are no colliders. While paused, the camera
+    /// moves immediately instead. A `Player` being followed will pull the camera back towards
+    /// itself once the transition ends.
+    pub fn fit_view(&mut self, viewport: na::Vector2<f32>, padding: f32) -> bool {
+        let camera = self.world.read_resource::<Camera>();
+        let rotation = camera.0.isometry.rotation;
+        // Measure in view-aligned space so a rotated view is filled as tightly as possible
+        let to_view = na::Isometry2::from_parts(na::one(), rotation.inverse());
+        let bounds = {
+            let collision = self.world.read_resource::<sim::CollisionWorld>();
+            match sim::collider_bounds(&collision, &to_view) {
+                Some(x) => x,
+                None => return false,
+            }
+        };
+        let extents = bounds.maxs() - bounds.mins();
+        let available = viewport.map(|x| (x - 2.0 * padding).max(1.0));
+        let zoom = (extents.x / available.x).max(extents.y / available.y);
+        let zoom = if zoom > 0.0 { zoom } else { camera.0.scaling() };
+        let position = rotation * bounds.center().coords;
+        drop(camera);
+        if !self.is_paused() {
+            self.world.write_resource::<Actions>().camera_target = Some((position, zoom));
+            return true;
+        }
+        // Transitions only advance with steps, so go straight there
+        let mut camera = self.world.write_resource::<Camera>();
+        camera.0.isometry.translation.vector = position;
+        camera.0.set_scaling(zoom);
+        drop(camera);
+        // Keep the cursor over the same point on screen
+        self.update_cursor_position();
+        true
+    }
+
+    /// Move the cursor to `position`, in physical pixels from the top left of a window whose
+    /// drawable area is `window_size` physical pixels
+    pub fn move_cursor(&mut self, position: &na::Point2<f32>, window_size: &na::Vector2<f32>) {
+        let centered = position.coords - window_size * 0.5;
+        self.world.write_resource::<Cursor>().window_position =
+            na::Vector2::new(centered.x, -centered.y);
+        self.update_cursor_position();
+    }
+
+    /// Recompute the cursor's world position from its window position, applying `CursorSnap`
+    fn update_cursor_position(&mut self) {
+        let mut cursor = self.world.write_resource::<Cursor>();
+        let raw = self.world.read_resource::<Camera>().0 * cursor.window_position;
+        cursor.position = match *self.world.read_resource::<CursorSnap>() {
+            CursorSnap::None => raw,
+            CursorSnap::Grid(spacing) => raw.map(|x| (x / spacing).round() * spacing),
+            CursorSnap::Collider => {
+                let radius = self.world.read_resource::<WorldScale>().snap_radius();
+                sim::nearest_surface_point(
+                    &self.world.read_resource::<sim::CollisionWorld>(),
+                    &na::Point2::from(raw),
+                    radius,
+                    sim::Layer::all(),
+                )
+                .map_or(raw, |x| x.coords)
+            }
+        };
+    }
+
+    /// Turn the camera `angle` radians counterclockwise about its center
+    pub fn rotate_camera(&mut self, angle: f32) {
+        let mut camera = self.world.write_resource::<Camera>();
+        let rotation = camera.rotation();
+        camera.set_rotation(rotation + angle);
+        drop(camera);
+        // Keep the cursor over the same point on screen
+        self.update_cursor_position();
     }
 
     pub fn cursor_pressed(&mut self, pressed: bool) {
         self.world.write_resource::<Cursor>().pressed = pressed;
     }
+
+    /// Record `key` being pressed or released, returning whether that changed its state
+    pub fn key_input(&mut self, key: VirtualKeyCode, pressed: bool) -> bool {
+        self.world
+            .write_resource::<InputState>()
+            .set_key(key, pressed)
+    }
+
+    /// Record the window gaining or losing focus, releasing every held key and button on losing it
+    pub fn set_focused(&mut self, focused: bool) {
+        self.world.write_resource::<Focused>().0 = focused;
+        if !focused {
+            self.world.write_resource::<InputState>().release_all();
+            self.world.write_resource::<Cursor>().pressed = false;
+            // Forget the press, so its release doesn't spawn anything
+            self.input = sim::Input::new();
+        }
+    }
+
+    /// Feed a typed character to the `TextInput`, returning whether that changed anything
+    pub fn receive_character(&mut self, c: char) -> bool {
+        self.world.write_resource::<TextInput>().receive(c)
+    }
+
+    pub fn text_input_active(&self) -> bool {
+        self.world.read_resource::<TextInput>().active
+    }
+
+    /// Mark `entity` alone as `Selected`, or select nothing
+    pub fn select(&mut self, entity: Option<Entity>) {
+        let mut selected = self.world.write_storage::<render::Selected>();
+        selected.clear();
+        if let Some(entity) = entity {
+            selected.insert(entity, render::Selected).unwrap();
+        }
+    }
+
+    /// Begin zooming to `ZOOM_PRESETS[index]`
+    pub fn select_zoom_preset(&mut self, index: usize) {
+        self.world.write_resource::<Actions>().zoom_preset = Some(index);
+    }
+
+    /// Serialize the components present on `entity` into a JSON object for inspection
+    ///
+    /// Collision object handles are meaningless outside this process, so colliders are represented
+    /// by the position of the object they refer to.
+    pub fn dump_entity(&self, entity: Entity) -> serde_json::Value {
+        let mut out = serde_json::Map::new();
+        dump_component::<Player>(&self.world, entity, "Player", &mut out);
+        dump_component::<render::Material>(&self.world, entity, "Material", &mut out);
+        dump_component::<render::Rounding>(&self.world, entity, "Rounding", &mut out);
+        dump_component::<render::AlphaCutoff>(&self.world, entity, "AlphaCutoff", &mut out);
+        dump_component::<render::Billboard>(&self.world, entity, "Billboard", &mut out);
+        dump_component::<render::Sprite>(&self.world, entity, "Sprite", &mut out);
+        dump_component::<render::Tint>(&self.world, entity, "Tint", &mut out);
+        dump_component::<render::Fade>(&self.world, entity, "Fade", &mut out);
+        dump_component::<render::Parallax>(&self.world, entity, "Parallax", &mut out);
+        if let Some(collider) = self.world.read_storage::<sim::Collider>().get(entity) {
+            let collision = self.world.read_resource::<sim::CollisionWorld>();
+            let position = collision
+                .collision_object(collider.root)
+                .expect("collider lifetime desync")
+                .position();
+            out.insert(
+                "Collider".into(),
+                json!({
+                    "position": [position.translation.vector.x, position.translation.vector.y],
+                    "rotation": position.rotation.angle(),
+                    "enabled": collider.is_enabled(),
+                }),
+            );
+        }
+        if let Some(transform) = self.world.read_storage::<sim::Transform>().get(entity) {
+            out.insert(
+                "Transform".into(),
+                json!({
+                    "position": [transform.0.translation.vector.x, transform.0.translation.vector.y],
+                    "rotation": transform.0.rotation.angle(),
+                }),
+            );
+        }
+        if let Some(velocity) = self.world.read_storage::<sim::Velocity>().get(entity) {
+            out.insert("Velocity".into(), json!([velocity.0.x, velocity.0.y]));
+        }
+        if let Some(velocity) = self
+            .world
+            .read_storage::<sim::AngularVelocity>()
+            .get(entity)
+        {
+            out.insert("AngularVelocity".into(), json!(velocity.0));
+        }
+        out.into()
+    }
+}
+
+fn dump_component<T: Component + Serialize>(
+    world: &World,
+    entity: Entity,
+    name: &str,
+    out: &mut serde_json::Map<String, serde_json::Value>,
+) {
+    if let Some(x) = world.read_storage::<T>().get(entity) {
+        out.insert(
+            name.into(),
+            serde_json::to_value(x).expect("component serialization failed"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ncollide2d::shape;
+    use ncollide2d::world::GeometricQueryType;
+    use specs::Builder;
+
+    use super::*;
+    use crate::event::EventChannel;
+
+    /// Spawn a ball `x` ball radii along the x axis, moving `speed` radii per second
+    fn ball(state: &mut State, x: f32, speed: f32) -> Entity {
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        let entity = sim::spawn_ball(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_storage(),
+            *state.world.read_resource(),
+            sim::Layer::ACTOR,
+            na::Vector2::new(x * radius, 0.0),
+            radius,
+            sim::TOUCH_CONTACTS,
+        )
+        .unwrap();
+        state
+            .world
+            .write_storage()
+            .insert(entity, sim::Velocity(na::Vector2::new(speed * radius, 0.0)))
+            .unwrap();
+        entity
+    }
+
+    fn position(state: &State, entity: Entity) -> na::Vector2<f32> {
+        let collider = state.world.read_storage::<sim::Collider>();
+        let root = collider.get(entity).unwrap().root;
+        state
+            .world
+            .read_resource::<sim::CollisionWorld>()
+            .collision_object(root)
+            .unwrap()
+            .position()
+            .translation
+            .vector
+    }
+
+    #[test]
+    fn nearest_fresh() {
+        let mut state = State::new();
+        let entity = ball(&mut state, 3.0, 0.0);
+        sim::refresh_queries(&mut state.world.write_resource());
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        let (found, distance) = sim::nearest(
+            &state.world.read_resource(),
+            &na::Point2::origin(),
+            4.0 * radius,
+            sim::Layer::ACTOR,
+        )
+        .unwrap();
+        assert_eq!(found, entity);
+        assert!((distance - 2.0 * radius).abs() < 1e-3 * radius);
+    }
+
+    #[test]
+    fn fired() {
+        let mut state = State::new();
+        let shooter = ball(&mut state, 0.0, 0.0);
+        let projectile = ball(&mut state, 0.0, 1.0);
+        state
+            .world
+            .write_storage()
+            .insert(projectile, sim::Fired { by: shooter })
+            .unwrap();
+        state.step();
+        assert_ne!(position(&state, projectile), na::zero());
+    }
+
+    #[test]
+    fn trigger() {
+        let mut state = State::new();
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        let trigger = sim::spawn_ball(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_storage(),
+            *state.world.read_resource(),
+            sim::Layer::TRIGGER,
+            na::Vector2::new(2.0 * radius, 0.0),
+            radius,
+            GeometricQueryType::Proximity(0.0),
+        )
+        .unwrap();
+        // Fast enough to overlap the trigger on the first step
+        let actor = ball(&mut state, 0.0, 120.0);
+        state.step();
+        assert!(position(&state, actor).x > 1.5 * radius);
+        let events = state
+            .world
+            .read_resource::<EventChannel<sim::CollisionEvent>>()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(events.iter().any(|x| match *x {
+            sim::CollisionEvent::Started(a, b) =>
+                (a, b) == (actor, trigger) || (b, a) == (actor, trigger),
+            _ => false,
+        }));
+        state.step();
+        assert!(position(&state, actor).x > 3.5 * radius);
+    }
+
+    #[test]
+    fn separate() {
+        // Overlapping balls can move apart, but not further together
+        for &(speed, moves) in &[(-1.0, true), (1.0, false)] {
+            let mut state = State::new();
+            let a = ball(&mut state, 0.0, speed);
+            ball(&mut state, 1.5, 0.0);
+            let start = position(&state, a);
+            state.step();
+            assert_eq!(position(&state, a) != start, moves);
+        }
+    }
+
+    /// Two balls on a collision course, the second nudged by `perturbation`
+    fn run(perturbation: f32) -> Vec<u64> {
+        let mut state = State::new();
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        for &(x, speed) in &[(-4.0, 1.0), (4.0, -1.0 - perturbation)] {
+            let entity = state
+                .spawn_player(na::Vector2::new(x * radius, 0.0))
+                .unwrap();
+            state
+                .world
+                .write_storage()
+                .insert(entity, sim::Velocity(na::Vector2::new(speed * radius, 0.0)))
+                .unwrap();
+        }
+        (0..10)
+            .map(|_| {
+                state.step();
+                state.state_hash()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_spawner_interval() {
+        let mut state = State::new();
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        for &interval in &[0.0, -1.0, f32::NAN] {
+            state
+                .world
+                .create_entity()
+                .with(sim::Transform(na::Isometry2::identity()))
+                .with(sim::Spawner {
+                    interval,
+                    last_spawn: 0.0,
+                    template: sim::SpawnTemplate {
+                        layer: sim::Layer::ACTOR,
+                        radius,
+                        velocity: None,
+                        lifetime: None,
+                        despawn_on_contact: false,
+                    },
+                })
+                .build();
+        }
+        state.step();
+        assert_eq!(
+            state.world.read_storage::<sim::Collider>().join().count(),
+            0
+        );
+    }
+
+    #[test]
+    fn spawn_fade() {
+        let mut state = State::new();
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        state
+            .world
+            .create_entity()
+            .with(sim::Transform(na::Isometry2::identity()))
+            .with(sim::Spawner {
+                interval: 1.0,
+                last_spawn: 1.0,
+                template: sim::SpawnTemplate {
+                    layer: sim::Layer::ACTOR,
+                    radius,
+                    velocity: None,
+                    lifetime: None,
+                    despawn_on_contact: false,
+                },
+            })
+            .build();
+        state.step();
+        let ball = (
+            &state.world.entities(),
+            &state.world.read_storage::<sim::Collider>(),
+        )
+            .join()
+            .next()
+            .unwrap()
+            .0;
+        // Drawn with a material that blends by the tint's alpha, so the fade shows
+        assert_eq!(
+            state.world.read_storage::<render::Material>().get(ball),
+            Some(&render::Material::Circle)
+        );
+        let alpha = |state: &State| {
+            state
+                .world
+                .read_storage::<render::Tint>()
+                .get(ball)
+                .unwrap()
+                .0
+                .w
+        };
+        let first = alpha(&state);
+        assert!(first < 1.0);
+        state.step();
+        assert!(alpha(&state) > first);
+        for _ in 0..100 {
+            state.step();
+        }
+        assert_eq!(alpha(&state), 1.0);
+    }
+
+    #[test]
+    fn time_scale() {
+        let mut state = State::new();
+        let entity = ball(&mut state, 0.0, 1.0);
+        state.set_time_scale(0.0);
+        state.step();
+        assert_eq!(state.world.read_resource::<Time>().dt, 0.0);
+        assert_eq!(
+            state.world.read_resource::<Time>().unscaled_dt,
+            TICK_DURATION
+        );
+        assert_eq!(state.world.read_resource::<Step>().0, 1);
+        assert_eq!(position(&state, entity), na::zero());
+        state.set_time_scale(1.0);
+        state.step();
+        assert_eq!(state.world.read_resource::<Step>().0, 2);
+        assert_ne!(position(&state, entity), na::zero());
+    }
+
+    #[test]
+    fn rescale_contacts() {
+        let mut state = State::new();
+        let radius = state.world.read_resource::<WorldScale>().ball_radius();
+        let entity = ball(&mut state, 0.0, 1.0);
+        sim::spawn_shape(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_storage(),
+            *state.world.read_resource(),
+            sim::Layer::TERRAIN,
+            na::Isometry2::translation(1.5 * radius, 0.0),
+            shape::ShapeHandle::new(shape::Cuboid::new(na::Vector2::repeat(radius))),
+            sim::TOUCH_CONTACTS,
+        )
+        .unwrap();
+        let contacts = |state: &State| {
+            let mut collision = state.world.write_resource::<sim::CollisionWorld>();
+            collision.update();
+            sim::contacts(&collision).collect::<Vec<_>>()
+        };
+        let before = contacts(&state);
+        assert_eq!(before.len(), 1);
+        let scale = state.world.read_resource::<WorldScale>().0;
+        state.set_world_scale(WorldScale(2.0 * scale));
+        let after = contacts(&state);
+        assert_eq!(after.len(), 1);
+        let (before, after) = (before[0], after[0]);
+        assert_eq!(before.entities, after.entities);
+        assert!((after.normal.into_inner() - before.normal.into_inner()).norm() < 1e-4);
+        assert!((after.depth - 2.0 * before.depth).abs() < 1e-4 * radius);
+        assert!((after.point - before.point * 2.0).norm() < 1e-4 * radius);
+        let velocity = state
+            .world
+            .read_storage::<sim::Velocity>()
+            .get(entity)
+            .unwrap()
+            .0;
+        assert_eq!(velocity, na::Vector2::new(2.0 * radius, 0.0));
+    }
+
+    #[test]
+    fn spawn_limit() {
+        let mut state = State::new();
+        *state.world.write_resource() = sim::SpawnLimit(2);
+        let first = ball(&mut state, 0.0, 0.0);
+        ball(&mut state, 4.0, 0.0);
+        assert!(state.spawn_player(na::Vector2::new(8.0, 0.0)).is_none());
+        sim::despawn(
+            &state.world.entities(),
+            &mut state.world.write_resource(),
+            &mut state.world.write_resource(),
+            &state.world.write_storage(),
+            first,
+        );
+        assert!(state.spawn_player(na::Vector2::new(8.0, 0.0)).is_some());
+    }
+
+    #[test]
+    fn dump_fade() {
+        let mut state = State::new();
+        let entity = state
+            .world
+            .create_entity()
+            .with(render::Tint(na::Vector4::new(1.0, 0.5, 0.25, 0.0)))
+            .with(render::Fade::new(0.0, 1.0, 2.0))
+            .build();
+        let dump = state.dump_entity(entity);
+        assert_eq!(dump["Tint"], json!([1.0, 0.5, 0.25, 0.0]));
+        assert_eq!(dump["Fade"]["duration"], json!(2.0));
+    }
+
+    #[test]
+    fn state_hash() {
+        let hashes = run(0.0);
+        assert_eq!(hashes, run(0.0));
+        // Each step changes the state
+        assert!(hashes.windows(2).all(|x| x[0] != x[1]));
+        let diverged = run(1e-6);
+        assert!(hashes.iter().zip(&diverged).all(|(x, y)| x != y));
+    }
 }
@@ -0,0 +1,635 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::{vk, Device};
+use log::warn;
+use specs::{Component, VecStorage};
+use specs_derive::Component;
+
+use crate::graphics::Graphics;
+use crate::window::read_png;
+
+const FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+/// Maximum number of textures that may be loaded at once
+const MAX_TEXTURES: u32 = 256;
+
+/// Draw an entity's sprite with a texture requested from `TextureLoader::load`
+///
+/// Until the texture is ready, the entity is drawn as if it had none.
+#[derive(Component, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[storage(VecStorage)]
+pub struct Texture(u32);
+
+/// Number of times an entity's `Texture` repeats across its sprite along each axis
+///
+/// Entities without this component show the texture once. The texture only tiles if its
+/// `Filtering::address_mode` repeats; otherwise the region past its first copy shows the texture's
+/// clamped edges.
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+#[storage(VecStorage)]
+pub struct TextureRepeat(pub na::Vector2<f32>);
+
+/// How a texture is sampled when drawn magnified, minified, or beyond its edges
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Filtering {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    /// Treatment of coordinates outside the texture, e.g. `REPEAT` to tile it
+    pub address_mode: vk::SamplerAddressMode,
+    /// Upper bound on the anisotropy of filtering, or 1 to disable anisotropic filtering
+    ///
+    /// Reduced to the device's limit, or to 1 if the device doesn't support anisotropic filtering.
+    pub max_anisotropy: f32,
+}
+
+impl Default for Filtering {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            max_anisotropy: 1.0,
+        }
+    }
+}
+
+/// Decodes images on background threads and uploads them without blocking rendering
+pub struct TextureLoader {
+    gfx: Arc<Graphics>,
+    set_layout: vk::DescriptorSetLayout,
+    /// Filtering for textures loaded without their own
+    filtering: Filtering,
+    /// Sampler for each `Filtering` in use, starting with `filtering`
+    samplers: Vec<(Filtering, vk::Sampler)>,
+    descriptor_pool: vk::DescriptorPool,
+    command_pool: vk::CommandPool,
+    /// Image requested for each `Texture`, indexed by its ID, and any filtering it overrides the
+    /// default with
+    paths: Vec<(PathBuf, Option<Filtering>)>,
+    /// Number of images being decoded
+    decoding: usize,
+    send: Mutex<mpsc::Sender<(Texture, Option<Pixels>)>>,
+    /// Decoded images awaiting upload, or `None` for those that failed to decode
+    recv: Mutex<mpsc::Receiver<(Texture, Option<Pixels>)>>,
+    /// Transfers that may still be in progress
+    uploads: Vec<Upload>,
+    /// Textures ready to be drawn
+    loaded: HashMap<Texture, Image>,
+}
+
+impl Drop for TextureLoader {
+    fn drop(&mut self) {
+        let device = &*self.gfx.device;
+        unsafe {
+            if !self.uploads.is_empty() {
+                // Fails only if the device was lost, after which nothing is still in flight
+                let _ = device.device_wait_idle();
+            }
+            for upload in &self.uploads {
+                upload.finish(device, self.command_pool);
+                upload.image.destroy(device);
+            }
+            for image in self.loaded.values() {
+                image.destroy(device);
+            }
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_command_pool(self.command_pool, None);
+            for &(_, sampler) in &self.samplers {
+                device.destroy_sampler(sampler, None);
+            }
+            device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+impl TextureLoader {
+    /// Construct a loader that samples textures with `filtering` unless they specify otherwise
+    pub fn new(gfx: Arc<Graphics>, filtering: Filtering) -> Self {
+        let device = &*gfx.device;
+        unsafe {
+            let set_layout = create_set_layout(device);
+            let sampler = create_sampler(&gfx, filtering);
+            let descriptor_pool = device
+                .create_descriptor_pool(
+                    &vk::DescriptorPoolCreateInfo::builder()
+                        .max_sets(MAX_TEXTURES)
+                        .pool_sizes(&[vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            descriptor_count: MAX_TEXTURES,
+                        }]),
+                    None,
+                )
+                .unwrap();
+            let command_pool = device
+                .create_command_pool(
+                    &vk::CommandPoolCreateInfo::builder()
+                        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                        .queue_family_index(gfx.queue_family),
+                    None,
+                )
+                .unwrap();
+            let (send, recv) = mpsc::channel();
+            Self {
+                gfx,
+                set_layout,
+                filtering,
+                samplers: vec![(filtering, sampler)],
+                descriptor_pool,
+                command_pool,
+                paths: Vec::new(),
+                decoding: 0,
+                send: Mutex::new(send),
+                recv: Mutex::new(recv),
+                uploads: Vec::new(),
+                loaded: HashMap::new(),
+            }
+        }
+    }
+
+    /// Begin loading the PNG image at `path` in the background
+    pub fn load(&mut self, path: PathBuf) -> Texture {
+        self.load_inner(path, None)
+    }
+
+    /// Like `load`, but sample the texture with `filtering` rather than the loader's default
+    pub fn load_with(&mut self, path: PathBuf, filtering: Filtering) -> Texture {
+        self.load_inner(path, Some(filtering))
+    }
+
+    fn load_inner(&mut self, path: PathBuf, filtering: Option<Filtering>) -> Texture {
+        if let Some(filtering) = filtering {
+            if !self.samplers.iter().any(|&(x, _)| x == filtering) {
+                let sampler = unsafe { create_sampler(&self.gfx, filtering) };
+                self.samplers.push((filtering, sampler));
+            }
+        }
+        let texture = Texture(self.paths.len() as u32);
+        self.paths.push((path.clone(), filtering));
+        self.decoding += 1;
+        let send = self.send.get_mut().unwrap().clone();
+        thread::spawn(move || {
+            let pixels = match read_png(&path) {
+                Ok((rgba, width, height)) => Some(Pixels {
+                    rgba,
+                    width,
+                    height,
+                }),
+                Err(e) => {
+                    warn!("failed to load texture {}: {}", path.display(), e);
+                    None
+                }
+            };
+            let _ = send.send((texture, pixels));
+        });
+        texture
+    }
+
+    /// A loader using `gfx` that requests every image this one was asked for, under the same
+    /// `Texture`s
+    ///
+    /// Allows recovering textures whose device was lost.
+    pub fn recreate(&self, gfx: Arc<Graphics>) -> Self {
+        let mut loader = Self::new(gfx, self.filtering);
+        for &(ref path, filtering) in &self.paths {
+            loader.load_inner(path.clone(), filtering);
+        }
+        loader
+    }
+
+    /// Begin uploading newly decoded images, and make completed uploads available for drawing
+    ///
+    /// Returns whether any texture became ready, or `ERROR_DEVICE_LOST`.
+    ///
+    /// # Safety
+    /// - Must not be called concurrently with other submissions to `Graphics::queue`
+    pub unsafe fn poll(&mut self) -> VkResult<bool> {
+        let gfx = self.gfx.clone();
+        let device = &*gfx.device;
+        let decoded = self.recv.get_mut().unwrap().try_iter().collect::<Vec<_>>();
+        for (texture, pixels) in decoded {
+            self.decoding -= 1;
+            let pixels = match pixels {
+                Some(x) => x,
+                None => continue,
+            };
+            if self.loaded.len() + self.uploads.len() >= MAX_TEXTURES as usize {
+                warn!("texture limit of {} reached", MAX_TEXTURES);
+                continue;
+            }
+            let upload = self.begin_upload(texture, &pixels);
+            self.uploads.push(upload);
+        }
+
+        let mut ready = false;
+        let mut i = 0;
+        while i < self.uploads.len() {
+            match device.get_fence_status(self.uploads[i].fence) {
+                Ok(()) => {}
+                Err(vk::Result::NOT_READY) => {
+                    i += 1;
+                    continue;
+                }
+                Err(vk::Result::ERROR_DEVICE_LOST) => return Err(vk::Result::ERROR_DEVICE_LOST),
+                Err(e) => panic!("{}", e),
+            }
+            let upload = self.uploads.swap_remove(i);
+            upload.finish(device, self.command_pool);
+            self.loaded.insert(upload.texture, upload.image);
+            ready = true;
+        }
+        Ok(ready)
+    }
+
+    /// Whether any requested texture has yet to be uploaded
+    pub fn is_loading(&self) -> bool {
+        self.decoding != 0 || !self.uploads.is_empty()
+    }
+
+    /// Descriptor set binding `texture` for sampling, if it's ready
+    pub fn descriptor_set(&self, texture: Texture) -> Option<vk::DescriptorSet> {
+        self.loaded.get(&texture).map(|x| x.descriptor_set)
+    }
+
+    /// Opacity of `texture`'s texels, if it's ready
+    pub fn alpha_mask(&self, texture: Texture) -> Option<Arc<AlphaMask>> {
+        self.loaded.get(&texture).map(|x| x.alpha.clone())
+    }
+
+    /// How `texture` is sampled
+    pub fn filtering(&self, texture: Texture) -> Filtering {
+        self.paths[texture.0 as usize].1.unwrap_or(self.filtering)
+    }
+
+    /// The sampler for `texture`'s filtering
+    fn sampler(&self, texture: Texture) -> vk::Sampler {
+        let filtering = self.filtering(texture);
+        self.samplers
+            .iter()
+            .find(|&&(x, _)| x == filtering)
+            .expect("sampler missing for filtering")
+            .1
+    }
+
+    unsafe fn begin_upload(&self, texture: Texture, pixels: &Pixels) -> Upload {
+        let gfx = &*self.gfx;
+        let device = &*gfx.device;
+
+        let size = pixels.rgba.len() as vk::DeviceSize;
+        let staging = device
+            .create_buffer(
+                &vk::BufferCreateInfo::builder()
+                    .size(size)
+                    .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                None,
+            )
+            .unwrap();
+        let reqs = device.get_buffer_memory_requirements(staging);
+        let memory_type = gfx
+            .memory_type(
+                reqs.memory_type_bits,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )
+            .expect("no suitable memory type for texture staging");
+        let staging_memory = device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(reqs.size)
+                    .memory_type_index(memory_type),
+                None,
+            )
+            .unwrap();
+        device
+            .bind_buffer_memory(staging, staging_memory, 0)
+            .unwrap();
+        let mapped = device
+            .map_memory(staging_memory, 0, size, vk::MemoryMapFlags::empty())
+            .unwrap();
+        ptr::copy_nonoverlapping(pixels.rgba.as_ptr(), mapped as *mut u8, pixels.rgba.len());
+        device.unmap_memory(staging_memory);
+
+        let extent = vk::Extent3D {
+            width: pixels.width,
+            height: pixels.height,
+            depth: 1,
+        };
+        let image = device
+            .create_image(
+                &vk::ImageCreateInfo::builder()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(FORMAT)
+                    .extent(extent)
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED),
+                None,
+            )
+            .unwrap();
+        let reqs = device.get_image_memory_requirements(image);
+        let memory_type = gfx
+            .memory_type(reqs.memory_type_bits, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            .expect("no suitable memory type for texture");
+        let memory = device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(reqs.size)
+                    .memory_type_index(memory_type),
+                None,
+            )
+            .unwrap();
+        device.bind_image_memory(image, memory, 0).unwrap();
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let view = device
+            .create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(FORMAT)
+                    .subresource_range(range),
+                None,
+            )
+            .unwrap();
+        let descriptor_set = device
+            .allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(self.descriptor_pool)
+                    .set_layouts(&[self.set_layout]),
+            )
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+        device.update_descriptor_sets(
+            &[vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&[vk::DescriptorImageInfo {
+                    sampler: self.sampler(texture),
+                    image_view: view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                }])
+                .build()],
+            &[],
+        );
+
+        let cmd = device
+            .allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(self.command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(1),
+            )
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+        device
+            .begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )
+            .unwrap();
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::builder()
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .build()],
+        );
+        device.cmd_copy_buffer_to_image(
+            cmd,
+            staging,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                image_extent: extent,
+            }],
+        );
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .build()],
+        );
+        device.end_command_buffer(cmd).unwrap();
+
+        let fence = device.create_fence(&Default::default(), None).unwrap();
+        let result = device.queue_submit(
+            gfx.queue,
+            &[vk::SubmitInfo::builder().command_buffers(&[cmd]).build()],
+            fence,
+        );
+        match result {
+            // Reported by the fence on the next poll
+            Ok(()) | Err(vk::Result::ERROR_DEVICE_LOST) => {}
+            Err(e) => panic!("{}", e),
+        }
+
+        Upload {
+            texture,
+            image: Image {
+                image,
+                memory,
+                view,
+                descriptor_set,
+                alpha: Arc::new(AlphaMask::new(pixels)),
+            },
+            staging,
+            staging_memory,
+            cmd,
+            fence,
+        }
+    }
+}
+
+/// Create a sampler applying `filtering`, within the limits of `gfx`'s device
+///
+/// # Safety
+/// - The result must be destroyed before `gfx`'s device
+unsafe fn create_sampler(gfx: &Graphics, filtering: Filtering) -> vk::Sampler {
+    let anisotropy =
+        if gfx.features.sampler_anisotropy == vk::FALSE || filtering.max_anisotropy <= 1.0 {
+            None
+        } else {
+            Some(
+                filtering
+                    .max_anisotropy
+                    .min(gfx.limits.max_sampler_anisotropy),
+            )
+        };
+    gfx.device
+        .create_sampler(
+            &vk::SamplerCreateInfo::builder()
+                .mag_filter(filtering.mag_filter)
+                .min_filter(filtering.min_filter)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(filtering.address_mode)
+                .address_mode_v(filtering.address_mode)
+                .address_mode_w(filtering.address_mode)
+                .anisotropy_enable(anisotropy.is_some())
+                .max_anisotropy(anisotropy.unwrap_or(1.0)),
+            None,
+        )
+        .unwrap()
+}
+
+/// Create a descriptor set layout compatible with those of `TextureLoader::descriptor_set`
+///
+/// # Safety
+/// - The result must be destroyed before `device`
+pub unsafe fn create_set_layout(device: &Device) -> vk::DescriptorSetLayout {
+    device
+        .create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[
+                vk::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                    ..Default::default()
+                },
+            ]),
+            None,
+        )
+        .unwrap()
+}
+
+struct Pixels {
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Alpha channel of a texture, kept in host memory for hit testing
+pub struct AlphaMask {
+    alpha: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl AlphaMask {
+    fn new(pixels: &Pixels) -> Self {
+        Self::from_rgba(&pixels.rgba, pixels.width, pixels.height)
+    }
+
+    pub fn from_rgba(rgba: &[u8], width: u32, height: u32) -> Self {
+        Self {
+            alpha: rgba.chunks(4).map(|x| x[3]).collect(),
+            width,
+            height,
+        }
+    }
+
+    /// Opacity of the texel nearest `(u, v)`, where `(0, 0)` is the top-left corner of the image
+    /// and `(1, 1)` the bottom-right, clamped to its edges
+    pub fn get(&self, u: f32, v: f32) -> f32 {
+        let texel = |x: f32, size: u32| ((x * size as f32) as u32).min(size - 1);
+        let (x, y) = (
+            texel(u.max(0.0), self.width),
+            texel(v.max(0.0), self.height),
+        );
+        f32::from(self.alpha[(y * self.width + x) as usize]) / 255.0
+    }
+
+    /// Like `get`, but treating coordinates outside the image as a sampler with `address_mode`
+    /// and a transparent border would
+    pub fn sample(&self, u: f32, v: f32, address_mode: vk::SamplerAddressMode) -> f32 {
+        let wrap = |x: f32| match address_mode {
+            vk::SamplerAddressMode::REPEAT => x - x.floor(),
+            // Period of two copies, the second reflected
+            vk::SamplerAddressMode::MIRRORED_REPEAT => {
+                1.0 - (x - 2.0 * (x * 0.5).floor() - 1.0).abs()
+            }
+            _ => x,
+        };
+        let inside = |x: f32| (0.0..=1.0).contains(&x);
+        if address_mode == vk::SamplerAddressMode::CLAMP_TO_BORDER && !(inside(u) && inside(v)) {
+            return 0.0;
+        }
+        self.get(wrap(u), wrap(v))
+    }
+}
+
+struct Upload {
+    texture: Texture,
+    image: Image,
+    staging: vk::Buffer,
+    staging_memory: vk::DeviceMemory,
+    cmd: vk::CommandBuffer,
+    fence: vk::Fence,
+}
+
+impl Upload {
+    /// Free the resources used only for the transfer, which must have completed
+    unsafe fn finish(&self, device: &Device, command_pool: vk::CommandPool) {
+        device.destroy_fence(self.fence, None);
+        device.free_command_buffers(command_pool, &[self.cmd]);
+        device.destroy_buffer(self.staging, None);
+        device.free_memory(self.staging_memory, None);
+    }
+}
+
+struct Image {
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    view: vk::ImageView,
+    descriptor_set: vk::DescriptorSet,
+    alpha: Arc<AlphaMask>,
+}
+
+impl Image {
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+    }
+}
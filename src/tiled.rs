@@ -0,0 +1,556 @@
+//! Loading maps saved by the Tiled editor in its JSON format
+//!
+//! Tile layers fill the `Tilemap`, and object layers spawn static colliders. Only finite maps
+//! with CSV-encoded tile data are supported, on orthogonal or pointy-topped hexagonal grids.
+//! Layers' parallax factors are ignored.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::warn;
+use ncollide2d::shape;
+use serde::{de::IgnoredAny, Deserialize};
+use specs::World;
+
+use crate::sim::{self, Layer};
+use crate::state::WorldScale;
+use crate::tilemap::{Tile, TileGrid, TileShape, Tilemap};
+
+/// Bits of a tile layer's global tile IDs that flip or rotate the tile rather than identify it
+const FLIP_FLAGS: u32 = 0xF000_0000;
+
+/// A map read from a Tiled JSON file
+#[derive(Debug, Deserialize)]
+pub struct TiledMap {
+    orientation: String,
+    #[serde(default)]
+    staggeraxis: Option<String>,
+    #[serde(default)]
+    staggerindex: Option<String>,
+    #[serde(default)]
+    hexsidelength: f32,
+    #[serde(default)]
+    infinite: bool,
+    tilewidth: f32,
+    tileheight: f32,
+    layers: Vec<MapLayer>,
+    #[serde(default)]
+    tilesets: Vec<Tileset>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum MapLayer {
+    TileLayer {
+        width: u32,
+        #[serde(default)]
+        encoding: Option<String>,
+        #[serde(default)]
+        data: TileData,
+        #[serde(default = "visible")]
+        visible: bool,
+        #[serde(flatten)]
+        parallax: LayerParallax,
+    },
+    ObjectGroup {
+        objects: Vec<Object>,
+        #[serde(default = "visible")]
+        visible: bool,
+        #[serde(flatten)]
+        parallax: LayerParallax,
+    },
+    Group {
+        layers: Vec<MapLayer>,
+        #[serde(default = "visible")]
+        visible: bool,
+        #[serde(flatten)]
+        parallax: LayerParallax,
+    },
+    ImageLayer {},
+}
+
+fn visible() -> bool {
+    true
+}
+
+/// Contents of a tile layer, in a form depending on its encoding
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TileData {
+    /// Global tile IDs, row by row, as written for CSV encoding
+    Gids(Vec<u32>),
+    /// Anything else, e.g. base64, possibly compressed, which isn't supported
+    Encoded(IgnoredAny),
+}
+
+impl Default for TileData {
+    fn default() -> Self {
+        TileData::Gids(Vec::new())
+    }
+}
+
+/// Scrolling factors of a layer, as introduced by Tiled 1.5
+///
+/// Tiles fill the `Tilemap` and objects spawn colliders, neither of which can scroll separately
+/// from the rest of the world, so anything but the default of 1 is ignored with a warning.
+#[derive(Debug, Deserialize)]
+struct LayerParallax {
+    #[serde(default = "unit_parallax")]
+    parallaxx: f32,
+    #[serde(default = "unit_parallax")]
+    parallaxy: f32,
+}
+
+impl LayerParallax {
+    fn is_unit(&self) -> bool {
+        self.parallaxx == 1.0 && self.parallaxy == 1.0
+    }
+}
+
+fn unit_parallax() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
+struct Tileset {
+    firstgid: u32,
+    /// Only those with properties or other custom data are listed
+    #[serde(default)]
+    tiles: Vec<TileInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TileInfo {
+    id: u32,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Property {
+    name: String,
+    value: serde_json::Value,
+}
+
+/// A shape placed on an object layer, in pixels with y down
+#[derive(Debug, Deserialize)]
+struct Object {
+    /// Named `class` by Tiled 1.9
+    #[serde(default, rename = "type", alias = "class")]
+    class: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    /// Degrees clockwise about `(x, y)`
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default)]
+    ellipse: bool,
+    #[serde(default)]
+    point: bool,
+    /// Relative to `(x, y)`
+    #[serde(default)]
+    polygon: Option<Vec<ObjectPoint>>,
+    #[serde(default)]
+    gid: Option<u32>,
+    #[serde(default = "visible")]
+    visible: bool,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+struct ObjectPoint {
+    x: f32,
+    y: f32,
+}
+
+/// Read the map saved at `path`
+pub fn load(path: &Path) -> io::Result<TiledMap> {
+    TiledMap::parse(&fs::read_to_string(path)?)
+}
+
+impl TiledMap {
+    /// Parse a map from Tiled JSON, rejecting those using unsupported features
+    pub fn parse(json: &str) -> io::Result<Self> {
+        let map = serde_json::from_str::<Self>(json)?;
+        let unsupported = |what: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported Tiled map: {}", what),
+            ))
+        };
+        match &map.orientation[..] {
+            "orthogonal" => {}
+            "hexagonal" if map.staggeraxis.as_ref().map(String::as_str) == Some("y") => {}
+            "hexagonal" => return unsupported("flat-topped hexagons"),
+            x => return unsupported(&format!("{} orientation", x)),
+        }
+        if map.infinite {
+            return unsupported("infinite map");
+        }
+        let mut layers = map.layers.iter().collect::<Vec<_>>();
+        while let Some(layer) = layers.pop() {
+            match *layer {
+                MapLayer::TileLayer { ref parallax, .. }
+                | MapLayer::ObjectGroup { ref parallax, .. }
+                | MapLayer::Group { ref parallax, .. }
+                    if !parallax.is_unit() =>
+                {
+                    warn!("ignoring layer parallax");
+                }
+                _ => {}
+            }
+            match *layer {
+                MapLayer::TileLayer {
+                    ref encoding,
+                    width,
+                    ref data,
+                    ..
+                } => {
+                    let encoding = encoding.as_ref().map_or("csv", String::as_str);
+                    let data = match (encoding, data) {
+                        ("csv", TileData::Gids(x)) => x,
+                        ("csv", TileData::Encoded(_)) => {
+                            return unsupported("tile data not a list of tile IDs")
+                        }
+                        _ => return unsupported(&format!("{} tile data encoding", encoding)),
+                    };
+                    if width == 0 || data.len() % width as usize != 0 {
+                        return unsupported("tile data not a whole number of rows");
+                    }
+                }
+                MapLayer::Group { layers: ref x, .. } => layers.extend(x),
+                _ => {}
+            }
+        }
+        Ok(map)
+    }
+
+    /// Shape of the map's tiles
+    pub fn shape(&self) -> TileShape {
+        if self.orientation == "hexagonal" {
+            TileShape::Hex
+        } else {
+            TileShape::Square
+        }
+    }
+
+    /// Replace the contents of the world's `Tilemap` with the map's tiles, and spawn a collider
+    /// for each object
+    ///
+    /// Tiles are colored by their tileset's `color` property if set, or otherwise by a shade of
+    /// gray unique to their ID. Objects whose type names a `Layer` are placed on it, and others
+    /// on `Layer::TERRAIN`. Rectangles, ellipses, points, and convex polygons are supported.
+    pub fn spawn(&self, world: &World) {
+        let grid = {
+            let mut tilemap = world.write_resource::<Tilemap>();
+            tilemap.grid.shape = self.shape();
+            tilemap.tiles = self.tiles(&tilemap.grid);
+            tilemap.grid
+        };
+        let ball_radius = world.read_resource::<WorldScale>().ball_radius();
+        let limit = *world.read_resource::<sim::SpawnLimit>();
+        let entities = world.entities();
+        let mut collision = world.write_resource::<sim::CollisionWorld>();
+        let mut owners = world.write_resource::<sim::ColliderOwners>();
+        let mut colliders = world.write_storage::<sim::Collider>();
+        for (layer, position, shape) in self.colliders(&grid, ball_radius) {
+            let spawned = sim::spawn_shape(
+                &entities,
+                &mut collision,
+                &mut owners,
+                &mut colliders,
+                limit,
+                layer,
+                position,
+                shape,
+                sim::TOUCH_CONTACTS,
+            );
+            if spawned.is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Color of each filled tile, with later layers drawn over earlier ones
+    fn tiles(&self, grid: &TileGrid) -> HashMap<Tile, na::Vector4<f32>> {
+        let colors = self
+            .tilesets
+            .iter()
+            .flat_map(|tileset| {
+                tileset.tiles.iter().filter_map(move |tile| {
+                    let property = tile.properties.iter().find(|x| x.name == "color")?;
+                    Some((
+                        tileset.firstgid + tile.id,
+                        parse_color(property.value.as_str()?)?,
+                    ))
+                })
+            })
+            .collect::<HashMap<_, _>>();
+        let mut tiles = HashMap::new();
+        for layer in visible_layers(&self.layers) {
+            let (width, data) = match *layer {
+                MapLayer::TileLayer {
+                    width,
+                    data: TileData::Gids(ref data),
+                    ..
+                } => (width as usize, data),
+                _ => continue,
+            };
+            for (i, &gid) in data.iter().enumerate() {
+                let gid = gid & !FLIP_FLAGS;
+                if gid == 0 {
+                    continue;
+                }
+                let color = colors.get(&gid).cloned().unwrap_or_else(|| {
+                    // Spread consecutive IDs far apart
+                    let shade = 0.1 + 0.3 * (gid as f32 * 0.618_034).fract();
+                    na::Vector4::new(shade, shade, shade, 1.0)
+                });
+                tiles.insert(self.tile(grid, i % width, i / width), color);
+            }
+        }
+        tiles
+    }
+
+    /// The cell of `grid` corresponding to the map's `column` and `row`, counted from the top left
+    fn tile(&self, grid: &TileGrid, column: usize, row: usize) -> Tile {
+        grid.from_offset(column as i32, -(row as i32) - self.stagger_offset())
+    }
+
+    /// Rows to shift the map by to align its staggered rows with `TileGrid::from_offset`'s
+    fn stagger_offset(&self) -> i32 {
+        // Negating a row preserves its parity, and `from_offset` staggers odd rows
+        match self.staggerindex.as_ref().map(String::as_str) {
+            Some("even") if self.shape() == TileShape::Hex => 1,
+            _ => 0,
+        }
+    }
+
+    /// World-space position of a point in the map's pixel coordinates
+    ///
+    /// The center of the top-left tile lies at the center of `grid`'s origin tile.
+    fn to_world(&self, grid: &TileGrid, x: f32, y: f32) -> na::Point2<f32> {
+        let (x, y) = (x - self.tilewidth * 0.5, y - self.tileheight * 0.5);
+        match self.shape() {
+            TileShape::Square => {
+                na::Point2::new(x / self.tilewidth, -y / self.tileheight) * grid.size
+            }
+            TileShape::Hex => {
+                let row_height = (self.tileheight + self.hexsidelength) * 0.5;
+                let row = y / row_height + self.stagger_offset() as f32;
+                na::Point2::new(3.0f32.sqrt() * x / self.tilewidth, -1.5 * row) * grid.size
+            }
+        }
+    }
+
+    /// Layer, position, and shape of a collider for each supported object
+    fn colliders(
+        &self,
+        grid: &TileGrid,
+        point_radius: f32,
+    ) -> Vec<(Layer, na::Isometry2<f32>, shape::ShapeHandle<f32>)> {
+        let mut result = Vec::new();
+        for layer in visible_layers(&self.layers) {
+            let objects = match *layer {
+                MapLayer::ObjectGroup { ref objects, .. } => objects,
+                _ => continue,
+            };
+            for object in objects.iter().filter(|x| x.visible) {
+                let layer = match &object.class.to_lowercase()[..] {
+                    "actor" => Layer::ACTOR,
+                    "projectile" => Layer::PROJECTILE,
+                    "trigger" => Layer::TRIGGER,
+                    _ => Layer::TERRAIN,
+                };
+                // Shapes are defined in pixels relative to `(x, y)`, rotated with the object
+                let rotation = na::UnitComplex::new(object.rotation.to_radians());
+                let to_world = |offset: na::Vector2<f32>| {
+                    let offset = rotation * offset;
+                    self.to_world(grid, object.x + offset.x, object.y + offset.y)
+                };
+                let origin = to_world(na::zero());
+                // Reflecting y turns clockwise rotations into counterclockwise ones
+                let position = |center: na::Point2<f32>| {
+                    na::Isometry2::new(center.coords, -object.rotation.to_radians())
+                };
+                let half_size = na::Vector2::new(object.width, object.height) * 0.5;
+                let center = to_world(half_size);
+                // World-space size of the object's bounds, ignoring rotation
+                let extents = (to_world(na::Vector2::new(object.width, 0.0)) - origin).norm()
+                    * na::Vector2::x()
+                    + (to_world(na::Vector2::new(0.0, object.height)) - origin).norm()
+                        * na::Vector2::y();
+                let collider = if object.gid.is_some() {
+                    warn!("ignoring tile object");
+                    continue;
+                } else if object.point {
+                    (
+                        position(origin),
+                        shape::ShapeHandle::new(shape::Ball::new(point_radius)),
+                    )
+                } else if let Some(ref polygon) = object.polygon {
+                    let points = polygon
+                        .iter()
+                        .map(|p| na::Point2::from(to_world(na::Vector2::new(p.x, p.y)) - origin))
+                        .collect::<Vec<_>>();
+                    match shape::ConvexPolygon::try_from_points(&points) {
+                        Some(x) => (
+                            na::Isometry2::new(origin.coords, 0.0),
+                            shape::ShapeHandle::new(x),
+                        ),
+                        None => {
+                            warn!("ignoring degenerate polygon object");
+                            continue;
+                        }
+                    }
+                } else if object.ellipse {
+                    // Approximated by the circle of the same mean radius
+                    let radius = (extents.x + extents.y) * 0.25;
+                    (
+                        position(center),
+                        shape::ShapeHandle::new(shape::Ball::new(radius)),
+                    )
+                } else {
+                    (
+                        position(center),
+                        shape::ShapeHandle::new(shape::Cuboid::new(extents * 0.5)),
+                    )
+                };
+                result.push((layer, collider.0, collider.1));
+            }
+        }
+        result
+    }
+}
+
+/// Every visible layer in `layers`, including those nested in visible groups, bottom-most first
+fn visible_layers(layers: &[MapLayer]) -> Vec<&MapLayer> {
+    let mut result = Vec::new();
+    for layer in layers {
+        match *layer {
+            MapLayer::TileLayer { visible: false, .. }
+            | MapLayer::ObjectGroup { visible: false, .. }
+            | MapLayer::Group { visible: false, .. } => {}
+            MapLayer::Group { ref layers, .. } => result.extend(visible_layers(layers)),
+            _ => result.push(layer),
+        }
+    }
+    result
+}
+
+/// Linear RGBA of a Tiled color property, formatted `#AARRGGBB` or `#RRGGBB` in sRGB
+fn parse_color(color: &str) -> Option<na::Vector4<f32>> {
+    if !color.starts_with('#') {
+        return None;
+    }
+    let hex = &color[1..];
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    let alpha = match hex.len() {
+        6 => 0xFF,
+        8 => value >> 24,
+        _ => return None,
+    };
+    let channel = |shift: u32| {
+        let x = ((value >> shift) & 0xFF) as f32 / 255.0;
+        if x <= 0.04045 {
+            x / 12.92
+        } else {
+            ((x + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Some(na::Vector4::new(
+        channel(16),
+        channel(8),
+        channel(0),
+        alpha as f32 / 255.0,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r##"{
+        "orientation": "orthogonal",
+        "width": 3,
+        "height": 2,
+        "tilewidth": 16,
+        "tileheight": 16,
+        "infinite": false,
+        "layers": [
+            {"type": "tilelayer", "width": 3, "height": 2, "data": [1, 0, 2, 0, 0, 1]},
+            {"type": "group", "layers": [
+                {"type": "tilelayer", "width": 3, "height": 2, "data": [0, 0, 0, 0, 0, 2]}
+            ]},
+            {"type": "tilelayer", "width": 3, "height": 2, "data": [2, 2, 2, 2, 2, 2],
+             "visible": false},
+            {"type": "objectgroup", "objects": [
+                {"id": 1, "type": "", "x": 0, "y": 0, "width": 32, "height": 16},
+                {"id": 2, "class": "trigger", "x": 16, "y": 16, "width": 0, "height": 0,
+                 "point": true}
+            ]}
+        ],
+        "tilesets": [
+            {"firstgid": 1, "tiles": [
+                {"id": 1, "properties": [{"name": "color", "type": "color", "value": "#ffff0000"}]}
+            ]}
+        ]
+    }"##;
+
+    const GRID: TileGrid = TileGrid {
+        shape: TileShape::Square,
+        size: 2.0,
+    };
+
+    #[test]
+    fn tiles() {
+        let map = TiledMap::parse(MAP).unwrap();
+        let tiles = map.tiles(&GRID);
+        assert_eq!(tiles.len(), 3);
+        // Rows run down the map, and up the grid
+        assert!(tiles.contains_key(&Tile::new(0, 0)));
+        assert_eq!(
+            tiles[&Tile::new(2, 0)],
+            na::Vector4::new(1.0, 0.0, 0.0, 1.0)
+        );
+        // Overwritten by the later layer
+        assert_eq!(tiles[&Tile::new(2, -1)], tiles[&Tile::new(2, 0)]);
+    }
+
+    #[test]
+    fn colliders() {
+        let map = TiledMap::parse(MAP).unwrap();
+        let colliders = map.colliders(&GRID, 0.5);
+        assert_eq!(colliders.len(), 2);
+        let (layer, position, ref shape) = colliders[0];
+        assert_eq!(layer, Layer::TERRAIN);
+        // Spans the two top-left tiles, whose centers are at x = 0 and x = 2
+        assert!((position.translation.vector - na::Vector2::new(1.0, 0.0)).norm() < 1e-5);
+        let cuboid = shape.as_shape::<shape::Cuboid<f32>>().unwrap();
+        assert_eq!(*cuboid.half_extents(), na::Vector2::new(2.0, 1.0));
+        let (layer, position, _) = colliders[1];
+        assert_eq!(layer, Layer::TRIGGER);
+        // The corner shared by the top-left tile and the one diagonally below it
+        assert!((position.translation.vector - na::Vector2::new(1.0, -1.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn unsupported() {
+        let infinite = MAP.replace("\"infinite\": false", "\"infinite\": true");
+        assert!(TiledMap::parse(&infinite).is_err());
+        let isometric = MAP.replace("orthogonal", "isometric");
+        assert!(TiledMap::parse(&isometric).is_err());
+        let base64 = MAP.replace(
+            r#""data": [1, 0, 2, 0, 0, 1]"#,
+            r#""encoding": "base64", "data": "AQAAAAAAAAACAAAAAAAAAAAAAAABAAAA""#,
+        );
+        let error = TiledMap::parse(&base64).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("base64 tile data encoding"));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use crate::state::WorldScale;
+
+const SQRT_3: f32 = 1.732_050_8;
+
+/// Shape of the cells of a `TileGrid`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TileShape {
+    Square,
+    /// Pointy-topped hexagons
+    Hex,
+}
+
+/// Coordinates of a cell in a `TileGrid`
+///
+/// On square grids these are the column and row. On hex grids they're axial coordinates: `q`
+/// increases to the east and `r` to the north-east, so `q + r` is constant along a north-west
+/// diagonal.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Tile {
+    pub q: i32,
+    pub r: i32,
+}
+
+impl Tile {
+    pub fn new(q: i32, r: i32) -> Self {
+        Self { q, r }
+    }
+}
+
+const SQUARE_NEIGHBORS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+/// Counterclockwise from the east
+const HEX_NEIGHBORS: [(i32, i32); 6] = [(1, 0), (0, 1), (-1, 1), (-1, 0), (0, -1), (1, -1)];
+
+/// Mapping between world space and the cells of a regular tiling centered on the origin
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TileGrid {
+    pub shape: TileShape,
+    /// Side length of a square, or distance from the center of a hexagon to its corners
+    pub size: f32,
+}
+
+impl TileGrid {
+    /// World-space center of `tile`
+    pub fn center(&self, tile: Tile) -> na::Point2<f32> {
+        let (q, r) = (tile.q as f32, tile.r as f32);
+        match self.shape {
+            TileShape::Square => na::Point2::new(q, r) * self.size,
+            TileShape::Hex => na::Point2::new(SQRT_3 * (q + r * 0.5), 1.5 * r) * self.size,
+        }
+    }
+
+    /// The tile containing `point`
+    pub fn tile_at(&self, point: &na::Point2<f32>) -> Tile {
+        let p = point.coords / self.size;
+        match self.shape {
+            TileShape::Square => Tile::new(p.x.round() as i32, p.y.round() as i32),
+            TileShape::Hex => {
+                let r = p.y * (2.0 / 3.0);
+                let q = p.x / SQRT_3 - r * 0.5;
+                hex_round(q, r)
+            }
+        }
+    }
+
+    /// Vertices of `tile`'s outline in world space, counterclockwise
+    pub fn corners(&self, tile: Tile) -> Vec<na::Point2<f32>> {
+        let center = self.center(tile);
+        match self.shape {
+            TileShape::Square => {
+                let h = self.size * 0.5;
+                vec![
+                    center + na::Vector2::new(h, -h),
+                    center + na::Vector2::new(h, h),
+                    center + na::Vector2::new(-h, h),
+                    center + na::Vector2::new(-h, -h),
+                ]
+            }
+            TileShape::Hex => (0..6)
+                .map(|i| {
+                    let angle = std::f32::consts::PI / 3.0 * (i as f32 + 0.5);
+                    center + na::Vector2::new(angle.cos(), angle.sin()) * self.size
+                })
+                .collect(),
+        }
+    }
+
+    /// Tiles sharing an edge with `tile`, counterclockwise from the east
+    pub fn neighbors(&self, tile: Tile) -> impl Iterator<Item = Tile> {
+        let offsets: &'static [(i32, i32)] = match self.shape {
+            TileShape::Square => &SQUARE_NEIGHBORS,
+            TileShape::Hex => &HEX_NEIGHBORS,
+        };
+        offsets
+            .iter()
+            .map(move |&(dq, dr)| Tile::new(tile.q + dq, tile.r + dr))
+    }
+
+    /// Number of steps between neighbors needed to get from `a` to `b`
+    pub fn distance(&self, a: Tile, b: Tile) -> u32 {
+        let (dq, dr) = (a.q - b.q, a.r - b.r);
+        match self.shape {
+            TileShape::Square => (dq.abs() + dr.abs()) as u32,
+            TileShape::Hex => ((dq.abs() + dr.abs() + (dq + dr).abs()) / 2) as u32,
+        }
+    }
+
+    /// Column and row of `tile` in a layout where each row is offset from the last
+    ///
+    /// On hex grids, odd rows are shifted half a tile east. On square grids this is the identity.
+    pub fn to_offset(&self, tile: Tile) -> (i32, i32) {
+        match self.shape {
+            TileShape::Square => (tile.q, tile.r),
+            TileShape::Hex => (tile.q + (tile.r - (tile.r & 1)) / 2, tile.r),
+        }
+    }
+
+    /// Inverse of `to_offset`
+    pub fn from_offset(&self, column: i32, row: i32) -> Tile {
+        match self.shape {
+            TileShape::Square => Tile::new(column, row),
+            TileShape::Hex => Tile::new(column - (row - (row & 1)) / 2, row),
+        }
+    }
+}
+
+/// Round fractional axial coordinates to the hexagon containing them
+fn hex_round(q: f32, r: f32) -> Tile {
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+    // The coordinates must sum to zero, so recompute whichever was rounded furthest
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+    Tile::new(rq as i32, rr as i32)
+}
+
+/// Colored tiles drawn beneath sprites
+pub struct Tilemap {
+    pub grid: TileGrid,
+    /// Color of each filled tile, in linear RGBA
+    pub tiles: HashMap<Tile, na::Vector4<f32>>,
+}
+
+impl Tilemap {
+    pub fn new(scale: WorldScale) -> Self {
+        Self {
+            grid: TileGrid {
+                shape: TileShape::Square,
+                size: scale.tile_size(),
+            },
+            tiles: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRIDS: [TileGrid; 2] = [
+        TileGrid {
+            shape: TileShape::Square,
+            size: 2.0,
+        },
+        TileGrid {
+            shape: TileShape::Hex,
+            size: 2.0,
+        },
+    ];
+
+    #[test]
+    fn center_round_trip() {
+        for grid in &GRIDS {
+            for q in -5..5 {
+                for r in -5..5 {
+                    let tile = Tile::new(q, r);
+                    assert_eq!(grid.tile_at(&grid.center(tile)), tile);
+                    for corner in grid.corners(tile) {
+                        // Points just inside a corner belong to the tile
+                        let inside = corner + (grid.center(tile) - corner) * 0.05;
+                        assert_eq!(grid.tile_at(&inside), tile);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neighbors() {
+        for grid in &GRIDS {
+            let tile = Tile::new(3, -2);
+            let center = grid.center(tile);
+            let step = na::distance(&center, &grid.center(grid.neighbors(tile).next().unwrap()));
+            for neighbor in grid.neighbors(tile) {
+                assert_eq!(grid.distance(tile, neighbor), 1);
+                assert!((na::distance(&center, &grid.center(neighbor)) - step).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn hex_distance() {
+        let grid = GRIDS[1];
+        assert_eq!(grid.distance(Tile::new(0, 0), Tile::new(2, -1)), 2);
+        assert_eq!(grid.distance(Tile::new(0, 0), Tile::new(-3, 3)), 3);
+        assert_eq!(grid.distance(Tile::new(1, 1), Tile::new(-1, -1)), 4);
+    }
+
+    #[test]
+    fn offset_round_trip() {
+        for grid in &GRIDS {
+            for q in -5..5 {
+                for r in -5..5 {
+                    let tile = Tile::new(q, r);
+                    let (column, row) = grid.to_offset(tile);
+                    assert_eq!(grid.from_offset(column, row), tile);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use std::ffi::CStr;
+use std::fs::File;
 #[cfg(target_os = "macos")]
 use std::mem;
 use std::ops::Drop;
+use std::path::Path;
 use std::sync::Arc;
 
 #[cfg(target_os = "macos")]
@@ -22,6 +24,7 @@ use ash::extensions::khr::{Surface, Swapchain};
 use ash::extensions::mvk::MacOSSurface;
 use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
 use ash::vk;
+use log::{debug, info};
 
 use crate::graphics::{Core, Graphics};
 
@@ -30,6 +33,36 @@ pub struct Window {
     pub window: winit::Window,
     surface_loader: Surface,
     surface: vk::SurfaceKHR,
+    constraints: SizeConstraints,
+}
+
+/// Limits on the inner size a `Window` can be resized to
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SizeConstraints {
+    pub min: Option<winit::dpi::LogicalSize>,
+    /// Width divided by height
+    pub aspect: Option<f64>,
+}
+
+impl SizeConstraints {
+    /// The size nearest `size` satisfying the constraints, preserving its width where possible
+    pub fn apply(&self, size: winit::dpi::LogicalSize) -> winit::dpi::LogicalSize {
+        let (mut width, mut height) = (size.width, size.height);
+        if let Some(aspect) = self.aspect {
+            height = width / aspect;
+        }
+        if let Some(min) = self.min {
+            if width < min.width {
+                width = min.width;
+                height = self.aspect.map_or(height, |aspect| width / aspect);
+            }
+            if height < min.height {
+                height = min.height;
+                width = self.aspect.map_or(width, |aspect| height * aspect);
+            }
+        }
+        winit::dpi::LogicalSize::new(width, height)
+    }
 }
 
 impl Drop for Window {
@@ -51,12 +84,33 @@ impl Window {
         vec![Surface::name(), x]
     }
 
-    pub fn new(events_loop: &winit::EventsLoop, core: Arc<Core>, size: winit::dpi::LogicalSize) -> Self {
-        let window = winit::WindowBuilder::new()
-            .with_title("rustlike")
-            .with_dimensions(size)
-            .build(&events_loop)
-            .unwrap();
+    pub fn new(
+        events_loop: &winit::EventsLoop,
+        core: Arc<Core>,
+        title: &str,
+        size: winit::dpi::LogicalSize,
+    ) -> Self {
+        Self::with_constraints(events_loop, core, title, size, SizeConstraints::default())
+    }
+
+    /// Create a window whose size is held to `constraints`, adjusting `size` to fit if necessary
+    ///
+    /// The minimum is enforced by the window system, while the aspect ratio is restored after each
+    /// resize by `constrain_resize`.
+    pub fn with_constraints(
+        events_loop: &winit::EventsLoop,
+        core: Arc<Core>,
+        title: &str,
+        size: winit::dpi::LogicalSize,
+        constraints: SizeConstraints,
+    ) -> Self {
+        let mut builder = winit::WindowBuilder::new()
+            .with_title(title)
+            .with_dimensions(constraints.apply(size));
+        if let Some(min) = constraints.min {
+            builder = builder.with_min_dimensions(min);
+        }
+        let window = builder.build(&events_loop).unwrap();
 
         unsafe {
             let surface = create_surface(&core.entry, &core.instance, &window).unwrap();
@@ -67,10 +121,51 @@ impl Window {
                 window,
                 surface_loader,
                 surface,
+                constraints,
             }
         }
     }
 
+    /// Handle the window being resized to `size`, returning the size it should be treated as
+    ///
+    /// Sizes violating the window's constraints are replaced with the nearest that doesn't, and
+    /// the window is resized to match.
+    pub fn constrain_resize(&self, size: winit::dpi::LogicalSize) -> winit::dpi::LogicalSize {
+        let constrained = self.constraints.apply(size);
+        // Tolerate rounding to whole pixels, lest the correction trigger another
+        if (constrained.width - size.width).abs() < 1.0
+            && (constrained.height - size.height).abs() < 1.0
+        {
+            return size;
+        }
+        self.window.set_inner_size(constrained);
+        constrained
+    }
+
+    /// Physical pixels per logical pixel on the monitor the window currently occupies
+    pub fn dpi_factor(&self) -> f64 {
+        self.window.get_hidpi_factor()
+    }
+
+    /// Current size of the window's drawable area in physical pixels, or zero if it's been closed
+    pub fn physical_size(&self) -> winit::dpi::PhysicalSize {
+        self.window
+            .get_inner_size()
+            .map_or(winit::dpi::PhysicalSize::new(0.0, 0.0), |x| {
+                x.to_physical(self.dpi_factor())
+            })
+    }
+
+    /// Set the icon shown in the titlebar and taskbar from 8-bit RGBA pixels
+    ///
+    /// Has no effect on macOS, where the application bundle's icon is used instead. Windows
+    /// scales the icon to 32x32 for the titlebar and taskbar alike, so that size is recommended.
+    pub fn set_icon(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), winit::BadIcon> {
+        let icon = winit::Icon::from_rgba(rgba, width, height)?;
+        self.window.set_window_icon(Some(icon));
+        Ok(())
+    }
+
     pub fn supports(&self, physical: vk::PhysicalDevice, queue_family_index: u32) -> bool {
         unsafe {
             self.surface_loader.get_physical_device_surface_support(
@@ -82,6 +177,33 @@ impl Window {
     }
 }
 
+/// Decode a PNG image into 8-bit RGBA pixels, returning them along with its width and height
+pub fn read_png(path: &Path) -> Result<(Vec<u8>, u32, u32), png::DecodingError> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+    let rgba = match info.color_type {
+        png::ColorType::RGBA => buf,
+        png::ColorType::RGB => buf
+            .chunks(3)
+            .flat_map(|x| x.iter().cloned().chain(Some(0xFF)))
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks(2)
+            .flat_map(|x| vec![x[0], x[0], x[0], x[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&x| vec![x, x, x, 0xFF]).collect(),
+        png::ColorType::Indexed => {
+            return Err(png::DecodingError::Format(
+                "indexed color survived expansion".into(),
+            ));
+        }
+    };
+    Ok((rgba, info.width, info.height))
+}
+
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
 unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     entry: &E,
@@ -157,26 +279,72 @@ unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     win32_surface_loader.create_win32_surface(&win32_create_info, None)
 }
 
+/// Surface formats used for HDR output, in order of preference
+///
+/// Linear extended SRGB can be rendered to directly, while HDR10 needs its output encoded by
+/// `Render`.
+const HDR_FORMATS: [vk::SurfaceFormatKHR; 2] = [
+    vk::SurfaceFormatKHR {
+        format: vk::Format::R16G16B16A16_SFLOAT,
+        color_space: vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+    },
+    vk::SurfaceFormatKHR {
+        format: vk::Format::A2B10G10R10_UNORM_PACK32,
+        color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+    },
+];
+
 pub struct SwapchainMgr {
     format: vk::SurfaceFormatKHR,
+    /// Usage requested for images in addition to `COLOR_ATTACHMENT`
+    extra_usage: vk::ImageUsageFlags,
+    /// Used where supported, otherwise FIFO
+    present_mode: vk::PresentModeKHR,
     state: SwapchainState,
 }
 
 impl SwapchainMgr {
-    pub fn new(window: Arc<Window>, gfx: Arc<Graphics>) -> Self {
+    /// Create a swapchain whose images can also be copied from where the surface allows
+    ///
+    /// If `hdr` is set, an HDR format is used where the surface offers one.
+    pub fn new(window: Arc<Window>, gfx: Arc<Graphics>, hdr: bool) -> Self {
+        Self::with_usage(window, gfx, vk::ImageUsageFlags::TRANSFER_SRC, hdr)
+    }
+
+    /// Create a swapchain whose images have `extra_usage` in addition to `COLOR_ATTACHMENT`,
+    /// except for any the surface doesn't support
+    pub fn with_usage(
+        window: Arc<Window>,
+        gfx: Arc<Graphics>,
+        extra_usage: vk::ImageUsageFlags,
+        hdr: bool,
+    ) -> Self {
         let surface_formats = unsafe {
             window
                 .surface_loader
                 .get_physical_device_surface_formats(gfx.physical, window.surface)
                 .unwrap()
         };
-        let desired_format = vk::SurfaceFormatKHR {
+        let hdr_format = if hdr {
+            HDR_FORMATS.iter().cloned().find(|x| {
+                surface_formats
+                    .iter()
+                    .any(|y| y.format == x.format && y.color_space == x.color_space)
+            })
+        } else {
+            None
+        };
+        if hdr && hdr_format.is_none() {
+            info!("no HDR surface format available, falling back to SRGB");
+        }
+        let desired_format = hdr_format.unwrap_or(vk::SurfaceFormatKHR {
             format: vk::Format::B8G8R8A8_SRGB,
             color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-        };
-        if (surface_formats.len() != 1
-            || (surface_formats[0].format != vk::Format::UNDEFINED
-                || surface_formats[0].color_space != desired_format.color_space))
+        });
+        if hdr_format.is_none()
+            && (surface_formats.len() != 1
+                || (surface_formats[0].format != vk::Format::UNDEFINED
+                    || surface_formats[0].color_space != desired_format.color_space))
             && surface_formats.iter().all(|x| {
                 x.format != desired_format.format || x.color_space != desired_format.color_space
             })
@@ -185,8 +353,19 @@ impl SwapchainMgr {
         }
 
         Self {
-            state: unsafe { SwapchainState::new(window, gfx, desired_format, None) },
+            state: unsafe {
+                SwapchainState::new(
+                    window,
+                    gfx,
+                    desired_format,
+                    extra_usage,
+                    vk::PresentModeKHR::MAILBOX,
+                    None,
+                )
+            },
             format: desired_format,
+            extra_usage,
+            present_mode: vk::PresentModeKHR::MAILBOX,
         }
     }
 
@@ -199,10 +378,34 @@ impl SwapchainMgr {
             self.state.window.clone(),
             self.state.gfx.clone(),
             self.format,
+            self.extra_usage,
+            self.present_mode,
             Some(&self.state),
         );
     }
 
+    /// Prefer `mode` from the next `update` on, e.g. `IMMEDIATE` to present without waiting for
+    /// vertical blank
+    pub fn set_present_mode(&mut self, mode: vk::PresentModeKHR) {
+        self.present_mode = mode;
+    }
+
+    /// Mode preferred by the most recent `set_present_mode`, or `MAILBOX`
+    pub fn preferred_present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    /// Mode the current swapchain presents with, which is `FIFO` if the preferred mode is
+    /// unsupported
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.state.present_mode
+    }
+
+    /// Number of images in the current swapchain, which may exceed the number requested
+    pub fn image_count(&self) -> u32 {
+        self.state.frames.len() as u32
+    }
+
     pub unsafe fn acquire_next_image(
         &self,
         signal_sem: vk::Semaphore,
@@ -234,9 +437,52 @@ impl SwapchainMgr {
         self.state.extent
     }
 
+    /// Whether the surface's current extent differs from the swapchain's, without waiting for
+    /// acquisition or presentation to report it
+    ///
+    /// Surfaces whose extent is determined by the swapchain, or which currently have no area, are
+    /// never considered stale.
+    pub fn is_stale(&self) -> bool {
+        let window = &self.state.window;
+        let current = unsafe {
+            window
+                .surface_loader
+                .get_physical_device_surface_capabilities(self.state.gfx.physical, window.surface)
+                .unwrap()
+                .current_extent
+        };
+        if current.width == std::u32::MAX || current.width == 0 || current.height == 0 {
+            return false;
+        }
+        current.width != self.state.extent.width || current.height != self.state.extent.height
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format.format
+    }
+
+    pub fn surface_format(&self) -> vk::SurfaceFormatKHR {
+        self.format
+    }
+
+    /// Whether images are presented in an HDR color space
+    pub fn is_hdr(&self) -> bool {
+        self.format.color_space != vk::ColorSpaceKHR::SRGB_NONLINEAR
+    }
+
     pub fn frames(&self) -> &[Frame] {
         &self.state.frames
     }
+
+    /// Usage the current images were created with
+    pub fn usage(&self) -> vk::ImageUsageFlags {
+        self.state.usage
+    }
+
+    /// Whether images can be copied from, e.g. for `FrameCapture`
+    pub fn readable(&self) -> bool {
+        self.state.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC)
+    }
 }
 
 struct SwapchainState {
@@ -246,6 +492,8 @@ struct SwapchainState {
     handle: vk::SwapchainKHR,
     loader: Arc<Swapchain>,
     frames: Vec<Frame>,
+    usage: vk::ImageUsageFlags,
+    present_mode: vk::PresentModeKHR,
 }
 
 impl SwapchainState {
@@ -253,6 +501,8 @@ impl SwapchainState {
         window: Arc<Window>,
         gfx: Arc<Graphics>,
         format: vk::SurfaceFormatKHR,
+        extra_usage: vk::ImageUsageFlags,
+        preferred_present_mode: vk::PresentModeKHR,
         old: Option<&Self>,
     ) -> Self {
         let device = &*gfx.device;
@@ -291,8 +541,23 @@ impl SwapchainState {
         let present_mode = present_modes
             .iter()
             .cloned()
-            .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
+            .find(|&mode| mode == preferred_present_mode)
             .unwrap_or(vk::PresentModeKHR::FIFO);
+        if present_mode != preferred_present_mode {
+            debug!(
+                "surface doesn't support present mode {:?}, using {:?}",
+                preferred_present_mode, present_mode
+            );
+        }
+
+        let supported = extra_usage & capabilities.supported_usage_flags;
+        if supported != extra_usage {
+            debug!(
+                "surface doesn't support swapchain image usage {:?}",
+                extra_usage & !supported
+            );
+        }
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | supported;
 
         let image_count = if capabilities.max_image_count > 0 {
             capabilities
@@ -310,7 +575,7 @@ impl SwapchainState {
                     .image_color_space(format.color_space)
                     .image_format(format.format)
                     .image_extent(extent)
-                    .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+                    .image_usage(usage)
                     .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
                     .pre_transform(pre_transform)
                     .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
@@ -349,6 +614,7 @@ impl SwapchainState {
                         None,
                     )
                     .unwrap();
+                gfx.set_name(image, cstr!("swapchain image"));
                 Frame { image, view }
             })
             .collect();
@@ -360,6 +626,8 @@ impl SwapchainState {
             handle,
             loader,
             frames,
+            usage,
+            present_mode,
         }
     }
 }